@@ -0,0 +1,145 @@
+//! Domain of the From header, the one signatures are checked against
+
+use crate::DKIMError;
+use mailparse::{MailAddr, MailHeaderMap};
+
+/// Domain of the author of the email (RFC 5322 From), lowercase and
+/// IDNA-normalized to its A-label. Display names, comments, group syntax and
+/// RFC 2047 encoded-words are handled by mailparse's address parser; several
+/// addresses are accepted if they all share the same domain.
+pub fn extract_from_domain(email: &mailparse::ParsedMail) -> Result<String, DKIMError> {
+    let mut domains: Vec<String> = vec![];
+    for header in email.headers.get_all_headers("From") {
+        let addrs = mailparse::addrparse_header(header).map_err(|err| {
+            DKIMError::FromDomainNotFound(format!("invalid From header: {}", err))
+        })?;
+        for addr in addrs.iter() {
+            let singles = match addr {
+                MailAddr::Single(info) => std::slice::from_ref(info),
+                MailAddr::Group(group) => &group.addrs[..],
+            };
+            for info in singles {
+                let domain = address_domain(&info.addr)?;
+                if !domains.contains(&domain) {
+                    domains.push(domain);
+                }
+            }
+        }
+    }
+
+    match domains.len() {
+        0 => Err(DKIMError::FromDomainNotFound(
+            "no address in the From header".to_owned(),
+        )),
+        1 => Ok(domains.remove(0)),
+        _ => Err(DKIMError::MultipleFromDomains(domains)),
+    }
+}
+
+/// Same as [extract_from_domain] with the raw bytes of the email
+pub fn extract_from_domain_raw(email_bytes: &[u8]) -> Result<String, DKIMError> {
+    let email = mailparse::parse_mail(email_bytes)
+        .map_err(|err| DKIMError::SignatureSyntaxError(err.to_string()))?;
+    extract_from_domain(&email)
+}
+
+fn address_domain(addr: &str) -> Result<String, DKIMError> {
+    let domain = match addr.rsplit_once('@') {
+        Some((_, domain)) if !domain.is_empty() => domain,
+        _ => {
+            return Err(DKIMError::FromDomainNotFound(format!(
+                "no domain in the address {}",
+                addr
+            )))
+        }
+    };
+    let domain = domain.strip_suffix('.').unwrap_or(domain);
+    idna::domain_to_ascii(domain).map_err(|_| {
+        DKIMError::FromDomainNotFound(format!("invalid domain in the address {}", addr))
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn from_domain(from: &str) -> Result<String, DKIMError> {
+        extract_from_domain_raw(format!("{}\r\nSubject: Hi\r\n\r\nHello\r\n", from).as_bytes())
+    }
+
+    #[test]
+    fn test_extract_from_domain() {
+        assert_eq!(
+            from_domain("From: Alice <alice@Example.COM>").unwrap(),
+            "example.com"
+        );
+        assert_eq!(
+            from_domain("From: alice@example.com").unwrap(),
+            "example.com"
+        );
+        // Quoted display names with an address, and comments
+        assert_eq!(
+            from_domain("From: \"bob@attacker.example\" <alice@example.com>").unwrap(),
+            "example.com"
+        );
+        assert_eq!(
+            from_domain("From: \"Smith, Alice (Sales)\" <alice@example.com>").unwrap(),
+            "example.com"
+        );
+        assert_eq!(
+            from_domain("From: alice@example.com (bob@attacker.example)").unwrap(),
+            "example.com"
+        );
+        // Encoded-word display name and folded header
+        assert_eq!(
+            from_domain("From: =?utf-8?q?bob=40attacker.example?=\r\n <alice@example.com>")
+                .unwrap(),
+            "example.com"
+        );
+        // Group syntax and several addresses of the same domain
+        assert_eq!(
+            from_domain("From: Team: alice@example.com, bob@EXAMPLE.com;").unwrap(),
+            "example.com"
+        );
+        // Internationalized domain
+        assert_eq!(
+            from_domain("From: =?utf-8?q?J=C3=BCrgen?= <j@xn--bcher-kva.example>").unwrap(),
+            "xn--bcher-kva.example"
+        );
+        let email = "From: j@b\u{fc}cher.example\r\n\r\nHello\r\n";
+        assert_eq!(
+            extract_from_domain_raw(email.as_bytes()).unwrap(),
+            "xn--bcher-kva.example"
+        );
+    }
+
+    #[test]
+    fn test_extract_from_domain_errors() {
+        assert_eq!(
+            from_domain("From: Alice <alice@example.com>, Bob <bob@example.net>").unwrap_err(),
+            DKIMError::MultipleFromDomains(vec![
+                "example.com".to_owned(),
+                "example.net".to_owned()
+            ])
+        );
+        assert_eq!(
+            from_domain("From: alice@example.com\r\nFrom: bob@example.net").unwrap_err(),
+            DKIMError::MultipleFromDomains(vec![
+                "example.com".to_owned(),
+                "example.net".to_owned()
+            ])
+        );
+        assert!(matches!(
+            from_domain("To: alice@example.com"),
+            Err(DKIMError::FromDomainNotFound(_))
+        ));
+        assert!(matches!(
+            from_domain("From: Undisclosed:;"),
+            Err(DKIMError::FromDomainNotFound(_))
+        ));
+        assert!(matches!(
+            from_domain("From: alice"),
+            Err(DKIMError::FromDomainNotFound(_))
+        ));
+    }
+}
@@ -0,0 +1,288 @@
+//! DMARC identifier alignment (RFC 7489 section 3.1.1) of a verified
+//! signature with the From domain
+
+use crate::{AlignmentMode, DKIMResult, Summary};
+
+/// Identifier of the signature aligned with the From domain
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "serde", serde(rename_all = "snake_case"))]
+pub enum AlignedIdentifier {
+    /// The signing domain (d=)
+    SigningDomain,
+    /// The domain of the agent or user identifier (i=)
+    Identity,
+}
+
+/// Result of [check_alignment]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct AlignmentResult {
+    /// Mode the identifiers were compared with
+    pub mode: AlignmentMode,
+    /// Identifier which aligned, the signing domain being checked first.
+    /// `None` if neither did or the signature didn't pass.
+    pub aligned: Option<AlignedIdentifier>,
+}
+
+impl AlignmentResult {
+    pub fn is_aligned(&self) -> bool {
+        self.aligned.is_some()
+    }
+}
+
+/// Check whether a passing signature aligns with the From domain. Strict mode
+/// requires the domains to be equal and relaxed mode their organizational
+/// domains, see [organizational_domain].
+///
+/// Without the `psl` feature, relaxed mode is a parent/child check and not a
+/// registrable domain one: a signing or identity domain of at least two
+/// labels aligns with itself, its subdomains and its parents, but sibling
+/// subdomains don't align.
+pub fn check_alignment(
+    from_domain: &str,
+    dkim_result: &DKIMResult,
+    mode: AlignmentMode,
+) -> AlignmentResult {
+    let from_domain = crate::normalize_domain(from_domain);
+    let identity_domain = dkim_result
+        .identity()
+        .and_then(|identity| identity.rsplit_once('@'))
+        .map(|(_, domain)| domain);
+
+    let aligned = if dkim_result.outcome() != Summary::Pass {
+        None
+    } else if domains_aligned(&from_domain, &dkim_result.domain_used(), mode) {
+        Some(AlignedIdentifier::SigningDomain)
+    } else if identity_domain.is_some_and(|domain| domains_aligned(&from_domain, domain, mode)) {
+        Some(AlignedIdentifier::Identity)
+    } else {
+        None
+    };
+    AlignmentResult { mode, aligned }
+}
+
+/// Whether a domain aligns with the From domain, given normalized, in the
+/// mode. Shared with the candidate selection of [crate::VerificationOptions].
+pub(crate) fn domains_aligned(from_domain: &str, domain: &str, mode: AlignmentMode) -> bool {
+    let domain = crate::normalize_domain(domain);
+    match mode {
+        AlignmentMode::Strict => from_domain == domain,
+        AlignmentMode::Relaxed if cfg!(feature = "psl") => {
+            organizational_domain(from_domain) == organizational_domain(&domain)
+        }
+        // Without the public suffix list, one has to be the other or one of
+        // its subdomains, a single label domain being a suffix such as `com`
+        AlignmentMode::Relaxed => {
+            domain.contains('.')
+                && (crate::is_same_or_subdomain(from_domain, &domain)
+                    || crate::is_same_or_subdomain(&domain, from_domain))
+        }
+    }
+}
+
+/// Organizational domain of a domain given as an A-label: its public suffix
+/// and the label before it, or the domain itself if it's a public suffix.
+/// Without the `psl` feature, the domain is returned lowercase and relaxed
+/// alignment is a parent/child check instead, see [check_alignment].
+pub fn organizational_domain(domain: &str) -> String {
+    let domain = crate::normalize_domain(domain);
+    #[cfg(feature = "psl")]
+    if let Some(registrable) = public_suffix::registrable_domain(&domain) {
+        return registrable.to_owned();
+    }
+    domain
+}
+
+#[cfg(feature = "psl")]
+mod public_suffix {
+    use std::collections::HashSet;
+    use std::sync::OnceLock;
+
+    /// Copy of https://publicsuffix.org/list/public_suffix_list.dat
+    const LIST: &str = include_str!("../data/public_suffix_list.dat");
+
+    struct Rules {
+        rules: HashSet<String>,
+        wildcards: HashSet<String>,
+        exceptions: HashSet<String>,
+    }
+
+    fn rules() -> &'static Rules {
+        static RULES: OnceLock<Rules> = OnceLock::new();
+        RULES.get_or_init(|| {
+            let mut rules = Rules {
+                rules: HashSet::new(),
+                wildcards: HashSet::new(),
+                exceptions: HashSet::new(),
+            };
+            for line in LIST.lines() {
+                let rule = match line.split_whitespace().next() {
+                    Some(rule) if !rule.starts_with("//") => rule,
+                    _ => continue,
+                };
+                let (set, name) = if let Some(name) = rule.strip_prefix("!") {
+                    (&mut rules.exceptions, name)
+                } else if let Some(name) = rule.strip_prefix("*.") {
+                    (&mut rules.wildcards, name)
+                } else {
+                    (&mut rules.rules, rule)
+                };
+                if let Ok(name) = idna::domain_to_ascii(name) {
+                    set.insert(name);
+                }
+            }
+            rules
+        })
+    }
+
+    /// Registrable domain (public suffix plus one label), `None` for a public
+    /// suffix
+    pub(super) fn registrable_domain(domain: &str) -> Option<&str> {
+        let rules = rules();
+        // Suffixes of the domain, longest first
+        let suffixes: Vec<&str> = std::iter::once(domain)
+            .chain(domain.match_indices('.').map(|(i, _)| &domain[i + 1..]))
+            .collect();
+
+        let mut suffix_len = 1;
+        for (i, suffix) in suffixes.iter().enumerate() {
+            let labels = suffixes.len() - i;
+            if rules.exceptions.contains(*suffix) {
+                suffix_len = labels - 1;
+                break;
+            }
+            if rules.rules.contains(*suffix) {
+                suffix_len = labels;
+                break;
+            }
+            if i + 1 < suffixes.len() && rules.wildcards.contains(suffixes[i + 1]) {
+                suffix_len = labels;
+                break;
+            }
+        }
+        if suffix_len >= suffixes.len() {
+            return None;
+        }
+        Some(suffixes[suffixes.len() - suffix_len - 1])
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{canonicalization, DKIMError};
+
+    fn pass(domain: &str, identity: Option<&str>) -> DKIMResult {
+        let mut header = format!("v=1; a=rsa-sha256; d={}; s=s1; h=from; bh=; b=", domain);
+        if let Some(identity) = identity {
+            header += &format!("; i={}", identity);
+        }
+        DKIMResult::pass(
+            domain.to_owned(),
+            canonicalization::Type::Relaxed,
+            canonicalization::Type::Relaxed,
+        )
+        .with_signature(0, &crate::get_header_unchecked(&header).unwrap())
+    }
+
+    #[test]
+    fn test_check_alignment() {
+        let res = pass("example.com", None);
+        assert_eq!(
+            check_alignment("Example.COM", &res, AlignmentMode::Strict),
+            AlignmentResult {
+                mode: AlignmentMode::Strict,
+                aligned: Some(AlignedIdentifier::SigningDomain)
+            }
+        );
+        assert!(!check_alignment("mail.example.com", &res, AlignmentMode::Strict).is_aligned());
+        assert!(check_alignment("mail.example.com", &res, AlignmentMode::Relaxed).is_aligned());
+        assert!(!check_alignment("evilexample.com", &res, AlignmentMode::Relaxed).is_aligned());
+
+        // Only the identity is aligned in strict mode
+        let res = pass("example.com", Some("alice@mail.example.com"));
+        assert_eq!(
+            check_alignment("mail.example.com", &res, AlignmentMode::Strict).aligned,
+            Some(AlignedIdentifier::Identity)
+        );
+        assert_eq!(
+            check_alignment("mail.example.com", &res, AlignmentMode::Relaxed).aligned,
+            Some(AlignedIdentifier::SigningDomain)
+        );
+
+        let res = DKIMResult::fail(DKIMError::SignatureDidNotVerify, "example.com".to_owned());
+        assert!(!check_alignment("example.com", &res, AlignmentMode::Relaxed).is_aligned());
+    }
+
+    #[test]
+    fn test_check_alignment_idna() {
+        // The domains are compared as lowercase A-labels, whatever their form
+        let res = pass("B\u{fc}cher.example", None);
+        assert!(check_alignment("xn--bcher-kva.example", &res, AlignmentMode::Strict).is_aligned());
+        assert!(
+            check_alignment("mail.XN--BCHER-KVA.example", &res, AlignmentMode::Relaxed)
+                .is_aligned()
+        );
+        let res = pass(
+            "xn--bcher-kva.example",
+            Some("alice@mail.b\u{fc}cher.example"),
+        );
+        assert!(check_alignment("b\u{fc}cher.example.", &res, AlignmentMode::Strict).is_aligned());
+        assert_eq!(
+            check_alignment("mail.b\u{fc}cher.example", &res, AlignmentMode::Strict).aligned,
+            Some(AlignedIdentifier::Identity)
+        );
+        assert!(!check_alignment("bucher.example", &res, AlignmentMode::Relaxed).is_aligned());
+    }
+
+    #[test]
+    #[cfg(not(feature = "psl"))]
+    fn test_check_alignment_without_psl() {
+        assert_eq!(
+            organizational_domain("Mail.Example.COM."),
+            "mail.example.com"
+        );
+
+        // A single label signing or identity domain never aligns
+        let res = pass("com", None);
+        assert!(!check_alignment("example.com", &res, AlignmentMode::Relaxed).is_aligned());
+        assert!(check_alignment("com", &res, AlignmentMode::Strict).is_aligned());
+        let res = pass("example.org", Some("alice@com"));
+        assert!(!check_alignment("example.com", &res, AlignmentMode::Relaxed).is_aligned());
+
+        // Parents and subdomains are aligned, but not siblings
+        let res = pass("mail.example.com", None);
+        assert!(check_alignment("example.com", &res, AlignmentMode::Relaxed).is_aligned());
+        assert!(check_alignment("a.mail.example.com", &res, AlignmentMode::Relaxed).is_aligned());
+        assert!(!check_alignment("news.example.com", &res, AlignmentMode::Relaxed).is_aligned());
+    }
+
+    #[test]
+    #[cfg(feature = "psl")]
+    fn test_check_alignment_psl() {
+        assert_eq!(organizational_domain("mail.example.co.uk"), "example.co.uk");
+        assert_eq!(organizational_domain("example.co.uk"), "example.co.uk");
+        assert_eq!(organizational_domain("co.uk"), "co.uk");
+        assert_eq!(organizational_domain("a.b.Example.COM."), "example.com");
+        // Wildcard and exception rules (*.ck, !www.ck)
+        assert_eq!(
+            organizational_domain("mail.example.foo.ck"),
+            "example.foo.ck"
+        );
+        assert_eq!(organizational_domain("mail.www.ck"), "www.ck");
+        // Unknown TLDs have a single label suffix
+        assert_eq!(
+            organizational_domain("mail.example.invalid"),
+            "example.invalid"
+        );
+
+        // Siblings under the same registrable domain are aligned, but not
+        // registrable domains under the same public suffix
+        let res = pass("mail.example.co.uk", None);
+        assert!(check_alignment("news.example.co.uk", &res, AlignmentMode::Relaxed).is_aligned());
+        assert!(!check_alignment("other.co.uk", &res, AlignmentMode::Relaxed).is_aligned());
+        let res = pass("co.uk", None);
+        assert!(!check_alignment("example.co.uk", &res, AlignmentMode::Relaxed).is_aligned());
+    }
+}
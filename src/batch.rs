@@ -0,0 +1,78 @@
+//! Verification of many messages sharing their DNS lookups
+
+use crate::{dns, extract_from_domain, verify_email_with_options};
+use crate::{DKIMError, DKIMResult, VerificationOptions};
+use futures::stream::{Stream, StreamExt};
+use std::sync::Arc;
+
+/// Options of [verify_batch]
+#[derive(Debug, Clone)]
+pub struct BatchOptions {
+    /// Options used to verify each message
+    pub verification: VerificationOptions,
+    /// Maximum number of messages verified concurrently
+    pub concurrency: usize,
+    /// Maximum number of DNS answers kept for the batch, see
+    /// [dns::CachingLookup]. 0 disables the cache.
+    pub dns_cache_capacity: usize,
+}
+
+impl Default for BatchOptions {
+    fn default() -> Self {
+        Self {
+            verification: VerificationOptions::default(),
+            concurrency: 16,
+            dns_cache_capacity: 1024,
+        }
+    }
+}
+
+/// Verify a stream of raw messages, like the ones of an mbox file (see the
+/// `mbox` feature) or an iterator with `futures::stream::iter`, against the
+/// domain of their From header.
+///
+/// The messages share a [dns::CachingLookup] of the resolver and up to
+/// [BatchOptions::concurrency] of them are verified at a time. The results
+/// come as the verifications complete, with the position of the message in
+/// the stream; a message that can't be parsed or has no From domain gets an
+/// error without stopping the others.
+pub fn verify_batch<'a, S>(
+    messages: S,
+    resolver: Arc<dyn dns::Lookup>,
+    options: &'a BatchOptions,
+) -> impl Stream<Item = (usize, Result<DKIMResult, DKIMError>)> + 'a
+where
+    S: Stream<Item = Vec<u8>> + 'a,
+{
+    let resolver: Arc<dyn dns::Lookup> = if options.dns_cache_capacity > 0 {
+        Arc::new(dns::CachingLookup::new(
+            resolver,
+            options.dns_cache_capacity,
+        ))
+    } else {
+        resolver
+    };
+
+    messages
+        .enumerate()
+        .map(move |(index, email_bytes)| {
+            let resolver = Arc::clone(&resolver);
+            async move {
+                let res = verify_message(&email_bytes, resolver, options).await;
+                (index, res)
+            }
+        })
+        .buffer_unordered(options.concurrency.max(1))
+}
+
+async fn verify_message(
+    email_bytes: &[u8],
+    resolver: Arc<dyn dns::Lookup>,
+    options: &BatchOptions,
+) -> Result<DKIMResult, DKIMError> {
+    options.verification.limits.check(email_bytes)?;
+    let email = mailparse::parse_mail(email_bytes)
+        .map_err(|err| DKIMError::SignatureSyntaxError(err.to_string()))?;
+    let from_domain = extract_from_domain(&email)?;
+    verify_email_with_options(&from_domain, &email, resolver, &options.verification).await
+}
@@ -0,0 +1,276 @@
+//! Command line tool to verify and sign messages read from the standard input
+
+use cfdkim::{dns, DkimPrivateKey, PassPolicy, SignerBuilder, Summary, VerificationOptions};
+use std::io::{Read, Write};
+use std::net::{IpAddr, SocketAddr};
+use std::process::ExitCode;
+use std::sync::Arc;
+
+const USAGE: &str = "\
+Usage:
+  cfdkim verify [--from-domain DOMAIN] [--resolver IP[:PORT]] [--offline-records FILE] < message
+  cfdkim sign --key FILE --selector SELECTOR --domain DOMAIN [--headers NAME,...] < message
+  cfdkim dns-record --key FILE --selector SELECTOR [--domain DOMAIN]
+
+verify prints the result of each signature and exits with 0 if an aligned
+signature passed, 1 otherwise. --offline-records reads the key records from a
+JSON object of names and TXT records instead of querying DNS.
+sign prints the message with the DKIM-Signature header, signing the given
+headers or else the recommended ones present in the message.
+dns-record prints the key record as a zone file entry.";
+
+/// Options given as `--name value` or `--name=value`
+struct Args {
+    command: String,
+    options: Vec<(String, String)>,
+}
+
+impl Args {
+    fn parse(args: impl IntoIterator<Item = String>, allowed: &[&str]) -> Result<Self, String> {
+        let mut args = args.into_iter();
+        let command = args.next().ok_or("missing command")?;
+        let mut options = vec![];
+        while let Some(arg) = args.next() {
+            let name = arg
+                .strip_prefix("--")
+                .ok_or_else(|| format!("unexpected argument {}", arg))?;
+            let (name, value) = match name.split_once('=') {
+                Some((name, value)) => (name.to_owned(), value.to_owned()),
+                None => {
+                    let value = args
+                        .next()
+                        .ok_or_else(|| format!("missing value for --{}", name))?;
+                    (name.to_owned(), value)
+                }
+            };
+            if !allowed.contains(&name.as_str()) {
+                return Err(format!("unknown option --{} for {}", name, command));
+            }
+            options.push((name, value));
+        }
+        Ok(Self { command, options })
+    }
+
+    fn get(&self, name: &str) -> Option<&str> {
+        self.options
+            .iter()
+            .rev()
+            .find(|(n, _)| n == name)
+            .map(|(_, value)| value.as_str())
+    }
+
+    fn required(&self, name: &str) -> Result<&str, String> {
+        self.get(name)
+            .ok_or_else(|| format!("missing --{} for {}", name, self.command))
+    }
+}
+
+fn options_of(command: &str) -> Option<&'static [&'static str]> {
+    match command {
+        "verify" => Some(&["from-domain", "resolver", "offline-records"]),
+        "sign" => Some(&["key", "selector", "domain", "headers"]),
+        "dns-record" => Some(&["key", "selector", "domain"]),
+        _ => None,
+    }
+}
+
+fn read_stdin() -> Result<Vec<u8>, String> {
+    let mut email = vec![];
+    std::io::stdin()
+        .read_to_end(&mut email)
+        .map_err(|err| format!("failed to read the message: {}", err))?;
+    Ok(email)
+}
+
+/// Nameserver address, port 53 by default
+fn nameserver(value: &str) -> Result<SocketAddr, String> {
+    value
+        .parse::<SocketAddr>()
+        .or_else(|_| value.parse::<IpAddr>().map(|ip| SocketAddr::new(ip, 53)))
+        .map_err(|_| format!("invalid resolver address {}", value))
+}
+
+fn verify(args: &Args) -> Result<bool, String> {
+    let email_bytes = read_stdin()?;
+    let email = mailparse::parse_mail(&email_bytes)
+        .map_err(|err| format!("failed to parse the message: {}", err))?;
+    let from_domain = match args.get("from-domain") {
+        Some(domain) => domain.to_owned(),
+        None => cfdkim::extract_from_domain(&email).map_err(|err| err.to_string())?,
+    };
+
+    let resolver: Arc<dyn dns::Lookup> = match (args.get("offline-records"), args.get("resolver")) {
+        (Some(_), Some(_)) => {
+            return Err("--offline-records and --resolver are mutually exclusive".to_owned())
+        }
+        (Some(path), None) => {
+            let json = std::fs::read_to_string(path)
+                .map_err(|err| format!("failed to read {}: {}", path, err))?;
+            Arc::new(dns::StaticLookup::from_json(&json).map_err(|err| err.to_string())?)
+        }
+        (None, Some(resolver)) => dns::resolver_from_nameservers(
+            &[nameserver(resolver)?],
+            &dns::ResolverOptions::default(),
+        )
+        .map_err(|err| err.to_string())?,
+        (None, None) => dns::system_resolver().map_err(|err| err.to_string())?,
+    };
+
+    let runtime = tokio::runtime::Builder::new_current_thread()
+        .enable_all()
+        .build()
+        .map_err(|err| format!("failed to start the runtime: {}", err))?;
+    let result = runtime
+        .block_on(cfdkim::verify_all_signatures(
+            &from_domain,
+            &email,
+            resolver,
+            &VerificationOptions::default(),
+        ))
+        .map_err(|err| err.to_string())?;
+
+    let mut out = std::io::stdout().lock();
+    for (index, signature) in result.signatures().iter().enumerate() {
+        let res = &signature.result;
+        // The error without the signature, printed already
+        let detail = match res.error() {
+            Some(err) => format!("{} ({})", res.outcome(), err.root_cause()),
+            None => res.outcome().to_string(),
+        };
+        let _ = writeln!(
+            out,
+            "signature {}: d={} s={}{} {}",
+            index,
+            res.domain_used(),
+            res.selector().unwrap_or("-"),
+            if signature.aligned {
+                ""
+            } else {
+                " (not aligned)"
+            },
+            detail
+        );
+    }
+    let summary = result.policy(PassPolicy::AnyAligned);
+    let _ = writeln!(out, "dkim={} header.from={}", summary, from_domain);
+    Ok(summary == Summary::Pass)
+}
+
+fn sign(args: &Args) -> Result<(), String> {
+    let key = DkimPrivateKey::from_file(args.required("key")?).map_err(|err| err.to_string())?;
+    let headers: Option<Vec<&str>> = args
+        .get("headers")
+        .map(|headers| headers.split(',').map(str::trim).collect());
+
+    let mut builder = SignerBuilder::new()
+        .with_private_key(key)
+        .with_selector(args.required("selector")?)
+        .with_signing_domain(args.required("domain")?);
+    builder = match &headers {
+        Some(headers) => builder
+            .with_signed_headers(headers)
+            .map_err(|err| err.to_string())?,
+        None => builder.with_header_selection(cfdkim::HeaderSelection::RecommendedPresent),
+    };
+    let signer = builder.build().map_err(|err| err.to_string())?;
+
+    let signed = signer
+        .sign_and_attach(&read_stdin()?)
+        .map_err(|err| err.to_string())?;
+    std::io::stdout()
+        .write_all(&signed)
+        .map_err(|err| format!("failed to write the message: {}", err))
+}
+
+fn dns_record(args: &Args) -> Result<(), String> {
+    let key = DkimPrivateKey::from_file(args.required("key")?).map_err(|err| err.to_string())?;
+    let name = match args.get("domain") {
+        Some(domain) => format!("{}._domainkey.{}.", args.required("selector")?, domain),
+        None => format!("{}._domainkey", args.required("selector")?),
+    };
+    println!(
+        "{} IN TXT ( {} )",
+        name,
+        character_strings(&key.to_dns_record())
+    );
+    Ok(())
+}
+
+/// Quoted character-strings of at most 255 bytes, for a zone file
+fn character_strings(text: &str) -> String {
+    text.as_bytes()
+        .chunks(255)
+        .map(|chunk| format!("\"{}\"", String::from_utf8_lossy(chunk)))
+        .collect::<Vec<_>>()
+        .join("\n    ")
+}
+
+fn main() -> ExitCode {
+    let mut args = std::env::args().skip(1).peekable();
+    let options = match args.peek().map(String::as_str) {
+        Some("-h" | "--help" | "help") | None => {
+            println!("{}", USAGE);
+            return ExitCode::SUCCESS;
+        }
+        Some(command) => options_of(command),
+    };
+    let res = match options {
+        Some(options) => Args::parse(args, options),
+        None => Err(format!(
+            "unknown command {}",
+            args.next().unwrap_or_default()
+        )),
+    };
+    let args = match res {
+        Ok(args) => args,
+        Err(err) => {
+            eprintln!("cfdkim: {}\n\n{}", err, USAGE);
+            return ExitCode::from(2);
+        }
+    };
+
+    let res = match args.command.as_str() {
+        "verify" => verify(&args),
+        "sign" => sign(&args).map(|_| true),
+        _ => dns_record(&args).map(|_| true),
+    };
+    match res {
+        Ok(true) => ExitCode::SUCCESS,
+        Ok(false) => ExitCode::FAILURE,
+        Err(err) => {
+            eprintln!("cfdkim: {}", err);
+            ExitCode::from(2)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(args: &[&str]) -> Result<Args, String> {
+        let args: Vec<String> = args.iter().map(|arg| arg.to_string()).collect();
+        Args::parse(args.clone(), options_of(&args[0]).unwrap())
+    }
+
+    #[test]
+    fn test_args() {
+        let args = parse(&["sign", "--key", "key.pem", "--selector=s1"]).unwrap();
+        assert_eq!(args.get("key"), Some("key.pem"));
+        assert_eq!(args.get("selector"), Some("s1"));
+        assert_eq!(
+            args.required("domain").unwrap_err(),
+            "missing --domain for sign"
+        );
+
+        assert!(parse(&["verify", "--key", "key.pem"]).is_err());
+        assert!(parse(&["verify", "--resolver"]).is_err());
+        assert!(parse(&["verify", "example.com"]).is_err());
+
+        assert_eq!(nameserver("8.8.8.8"), Ok("8.8.8.8:53".parse().unwrap()));
+        assert_eq!(
+            nameserver("[2001:4860:4860::8888]:5353"),
+            Ok("[2001:4860:4860::8888]:5353".parse().unwrap())
+        );
+    }
+}
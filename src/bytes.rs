@@ -1,44 +1,37 @@
 //! Various utility functions to operate on bytes
 
-pub(crate) fn get_all_after<'a>(bytes: &'a [u8], end: &[u8]) -> &'a [u8] {
-    if let Some(mut end_index) = find(bytes, end) {
-        end_index += end.len();
-        &bytes[end_index..]
-    } else {
-        &[]
-    }
-}
-
-/// Find the offset of specific bytes in bytes
-pub(crate) fn find(bytes: &[u8], search: &[u8]) -> Option<usize> {
-    bytes
-        .windows(search.len())
-        .position(|window| window == search)
-}
-
-pub(crate) fn replace(bytes: &mut [u8], from: char, to: char) {
-    for byte in bytes.iter_mut() {
-        if *byte == from as u8 {
-            *byte = to as u8;
+/// Whether the bytes contain a LF which isn't preceded by a CR
+pub(crate) fn has_bare_lf(bytes: &[u8]) -> bool {
+    let mut previous_cr = false;
+    for &byte in bytes {
+        if byte == b'\n' && !previous_cr {
+            return true;
         }
+        previous_cr = byte == b'\r';
     }
+    false
 }
 
-pub(crate) fn replace_slice(source: &[u8], from: &[u8], to: &[u8]) -> Vec<u8> {
-    let mut result = source.to_vec();
-    let from_len = from.len();
-    let to_len = to.len();
-
-    let mut i = 0;
-    while i + from_len <= result.len() {
-        if result[i..].starts_with(from) {
-            result.splice(i..i + from_len, to.iter().cloned());
-            i += to_len;
-        } else {
-            i += 1;
+/// Normalize the line endings to CRLF, the form hashed by signers and
+/// verifiers alike:
+///
+/// - a CRLF is kept
+/// - a LF which isn't preceded by a CR (bare LF) becomes a CRLF
+/// - a CR which isn't followed by a LF (lone CR) is kept as it is, as content
+///   of the line for the canonicalization
+///
+/// The bytes are read with a single state, whether the previous input byte
+/// was a CR, so `\r\r\n` is a lone CR followed by a CRLF.
+pub(crate) fn to_crlf(bytes: &[u8]) -> Vec<u8> {
+    let mut result = Vec::with_capacity(bytes.len());
+    let mut previous_cr = false;
+    for &byte in bytes {
+        if byte == b'\n' && !previous_cr {
+            result.push(b'\r');
         }
+        previous_cr = byte == b'\r';
+        result.push(byte);
     }
-
     result
 }
 
@@ -47,16 +40,27 @@ mod tests {
     use super::*;
 
     #[test]
-    fn it_find() {
-        assert_eq!(find(&[97, 98, 99], &[1]), None);
-        assert_eq!(find(&[97, 98, 99], &[97]), Some(0));
-        assert_eq!(find(&[97, 98, 99], &[97, 98]), Some(0));
+    fn it_to_crlf() {
+        assert!(!has_bare_lf(b"a\r\nb\r\n"));
+        assert!(has_bare_lf(b"a\r\nb\n"));
+        assert!(has_bare_lf(b"\na"));
+        assert_eq!(to_crlf(b"\na\nb\r\nc\r\r\n"), b"\r\na\r\nb\r\nc\r\r\n");
     }
 
     #[test]
-    fn it_replace_slice() {
-        let source = "aba".as_bytes();
-        assert_eq!(replace_slice(source, &[97], &[99]), "cbc".as_bytes());
-        assert_eq!(replace_slice(source, &[97, 98], &[]), "a".as_bytes());
+    fn it_to_crlf_edge_cases() {
+        for (input, expected) in [
+            (&b"\n\n"[..], &b"\r\n\r\n"[..]),
+            (b"\r\n\n", b"\r\n\r\n"),
+            (b"\n\r\n", b"\r\n\r\n"),
+            (b"a\rb", b"a\rb"),
+            (b"a\r", b"a\r"),
+            (b"\r\r\n", b"\r\r\n"),
+            (b"a\nlast line", b"a\r\nlast line"),
+            (b"", b""),
+        ] {
+            assert_eq!(to_crlf(input), expected, "{:?}", input);
+            assert_eq!(has_bare_lf(input), input != expected, "{:?}", input);
+        }
     }
 }
@@ -1,18 +1,20 @@
 use mailparse::parse_headers;
 
 // Inspired from https://docs.rs/dkim/latest/src/dkim/canonicalization.rs.html
-use crate::bytes;
+use crate::{bytes, DKIMError};
 
 #[derive(PartialEq, Clone, Debug)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "serde", serde(rename_all = "snake_case"))]
 pub enum Type {
     Simple,
     Relaxed,
 }
-impl std::string::ToString for Type {
-    fn to_string(&self) -> String {
+impl std::fmt::Display for Type {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         match self {
-            Self::Simple => "simple".to_owned(),
-            Self::Relaxed => "relaxed".to_owned(),
+            Self::Simple => write!(f, "simple"),
+            Self::Relaxed => write!(f, "relaxed"),
         }
     }
 }
@@ -26,92 +28,140 @@ pub enum ContentTransferEncoding {
     Binary,
 }
 
-fn normalize_body_content(body_content: Vec<u8>) -> Vec<u8> {
-    // Trim trailing whitespace
-    let trimmed_content = body_content
-        .iter()
-        .rev()
-        .skip_while(|&&b| b.is_ascii_whitespace())
-        .cloned()
-        .collect::<Vec<_>>()
-        .into_iter()
-        .rev()
-        .collect::<Vec<_>>();
-
-    // Make sure there is only one space
-    let mut normalized_content = Vec::new();
-    let mut previous_was_whitespace = false;
-    for &byte in &trimmed_content {
-        if byte == b' ' || byte == b'\t' {
-            if !previous_was_whitespace {
-                normalized_content.push(b' ');
-                previous_was_whitespace = true;
+/// Returns the body of the raw email, with its line endings converted to
+/// CRLF, canonicalized with the given algorithm
+pub(crate) fn get_canonicalized_body(
+    email_bytes: &[u8],
+    canonicalization_type: Type,
+) -> Result<Vec<u8>, DKIMError> {
+    let (_, ix) = parse_headers(email_bytes)
+        .map_err(|err| DKIMError::SignatureSyntaxError(err.to_string()))?;
+    let body = bytes::to_crlf(&email_bytes[ix..]);
+
+    Ok(canonicalize_body(&body, canonicalization_type))
+}
+
+/// Canonicalize a body with the given algorithm, see
+/// [canonicalize_body_simple] and [canonicalize_body_relaxed]
+pub fn canonicalize_body(body: &[u8], canonicalization_type: Type) -> Vec<u8> {
+    match canonicalization_type {
+        Type::Simple => canonicalize_body_simple(body),
+        Type::Relaxed => canonicalize_body_relaxed(body),
+    }
+}
+
+/// Incremental body canonicalization, producing the same output as
+/// [canonicalize_body] without holding the whole body in memory.
+///
+/// The body is given in chunks of any size with [BodyCanonicalizer::update]
+/// and the canonicalized bytes are appended to the output as soon as they are
+/// known. Line endings, and whitespace with relaxed, are held back until the
+/// next content or [BodyCanonicalizer::finalize], so that empty lines at the
+/// end of the body are removed even when split across chunks.
+#[derive(Debug, Clone)]
+pub struct BodyCanonicalizer {
+    canonicalization_type: Type,
+    /// CR that may start a CRLF
+    pending_cr: bool,
+    /// Run of whitespace reduced to a single space (relaxed only)
+    pending_space: bool,
+    /// Number of CRLFs since the last content
+    pending_crlfs: usize,
+    /// Whether any input was given
+    started: bool,
+    /// Whether any content was written to the output
+    emitted: bool,
+}
+
+impl BodyCanonicalizer {
+    pub fn new(canonicalization_type: Type) -> Self {
+        Self {
+            canonicalization_type,
+            pending_cr: false,
+            pending_space: false,
+            pending_crlfs: 0,
+            started: false,
+            emitted: false,
+        }
+    }
+
+    /// Canonicalize the next chunk of the body, appending the output to `out`
+    pub fn update(&mut self, chunk: &[u8], out: &mut Vec<u8>) {
+        self.started |= !chunk.is_empty();
+        for &byte in chunk {
+            if self.pending_cr {
+                self.pending_cr = false;
+                if byte == b'\n' {
+                    // Whitespace at the end of a line is ignored
+                    self.pending_space = false;
+                    self.pending_crlfs += 1;
+                    continue;
+                }
+                self.content(b'\r', out);
+            }
+            match byte {
+                b'\r' => self.pending_cr = true,
+                b' ' | b'\t' if self.canonicalization_type == Type::Relaxed => {
+                    self.pending_space = true
+                }
+                byte => self.content(byte, out),
             }
-        } else {
-            normalized_content.push(byte);
-            previous_was_whitespace = false;
         }
     }
 
-    // Replace LF with CRLF and remove any CR before LF to avoid duplicating CR
-    let mut crlf_content = Vec::new();
-    let mut previous_byte = 32;
-    for &byte in &normalized_content {
-        if byte == b'\n' && previous_byte != b'\r' {
-            crlf_content.push(b'\r');
+    /// Finish the canonicalization, appending the end of the body to `out`
+    pub fn finalize(mut self, out: &mut Vec<u8>) {
+        if self.pending_cr {
+            self.pending_cr = false;
+            self.content(b'\r', out);
         }
-        if byte != b'\r' || previous_byte != b'\r' {
-            crlf_content.push(byte);
+        let final_crlf = match self.canonicalization_type {
+            // An empty body becomes a single CRLF
+            Type::Simple => self.pending_crlfs > 0 || !self.started,
+            // Whitespace at the end of the last line is ignored even without
+            // a line ending, and a non-empty body always ends with a CRLF
+            Type::Relaxed => self.pending_crlfs > 0 || self.emitted,
+        };
+        if final_crlf {
+            out.extend_from_slice(b"\r\n");
         }
-        previous_byte = byte;
     }
 
-    // Ensure the content ends with CRLF
-    if crlf_content.len() < 2
-        || crlf_content[crlf_content.len() - 2] != b'\r'
-        || crlf_content[crlf_content.len() - 1] != b'\n'
-    {
-        crlf_content.extend_from_slice(b"\r\n");
+    fn content(&mut self, byte: u8, out: &mut Vec<u8>) {
+        self.flush(out);
+        out.push(byte);
     }
 
-    // No spaces before \r\n anywhere in the content
-    let mut no_space_before_crlf_content = Vec::new();
-    let mut iter = crlf_content.iter().peekable();
-    while let Some(&byte) = iter.next() {
-        // Look ahead to check for space followed by \r\n
-        if byte == b' ' && iter.peek() == Some(&&b'\r') && iter.clone().nth(1) == Some(&b'\n') {
-            continue; // Skip adding the space to the output
+    /// Write the line endings and whitespace followed by content
+    fn flush(&mut self, out: &mut Vec<u8>) {
+        for _ in 0..self.pending_crlfs {
+            out.extend_from_slice(b"\r\n");
         }
-        no_space_before_crlf_content.push(byte);
+        self.pending_crlfs = 0;
+        if self.pending_space {
+            out.push(b' ');
+            self.pending_space = false;
+        }
+        self.emitted = true;
     }
-
-    no_space_before_crlf_content
 }
 
-pub(crate) fn get_canonicalized_body(email_bytes: &[u8]) -> Vec<u8> {
-    let (_, ix) = parse_headers(email_bytes).unwrap();
-    let body = &email_bytes[ix..];
-
-    // Check if \n is used instead of \r\n for line endings if so replace it
-    let body = body
-        .iter()
-        .enumerate()
-        .flat_map(|(i, &b)| {
-            if b == b'\n' && body.get(i.wrapping_sub(1)) != Some(&b'\r') {
-                vec![b'\r', b'\n'].into_iter()
-            } else {
-                vec![b].into_iter()
-            }
-        })
-        .collect::<Vec<u8>>();
-
-    normalize_body_content(body)
+/// Canonicalize a header field with the given algorithm, see
+/// [canonicalize_header_simple] and [canonicalize_header_relaxed]
+pub fn canonicalize_header(name: &str, value: &[u8], canonicalization_type: Type) -> Vec<u8> {
+    match canonicalization_type {
+        Type::Simple => canonicalize_header_simple(name, value),
+        Type::Relaxed => canonicalize_header_relaxed(name, value),
+    }
 }
 
 /// Canonicalize body using the simple canonicalization algorithm.
 ///
-/// The first argument **must** be the body of the mail.
-pub(crate) fn canonicalize_body_simple(mut body: &[u8]) -> Vec<u8> {
+/// The first argument **must** be the body of the mail, everything after
+/// the empty line ending the header, with CRLF line endings. Empty lines at
+/// the end are removed and an empty body becomes a single CRLF.
+/// <https://datatracker.ietf.org/doc/html/rfc6376#section-3.4.3>
+pub fn canonicalize_body_simple(mut body: &[u8]) -> Vec<u8> {
     if body.is_empty() {
         return b"\r\n".to_vec();
     }
@@ -123,53 +173,33 @@ pub(crate) fn canonicalize_body_simple(mut body: &[u8]) -> Vec<u8> {
     body.to_vec()
 }
 
-/// https://datatracker.ietf.org/doc/html/rfc6376#section-3.4.3
-/// Canonicalize body using the relaxed canonicalization algorithm.  
+/// Canonicalize body using the relaxed canonicalization algorithm.
 ///
-/// The first argument **must** be the body of the mail.
-pub(crate) fn canonicalize_body_relaxed(body: &[u8]) -> Vec<u8> {
-    let mut body = body.to_vec();
-    // See https://tools.ietf.org/html/rfc6376#section-3.4.4 for implementation details
-
-    // Reduce all sequences of WSP within a line to a single SP character.
-    bytes::replace(&mut body, '\t', ' ');
-    let mut previous = false;
-    body.retain(|c| {
-        if *c == b' ' {
-            if previous {
-                false
-            } else {
-                previous = true;
-                true
-            }
-        } else {
-            previous = false;
-            true
-        }
-    });
-
-    // Ignore all whitespace at the end of lines. Implementations MUST NOT remove the CRLF at the end of the line.
-    while let Some(idx) = bytes::find(&body, b" \r\n") {
-        body.remove(idx);
-    }
+/// The first argument **must** be the body of the mail, as for
+/// [canonicalize_body_simple]. Whitespace is also reduced to a single space
+/// inside lines and removed at their end, including the last line when it
+/// has no line ending, and a non-empty body always ends with a CRLF.
+/// <https://datatracker.ietf.org/doc/html/rfc6376#section-3.4.4>
+pub fn canonicalize_body_relaxed(body: &[u8]) -> Vec<u8> {
+    let mut out = Vec::with_capacity(body.len());
+    let mut canonicalizer = BodyCanonicalizer::new(Type::Relaxed);
+    canonicalizer.update(body, &mut out);
+    canonicalizer.finalize(&mut out);
 
-    // Ignore all empty lines at the end of the message body. "Empty line" is defined in Section 3.4.3.
-    while body.ends_with(b"\r\n\r\n") {
-        body.remove(body.len() - 1);
-        body.remove(body.len() - 1);
-    }
-
-    // If the body is non-empty but does not end with a CRLF, a CRLF is added. (For email, this is only possible when using extensions to SMTP or non-SMTP transport mechanisms.)
-    if !body.is_empty() && !body.ends_with(b"\r\n") {
-        body.push(b'\r');
-        body.push(b'\n');
-    }
-
-    body
+    out
 }
 
-// https://datatracker.ietf.org/doc/html/rfc6376#section-3.4.1
-pub(crate) fn canonicalize_header_simple(key: &str, value: &[u8]) -> Vec<u8> {
+/// Canonicalize a single header field using the simple canonicalization
+/// algorithm: the name and the value are kept as they are, folding included,
+/// joined by `: ` and followed by a CRLF.
+///
+/// `value` **must** be the raw value of one header field, without the
+/// whitespace after the colon nor the final CRLF, like
+/// [mailparse::MailHeader::get_value_raw]. The hashes of signatures use the
+/// header field as it appears in the message instead, which keeps the
+/// whitespace around the colon, and only fall back to this rebuilt one.
+/// <https://datatracker.ietf.org/doc/html/rfc6376#section-3.4.1>
+pub fn canonicalize_header_simple(key: &str, value: &[u8]) -> Vec<u8> {
     let mut out = Vec::new();
     out.extend_from_slice(key.as_bytes());
     out.extend_from_slice(b": ");
@@ -179,8 +209,15 @@ pub(crate) fn canonicalize_header_simple(key: &str, value: &[u8]) -> Vec<u8> {
     out
 }
 
-// https://datatracker.ietf.org/doc/html/rfc6376#section-3.4.2
-pub(crate) fn canonicalize_header_relaxed(key: &str, value: &[u8]) -> Vec<u8> {
+/// Canonicalize a single header field using the relaxed canonicalization
+/// algorithm: the name is lowercased, the value is unfolded, whitespace is
+/// reduced to a single space and removed at both ends, and the result is
+/// `name:value` followed by a CRLF.
+///
+/// `value` **must** be the raw value of one header field, as for
+/// [canonicalize_header_simple].
+/// <https://datatracker.ietf.org/doc/html/rfc6376#section-3.4.2>
+pub fn canonicalize_header_relaxed(key: &str, value: &[u8]) -> Vec<u8> {
     let key = key.to_lowercase();
     let key = key.trim_end();
     let value = canonicalize_header_value_relaxed(value);
@@ -194,38 +231,194 @@ pub(crate) fn canonicalize_header_relaxed(key: &str, value: &[u8]) -> Vec<u8> {
     out
 }
 
+/// Unfold the value, reduce whitespace to a single space and remove it at
+/// both ends, in a single pass
 fn canonicalize_header_value_relaxed(value: &[u8]) -> Vec<u8> {
-    let mut value = value.to_vec();
-    bytes::replace(&mut value, '\t', ' ');
-    value = bytes::replace_slice(&value, b"\r\n", b"");
-
-    while value.ends_with(b" ") {
-        value.remove(value.len() - 1);
-    }
-    while value.starts_with(b" ") {
-        value.remove(0);
-    }
-    let mut previous = false;
-    value.retain(|c| {
-        if *c == b' ' {
-            if previous {
-                false
-            } else {
-                previous = true;
-                true
+    let mut out = Vec::with_capacity(value.len());
+    let mut pending_space = false;
+    let mut i = 0;
+    while i < value.len() {
+        match value[i] {
+            b'\r' if value.get(i + 1) == Some(&b'\n') => i += 1,
+            b' ' | b'\t' => pending_space = true,
+            byte => {
+                if pending_space && !out.is_empty() {
+                    out.push(b' ');
+                }
+                pending_space = false;
+                out.push(byte);
             }
-        } else {
-            previous = false;
-            true
         }
-    });
+        i += 1;
+    }
 
-    value
+    out
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use std::time::{Duration, Instant};
+
+    /// Reduce runs of spaces to a single one
+    fn collapse_spaces(bytes: &mut Vec<u8>) {
+        let mut previous = false;
+        bytes.retain(|c| {
+            let keep = *c != b' ' || !previous;
+            previous = *c == b' ';
+            keep
+        });
+    }
+
+    fn tabs_to_spaces(bytes: &mut [u8]) {
+        for byte in bytes.iter_mut().filter(|byte| **byte == b'\t') {
+            *byte = b' ';
+        }
+    }
+
+    /// Previous quadratic implementation of [canonicalize_body_relaxed]
+    fn reference_body_relaxed(body: &[u8]) -> Vec<u8> {
+        let mut body = body.to_vec();
+        tabs_to_spaces(&mut body);
+        collapse_spaces(&mut body);
+        while let Some(idx) = body.windows(3).position(|window| window == b" \r\n") {
+            body.remove(idx);
+        }
+        while body.ends_with(b"\r\n\r\n") {
+            body.remove(body.len() - 1);
+            body.remove(body.len() - 1);
+        }
+        if !body.is_empty() && !body.ends_with(b"\r\n") {
+            body.extend_from_slice(b"\r\n");
+        }
+        body
+    }
+
+    /// Previous quadratic implementation of
+    /// [canonicalize_header_value_relaxed]
+    fn reference_header_value_relaxed(value: &[u8]) -> Vec<u8> {
+        let mut value = value.to_vec();
+        tabs_to_spaces(&mut value);
+        let mut i = 0;
+        while i + 2 <= value.len() {
+            if value[i..].starts_with(b"\r\n") {
+                value.drain(i..i + 2);
+            } else {
+                i += 1;
+            }
+        }
+        while value.ends_with(b" ") {
+            value.remove(value.len() - 1);
+        }
+        while value.starts_with(b" ") {
+            value.remove(0);
+        }
+        collapse_spaces(&mut value);
+        value
+    }
+
+    const FIXTURES: &[&[u8]] = &[
+        b"",
+        b"\r\n",
+        b"\r\n\r\n\r\n",
+        b" ",
+        b"\t \r\n",
+        b"hey        \r\n",
+        b"Hello \t World  \r\n\r\n\r\n",
+        b"Hello\r\n \r\n\t\r\n",
+        b"Hello\r\n \t",
+        b"no final line ending  ",
+        b"lone \r and \n  \r\r\n\n\r",
+        b"  leading\r\n\r\n  and   inner\t\twhitespace \r\n\r\n",
+        b"end with CR \r",
+        b" AbC\r\n",
+        b"\t Kimi \t \r\n No \t\r\n Na Wa\r\n",
+        b"Hello\r\n  World ",
+        b"a\r\r\nb \r\n\r\n c",
+    ];
+
+    /// Fixtures canonicalized differently from [reference_body_relaxed] on
+    /// purpose, with its output and the current one: the whitespace of a
+    /// last line without line ending is now ignored like the one of the
+    /// other lines
+    const RELAXED_DIVERGENCES: &[(&[u8], &[u8], &[u8])] = &[
+        (b" ", b" \r\n", b""),
+        (b"Hello\r\n \t", b"Hello\r\n \r\n", b"Hello\r\n"),
+        (
+            b"no final line ending  ",
+            b"no final line ending \r\n",
+            b"no final line ending\r\n",
+        ),
+        (
+            b"Hello\r\n  World ",
+            b"Hello\r\n World \r\n",
+            b"Hello\r\n World\r\n",
+        ),
+    ];
+
+    /// Expected relaxed canonicalization of a fixture
+    fn expected_body_relaxed(body: &[u8]) -> Vec<u8> {
+        match RELAXED_DIVERGENCES
+            .iter()
+            .find(|(fixture, _, _)| *fixture == body)
+        {
+            Some((_, _, expected)) => expected.to_vec(),
+            None => reference_body_relaxed(body),
+        }
+    }
+
+    #[test]
+    fn test_relaxed_matches_reference() {
+        for fixture in FIXTURES {
+            assert_eq!(
+                canonicalize_body_relaxed(fixture),
+                expected_body_relaxed(fixture),
+                "{:?}",
+                String::from_utf8_lossy(fixture)
+            );
+            assert_eq!(
+                canonicalize_header_value_relaxed(fixture),
+                reference_header_value_relaxed(fixture),
+                "{:?}",
+                String::from_utf8_lossy(fixture)
+            );
+        }
+    }
+
+    #[test]
+    fn test_relaxed_divergences() {
+        for (fixture, previous, current) in RELAXED_DIVERGENCES {
+            assert!(FIXTURES.contains(fixture));
+            assert_eq!(reference_body_relaxed(fixture), *previous);
+            assert_eq!(canonicalize_body_relaxed(fixture), *current);
+        }
+    }
+
+    #[test]
+    fn test_relaxed_linear_time() {
+        // 8 MB of wrapped lines with trailing whitespace, which took minutes
+        // with the previous implementation
+        let body = "wrapped line of text with trailing whitespace \t \r\n".repeat(160_000);
+        let value = "folded \t header value\r\n\t".repeat(100_000);
+
+        let start = Instant::now();
+        let canonicalized = canonicalize_body_relaxed(body.as_bytes());
+        let canonicalized_value = canonicalize_header_value_relaxed(value.as_bytes());
+        let elapsed = start.elapsed();
+
+        assert_eq!(
+            canonicalized,
+            "wrapped line of text with trailing whitespace\r\n"
+                .repeat(160_000)
+                .as_bytes()
+        );
+        assert!(canonicalized_value.ends_with(b"folded header value"));
+        assert!(
+            elapsed < Duration::from_secs(10),
+            "relaxed canonicalization took {:?}",
+            elapsed
+        );
+    }
 
     #[test]
     fn test_canonicalize_header_relaxed() {
@@ -243,6 +436,66 @@ mod tests {
         );
     }
 
+    #[test]
+    fn test_canonicalize_header() {
+        assert_eq!(
+            canonicalize_header("Subject", b"Hello\r\n  World ", Type::Simple),
+            b"Subject: Hello\r\n  World \r\n"
+        );
+        assert_eq!(
+            canonicalize_header("Subject", b"Hello\r\n  World ", Type::Relaxed),
+            b"subject:Hello World\r\n"
+        );
+    }
+
+    #[test]
+    fn test_canonicalize_body() {
+        let body = b"Hello \t World  \r\n\r\n\r\n";
+        assert_eq!(
+            canonicalize_body(body, Type::Simple),
+            b"Hello \t World  \r\n"
+        );
+        assert_eq!(canonicalize_body(body, Type::Relaxed), b"Hello World\r\n");
+        assert_eq!(canonicalize_body(b"", Type::Simple), b"\r\n");
+        assert_eq!(canonicalize_body(b"", Type::Relaxed), b"");
+    }
+
+    fn canonicalize_body_chunked(
+        body: &[u8],
+        canonicalization_type: Type,
+        chunk_size: usize,
+    ) -> Vec<u8> {
+        let mut canonicalizer = BodyCanonicalizer::new(canonicalization_type);
+        let mut out = vec![];
+        for chunk in body.chunks(chunk_size) {
+            canonicalizer.update(chunk, &mut out);
+        }
+        canonicalizer.finalize(&mut out);
+        out
+    }
+
+    #[test]
+    fn test_body_canonicalizer() {
+        for body in FIXTURES {
+            for canonicalization_type in [Type::Simple, Type::Relaxed] {
+                let expected = match canonicalization_type {
+                    Type::Simple => canonicalize_body_simple(body),
+                    Type::Relaxed => expected_body_relaxed(body),
+                };
+                for chunk_size in 1..=body.len().max(1) {
+                    assert_eq!(
+                        canonicalize_body_chunked(body, canonicalization_type.clone(), chunk_size),
+                        expected,
+                        "{:?} {} in chunks of {}",
+                        String::from_utf8_lossy(body),
+                        canonicalization_type,
+                        chunk_size
+                    );
+                }
+            }
+        }
+    }
+
     #[test]
     fn test_canonicalize_body_relaxed() {
         assert_eq!(canonicalize_body_relaxed(b"\r\n"), b"\r\n");
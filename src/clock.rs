@@ -0,0 +1,49 @@
+//! Source of the current time, used for the signature timestamp (t=) when
+//! signing and for the expiration checks when verifying
+
+use std::fmt;
+
+/// Returns the current time (UTC)
+pub trait Clock: Send + Sync {
+    fn now(&self) -> chrono::NaiveDateTime;
+}
+
+/// A placeholder, reading the time would make the options it is part of
+/// print differently each time
+impl fmt::Debug for dyn Clock {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("Clock(..)")
+    }
+}
+
+/// The system clock, or `Date` in the browser
+#[derive(Debug, Clone, Copy, Default)]
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    #[cfg(target_arch = "wasm32")]
+    fn now(&self) -> chrono::NaiveDateTime {
+        use js_sys::Date;
+        let now = Date::new_0();
+        let timestamp = now.get_time() / 1000.0; // Convert milliseconds to seconds
+        chrono::DateTime::from_timestamp(timestamp as i64, 0)
+            .expect("Invalid timestamp from browser")
+            .naive_utc()
+    }
+
+    #[cfg(not(target_arch = "wasm32"))]
+    fn now(&self) -> chrono::NaiveDateTime {
+        chrono::Utc::now().naive_utc()
+    }
+}
+
+/// A clock which always returns the same time, for tests and deterministic
+/// pipelines
+#[derive(Debug, Clone, Copy)]
+pub struct FixedClock(pub chrono::NaiveDateTime);
+
+impl Clock for FixedClock {
+    fn now(&self) -> chrono::NaiveDateTime {
+        self.0
+    }
+}
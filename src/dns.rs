@@ -1,47 +1,872 @@
 use crate::DKIMError;
 use futures::future::BoxFuture;
+#[cfg(all(feature = "dns", not(target_arch = "wasm32")))]
+use futures::StreamExt;
 use std::sync::Arc;
-#[cfg(not(target_arch = "wasm32"))]
+#[cfg(all(feature = "dns", not(target_arch = "wasm32")))]
+use std::{net::SocketAddr, time::Duration};
+#[cfg(all(feature = "dns", not(target_arch = "wasm32")))]
+use trust_dns_resolver::config::{
+    NameServerConfig, NameServerConfigGroup, Protocol, ResolverConfig, ResolverOpts,
+};
+#[cfg(all(feature = "dns", not(target_arch = "wasm32")))]
 use trust_dns_resolver::error::{ResolveError, ResolveErrorKind};
-#[cfg(not(target_arch = "wasm32"))]
+#[cfg(all(feature = "dns", not(target_arch = "wasm32")))]
+use trust_dns_resolver::name_server::{NameServerPool, TokioConnectionProvider};
+#[cfg(all(feature = "dns", not(target_arch = "wasm32")))]
+use trust_dns_resolver::proto::op::{Edns, Message, MessageType, OpCode, Query, ResponseCode};
+#[cfg(all(feature = "dns", not(target_arch = "wasm32")))]
+use trust_dns_resolver::proto::rr::{RData, Record, RecordType};
+#[cfg(all(feature = "dns", not(target_arch = "wasm32")))]
+use trust_dns_resolver::proto::xfer::{DnsHandle, DnsRequest, DnsRequestOptions};
+#[cfg(all(feature = "dns", not(target_arch = "wasm32")))]
+use trust_dns_resolver::Name;
+#[cfg(all(feature = "dns", not(target_arch = "wasm32")))]
 use trust_dns_resolver::TokioAsyncResolver;
 
+mod cache;
+#[cfg(feature = "doh")]
+mod doh;
+pub mod doh_json;
+#[cfg(all(feature = "doh", target_arch = "wasm32"))]
+mod fetch;
+mod retry;
+mod static_lookup;
+#[cfg(all(feature = "dns", not(target_arch = "wasm32")))]
+mod throttle;
+pub use cache::CachingLookup;
+#[cfg(feature = "doh")]
+pub use doh::{DohLookup, HttpClient};
+pub use doh_json::{CLOUDFLARE_ENDPOINT, GOOGLE_ENDPOINT};
+#[cfg(all(feature = "doh", target_arch = "wasm32"))]
+pub use fetch::FetchClient;
+pub use retry::{RetryingLookup, SleepFn};
+pub use static_lookup::StaticLookup;
+#[cfg(all(feature = "dns", not(target_arch = "wasm32")))]
+pub use throttle::ThrottledLookup;
+
 /// A trait for entities that perform DNS resolution.
+///
+/// Failures that may succeed when retried, like timeouts and SERVFAIL, are
+/// reported with [DKIMError::TemporaryDnsFailure]. A non-existent name is
+/// reported with [DKIMError::NoKeyForSignature].
 pub trait Lookup: Sync + Send {
     fn lookup_txt<'a>(&'a self, name: &'a str) -> BoxFuture<'a, Result<Vec<String>, DKIMError>>;
+
+    /// TXT records with their character-strings kept apart and their TTL,
+    /// see [TxtAnswer], failing with a [LookupError].
+    ///
+    /// By default each record returned by [Lookup::lookup_txt] is a single
+    /// string with an unknown TTL, and the answer has no authentic data bit.
+    fn lookup_txt_answer<'a>(
+        &'a self,
+        name: &'a str,
+    ) -> BoxFuture<'a, Result<TxtAnswer, LookupError>> {
+        Box::pin(async move {
+            let records = self.lookup_txt(name).await?;
+            Ok(TxtAnswer {
+                records: records
+                    .into_iter()
+                    .map(|record| TxtRecord {
+                        strings: vec![record.into_bytes()],
+                        ttl: 0,
+                    })
+                    .collect(),
+                authentic_data: false,
+            })
+        })
+    }
+
+    /// Target of the CNAME record of a name, if any. Used by
+    /// [lookup_txt_following_cnames] when a name has no TXT record.
+    ///
+    /// By default no CNAME is returned, for resolvers following them already.
+    fn lookup_cname<'a>(
+        &'a self,
+        _name: &'a str,
+    ) -> BoxFuture<'a, Result<Option<String>, DKIMError>> {
+        Box::pin(futures::future::ready(Ok(None)))
+    }
+}
+
+/// Default maximum number of CNAMEs followed by [lookup_txt_following_cnames]
+pub const DEFAULT_MAX_CNAME_CHAIN: usize = 5;
+
+/// Maximum length of a label of a DNS name, in octets
+const MAX_LABEL_LENGTH: usize = 63;
+/// Maximum length of a DNS name in its text form without the trailing dot,
+/// in octets
+const MAX_NAME_LENGTH: usize = 253;
+
+/// Check the lengths of a name before querying it, failing with
+/// [DKIMError::InvalidDnsName]
+/// <https://datatracker.ietf.org/doc/html/rfc1035#section-2.3.4>
+pub(crate) fn check_name(name: &str) -> Result<(), DKIMError> {
+    let name = name.strip_suffix('.').unwrap_or(name);
+    if name.len() > MAX_NAME_LENGTH {
+        return Err(DKIMError::InvalidDnsName(format!(
+            "name of {} octets",
+            name.len()
+        )));
+    }
+    for label in name.split('.') {
+        if label.is_empty() {
+            return Err(DKIMError::InvalidDnsName("empty label".to_owned()));
+        }
+        if label.len() > MAX_LABEL_LENGTH {
+            return Err(DKIMError::InvalidDnsName(format!(
+                "label of {} octets",
+                label.len()
+            )));
+        }
+    }
+    Ok(())
+}
+
+/// Normalize a name for comparisons
+fn canonical_name(name: &str) -> String {
+    name.trim_end_matches('.').to_lowercase()
+}
+
+/// Look up the TXT records of a name, following up to `max_cname_chain`
+/// CNAMEs when the name has no TXT record, like
+/// `selector1._domainkey.example.com` pointing to a record of an email
+/// provider. Returns the name the records were found at with the answer.
+pub async fn lookup_txt_following_cnames<L: Lookup + ?Sized>(
+    lookup: &L,
+    name: &str,
+    max_cname_chain: usize,
+) -> Result<(String, TxtAnswer), DKIMError> {
+    let mut name = name.to_owned();
+    let mut visited = vec![canonical_name(&name)];
+    loop {
+        match lookup.lookup_txt_answer(&name).await {
+            Ok(answer) if !answer.records.is_empty() => return Ok((name, answer)),
+            Ok(_) | Err(LookupError::NxDomain | LookupError::NoData) => {}
+            Err(err) => return Err(err.into()),
+        }
+
+        let target = match lookup.lookup_cname(&name).await? {
+            Some(target) => target,
+            None => return Err(DKIMError::NoKeyForSignature),
+        };
+        if visited.contains(&canonical_name(&target)) {
+            return Err(DKIMError::PermanentDnsFailure(format!(
+                "CNAME loop at {}",
+                target
+            )));
+        }
+        if visited.len() > max_cname_chain {
+            return Err(DKIMError::PermanentDnsFailure(format!(
+                "more than {} CNAMEs for {}",
+                max_cname_chain, visited[0]
+            )));
+        }
+        visited.push(canonical_name(&target));
+        name = target;
+    }
+}
+
+/// TXT records returned by [Lookup::lookup_txt_answer]
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub struct TxtAnswer {
+    pub records: Vec<TxtRecord>,
+    /// Whether the resolver set the authentic data (AD) bit of the response,
+    /// asserting that it validated the answer with DNSSEC. Nothing is
+    /// validated locally and anyone on the path to the resolver can set the
+    /// bit, so it only means something with a trusted resolver, like one
+    /// running on the same host.
+    pub authentic_data: bool,
+}
+
+/// TXT record
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub struct TxtRecord {
+    /// Character-strings of the record. A record longer than 255 bytes, like
+    /// a 2048-bit RSA key, is published as several strings which have to be
+    /// concatenated without separator.
+    pub strings: Vec<Vec<u8>>,
+    /// Time to live in seconds, 0 if unknown
+    pub ttl: u32,
+}
+
+impl TxtRecord {
+    /// Record from its character-strings
+    pub fn new<S: AsRef<[u8]>>(strings: &[S], ttl: u32) -> Self {
+        Self {
+            strings: strings.iter().map(|s| s.as_ref().to_vec()).collect(),
+            ttl,
+        }
+    }
+
+    /// Concatenated character-strings, with invalid UTF-8 replaced
+    pub fn text(&self) -> String {
+        String::from_utf8_lossy(&self.strings.concat()).into_owned()
+    }
+}
+
+/// Failure of [Lookup::lookup_txt_answer]
+#[derive(Debug, Clone, PartialEq)]
+pub enum LookupError {
+    /// The name doesn't exist (NXDOMAIN)
+    NxDomain,
+    /// The name exists but has no TXT record (NODATA)
+    NoData,
+    /// The nameservers didn't answer in time
+    Timeout,
+    /// The nameservers failed to answer (SERVFAIL)
+    ServerFailure,
+    /// Any other failure
+    Other(DKIMError),
+}
+
+impl LookupError {
+    /// Whether retrying the lookup may succeed
+    pub fn is_temporary(&self) -> bool {
+        match self {
+            LookupError::Timeout | LookupError::ServerFailure => true,
+            LookupError::Other(err) => matches!(err, DKIMError::TemporaryDnsFailure(_)),
+            LookupError::NxDomain | LookupError::NoData => false,
+        }
+    }
 }
 
-fn to_lookup_error(err: ResolveError) -> DKIMError {
+impl std::fmt::Display for LookupError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            LookupError::NxDomain => write!(f, "non-existent domain"),
+            LookupError::NoData => write!(f, "no TXT record"),
+            LookupError::Timeout => write!(f, "request timed out"),
+            LookupError::ServerFailure => write!(f, "server failure"),
+            LookupError::Other(err) => write!(f, "{}", err),
+        }
+    }
+}
+
+impl From<LookupError> for DKIMError {
+    fn from(err: LookupError) -> Self {
+        match err {
+            LookupError::NxDomain | LookupError::NoData => DKIMError::NoKeyForSignature,
+            LookupError::Timeout | LookupError::ServerFailure => {
+                DKIMError::TemporaryDnsFailure(format!("failed to query DNS: {}", err))
+            }
+            LookupError::Other(err) => err,
+        }
+    }
+}
+
+/// Used by the default [Lookup::lookup_txt_answer], which doesn't know
+/// whether a missing key was a non-existent name or a missing record
+impl From<DKIMError> for LookupError {
+    fn from(err: DKIMError) -> Self {
+        match err {
+            DKIMError::NoKeyForSignature => LookupError::NxDomain,
+            err => LookupError::Other(err),
+        }
+    }
+}
+
+impl<L: Lookup + ?Sized> Lookup for Arc<L> {
+    fn lookup_txt<'a>(&'a self, name: &'a str) -> BoxFuture<'a, Result<Vec<String>, DKIMError>> {
+        (**self).lookup_txt(name)
+    }
+
+    fn lookup_txt_answer<'a>(
+        &'a self,
+        name: &'a str,
+    ) -> BoxFuture<'a, Result<TxtAnswer, LookupError>> {
+        (**self).lookup_txt_answer(name)
+    }
+
+    fn lookup_cname<'a>(
+        &'a self,
+        name: &'a str,
+    ) -> BoxFuture<'a, Result<Option<String>, DKIMError>> {
+        (**self).lookup_cname(name)
+    }
+}
+
+/// Concatenate quoted DNS character-strings (`"v=DKIM1; " "p=..."`), handling
+/// the `\X` and `\DDD` escapes. Returns nothing if the data is malformed.
+pub(crate) fn parse_character_strings(data: &str) -> Option<String> {
+    let mut out = vec![];
+    let mut bytes = data.trim().bytes();
+    loop {
+        match bytes.next() {
+            None => break,
+            Some(b' ' | b'\t' | b'\r' | b'\n') => continue,
+            Some(b'"') => {}
+            Some(_) => return None,
+        }
+        // Inside a character-string
+        loop {
+            match bytes.next()? {
+                b'"' => break,
+                b'\\' => {
+                    let escape = bytes.next()?;
+                    if escape.is_ascii_digit() {
+                        let digits = [escape, bytes.next()?, bytes.next()?];
+                        let value = std::str::from_utf8(&digits).ok()?.parse::<u8>().ok()?;
+                        out.push(value);
+                    } else {
+                        out.push(escape);
+                    }
+                }
+                byte => out.push(byte),
+            }
+        }
+    }
+    Some(String::from_utf8_lossy(&out).into_owned())
+}
+
+#[cfg(all(feature = "dns", not(target_arch = "wasm32")))]
+fn to_lookup_error(err: ResolveError) -> LookupError {
     match err.kind() {
-        ResolveErrorKind::NoRecordsFound { .. } => DKIMError::NoKeyForSignature,
-        _ => DKIMError::KeyUnavailable(format!("failed to query DNS: {}", err)),
+        ResolveErrorKind::NoRecordsFound { response_code, .. } => match *response_code {
+            ResponseCode::NXDomain => LookupError::NxDomain,
+            ResponseCode::ServFail => LookupError::ServerFailure,
+            ResponseCode::NoError => LookupError::NoData,
+            _ => LookupError::Other(DKIMError::KeyUnavailable(format!(
+                "failed to query DNS: {}",
+                err
+            ))),
+        },
+        ResolveErrorKind::Timeout => LookupError::Timeout,
+        ResolveErrorKind::Io(_) | ResolveErrorKind::NoConnections => LookupError::Other(
+            DKIMError::TemporaryDnsFailure(format!("failed to query DNS: {}", err)),
+        ),
+        _ => LookupError::Other(DKIMError::KeyUnavailable(format!(
+            "failed to query DNS: {}",
+            err
+        ))),
     }
 }
 
+#[cfg(all(feature = "dns", not(target_arch = "wasm32")))]
 // Technically we should be able to implemement Lookup for TokioAsyncResolver
 // directly but it's failing for some reason.
 struct TokioAsyncResolverWrapper {
     inner: TokioAsyncResolver,
 }
+#[cfg(all(feature = "dns", not(target_arch = "wasm32")))]
 impl Lookup for TokioAsyncResolverWrapper {
     fn lookup_txt<'a>(&'a self, name: &'a str) -> BoxFuture<'a, Result<Vec<String>, DKIMError>> {
         Box::pin(async move {
-            self.inner
-                .txt_lookup(name)
+            let answer = self.lookup_txt_answer(name).await?;
+            Ok(answer.records.iter().map(TxtRecord::text).collect())
+        })
+    }
+
+    fn lookup_txt_answer<'a>(
+        &'a self,
+        name: &'a str,
+    ) -> BoxFuture<'a, Result<TxtAnswer, LookupError>> {
+        Box::pin(async move {
+            let lookup = self.inner.txt_lookup(name).await.map_err(to_lookup_error)?;
+            Ok(TxtAnswer {
+                records: txt_records(lookup.as_lookup().records()),
+                authentic_data: false,
+            })
+        })
+    }
+
+    fn lookup_cname<'a>(
+        &'a self,
+        name: &'a str,
+    ) -> BoxFuture<'a, Result<Option<String>, DKIMError>> {
+        Box::pin(async move {
+            let lookup = match self.inner.lookup(name, RecordType::CNAME).await {
+                Ok(lookup) => lookup,
+                Err(err) => match to_lookup_error(err) {
+                    LookupError::NxDomain | LookupError::NoData => return Ok(None),
+                    err => return Err(err.into()),
+                },
+            };
+            Ok(lookup.iter().find_map(|data| match data {
+                RData::CNAME(cname) => Some(cname.0.to_ascii()),
+                _ => None,
+            }))
+        })
+    }
+}
+
+/// TXT records of an answer section, whatever their name since a resolver
+/// may return the TXT records of the target of a CNAME
+#[cfg(all(feature = "dns", not(target_arch = "wasm32")))]
+fn txt_records(records: &[Record]) -> Vec<TxtRecord> {
+    records
+        .iter()
+        .filter_map(|record| match record.data() {
+            Some(RData::TXT(txt)) => Some(TxtRecord::new(txt.txt_data(), record.ttl())),
+            _ => None,
+        })
+        .collect()
+}
+
+/// [Lookup] sending the queries directly to validating resolvers and trusting
+/// the authentic data (AD) bit of their responses, see
+/// `ResolverOptions::dnssec`
+#[cfg(all(feature = "dns", not(target_arch = "wasm32")))]
+struct AuthenticDataLookup {
+    pool: NameServerPool<TokioConnectionProvider>,
+}
+
+#[cfg(all(feature = "dns", not(target_arch = "wasm32")))]
+impl Lookup for AuthenticDataLookup {
+    fn lookup_txt<'a>(&'a self, name: &'a str) -> BoxFuture<'a, Result<Vec<String>, DKIMError>> {
+        Box::pin(async move {
+            let answer = self.lookup_txt_answer(name).await?;
+            Ok(answer.records.iter().map(TxtRecord::text).collect())
+        })
+    }
+
+    fn lookup_txt_answer<'a>(
+        &'a self,
+        name: &'a str,
+    ) -> BoxFuture<'a, Result<TxtAnswer, LookupError>> {
+        Box::pin(async move {
+            let query_name = Name::from_str_relaxed(name).map_err(|err| {
+                DKIMError::PermanentDnsFailure(format!("invalid DNS name {}: {}", name, err))
+            })?;
+
+            // Ask for the validation (RFC 6840 section 5.7) and the DNSSEC
+            // records (RFC 3225)
+            let mut edns = Edns::new();
+            edns.set_dnssec_ok(true).set_max_payload(1232);
+            let mut message = Message::new();
+            message
+                .set_message_type(MessageType::Query)
+                .set_op_code(OpCode::Query)
+                .set_recursion_desired(true)
+                .set_authentic_data(true)
+                .set_edns(edns)
+                .add_query(Query::query(query_name, RecordType::TXT));
+            let request = DnsRequest::new(message, DnsRequestOptions::default());
+
+            let response = self
+                .pool
+                .clone()
+                .send(request)
+                .next()
                 .await
-                .map_err(to_lookup_error)?
-                .into_iter()
-                .map(|txt| {
-                    Ok(txt
-                        .iter()
-                        .map(|data| String::from_utf8_lossy(data))
-                        .collect())
-                })
-                .collect()
+                .ok_or(LookupError::Timeout)?
+                .map_err(to_lookup_error)?;
+
+            let records = txt_records(response.answers());
+            if records.is_empty() {
+                return Err(LookupError::NoData);
+            }
+            Ok(TxtAnswer {
+                records,
+                authentic_data: response.authentic_data(),
+            })
         })
     }
 }
 
+/// Create a [Lookup] querying the nameservers of the system configuration
+/// (`/etc/resolv.conf`)
+#[cfg(all(feature = "dns", not(target_arch = "wasm32")))]
+pub fn system_resolver() -> Result<Arc<dyn Lookup>, DKIMError> {
+    let resolver = TokioAsyncResolver::tokio_from_system_conf().map_err(|err| {
+        DKIMError::UnknownInternalError(format!("failed to create DNS resolver: {}", err))
+    })?;
+    Ok(from_tokio_resolver(resolver))
+}
+
+#[cfg(all(feature = "dns", not(target_arch = "wasm32")))]
 pub fn from_tokio_resolver(resolver: TokioAsyncResolver) -> Arc<dyn Lookup> {
     Arc::new(TokioAsyncResolverWrapper { inner: resolver })
 }
+
+/// Transport used to query the nameservers
+#[cfg(all(feature = "dns", not(target_arch = "wasm32")))]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Transport {
+    #[default]
+    Udp,
+    Tcp,
+}
+
+/// Options for [resolver_from_nameservers]
+#[cfg(all(feature = "dns", not(target_arch = "wasm32")))]
+#[derive(Debug, Clone)]
+pub struct ResolverOptions {
+    /// Transport of the queries
+    pub transport: Transport,
+    /// Retry over TCP when a UDP response is truncated, which happens with
+    /// large key records
+    pub tcp_fallback: bool,
+    /// Local address the queries are sent from
+    pub bind_addr: Option<SocketAddr>,
+    /// Timeout of each query
+    pub timeout: Duration,
+    /// Number of attempts of each query before giving up
+    pub attempts: usize,
+    /// Request DNSSEC validation from the nameservers and report the
+    /// authentic data (AD) bit of their responses in
+    /// [TxtAnswer::authentic_data].
+    ///
+    /// The validation isn't done locally: the nameservers have to be
+    /// validating resolvers reached over a trusted path, like a resolver
+    /// running on the same host. The answers aren't cached.
+    pub dnssec: bool,
+}
+
+#[cfg(all(feature = "dns", not(target_arch = "wasm32")))]
+impl Default for ResolverOptions {
+    fn default() -> Self {
+        Self {
+            transport: Transport::Udp,
+            tcp_fallback: true,
+            bind_addr: None,
+            timeout: Duration::from_secs(5),
+            attempts: 2,
+            dnssec: false,
+        }
+    }
+}
+
+#[cfg(all(feature = "dns", not(target_arch = "wasm32")))]
+fn resolver_config(nameservers: &[SocketAddr], options: &ResolverOptions) -> ResolverConfig {
+    let mut protocols = vec![];
+    match options.transport {
+        Transport::Udp if options.tcp_fallback => protocols.extend([Protocol::Udp, Protocol::Tcp]),
+        Transport::Udp => protocols.push(Protocol::Udp),
+        Transport::Tcp => protocols.push(Protocol::Tcp),
+    }
+
+    let mut group = NameServerConfigGroup::new();
+    for addr in nameservers {
+        for protocol in &protocols {
+            let mut config = NameServerConfig::new(*addr, *protocol);
+            config.bind_addr = options.bind_addr;
+            group.push(config);
+        }
+    }
+    ResolverConfig::from_parts(None, vec![], group)
+}
+
+/// Create a [Lookup] querying the given nameservers instead of the ones of
+/// the system configuration, for example `8.8.8.8:53`
+#[cfg(all(feature = "dns", not(target_arch = "wasm32")))]
+pub fn resolver_from_nameservers(
+    nameservers: &[SocketAddr],
+    options: &ResolverOptions,
+) -> Result<Arc<dyn Lookup>, DKIMError> {
+    if nameservers.is_empty() {
+        return Err(DKIMError::UnknownInternalError(
+            "failed to create DNS resolver: no nameservers".to_owned(),
+        ));
+    }
+
+    let mut opts = ResolverOpts::default();
+    opts.timeout = options.timeout;
+    opts.attempts = options.attempts;
+    let config = resolver_config(nameservers, options);
+    if options.dnssec {
+        let group = NameServerConfigGroup::from(config.name_servers().to_vec());
+        let pool = NameServerPool::from_config(group, &opts, TokioConnectionProvider::default());
+        return Ok(Arc::new(AuthenticDataLookup { pool }));
+    }
+    let resolver = TokioAsyncResolver::tokio(config, opts);
+    Ok(from_tokio_resolver(resolver))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_character_strings() {
+        assert_eq!(parse_character_strings(r#""a" "b"  "c""#).unwrap(), "abc");
+        assert_eq!(
+            parse_character_strings(r#""a\"b\\c\032d""#).unwrap(),
+            "a\"b\\c d"
+        );
+        assert_eq!(parse_character_strings(r#""a" b"#), None);
+        assert_eq!(parse_character_strings(r#""a"#), None);
+        assert_eq!(parse_character_strings(r#""\03""#), None);
+    }
+
+    #[tokio::test]
+    async fn test_lookup_txt_following_cnames() {
+        let mut lookup = StaticLookup::default();
+        lookup.insert("a.example.com", "v=DKIM1; p=a");
+        lookup.insert_cname("b.example.com", "a.example.com.");
+        lookup.insert_cname("c.example.com", "B.example.com");
+        lookup.insert_cname("loop1.example.com", "loop2.example.com");
+        lookup.insert_cname("loop2.example.com", "loop1.example.com");
+        lookup.insert_cname("dangling.example.com", "missing.example.com");
+
+        let (name, answer) = lookup_txt_following_cnames(&lookup, "a.example.com", 5)
+            .await
+            .unwrap();
+        assert_eq!(name, "a.example.com");
+        assert_eq!(answer.records, vec![TxtRecord::new(&["v=DKIM1; p=a"], 0)]);
+
+        let (name, answer) = lookup_txt_following_cnames(&lookup, "c.example.com", 5)
+            .await
+            .unwrap();
+        assert_eq!(name, "a.example.com");
+        assert_eq!(answer.records, vec![TxtRecord::new(&["v=DKIM1; p=a"], 0)]);
+        assert!(lookup_txt_following_cnames(&lookup, "c.example.com", 2)
+            .await
+            .is_ok());
+        assert!(lookup_txt_following_cnames(&lookup, "c.example.com", 1)
+            .await
+            .is_err());
+
+        assert_eq!(
+            lookup_txt_following_cnames(&lookup, "loop1.example.com", 5).await,
+            Err(DKIMError::PermanentDnsFailure(
+                "CNAME loop at loop1.example.com".to_owned()
+            ))
+        );
+        assert_eq!(
+            lookup_txt_following_cnames(&lookup, "dangling.example.com", 5).await,
+            Err(DKIMError::NoKeyForSignature)
+        );
+    }
+
+    #[test]
+    fn test_lookup_error() {
+        assert_eq!(
+            DKIMError::from(LookupError::NoData),
+            DKIMError::NoKeyForSignature
+        );
+        assert_eq!(
+            DKIMError::from(LookupError::Timeout),
+            DKIMError::TemporaryDnsFailure("failed to query DNS: request timed out".to_owned())
+        );
+        assert!(LookupError::ServerFailure.is_temporary());
+        assert!(!LookupError::NxDomain.is_temporary());
+        assert!(LookupError::from(DKIMError::TemporaryDnsFailure(String::new())).is_temporary());
+
+        let record = TxtRecord::new(&[&b"v=DKIM1; "[..], b"p=\xff"], 60);
+        assert_eq!(record.text(), "v=DKIM1; p=\u{fffd}");
+    }
+
+    #[cfg(all(feature = "dns", not(target_arch = "wasm32")))]
+    #[test]
+    fn test_to_lookup_error() {
+        assert_eq!(
+            to_lookup_error(ResolveErrorKind::Timeout.into()),
+            LookupError::Timeout
+        );
+        assert!(matches!(
+            to_lookup_error(ResolveErrorKind::Message("invalid").into()),
+            LookupError::Other(DKIMError::KeyUnavailable(_))
+        ));
+    }
+
+    #[cfg(all(feature = "dns", not(target_arch = "wasm32")))]
+    mod nameservers {
+        use super::*;
+        use trust_dns_resolver::proto::op::{Message, MessageType};
+        use trust_dns_resolver::proto::rr::rdata::TXT;
+
+        /// Answer a query like a validating resolver serving a TXT record at
+        /// `s._domainkey.example.com` in a signed zone and one at
+        /// `unsigned.example.com`, failing with SERVFAIL for `fail.example.com`
+        fn answer(query: &[u8]) -> Vec<u8> {
+            let query = Message::from_vec(query).unwrap();
+            let mut response = Message::new();
+            response
+                .set_id(query.id())
+                .set_message_type(MessageType::Response)
+                .set_op_code(query.op_code())
+                .set_recursion_desired(query.recursion_desired())
+                .set_recursion_available(true)
+                .add_queries(query.queries().to_vec());
+
+            let name = query.queries()[0].name().clone();
+            let dnssec_ok = query
+                .extensions()
+                .as_ref()
+                .is_some_and(|edns| edns.dnssec_ok());
+            match name.to_ascii().trim_end_matches('.') {
+                "s._domainkey.example.com" => {
+                    let txt = TXT::new(vec!["v=DKIM1; ".to_owned(), "p=abc".to_owned()]);
+                    response.add_answer(Record::from_rdata(name, 60, RData::TXT(txt)));
+                    response.set_authentic_data(dnssec_ok || query.authentic_data());
+                }
+                "unsigned.example.com" => {
+                    let txt = TXT::new(vec!["v=DKIM1; p=def".to_owned()]);
+                    response.add_answer(Record::from_rdata(name, 60, RData::TXT(txt)));
+                }
+                "fail.example.com" => {
+                    response.set_response_code(ResponseCode::ServFail);
+                }
+                _ => {
+                    response.set_response_code(ResponseCode::NXDomain);
+                }
+            }
+            response.to_vec().unwrap()
+        }
+
+        async fn udp_nameserver() -> SocketAddr {
+            let socket = tokio::net::UdpSocket::bind("127.0.0.1:0").await.unwrap();
+            let addr = socket.local_addr().unwrap();
+            tokio::spawn(async move {
+                let mut buffer = [0; 512];
+                while let Ok((len, peer)) = socket.recv_from(&mut buffer).await {
+                    let _ = socket.send_to(&answer(&buffer[..len]), peer).await;
+                }
+            });
+            addr
+        }
+
+        async fn tcp_nameserver() -> SocketAddr {
+            use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+            let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+            let addr = listener.local_addr().unwrap();
+            tokio::spawn(async move {
+                while let Ok((mut stream, _)) = listener.accept().await {
+                    tokio::spawn(async move {
+                        // Messages are prefixed by their length
+                        while let Ok(len) = stream.read_u16().await {
+                            let mut query = vec![0; len as usize];
+                            if stream.read_exact(&mut query).await.is_err() {
+                                return;
+                            }
+                            let response = answer(&query);
+                            let _ = stream.write_u16(response.len() as u16).await;
+                            let _ = stream.write_all(&response).await;
+                        }
+                    });
+                }
+            });
+            addr
+        }
+
+        fn options(transport: Transport) -> ResolverOptions {
+            ResolverOptions {
+                transport,
+                tcp_fallback: false,
+                timeout: Duration::from_secs(1),
+                attempts: 1,
+                ..ResolverOptions::default()
+            }
+        }
+
+        #[test]
+        fn test_resolver_config() {
+            let addrs: Vec<SocketAddr> = vec!["8.8.8.8:53".parse().unwrap()];
+            let bind_addr = Some("192.0.2.1:0".parse().unwrap());
+
+            let config = resolver_config(
+                &addrs,
+                &ResolverOptions {
+                    bind_addr,
+                    ..ResolverOptions::default()
+                },
+            );
+            let protocols: Vec<_> = config
+                .name_servers()
+                .iter()
+                .map(|ns| (ns.socket_addr, ns.protocol, ns.bind_addr))
+                .collect();
+            assert_eq!(
+                protocols,
+                vec![
+                    (addrs[0], Protocol::Udp, bind_addr),
+                    (addrs[0], Protocol::Tcp, bind_addr)
+                ]
+            );
+
+            let config = resolver_config(&addrs, &options(Transport::Tcp));
+            assert_eq!(config.name_servers().len(), 1);
+            assert_eq!(config.name_servers()[0].protocol, Protocol::Tcp);
+
+            assert!(matches!(
+                resolver_from_nameservers(&[], &ResolverOptions::default()),
+                Err(DKIMError::UnknownInternalError(_))
+            ));
+        }
+
+        #[tokio::test]
+        async fn test_resolver_from_nameservers() {
+            for (transport, addr) in [
+                (Transport::Udp, udp_nameserver().await),
+                (Transport::Tcp, tcp_nameserver().await),
+            ] {
+                let resolver = resolver_from_nameservers(&[addr], &options(transport)).unwrap();
+                assert_eq!(
+                    resolver
+                        .lookup_txt_answer("s._domainkey.example.com")
+                        .await
+                        .unwrap(),
+                    TxtAnswer {
+                        records: vec![TxtRecord::new(&["v=DKIM1; ", "p=abc"], 60)],
+                        authentic_data: false,
+                    }
+                );
+                assert_eq!(
+                    resolver.lookup_txt_answer("missing.example.com").await,
+                    Err(LookupError::NxDomain)
+                );
+                assert_eq!(
+                    resolver.lookup_txt_answer("fail.example.com").await,
+                    Err(LookupError::ServerFailure)
+                );
+                assert_eq!(
+                    resolver.lookup_txt("s._domainkey.example.com").await,
+                    Ok(vec!["v=DKIM1; p=abc".to_owned()])
+                );
+                assert_eq!(
+                    resolver.lookup_txt("missing.example.com").await,
+                    Err(DKIMError::NoKeyForSignature)
+                );
+                assert!(matches!(
+                    resolver.lookup_txt("fail.example.com").await,
+                    Err(DKIMError::TemporaryDnsFailure(_))
+                ));
+            }
+        }
+
+        #[tokio::test]
+        async fn test_resolver_from_nameservers_dnssec() {
+            let options = ResolverOptions {
+                dnssec: true,
+                ..options(Transport::Udp)
+            };
+            let resolver = resolver_from_nameservers(&[udp_nameserver().await], &options).unwrap();
+
+            assert_eq!(
+                resolver
+                    .lookup_txt_answer("s._domainkey.example.com")
+                    .await
+                    .unwrap(),
+                TxtAnswer {
+                    records: vec![TxtRecord::new(&["v=DKIM1; ", "p=abc"], 60)],
+                    authentic_data: true,
+                }
+            );
+            assert_eq!(
+                resolver.lookup_txt_answer("unsigned.example.com").await,
+                Ok(TxtAnswer {
+                    records: vec![TxtRecord::new(&["v=DKIM1; p=def"], 60)],
+                    authentic_data: false,
+                })
+            );
+            assert_eq!(
+                resolver.lookup_txt("s._domainkey.example.com").await,
+                Ok(vec!["v=DKIM1; p=abc".to_owned()])
+            );
+            assert_eq!(
+                resolver.lookup_txt("missing.example.com").await,
+                Err(DKIMError::NoKeyForSignature)
+            );
+            assert_eq!(
+                resolver.lookup_txt_answer("fail.example.com").await,
+                Err(LookupError::ServerFailure)
+            );
+            assert!(matches!(
+                resolver.lookup_txt("fail.example.com").await,
+                Err(DKIMError::TemporaryDnsFailure(_))
+            ));
+        }
+    }
+}
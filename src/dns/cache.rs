@@ -0,0 +1,286 @@
+//! Caching the answers of another lookup
+
+use super::{canonical_name, Lookup, LookupError, TxtAnswer, TxtRecord};
+use crate::{Clock, DKIMError, SystemClock};
+use futures::future::BoxFuture;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+
+const DEFAULT_TTL: chrono::Duration = chrono::Duration::minutes(5);
+const MAX_TTL: chrono::Duration = chrono::Duration::days(1);
+
+/// [Lookup] keeping the answers of another one, including the non-existent
+/// names, for the TTL of their records, to share the lookups of the messages
+/// of a batch coming from the same senders.
+///
+/// Answers with an unknown TTL are kept 5 minutes, and no answer is kept
+/// longer than a day. Temporary failures aren't cached.
+pub struct CachingLookup<L> {
+    inner: L,
+    capacity: usize,
+    default_ttl: chrono::Duration,
+    clock: Arc<dyn Clock>,
+    entries: Mutex<HashMap<(Kind, String), Entry>>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+enum Kind {
+    Txt,
+    Cname,
+}
+
+#[derive(Clone)]
+enum Answer {
+    Txt(Result<TxtAnswer, LookupError>),
+    Cname(Option<String>),
+}
+
+struct Entry {
+    answer: Answer,
+    expires_at: chrono::NaiveDateTime,
+}
+
+impl<L: Lookup> CachingLookup<L> {
+    /// New lookup keeping up to `capacity` answers
+    pub fn new(inner: L, capacity: usize) -> Self {
+        Self {
+            inner,
+            capacity: capacity.max(1),
+            default_ttl: DEFAULT_TTL,
+            clock: Arc::new(SystemClock),
+            entries: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Keep the answers with an unknown TTL, and the non-existent names, for
+    /// this long
+    pub fn with_default_ttl(mut self, ttl: chrono::Duration) -> Self {
+        self.default_ttl = ttl;
+        self
+    }
+
+    /// Clock used to expire the answers. Defaults to the system clock.
+    pub fn with_clock(mut self, clock: Arc<dyn Clock>) -> Self {
+        self.clock = clock;
+        self
+    }
+
+    /// Drop all the answers
+    pub fn clear(&self) {
+        self.entries.lock().unwrap().clear();
+    }
+
+    /// Number of answers kept, including the expired ones not evicted yet
+    pub fn len(&self) -> usize {
+        self.entries.lock().unwrap().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    fn get(&self, kind: Kind, name: &str) -> Option<Answer> {
+        let now = self.clock.now();
+        let key = (kind, canonical_name(name));
+        let mut entries = self.entries.lock().unwrap();
+        match entries.get(&key) {
+            Some(entry) if entry.expires_at > now => Some(entry.answer.clone()),
+            Some(_) => {
+                entries.remove(&key);
+                None
+            }
+            None => None,
+        }
+    }
+
+    fn insert(&self, kind: Kind, name: &str, answer: Answer, ttl: chrono::Duration) {
+        let now = self.clock.now();
+        let mut entries = self.entries.lock().unwrap();
+        if entries.len() >= self.capacity {
+            entries.retain(|_, entry| entry.expires_at > now);
+        }
+        if entries.len() >= self.capacity {
+            // Evict the answer expiring first
+            if let Some(key) = entries
+                .iter()
+                .min_by_key(|(_, entry)| entry.expires_at)
+                .map(|(key, _)| key.clone())
+            {
+                entries.remove(&key);
+            }
+        }
+        entries.insert(
+            (kind, canonical_name(name)),
+            Entry {
+                answer,
+                expires_at: now + ttl.min(MAX_TTL),
+            },
+        );
+    }
+
+    /// TTL of an answer, the smallest one of its records
+    fn answer_ttl(&self, answer: &TxtAnswer) -> chrono::Duration {
+        match answer.records.iter().map(|record| record.ttl).min() {
+            Some(ttl) if ttl > 0 => chrono::Duration::seconds(i64::from(ttl)),
+            _ => self.default_ttl,
+        }
+    }
+}
+
+impl<L: Lookup> Lookup for CachingLookup<L> {
+    fn lookup_txt<'a>(&'a self, name: &'a str) -> BoxFuture<'a, Result<Vec<String>, DKIMError>> {
+        Box::pin(async move {
+            let answer = self.lookup_txt_answer(name).await?;
+            Ok(answer.records.iter().map(TxtRecord::text).collect())
+        })
+    }
+
+    fn lookup_txt_answer<'a>(
+        &'a self,
+        name: &'a str,
+    ) -> BoxFuture<'a, Result<TxtAnswer, LookupError>> {
+        Box::pin(async move {
+            if let Some(Answer::Txt(answer)) = self.get(Kind::Txt, name) {
+                return answer;
+            }
+            let answer = self.inner.lookup_txt_answer(name).await;
+            let ttl = match &answer {
+                Ok(answer) => self.answer_ttl(answer),
+                Err(LookupError::NxDomain | LookupError::NoData) => self.default_ttl,
+                Err(_) => return answer,
+            };
+            self.insert(Kind::Txt, name, Answer::Txt(answer.clone()), ttl);
+            answer
+        })
+    }
+
+    fn lookup_cname<'a>(
+        &'a self,
+        name: &'a str,
+    ) -> BoxFuture<'a, Result<Option<String>, DKIMError>> {
+        Box::pin(async move {
+            if let Some(Answer::Cname(target)) = self.get(Kind::Cname, name) {
+                return Ok(target);
+            }
+            let target = self.inner.lookup_cname(name).await?;
+            self.insert(
+                Kind::Cname,
+                name,
+                Answer::Cname(target.clone()),
+                self.default_ttl,
+            );
+            Ok(target)
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::FixedClock;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    /// Answers with a record of the given TTL for the names starting with
+    /// "key", counting the queries
+    struct CountingLookup {
+        queries: AtomicUsize,
+        ttl: u32,
+    }
+
+    impl Lookup for CountingLookup {
+        fn lookup_txt<'a>(
+            &'a self,
+            name: &'a str,
+        ) -> BoxFuture<'a, Result<Vec<String>, DKIMError>> {
+            Box::pin(async move {
+                let answer = self.lookup_txt_answer(name).await?;
+                Ok(answer.records.iter().map(TxtRecord::text).collect())
+            })
+        }
+
+        fn lookup_txt_answer<'a>(
+            &'a self,
+            name: &'a str,
+        ) -> BoxFuture<'a, Result<TxtAnswer, LookupError>> {
+            self.queries.fetch_add(1, Ordering::SeqCst);
+            let answer = if name.starts_with("key") {
+                Ok(TxtAnswer {
+                    records: vec![TxtRecord::new(&["v=DKIM1; p="], self.ttl)],
+                    authentic_data: false,
+                })
+            } else if name.starts_with("servfail") {
+                Err(LookupError::ServerFailure)
+            } else {
+                Err(LookupError::NxDomain)
+            };
+            Box::pin(futures::future::ready(answer))
+        }
+    }
+
+    fn at(seconds: i64) -> Arc<dyn Clock> {
+        Arc::new(FixedClock(
+            chrono::DateTime::from_timestamp(1_700_000_000 + seconds, 0)
+                .unwrap()
+                .naive_utc(),
+        ))
+    }
+
+    #[tokio::test]
+    async fn test_caching_lookup() {
+        let inner = Arc::new(CountingLookup {
+            queries: AtomicUsize::new(0),
+            ttl: 60,
+        });
+        let queries = || inner.queries.load(Ordering::SeqCst);
+        let lookup = CachingLookup::new(Arc::clone(&inner), 2).with_clock(at(0));
+
+        lookup.lookup_txt_answer("key.example.com").await.unwrap();
+        lookup.lookup_txt("KEY.example.com.").await.unwrap();
+        assert_eq!(queries(), 1);
+
+        // Non-existent names are cached too, not the temporary failures
+        for _ in 0..2 {
+            assert_eq!(
+                lookup.lookup_txt("missing.example.com").await,
+                Err(DKIMError::NoKeyForSignature)
+            );
+            lookup
+                .lookup_txt_answer("servfail.example.com")
+                .await
+                .unwrap_err();
+        }
+        assert_eq!(queries(), 4);
+        assert_eq!(lookup.len(), 2);
+
+        // Expired after the TTL of the record
+        let lookup = lookup.with_clock(at(60));
+        lookup.lookup_txt_answer("key.example.com").await.unwrap();
+        assert_eq!(queries(), 5);
+
+        lookup.clear();
+        assert!(lookup.is_empty());
+
+        // The inner lookup answers the strings of its records
+        assert_eq!(
+            inner.lookup_txt("key.example.com").await,
+            Ok(vec!["v=DKIM1; p=".to_owned()])
+        );
+        assert_eq!(queries(), 6);
+    }
+
+    #[tokio::test]
+    async fn test_caching_lookup_capacity() {
+        let inner = Arc::new(CountingLookup {
+            queries: AtomicUsize::new(0),
+            ttl: 0,
+        });
+        let lookup = CachingLookup::new(Arc::clone(&inner), 2)
+            .with_default_ttl(chrono::Duration::seconds(10))
+            .with_clock(at(0));
+        for name in ["key1.example.com", "key2.example.com", "key3.example.com"] {
+            lookup.lookup_txt_answer(name).await.unwrap();
+        }
+        assert_eq!(lookup.len(), 2);
+        assert_eq!(inner.queries.load(Ordering::SeqCst), 3);
+    }
+}
@@ -0,0 +1,137 @@
+//! DNS-over-HTTPS resolution using the JSON API of public resolvers
+//! (<https://developers.google.com/speed/public-dns/docs/doh/json>)
+
+use super::doh_json::{parse_response, query_url, CLOUDFLARE_ENDPOINT, GOOGLE_ENDPOINT};
+use super::{Lookup, LookupError, TxtAnswer, TxtRecord};
+use crate::DKIMError;
+use futures::future::BoxFuture;
+use std::sync::Arc;
+
+/// HTTP transport used by [DohLookup], for example backed by reqwest
+/// natively or by fetch on wasm32.
+///
+/// ```ignore
+/// struct ReqwestClient(reqwest::Client);
+///
+/// impl HttpClient for ReqwestClient {
+///     fn get_json<'a>(&'a self, url: &'a str) -> BoxFuture<'a, Result<String, DKIMError>> {
+///         Box::pin(async move {
+///             let res = self.0.get(url).header("accept", "application/dns-json").send().await;
+///             res.and_then(|res| res.error_for_status())
+///                 .map_err(|err| DKIMError::KeyUnavailable(err.to_string()))?
+///                 .text()
+///                 .await
+///                 .map_err(|err| DKIMError::KeyUnavailable(err.to_string()))
+///         })
+///     }
+/// }
+/// ```
+pub trait HttpClient: Sync + Send {
+    /// Perform a GET request with the `Accept: application/dns-json` header
+    /// and return the body of the response
+    fn get_json<'a>(&'a self, url: &'a str) -> BoxFuture<'a, Result<String, DKIMError>>;
+}
+
+/// [Lookup] querying the JSON API of a DNS-over-HTTPS resolver
+pub struct DohLookup {
+    client: Arc<dyn HttpClient>,
+    endpoint: String,
+}
+
+impl DohLookup {
+    /// New lookup using the given endpoint, like [GOOGLE_ENDPOINT]
+    pub fn new(client: Arc<dyn HttpClient>, endpoint: &str) -> Self {
+        Self {
+            client,
+            endpoint: endpoint.to_owned(),
+        }
+    }
+
+    /// New lookup using Google Public DNS
+    pub fn google(client: Arc<dyn HttpClient>) -> Self {
+        Self::new(client, GOOGLE_ENDPOINT)
+    }
+
+    /// New lookup using Cloudflare DNS
+    pub fn cloudflare(client: Arc<dyn HttpClient>) -> Self {
+        Self::new(client, CLOUDFLARE_ENDPOINT)
+    }
+
+    /// URL of the TXT query for a name
+    pub fn query_url(&self, name: &str) -> String {
+        query_url(&self.endpoint, name)
+    }
+}
+
+impl Lookup for DohLookup {
+    fn lookup_txt<'a>(&'a self, name: &'a str) -> BoxFuture<'a, Result<Vec<String>, DKIMError>> {
+        Box::pin(async move {
+            let answer = self.lookup_txt_answer(name).await?;
+            Ok(answer.records.iter().map(TxtRecord::text).collect())
+        })
+    }
+
+    /// The answer has the authentic data bit if the resolver set it, having
+    /// validated it with DNSSEC (AD flag of the response)
+    fn lookup_txt_answer<'a>(
+        &'a self,
+        name: &'a str,
+    ) -> BoxFuture<'a, Result<TxtAnswer, LookupError>> {
+        Box::pin(async move {
+            let response = self.client.get_json(&self.query_url(name)).await?;
+            parse_response(name, &response)
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+
+    struct MockClient {
+        responses: HashMap<String, String>,
+    }
+
+    impl HttpClient for MockClient {
+        fn get_json<'a>(&'a self, url: &'a str) -> BoxFuture<'a, Result<String, DKIMError>> {
+            let res = self
+                .responses
+                .get(url)
+                .cloned()
+                .ok_or_else(|| DKIMError::KeyUnavailable(format!("unexpected url: {}", url)));
+            Box::pin(async move { res })
+        }
+    }
+
+    #[test]
+    fn test_query_url() {
+        let client = Arc::new(MockClient {
+            responses: HashMap::new(),
+        });
+        assert_eq!(
+            DohLookup::google(client.clone()).query_url("s._domainkey.example.com"),
+            "https://dns.google/resolve?name=s._domainkey.example.com&type=TXT"
+        );
+        assert_eq!(
+            DohLookup::cloudflare(client).query_url("a b&c"),
+            "https://cloudflare-dns.com/dns-query?name=a%20b%26c&type=TXT"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_lookup_txt() {
+        let url = "https://dns.google/resolve?name=s._domainkey.example.com&type=TXT";
+        let response = r#"{"Status":0,"TC":false,"RD":true,"RA":true,"AD":false,"CD":false,
+            "Question":[{"name":"s._domainkey.example.com.","type":16}],
+            "Answer":[{"name":"s._domainkey.example.com.","type":16,"TTL":3600,
+                "data":"\"v=DKIM1; k=rsa; \" \"p=MIIBIjANBg\\\"kqh\""}]}"#;
+        let lookup = DohLookup::google(Arc::new(MockClient {
+            responses: HashMap::from([(url.to_owned(), response.to_owned())]),
+        }));
+        assert_eq!(
+            lookup.lookup_txt("s._domainkey.example.com").await.unwrap(),
+            vec!["v=DKIM1; k=rsa; p=MIIBIjANBg\"kqh"]
+        );
+    }
+}
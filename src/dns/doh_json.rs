@@ -0,0 +1,569 @@
+//! Reading the JSON API of DNS-over-HTTPS resolvers
+//! (<https://developers.google.com/speed/public-dns/docs/doh/json>)
+//!
+//! Available on all targets for environments doing the HTTPS requests
+//! themselves, like fetch on wasm32.
+
+use super::{parse_character_strings, LookupError, TxtAnswer, TxtRecord};
+use crate::{json, list_signatures, public_key, DKIMError, DkimPublicKey, DNS_NAMESPACE};
+use rsa::RsaPublicKey;
+
+/// Google Public DNS JSON API endpoint
+pub const GOOGLE_ENDPOINT: &str = "https://dns.google/resolve";
+/// Cloudflare DNS JSON API endpoint
+pub const CLOUDFLARE_ENDPOINT: &str = "https://cloudflare-dns.com/dns-query";
+
+/// Maximum number of CNAMEs followed in an answer
+const MAX_CNAME_CHAIN: usize = 8;
+
+const TXT_TYPE: u64 = 16;
+const CNAME_TYPE: u64 = 5;
+
+const NOERROR: u64 = 0;
+const SERVFAIL: u64 = 2;
+const NXDOMAIN: u64 = 3;
+
+/// Resolver answering the queries of [get_dns_query_urls]
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub enum DohProvider {
+    /// Google Public DNS
+    #[default]
+    Google,
+    /// Cloudflare DNS
+    Cloudflare,
+    /// Another resolver with the same JSON API, by the URL of its endpoint
+    Custom(String),
+}
+
+impl DohProvider {
+    /// URL of the JSON API endpoint
+    pub fn endpoint(&self) -> &str {
+        match self {
+            DohProvider::Google => GOOGLE_ENDPOINT,
+            DohProvider::Cloudflare => CLOUDFLARE_ENDPOINT,
+            DohProvider::Custom(endpoint) => endpoint,
+        }
+    }
+}
+
+/// Query of the key record of a signature, see [get_dns_query_urls]
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct DnsQuery {
+    /// Position of the signature among the DKIM-Signature headers
+    pub index: usize,
+    /// Selector of the key (s=)
+    pub selector: String,
+    /// Signing domain (d=)
+    pub domain: String,
+    /// URL of the query
+    pub url: String,
+}
+
+impl DnsQuery {
+    /// Name of the key record
+    pub fn name(&self) -> String {
+        key_name(&self.selector, &self.domain)
+    }
+}
+
+fn key_name(selector: &str, domain: &str) -> String {
+    format!("{}.{}.{}", selector, DNS_NAMESPACE, domain)
+}
+
+/// URL of the Google Public DNS query for the key record of a selector
+pub fn get_google_dns_url(selector: &str, domain: &str) -> String {
+    query_url(GOOGLE_ENDPOINT, &key_name(selector, domain))
+}
+
+/// Queries of the key records of every signature of the email, in header
+/// order. Headers which aren't valid tag-lists are skipped, see
+/// [list_signatures].
+pub fn get_dns_query_urls(
+    email_bytes: &[u8],
+    provider: &DohProvider,
+) -> Result<Vec<DnsQuery>, DKIMError> {
+    Ok(list_signatures(email_bytes)?
+        .into_iter()
+        .map(|signature| DnsQuery {
+            index: signature.index,
+            url: query_url(
+                provider.endpoint(),
+                &key_name(&signature.selector, &signature.domain),
+            ),
+            selector: signature.selector,
+            domain: signature.domain,
+        })
+        .collect())
+}
+
+/// Extract the public key of a query of [get_dns_query_urls] from its
+/// response. A response to another query is rejected.
+pub fn get_public_key_for_query(
+    query: &DnsQuery,
+    response: &str,
+) -> Result<DkimPublicKey, DKIMError> {
+    find_key(&query.selector, &query.domain, response, "key", Some)
+}
+
+/// Extract the public key of a selector from the response of the query
+/// returned by [get_google_dns_url]. The first usable record is used, in the
+/// order of the answer.
+pub fn get_public_key_from_google_dns(
+    selector: &str,
+    domain: &str,
+    response: &str,
+) -> Result<DkimPublicKey, DKIMError> {
+    find_key(selector, domain, response, "key", Some)
+}
+
+/// Extract the RSA public key of a selector from the response of the query
+/// returned by [get_google_dns_url], skipping the keys of other types
+pub fn get_rsa_public_key_from_google_dns(
+    selector: &str,
+    domain: &str,
+    response: &str,
+) -> Result<RsaPublicKey, DKIMError> {
+    find_key(selector, domain, response, "RSA key", |key| match key {
+        DkimPublicKey::Rsa(key) => Some(key),
+        _ => None,
+    })
+}
+
+/// Extract the key record of a selector from the response of the query
+/// returned by [get_google_dns_url]: the first one with a usable key, in the
+/// order of the answer
+pub fn get_key_record_from_google_dns(
+    selector: &str,
+    domain: &str,
+    response: &str,
+) -> Result<public_key::DkimKeyRecord, DKIMError> {
+    find_record(selector, domain, response, "key", |record| {
+        record.public_key()?;
+        Ok(Some(record))
+    })
+}
+
+fn find_key<T>(
+    selector: &str,
+    domain: &str,
+    response: &str,
+    description: &str,
+    select: impl Fn(DkimPublicKey) -> Option<T>,
+) -> Result<T, DKIMError> {
+    find_record(selector, domain, response, description, |record| {
+        Ok(select(record.public_key()?))
+    })
+}
+
+/// First usable record of the answer accepted by `select`, or the error of
+/// the last record which couldn't be parsed
+fn find_record<T>(
+    selector: &str,
+    domain: &str,
+    response: &str,
+    description: &str,
+    select: impl Fn(public_key::DkimKeyRecord) -> Result<Option<T>, DKIMError>,
+) -> Result<T, DKIMError> {
+    let name = key_name(selector, domain);
+    let answer = parse_response(&name, response)?;
+    let mut last_error = None;
+    for record in &answer.records {
+        let selected = public_key::parse_key_record_lenient(&record.text()).and_then(|record| {
+            record.check_usable(selector, domain)?;
+            select(record)
+        });
+        match selected {
+            Ok(Some(selected)) => return Ok(selected),
+            Ok(None) => {}
+            Err(err) => last_error = Some(err),
+        }
+    }
+    Err(last_error.unwrap_or_else(|| {
+        DKIMError::KeyUnavailable(format!(
+            "no {} found in the DNS response for {}",
+            description, name
+        ))
+    }))
+}
+
+/// URL of the TXT query for a name
+pub(crate) fn query_url(endpoint: &str, name: &str) -> String {
+    format!("{}?name={}&type=TXT", endpoint, percent_encode(name))
+}
+
+fn percent_encode(value: &str) -> String {
+    let mut out = String::with_capacity(value.len());
+    for byte in value.bytes() {
+        if byte.is_ascii_alphanumeric() || matches!(byte, b'-' | b'.' | b'_' | b'~') {
+            out.push(byte as char);
+        } else {
+            out += &format!("%{:02X}", byte);
+        }
+    }
+    out
+}
+
+/// Compare DNS names, ignoring the case and the trailing dot
+fn same_name(a: &str, b: &str) -> bool {
+    a.trim_end_matches('.')
+        .eq_ignore_ascii_case(b.trim_end_matches('.'))
+}
+
+/// Extract the TXT records for a name from a DoH JSON response, following
+/// the CNAMEs present in the answer section. The response is rejected when
+/// its question is for another name.
+pub(crate) fn parse_response(name: &str, response: &str) -> Result<TxtAnswer, LookupError> {
+    let value = json::parse(response)
+        .map_err(|err| DKIMError::KeyUnavailable(format!("invalid DoH response: {}", err)))?;
+
+    let question = value
+        .get("Question")
+        .and_then(json::Value::as_array)
+        .and_then(|questions| questions.first()?.get("name")?.as_str());
+    if let Some(question) = question {
+        if !same_name(question, name) {
+            return Err(DKIMError::KeyUnavailable(format!(
+                "DoH response for {} instead of {}",
+                question.trim_end_matches('.'),
+                name
+            ))
+            .into());
+        }
+    }
+
+    match value.get("Status").and_then(json::Value::as_u64) {
+        Some(NOERROR) => {}
+        Some(NXDOMAIN) => return Err(LookupError::NxDomain),
+        Some(SERVFAIL) => return Err(LookupError::ServerFailure),
+        Some(status) => {
+            return Err(DKIMError::KeyUnavailable(format!(
+                "failed to query DNS: response code {}",
+                status
+            ))
+            .into())
+        }
+        None => {
+            return Err(DKIMError::KeyUnavailable(
+                "invalid DoH response: missing Status".to_owned(),
+            )
+            .into())
+        }
+    }
+
+    let answers = value
+        .get("Answer")
+        .and_then(json::Value::as_array)
+        .unwrap_or_default();
+    let mut owner = name.to_owned();
+    for _ in 0..=MAX_CNAME_CHAIN {
+        let txts = records(answers, TXT_TYPE, &owner)
+            .map(|(data, ttl)| {
+                Ok(TxtRecord {
+                    strings: vec![parse_txt_data(data)?.into_bytes()],
+                    ttl,
+                })
+            })
+            .collect::<Result<Vec<_>, DKIMError>>()?;
+        if !txts.is_empty() {
+            return Ok(TxtAnswer {
+                records: txts,
+                authentic_data: value.get("AD") == Some(&json::Value::Bool(true)),
+            });
+        }
+
+        let target = records(answers, CNAME_TYPE, &owner).next();
+        match target {
+            Some((target, _)) => owner = target.to_owned(),
+            None => return Err(LookupError::NoData),
+        }
+    }
+
+    Err(DKIMError::PermanentDnsFailure(format!(
+        "failed to query DNS: more than {} CNAMEs for {}",
+        MAX_CNAME_CHAIN, name
+    ))
+    .into())
+}
+
+/// Data and TTL of the answers of a type for a name
+fn records<'a>(
+    answers: &'a [json::Value],
+    record_type: u64,
+    owner: &'a str,
+) -> impl Iterator<Item = (&'a str, u32)> + 'a {
+    answers
+        .iter()
+        .filter(move |answer| {
+            answer.get("type").and_then(json::Value::as_u64) == Some(record_type)
+                && answer
+                    .get("name")
+                    .and_then(json::Value::as_str)
+                    .is_some_and(|name| same_name(name, owner))
+        })
+        .filter_map(|answer| {
+            let data = answer.get("data").and_then(json::Value::as_str)?;
+            let ttl = answer.get("TTL").and_then(json::Value::as_u64).unwrap_or(0);
+            Some((data, u32::try_from(ttl).unwrap_or(u32::MAX)))
+        })
+}
+
+/// Concatenate the character-strings of TXT data. Some resolvers return them
+/// quoted (`"v=DKIM1; " "p=..."`) and others already joined.
+fn parse_txt_data(data: &str) -> Result<String, DKIMError> {
+    let data = data.trim();
+    if !data.starts_with('"') {
+        return Ok(data.to_owned());
+    }
+    parse_character_strings(data)
+        .ok_or_else(|| DKIMError::KeyUnavailable(format!("invalid TXT data: {}", data)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_google_dns() {
+        assert_eq!(
+            get_google_dns_url("2022", "cloudflare.com"),
+            "https://dns.google/resolve?name=2022._domainkey.cloudflare.com&type=TXT"
+        );
+
+        // Google returns the character-strings already joined
+        let zone = std::fs::read_to_string("./test/keys/2022.txt").unwrap();
+        let record =
+            parse_character_strings(&zone[zone.find('"').unwrap()..zone.rfind('"').unwrap() + 1])
+                .unwrap();
+        let response = format!(
+            r#"{{"Status":0,"Answer":[
+                {{"name":"2022._domainkey.cloudflare.com.","type":16,"TTL":300,"data":"v=DKIM1; k=ed25519; p=11qYAYKxCrfVS/7TyWQHOg7hcvPapiMlrwIaaPcHURo="}},
+                {{"name":"2022._domainkey.cloudflare.com.","type":16,"TTL":300,"data":"{}"}}]}}"#,
+            record
+        );
+        let key = get_rsa_public_key_from_google_dns("2022", "cloudflare.com", &response).unwrap();
+        assert_eq!(rsa::traits::PublicKeyParts::size(&key), 256);
+
+        // Errors are returned instead of panicking
+        assert_eq!(
+            get_rsa_public_key_from_google_dns(
+                "2022",
+                "cloudflare.com",
+                r#"{"Status":0,"Answer":[]}"#
+            ),
+            Err(DKIMError::NoKeyForSignature)
+        );
+        assert!(matches!(
+            get_rsa_public_key_from_google_dns("2022", "cloudflare.com", "{"),
+            Err(DKIMError::KeyUnavailable(_))
+        ));
+        let response = r#"{"Status":0,"Answer":[{"name":"2022._domainkey.cloudflare.com.","type":16,"TTL":300,"data":"v=DKIM1; k=ed25519; p=11qYAYKxCrfVS/7TyWQHOg7hcvPapiMlrwIaaPcHURo="}]}"#;
+        assert_eq!(
+            get_rsa_public_key_from_google_dns("2022", "cloudflare.com", response),
+            Err(DKIMError::KeyUnavailable(
+                "no RSA key found in the DNS response for 2022._domainkey.cloudflare.com"
+                    .to_owned()
+            ))
+        );
+        let response = r#"{"Status":0,"Answer":[{"name":"2022._domainkey.cloudflare.com.","type":16,"TTL":300,"data":"v=DKIM1; p="}]}"#;
+        assert_eq!(
+            get_rsa_public_key_from_google_dns("2022", "cloudflare.com", response),
+            Err(DKIMError::KeyRevoked {
+                selector: "2022".to_owned(),
+                domain: "cloudflare.com".to_owned()
+            })
+        );
+    }
+
+    #[test]
+    fn test_get_public_key_from_google_dns() {
+        // Quoted and split in several strings
+        let response = r#"{"Status":0,"Answer":[{"name":"brisbane._domainkey.football.example.com.","type":16,"TTL":300,
+            "data":"\"v=DKIM1; k=ed25519; \" \"p=11qYAYKxCrfVS/7TyWQHOg7hcvPapiMlrwIaaPcHURo=\""}]}"#;
+        let key =
+            get_public_key_from_google_dns("brisbane", "football.example.com", response).unwrap();
+        assert!(matches!(key, DkimPublicKey::Ed25519(_)));
+        assert!(matches!(
+            get_rsa_public_key_from_google_dns("brisbane", "football.example.com", response),
+            Err(DKIMError::KeyUnavailable(_))
+        ));
+
+        // Without k=, the key is RSA
+        let record = std::fs::read_to_string("./test/keys/rsa4096.public").unwrap();
+        let response = format!(
+            r#"{{"Status":0,"Answer":[{{"name":"s._domainkey.example.com.","type":16,"TTL":300,"data":"v=DKIM1; p={}"}}]}}"#,
+            record.trim()
+        );
+        let key = get_public_key_from_google_dns("s", "example.com", &response).unwrap();
+        assert!(matches!(key, DkimPublicKey::Rsa(_)));
+
+        assert!(matches!(
+            get_public_key_from_google_dns("s", "example.com", r#"{"Status":0,"Answer":[]}"#),
+            Err(DKIMError::NoKeyForSignature)
+        ));
+        let response = r#"{"Status":0,"Answer":[{"name":"s._domainkey.example.com.","type":16,"TTL":300,"data":"v=DKIM1; k=ed25519; p=abc"}]}"#;
+        assert!(matches!(
+            get_public_key_from_google_dns("s", "example.com", response),
+            Err(DKIMError::KeySyntaxError)
+        ));
+    }
+
+    #[test]
+    fn test_get_key_record_from_google_dns() {
+        // Responses as returned by dns.google for a Gmail and an Outlook
+        // selector, with the test keys: the key is split in several
+        // character-strings, and for Outlook found through a CNAME, with p=
+        // before k= and ending with base64 padding
+        let response = std::fs::read_to_string("./test/doh/gmail.json").unwrap();
+        let record = get_key_record_from_google_dns("20230601", "gmail.com", &response).unwrap();
+        assert_eq!(record.key_type, public_key::KeyType::Rsa);
+        assert_eq!(record.public_key().unwrap().bits(), 2048);
+        let key = get_rsa_public_key_from_google_dns("20230601", "gmail.com", &response).unwrap();
+        assert_eq!(rsa::traits::PublicKeyParts::size(&key), 256);
+
+        let response = std::fs::read_to_string("./test/doh/outlook.json").unwrap();
+        let record = get_key_record_from_google_dns("selector1", "outlook.com", &response).unwrap();
+        assert_eq!(record.key_type, public_key::KeyType::Rsa);
+        assert_eq!(record.public_key.len(), 550);
+        let key = get_public_key_from_google_dns("selector1", "outlook.com", &response).unwrap();
+        assert_eq!(key.bits(), 4096);
+
+        // A record without usable key is skipped
+        let response = r#"{"Status":0,"Answer":[
+            {"name":"s._domainkey.example.com.","type":16,"TTL":300,"data":"v=DKIM1; k=ed25519; p=abc"},
+            {"name":"s._domainkey.example.com.","type":16,"TTL":300,"data":"v=DKIM1; k=ed25519; p=11qYAYKxCrfVS/7TyWQHOg7hcvPapiMlrwIaaPcHURo="}]}"#;
+        let record = get_key_record_from_google_dns("s", "example.com", response).unwrap();
+        assert_eq!(record.key_type, public_key::KeyType::Ed25519);
+        assert!(matches!(
+            get_key_record_from_google_dns(
+                "s",
+                "example.com",
+                &response.replace("p=11qYAYKxCrfVS/7TyWQHOg7hcvPapiMlrwIaaPcHURo=", "p=")
+            ),
+            Err(DKIMError::KeyRevoked { .. })
+        ));
+    }
+
+    #[test]
+    fn test_parse_response() {
+        // Google returns the character-strings already joined
+        let response = r#"{"Status":0,"Answer":[
+            {"name":"s._domainkey.example.com.","type":16,"TTL":60,"data":"v=DKIM1; p=abc"},
+            {"name":"s._domainkey.example.com.","type":16,"TTL":60,"data":"v=DKIM1; p=def"}]}"#;
+        assert_eq!(
+            parse_response("s._domainkey.example.com", response).unwrap(),
+            TxtAnswer {
+                records: vec![
+                    TxtRecord::new(&["v=DKIM1; p=abc"], 60),
+                    TxtRecord::new(&["v=DKIM1; p=def"], 60)
+                ],
+                authentic_data: false,
+            }
+        );
+
+        // Two CNAMEs before the record, validated with DNSSEC
+        let response = r#"{"Status":0,"AD":true,"Answer":[
+            {"name":"s._domainkey.example.com.","type":5,"TTL":60,"data":"s-example-com._domainkey.provider.net."},
+            {"name":"s-example-com._domainkey.provider.net.","type":5,"TTL":60,"data":"key1.provider.net."},
+            {"name":"key1.provider.net.","type":16,"TTL":300,"data":"\"v=DKIM1; \\059 p=abc\""}]}"#;
+        assert_eq!(
+            parse_response("S._domainkey.example.com", response).unwrap(),
+            TxtAnswer {
+                records: vec![TxtRecord::new(&["v=DKIM1; ; p=abc"], 300)],
+                authentic_data: true,
+            }
+        );
+
+        // CNAME loop
+        let response = r#"{"Status":0,"Answer":[
+            {"name":"a.example.com.","type":5,"TTL":60,"data":"b.example.com."},
+            {"name":"b.example.com.","type":5,"TTL":60,"data":"a.example.com."}]}"#;
+        assert!(matches!(
+            parse_response("a.example.com", response),
+            Err(LookupError::Other(DKIMError::PermanentDnsFailure(_)))
+        ));
+
+        // No data
+        assert_eq!(
+            parse_response("a.example.com", r#"{"Status":0}"#),
+            Err(LookupError::NoData)
+        );
+        assert_eq!(
+            parse_response("a.example.com", r#"{"Status":3}"#),
+            Err(LookupError::NxDomain)
+        );
+        assert_eq!(
+            parse_response("a.example.com", r#"{"Status":2}"#),
+            Err(LookupError::ServerFailure)
+        );
+        assert!(matches!(
+            parse_response("a.example.com", "<html>"),
+            Err(LookupError::Other(DKIMError::KeyUnavailable(_)))
+        ));
+    }
+
+    #[test]
+    fn test_get_dns_query_urls() {
+        let email =
+            "DKIM-Signature: v=1; a=rsa-sha256; d=example.com; s=s1; h=from; bh=YWJj; b=YWJj\r\n\
+            DKIM-Signature: v=1; a\r\n\
+            DKIM-Signature: v=1; a=ed25519-sha256; d=example.net; s=s2; h=from; bh=YWJj; b=YWJj\r\n\
+            From: alice@example.com\r\n\r\nHello\r\n";
+        let queries = get_dns_query_urls(email.as_bytes(), &DohProvider::Cloudflare).unwrap();
+        assert_eq!(
+            queries,
+            vec![
+                DnsQuery {
+                    index: 0,
+                    selector: "s1".to_owned(),
+                    domain: "example.com".to_owned(),
+                    url: "https://cloudflare-dns.com/dns-query?name=s1._domainkey.example.com&type=TXT"
+                        .to_owned(),
+                },
+                DnsQuery {
+                    index: 2,
+                    selector: "s2".to_owned(),
+                    domain: "example.net".to_owned(),
+                    url: "https://cloudflare-dns.com/dns-query?name=s2._domainkey.example.net&type=TXT"
+                        .to_owned(),
+                },
+            ]
+        );
+
+        let provider = DohProvider::Custom("https://doh.example.net/resolve".to_owned());
+        let queries = get_dns_query_urls(email.as_bytes(), &provider).unwrap();
+        assert_eq!(
+            queries[0].url,
+            "https://doh.example.net/resolve?name=s1._domainkey.example.com&type=TXT"
+        );
+        assert_eq!(queries[0].name(), "s1._domainkey.example.com");
+        assert_eq!(
+            get_dns_query_urls(email.as_bytes(), &DohProvider::default()).unwrap()[0].url,
+            get_google_dns_url("s1", "example.com")
+        );
+        // Names are percent-encoded as UTF-8
+        assert_eq!(
+            get_google_dns_url("s1", "bücher.example"),
+            "https://dns.google/resolve?name=s1._domainkey.b%C3%BCcher.example&type=TXT"
+        );
+
+        // The key of a response is only used for its own query
+        let response = r#"{"Status":0,"Question":[{"name":"s1._domainkey.example.com.","type":16}],
+            "Answer":[{"name":"s1._domainkey.example.com.","type":16,"TTL":300,"data":"v=DKIM1; k=ed25519; p=11qYAYKxCrfVS/7TyWQHOg7hcvPapiMlrwIaaPcHURo="}]}"#;
+        assert!(get_public_key_for_query(&queries[0], response).is_ok());
+        assert_eq!(
+            get_public_key_for_query(&queries[1], response).err(),
+            Some(DKIMError::KeyUnavailable(
+                "DoH response for s1._domainkey.example.com instead of s2._domainkey.example.net"
+                    .to_owned()
+            ))
+        );
+    }
+
+    #[test]
+    fn test_parse_txt_data() {
+        assert_eq!(parse_txt_data("v=DKIM1; p=abc").unwrap(), "v=DKIM1; p=abc");
+        assert_eq!(parse_txt_data(r#""a" "b"  "c""#).unwrap(), "abc");
+        assert!(parse_txt_data(r#""a" b"#).is_err());
+    }
+}
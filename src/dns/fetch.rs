@@ -0,0 +1,152 @@
+//! [HttpClient] using the fetch API of browsers and workers, to resolve keys
+//! with DNS-over-HTTPS on wasm32
+
+use super::HttpClient;
+use crate::DKIMError;
+use futures::future::BoxFuture;
+use std::cell::RefCell;
+use std::future::Future;
+use std::pin::Pin;
+use std::rc::Rc;
+use std::task::{Context, Poll, Waker};
+use wasm_bindgen::prelude::*;
+use wasm_bindgen::JsCast;
+
+#[wasm_bindgen]
+extern "C" {
+    type Response;
+
+    #[wasm_bindgen(method, getter)]
+    fn status(this: &Response) -> u16;
+
+    #[wasm_bindgen(method)]
+    fn text(this: &Response) -> js_sys::Promise;
+
+    /// The global fetch, available in windows and workers
+    #[wasm_bindgen(js_name = fetch)]
+    fn fetch_with_init(url: &str, init: &js_sys::Object) -> js_sys::Promise;
+}
+
+/// [HttpClient] calling the global `fetch`, for a
+/// [DohLookup](super::DohLookup) in the browser:
+///
+/// ```ignore
+/// let resolver = Arc::new(DohLookup::google(Arc::new(FetchClient)));
+/// let res = cfdkim::verify_raw_email("example.com", email_bytes, resolver).await?;
+/// ```
+#[derive(Debug, Clone, Copy, Default)]
+pub struct FetchClient;
+
+impl HttpClient for FetchClient {
+    /// Network errors and 5xx responses fail with
+    /// [DKIMError::TemporaryDnsFailure]
+    fn get_json<'a>(&'a self, url: &'a str) -> BoxFuture<'a, Result<String, DKIMError>> {
+        Box::pin(AssertSend(Box::pin(get_json(url.to_owned()))))
+    }
+}
+
+async fn get_json(url: String) -> Result<String, DKIMError> {
+    let network_error =
+        |err: JsValue| DKIMError::TemporaryDnsFailure(format!("fetch {} failed: {:?}", url, err));
+
+    let headers = js_sys::Object::new();
+    js_sys::Reflect::set(&headers, &"accept".into(), &"application/dns-json".into())
+        .map_err(network_error)?;
+    let init = js_sys::Object::new();
+    js_sys::Reflect::set(&init, &"headers".into(), &headers).map_err(network_error)?;
+
+    let response: Response = PromiseFuture::new(fetch_with_init(&url, &init))
+        .await
+        .map_err(network_error)?
+        .unchecked_into();
+    match response.status() {
+        200..=299 => {}
+        status @ 500..=599 => {
+            return Err(DKIMError::TemporaryDnsFailure(format!(
+                "fetch {} failed with status {}",
+                url, status
+            )))
+        }
+        status => {
+            return Err(DKIMError::KeyUnavailable(format!(
+                "fetch {} failed with status {}",
+                url, status
+            )))
+        }
+    }
+
+    let text = PromiseFuture::new(response.text())
+        .await
+        .map_err(network_error)?;
+    text.as_string()
+        .ok_or_else(|| DKIMError::KeyUnavailable(format!("fetch {} returned no text", url)))
+}
+
+/// Outcome of a promise, and the task to wake once it's settled
+#[derive(Default)]
+struct PromiseState {
+    result: Option<Result<JsValue, JsValue>>,
+    waker: Option<Waker>,
+}
+
+/// Future settled with a JavaScript promise
+struct PromiseFuture {
+    state: Rc<RefCell<PromiseState>>,
+    // Kept alive until the promise calls them
+    _callbacks: (Closure<dyn FnMut(JsValue)>, Closure<dyn FnMut(JsValue)>),
+}
+
+impl PromiseFuture {
+    fn new(promise: js_sys::Promise) -> Self {
+        let state = Rc::new(RefCell::new(PromiseState::default()));
+        let settle = |ok: bool| -> Closure<dyn FnMut(JsValue)> {
+            let state = Rc::clone(&state);
+            Closure::new(move |value: JsValue| {
+                let mut state = state.borrow_mut();
+                state.result = Some(if ok { Ok(value) } else { Err(value) });
+                if let Some(waker) = state.waker.take() {
+                    waker.wake();
+                }
+            })
+        };
+        let resolve = settle(true);
+        let reject = settle(false);
+        let _ = promise.then2(&resolve, &reject);
+        Self {
+            state,
+            _callbacks: (resolve, reject),
+        }
+    }
+}
+
+impl Future for PromiseFuture {
+    type Output = Result<JsValue, JsValue>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let mut state = self.state.borrow_mut();
+        match state.result.take() {
+            Some(result) => Poll::Ready(result),
+            None => {
+                state.waker = Some(cx.waker().clone());
+                Poll::Pending
+            }
+        }
+    }
+}
+
+/// Future holding JavaScript values, which aren't `Send`, to return it as a
+/// [BoxFuture]
+struct AssertSend<F>(Pin<Box<F>>);
+
+// SAFETY: without the atomics target feature wasm32 runs a single thread, the
+// future can't be sent to another one
+#[cfg(not(target_feature = "atomics"))]
+unsafe impl<F> Send for AssertSend<F> {}
+
+impl<F: Future> Future for AssertSend<F> {
+    type Output = F::Output;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        self.0.as_mut().poll(cx)
+    }
+}
@@ -0,0 +1,275 @@
+//! Retrying lookups failing with transient errors
+
+use super::{Lookup, LookupError, TxtAnswer};
+use crate::DKIMError;
+use futures::future::BoxFuture;
+use std::collections::hash_map::RandomState;
+use std::hash::{BuildHasher, Hasher};
+use std::sync::Arc;
+use std::time::Duration;
+
+/// Waits for the given duration between two attempts, for example
+/// `tokio::time::sleep`
+pub type SleepFn = Arc<dyn Fn(Duration) -> BoxFuture<'static, ()> + Send + Sync>;
+
+const DEFAULT_ATTEMPTS: u32 = 3;
+const DEFAULT_INITIAL_BACKOFF: Duration = Duration::from_millis(100);
+const DEFAULT_MAX_BACKOFF: Duration = Duration::from_secs(2);
+
+/// [Lookup] retrying the lookups of another one when they fail with
+/// [DKIMError::TemporaryDnsFailure] or a temporary [LookupError], waiting with an exponential backoff
+/// between the attempts. Other errors, like a non-existent name, are returned
+/// immediately.
+pub struct RetryingLookup<L> {
+    inner: L,
+    attempts: u32,
+    initial_backoff: Duration,
+    max_backoff: Duration,
+    jitter: bool,
+    sleep: SleepFn,
+}
+
+impl<L: Lookup> RetryingLookup<L> {
+    /// New lookup doing 3 attempts, waiting 100ms then 200ms with jitter.
+    ///
+    /// With the `dns` feature the waits use tokio, otherwise no wait happens
+    /// unless a function is provided with [RetryingLookup::with_sleep].
+    pub fn new(inner: L) -> Self {
+        Self {
+            inner,
+            attempts: DEFAULT_ATTEMPTS,
+            initial_backoff: DEFAULT_INITIAL_BACKOFF,
+            max_backoff: DEFAULT_MAX_BACKOFF,
+            jitter: true,
+            sleep: default_sleep(),
+        }
+    }
+
+    /// Maximum number of attempts, including the first one
+    pub fn with_attempts(mut self, attempts: u32) -> Self {
+        self.attempts = attempts.max(1);
+        self
+    }
+
+    /// Wait before the first retry, doubled for each following retry up to
+    /// `max`
+    pub fn with_backoff(mut self, initial: Duration, max: Duration) -> Self {
+        self.initial_backoff = initial;
+        self.max_backoff = max;
+        self
+    }
+
+    /// Wait exactly the backoff instead of a random duration between half of
+    /// it and all of it
+    pub fn without_jitter(mut self) -> Self {
+        self.jitter = false;
+        self
+    }
+
+    /// Function used to wait between two attempts
+    pub fn with_sleep(mut self, sleep: SleepFn) -> Self {
+        self.sleep = sleep;
+        self
+    }
+
+    /// Wait before the given retry (starting at 0)
+    fn backoff(&self, retry: u32) -> Duration {
+        let backoff = self
+            .initial_backoff
+            .saturating_mul(2u32.saturating_pow(retry))
+            .min(self.max_backoff);
+        if !self.jitter {
+            return backoff;
+        }
+
+        let half = backoff / 2;
+        let random = RandomState::new().build_hasher().finish();
+        let jitter = (half.as_nanos() as u64)
+            .checked_add(1)
+            .map_or(0, |n| random % n);
+        half + Duration::from_nanos(jitter)
+    }
+
+    /// Run a lookup until it succeeds, fails permanently or runs out of
+    /// attempts
+    async fn retry<'a, T, E: Transient>(
+        &'a self,
+        lookup: impl Fn() -> BoxFuture<'a, Result<T, E>>,
+    ) -> Result<T, E> {
+        let mut retry = 0;
+        loop {
+            match lookup().await {
+                Err(err) if err.is_transient() && retry + 1 < self.attempts => {
+                    (self.sleep)(self.backoff(retry)).await;
+                    retry += 1;
+                }
+                res => return res,
+            }
+        }
+    }
+}
+
+/// Errors worth retrying
+trait Transient {
+    fn is_transient(&self) -> bool;
+}
+
+impl Transient for DKIMError {
+    fn is_transient(&self) -> bool {
+        matches!(self, DKIMError::TemporaryDnsFailure(_))
+    }
+}
+
+impl Transient for LookupError {
+    fn is_transient(&self) -> bool {
+        self.is_temporary()
+    }
+}
+
+#[cfg(all(feature = "dns", not(target_arch = "wasm32")))]
+fn default_sleep() -> SleepFn {
+    Arc::new(|duration| Box::pin(tokio::time::sleep(duration)))
+}
+
+#[cfg(not(all(feature = "dns", not(target_arch = "wasm32"))))]
+fn default_sleep() -> SleepFn {
+    Arc::new(|_| Box::pin(futures::future::ready(())))
+}
+
+impl<L: Lookup> Lookup for RetryingLookup<L> {
+    fn lookup_txt<'a>(&'a self, name: &'a str) -> BoxFuture<'a, Result<Vec<String>, DKIMError>> {
+        Box::pin(self.retry(move || self.inner.lookup_txt(name)))
+    }
+
+    fn lookup_txt_answer<'a>(
+        &'a self,
+        name: &'a str,
+    ) -> BoxFuture<'a, Result<TxtAnswer, LookupError>> {
+        Box::pin(self.retry(move || self.inner.lookup_txt_answer(name)))
+    }
+
+    fn lookup_cname<'a>(
+        &'a self,
+        name: &'a str,
+    ) -> BoxFuture<'a, Result<Option<String>, DKIMError>> {
+        Box::pin(self.retry(move || self.inner.lookup_cname(name)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Mutex;
+
+    /// Fails the first lookups with the given error
+    struct FlakyLookup {
+        failures: usize,
+        error: DKIMError,
+        calls: AtomicUsize,
+    }
+
+    impl Lookup for FlakyLookup {
+        fn lookup_txt<'a>(
+            &'a self,
+            _name: &'a str,
+        ) -> BoxFuture<'a, Result<Vec<String>, DKIMError>> {
+            let call = self.calls.fetch_add(1, Ordering::SeqCst);
+            let res = if call < self.failures {
+                Err(self.error.clone())
+            } else {
+                Ok(vec!["v=DKIM1; p=".to_owned()])
+            };
+            Box::pin(futures::future::ready(res))
+        }
+    }
+
+    fn flaky(failures: usize, error: DKIMError) -> FlakyLookup {
+        FlakyLookup {
+            failures,
+            error,
+            calls: AtomicUsize::new(0),
+        }
+    }
+
+    fn recording_sleep() -> (SleepFn, Arc<Mutex<Vec<Duration>>>) {
+        let sleeps = Arc::new(Mutex::new(vec![]));
+        let recorded = Arc::clone(&sleeps);
+        let sleep: SleepFn = Arc::new(move |duration| {
+            recorded.lock().unwrap().push(duration);
+            Box::pin(futures::future::ready(()))
+        });
+        (sleep, sleeps)
+    }
+
+    #[tokio::test]
+    async fn test_retrying_lookup() {
+        let transient = DKIMError::TemporaryDnsFailure("SERVFAIL".to_owned());
+
+        // Succeeds on the last attempt
+        let (sleep, sleeps) = recording_sleep();
+        let lookup = RetryingLookup::new(flaky(3, transient.clone()))
+            .with_attempts(4)
+            .with_backoff(Duration::from_millis(10), Duration::from_millis(25))
+            .without_jitter()
+            .with_sleep(sleep);
+        assert!(lookup.lookup_txt("a.example.com").await.is_ok());
+        assert_eq!(lookup.inner.calls.load(Ordering::SeqCst), 4);
+        assert_eq!(
+            *sleeps.lock().unwrap(),
+            vec![
+                Duration::from_millis(10),
+                Duration::from_millis(20),
+                Duration::from_millis(25)
+            ]
+        );
+
+        // Gives up after the attempts
+        let (sleep, _) = recording_sleep();
+        let lookup = RetryingLookup::new(flaky(3, transient.clone()))
+            .with_attempts(3)
+            .with_sleep(sleep);
+        assert_eq!(
+            lookup.lookup_txt("a.example.com").await,
+            Err(transient.clone())
+        );
+        assert_eq!(lookup.inner.calls.load(Ordering::SeqCst), 3);
+
+        // A non-existent name isn't retried
+        let (sleep, sleeps) = recording_sleep();
+        let lookup = RetryingLookup::new(flaky(1, DKIMError::NoKeyForSignature)).with_sleep(sleep);
+        assert_eq!(
+            lookup.lookup_txt("a.example.com").await,
+            Err(DKIMError::NoKeyForSignature)
+        );
+        assert_eq!(lookup.inner.calls.load(Ordering::SeqCst), 1);
+        assert!(sleeps.lock().unwrap().is_empty());
+
+        // Nor is a name without TXT record in a typed answer
+        let (sleep, _) = recording_sleep();
+        let lookup = RetryingLookup::new(flaky(1, DKIMError::NoKeyForSignature)).with_sleep(sleep);
+        assert_eq!(
+            lookup.lookup_txt_answer("a.example.com").await,
+            Err(LookupError::NxDomain)
+        );
+        assert_eq!(lookup.inner.calls.load(Ordering::SeqCst), 1);
+
+        // But a temporary failure is
+        let (sleep, _) = recording_sleep();
+        let lookup = RetryingLookup::new(flaky(2, transient)).with_sleep(sleep);
+        let answer = lookup.lookup_txt_answer("a.example.com").await.unwrap();
+        assert_eq!(answer.records[0].text(), "v=DKIM1; p=");
+        assert_eq!(lookup.inner.calls.load(Ordering::SeqCst), 3);
+    }
+
+    #[test]
+    fn test_backoff_jitter() {
+        let lookup = RetryingLookup::new(flaky(0, DKIMError::NoKeyForSignature))
+            .with_backoff(Duration::from_millis(100), Duration::from_secs(1));
+        for retry in 0..6 {
+            let backoff = Duration::from_millis(100 * 2u64.pow(retry)).min(Duration::from_secs(1));
+            let jittered = lookup.backoff(retry);
+            assert!(jittered >= backoff / 2 && jittered <= backoff);
+        }
+    }
+}
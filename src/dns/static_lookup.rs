@@ -0,0 +1,402 @@
+//! Resolution from a fixed set of records, for tests and for verifying
+//! archived mail against snapshotted keys
+
+use super::{parse_character_strings, Lookup};
+use crate::DKIMError;
+use futures::future::BoxFuture;
+use std::collections::HashMap;
+use std::path::Path;
+
+/// [Lookup] answering from a fixed set of TXT and CNAME records.
+///
+/// A record whose name starts with `*.` matches any name with one more label
+/// in place of the `*`, like `*._domainkey.example.com` for every selector of
+/// example.com. Unknown names fail with [DKIMError::NoKeyForSignature], as
+/// names which don't exist.
+#[derive(Debug, Clone, Default)]
+pub struct StaticLookup {
+    records: HashMap<String, Vec<String>>,
+    cnames: HashMap<String, String>,
+}
+
+/// Lowercase the name and remove the trailing dot
+fn normalize_name(name: &str) -> String {
+    name.trim_end_matches('.').to_lowercase()
+}
+
+impl StaticLookup {
+    /// New lookup from the TXT records of each name
+    pub fn new(records: HashMap<String, Vec<String>>) -> Self {
+        let mut lookup = Self::default();
+        for (name, values) in records {
+            lookup
+                .records
+                .entry(normalize_name(&name))
+                .or_default()
+                .extend(values);
+        }
+        lookup
+    }
+
+    /// Add a TXT record for a name
+    pub fn insert(&mut self, name: &str, value: &str) {
+        self.records
+            .entry(normalize_name(name))
+            .or_default()
+            .push(value.to_owned());
+    }
+
+    /// Add a CNAME record pointing a name to another
+    pub fn insert_cname(&mut self, name: &str, target: &str) {
+        self.cnames
+            .insert(normalize_name(name), normalize_name(target));
+    }
+
+    /// Load the TXT records of a zone file, like the ones generated by
+    /// opendkim-genkey:
+    ///
+    /// ```text
+    /// $ORIGIN example.com.
+    /// 2022._domainkey IN TXT ( "v=DKIM1; k=rsa; "
+    ///     "p=MIGfMA0G..." )  ; ----- DKIM key 2022 for example.com
+    /// ```
+    ///
+    /// Names are relative to the last `$ORIGIN`, the TTL and class are
+    /// optional and records other than TXT and CNAME are ignored.
+    pub fn from_zone(zone: &str) -> Result<Self, DKIMError> {
+        let mut lookup = Self::default();
+        let mut origin: Option<String> = None;
+
+        for (line, tokens) in zone_entries(zone)? {
+            let invalid = |reason: &str| {
+                DKIMError::KeyUnavailable(format!(
+                    "invalid zone entry at line {}: {}",
+                    line, reason
+                ))
+            };
+            let mut tokens = tokens.into_iter();
+            let name = match tokens.next() {
+                Some(name) => name,
+                None => continue,
+            };
+
+            if name.eq_ignore_ascii_case("$ORIGIN") {
+                let value = tokens.next().ok_or_else(|| invalid("missing origin"))?;
+                origin = Some(normalize_name(&value));
+                continue;
+            }
+            if name.starts_with('$') {
+                continue;
+            }
+
+            let name = match (&origin, name.ends_with('.')) {
+                (_, true) => normalize_name(&name),
+                (Some(origin), false) if name == "@" => origin.clone(),
+                (Some(origin), false) => format!("{}.{}", normalize_name(&name), origin),
+                (None, false) => normalize_name(&name),
+            };
+
+            // Optional TTL and class before the type
+            let mut tokens = tokens.skip_while(|token| {
+                token.bytes().all(|b| b.is_ascii_digit()) || token.eq_ignore_ascii_case("IN")
+            });
+            match tokens.next() {
+                Some(record_type) if record_type.eq_ignore_ascii_case("TXT") => {}
+                Some(record_type) if record_type.eq_ignore_ascii_case("CNAME") => {
+                    let target = tokens
+                        .next()
+                        .ok_or_else(|| invalid("missing CNAME target"))?;
+                    let target = match (&origin, target.ends_with('.')) {
+                        (Some(origin), false) => format!("{}.{}", target, origin),
+                        _ => target,
+                    };
+                    lookup.insert_cname(&name, &target);
+                    continue;
+                }
+                Some(_) => continue,
+                None => return Err(invalid("missing record type")),
+            }
+
+            let data: Vec<String> = tokens.collect();
+            if data.is_empty() {
+                return Err(invalid("missing TXT data"));
+            }
+            let value = if data[0].starts_with('"') {
+                parse_character_strings(&data.join(" "))
+                    .ok_or_else(|| invalid("invalid character-string"))?
+            } else {
+                data.join(" ")
+            };
+            lookup.records.entry(name).or_default().push(value);
+        }
+
+        Ok(lookup)
+    }
+
+    /// Load the TXT records of a JSON object, giving each name a record or an
+    /// array of records:
+    ///
+    /// ```text
+    /// {
+    ///   "2022._domainkey.example.com": "v=DKIM1; k=ed25519; p=...",
+    ///   "s1._domainkey.example.org": ["v=DKIM1; p=...", "v=DKIM1; p=..."]
+    /// }
+    /// ```
+    pub fn from_json(json: &str) -> Result<Self, DKIMError> {
+        let invalid =
+            |reason: String| DKIMError::KeyUnavailable(format!("invalid records: {}", reason));
+        let members = match crate::json::parse(json).map_err(invalid)? {
+            crate::json::Value::Object(members) => members,
+            _ => return Err(invalid("not an object".to_owned())),
+        };
+
+        let mut lookup = Self::default();
+        for (name, value) in members {
+            let values = match &value {
+                crate::json::Value::String(_) => std::slice::from_ref(&value),
+                crate::json::Value::Array(values) => &values[..],
+                _ => {
+                    return Err(invalid(format!(
+                        "records of {} not a string or an array",
+                        name
+                    )))
+                }
+            };
+            for value in values {
+                let value = value
+                    .as_str()
+                    .ok_or_else(|| invalid(format!("record of {} not a string", name)))?;
+                lookup.insert(&name, value);
+            }
+        }
+        Ok(lookup)
+    }
+
+    /// Load the TXT records of a zone file, see [StaticLookup::from_zone]
+    pub fn from_file<P: AsRef<Path>>(path: P) -> Result<Self, DKIMError> {
+        let zone = std::fs::read_to_string(path.as_ref()).map_err(|err| {
+            DKIMError::KeyUnavailable(format!(
+                "failed to read {}: {}",
+                path.as_ref().display(),
+                err
+            ))
+        })?;
+        Self::from_zone(&zone)
+    }
+
+    fn get<'a, T>(records: &'a HashMap<String, T>, name: &str) -> Option<&'a T> {
+        let name = normalize_name(name);
+        records.get(&name).or_else(|| {
+            let (_, parent) = name.split_once('.')?;
+            records.get(&format!("*.{}", parent))
+        })
+    }
+}
+
+impl Lookup for StaticLookup {
+    /// A name with only a CNAME record has no TXT record
+    fn lookup_txt<'a>(&'a self, name: &'a str) -> BoxFuture<'a, Result<Vec<String>, DKIMError>> {
+        let res = match Self::get(&self.records, name) {
+            Some(records) => Ok(records.clone()),
+            None if Self::get(&self.cnames, name).is_some() => Ok(vec![]),
+            None => Err(DKIMError::NoKeyForSignature),
+        };
+        Box::pin(futures::future::ready(res))
+    }
+
+    fn lookup_cname<'a>(
+        &'a self,
+        name: &'a str,
+    ) -> BoxFuture<'a, Result<Option<String>, DKIMError>> {
+        let res = Ok(Self::get(&self.cnames, name).cloned());
+        Box::pin(futures::future::ready(res))
+    }
+}
+
+/// Split a zone file into entries of tokens with their line number. Quoted
+/// tokens keep their quotes, parentheses continue an entry over several lines
+/// and comments start with `;`.
+fn zone_entries(zone: &str) -> Result<Vec<(usize, Vec<String>)>, DKIMError> {
+    let mut entries = vec![];
+    let mut tokens = vec![];
+    let mut token = String::new();
+    let mut start_line = 1;
+    let mut line = 1;
+    let mut depth = 0;
+    let mut chars = zone.chars().peekable();
+
+    fn flush(token: &mut String, tokens: &mut Vec<String>) {
+        if !token.is_empty() {
+            tokens.push(std::mem::take(token));
+        }
+    }
+
+    while let Some(c) = chars.next() {
+        match c {
+            '"' => {
+                flush(&mut token, &mut tokens);
+                token.push('"');
+                loop {
+                    let c = chars.next().ok_or_else(|| {
+                        DKIMError::KeyUnavailable(format!(
+                            "invalid zone entry at line {}: unterminated string",
+                            line
+                        ))
+                    })?;
+                    token.push(c);
+                    match c {
+                        '\\' => token.extend(chars.next()),
+                        '"' => break,
+                        '\n' => line += 1,
+                        _ => {}
+                    }
+                }
+                flush(&mut token, &mut tokens);
+            }
+            ';' => {
+                flush(&mut token, &mut tokens);
+                while chars.peek().is_some_and(|c| *c != '\n') {
+                    chars.next();
+                }
+            }
+            '(' => {
+                flush(&mut token, &mut tokens);
+                depth += 1;
+            }
+            ')' => {
+                flush(&mut token, &mut tokens);
+                depth -= 1;
+            }
+            '\n' => {
+                flush(&mut token, &mut tokens);
+                if depth <= 0 {
+                    if !tokens.is_empty() {
+                        entries.push((start_line, std::mem::take(&mut tokens)));
+                    }
+                    depth = 0;
+                    start_line = line + 1;
+                }
+                line += 1;
+            }
+            c if c.is_whitespace() => flush(&mut token, &mut tokens),
+            c => token.push(c),
+        }
+    }
+    flush(&mut token, &mut tokens);
+    if !tokens.is_empty() {
+        entries.push((start_line, tokens));
+    }
+
+    Ok(entries)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_static_lookup() {
+        let lookup = StaticLookup::new(HashMap::from([
+            (
+                "S1._domainkey.example.com.".to_owned(),
+                vec!["v=DKIM1; p=a".to_owned()],
+            ),
+            (
+                "*._domainkey.example.org".to_owned(),
+                vec!["v=DKIM1; p=b".to_owned()],
+            ),
+        ]));
+
+        assert_eq!(
+            lookup.lookup_txt("s1._domainkey.example.com").await,
+            Ok(vec!["v=DKIM1; p=a".to_owned()])
+        );
+        assert_eq!(
+            lookup.lookup_txt("any._domainkey.example.org").await,
+            Ok(vec!["v=DKIM1; p=b".to_owned()])
+        );
+        // Unknown names don't exist, a permanent failure
+        let err = lookup
+            .lookup_txt("s2._domainkey.example.com")
+            .await
+            .unwrap_err();
+        assert_eq!(err, DKIMError::NoKeyForSignature);
+        assert_eq!(err.classification(), crate::ErrorClass::Permfail);
+        assert_eq!(
+            lookup.lookup_txt_answer("s2._domainkey.example.com").await,
+            Err(super::super::LookupError::NxDomain)
+        );
+        // The wildcard only replaces a single label
+        assert!(lookup
+            .lookup_txt("a.b._domainkey.example.org")
+            .await
+            .is_err());
+    }
+
+    #[tokio::test]
+    async fn test_static_lookup_from_json() {
+        let lookup = StaticLookup::from_json(
+            r#"{
+                "s1._domainkey.example.com": "v=DKIM1; p=a",
+                "S2._domainkey.example.com.": ["v=DKIM1; p=b", "v=DKIM1; p=c"]
+            }"#,
+        )
+        .unwrap();
+        assert_eq!(
+            lookup.lookup_txt("s1._domainkey.example.com").await,
+            Ok(vec!["v=DKIM1; p=a".to_owned()])
+        );
+        assert_eq!(
+            lookup.lookup_txt("s2._domainkey.example.com").await,
+            Ok(vec!["v=DKIM1; p=b".to_owned(), "v=DKIM1; p=c".to_owned()])
+        );
+
+        assert!(StaticLookup::from_json(r#"["v=DKIM1; p=a"]"#).is_err());
+        assert!(StaticLookup::from_json(r#"{"s1._domainkey.example.com": 1}"#).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_static_lookup_from_zone() {
+        let zone = r#"
+; Archived key records
+$ORIGIN example.com.
+$TTL 3600
+s1._domainkey 300 IN TXT "v=DKIM1; k=ed25519; p=abc"
+s2._domainkey.example.net. TXT "v=DKIM1; p=def"
+s3._domainkey IN TXT ( "v=DKIM1; h=sha256; k=rsa; "
+    "p=ghi\"jkl" )  ; ----- DKIM key s3 for example.com
+s4._domainkey IN CNAME s4.provider.net.
+s5._domainkey CNAME s3._domainkey
+@ IN MX 10 mail.example.com.
+"#;
+        let lookup = StaticLookup::from_zone(zone).unwrap();
+        assert_eq!(
+            lookup.lookup_txt("s1._domainkey.example.com").await,
+            Ok(vec!["v=DKIM1; k=ed25519; p=abc".to_owned()])
+        );
+        assert_eq!(
+            lookup.lookup_txt("s2._domainkey.example.net").await,
+            Ok(vec!["v=DKIM1; p=def".to_owned()])
+        );
+        assert_eq!(
+            lookup.lookup_txt("s3._domainkey.example.com").await,
+            Ok(vec!["v=DKIM1; h=sha256; k=rsa; p=ghi\"jkl".to_owned()])
+        );
+        assert!(lookup.lookup_txt("example.com").await.is_err());
+        assert_eq!(
+            lookup.lookup_cname("s4._domainkey.example.com").await,
+            Ok(Some("s4.provider.net".to_owned()))
+        );
+        assert_eq!(
+            lookup.lookup_cname("s5._domainkey.example.com").await,
+            Ok(Some("s3._domainkey.example.com".to_owned()))
+        );
+        assert_eq!(
+            lookup.lookup_txt("s5._domainkey.example.com").await,
+            Ok(vec![])
+        );
+
+        assert!(StaticLookup::from_zone("a TXT \"abc").is_err());
+        assert!(StaticLookup::from_zone("a IN").is_err());
+    }
+}
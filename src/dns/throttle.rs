@@ -0,0 +1,185 @@
+//! Limiting the load put on resolvers
+
+use super::{Lookup, LookupError, TxtAnswer};
+use crate::DKIMError;
+use futures::future::BoxFuture;
+use std::sync::Mutex;
+use std::time::Duration;
+use tokio::sync::Semaphore;
+use tokio::time::Instant;
+
+/// [Lookup] limiting the number of in-flight queries of another one and
+/// optionally their rate, to avoid being throttled by public resolvers when
+/// verifying bursts of mail.
+///
+/// It can wrap or be wrapped in the other lookups, like
+/// `RetryingLookup::new(ThrottledLookup::new(lookup, 8))` for the retries to
+/// be throttled too.
+pub struct ThrottledLookup<L> {
+    inner: L,
+    semaphore: Semaphore,
+    rate_limit: Option<Mutex<TokenBucket>>,
+}
+
+/// Bucket holding up to one second of queries, refilled continuously
+struct TokenBucket {
+    queries_per_second: f64,
+    tokens: f64,
+    refilled_at: Instant,
+}
+
+impl TokenBucket {
+    fn new(queries_per_second: u32) -> Self {
+        let queries_per_second = f64::from(queries_per_second.max(1));
+        Self {
+            queries_per_second,
+            tokens: queries_per_second,
+            refilled_at: Instant::now(),
+        }
+    }
+
+    /// Take a token, or return how long to wait for the next one
+    fn take(&mut self) -> Result<(), Duration> {
+        let now = Instant::now();
+        let elapsed = now.duration_since(self.refilled_at).as_secs_f64();
+        self.tokens =
+            (self.tokens + elapsed * self.queries_per_second).min(self.queries_per_second);
+        self.refilled_at = now;
+
+        if self.tokens >= 1.0 {
+            self.tokens -= 1.0;
+            Ok(())
+        } else {
+            Err(Duration::from_secs_f64(
+                (1.0 - self.tokens) / self.queries_per_second,
+            ))
+        }
+    }
+}
+
+impl<L: Lookup> ThrottledLookup<L> {
+    /// New lookup allowing at most `max_concurrent` queries in flight
+    pub fn new(inner: L, max_concurrent: usize) -> Self {
+        Self {
+            inner,
+            semaphore: Semaphore::new(max_concurrent.max(1)),
+            rate_limit: None,
+        }
+    }
+
+    /// Start at most `queries_per_second` queries per second, allowing bursts
+    /// of as many queries
+    pub fn with_rate_limit(mut self, queries_per_second: u32) -> Self {
+        self.rate_limit = Some(Mutex::new(TokenBucket::new(queries_per_second)));
+        self
+    }
+
+    /// Run a lookup once a token and a permit are available
+    async fn throttle<'a, T, E: From<DKIMError>>(
+        &'a self,
+        lookup: BoxFuture<'a, Result<T, E>>,
+    ) -> Result<T, E> {
+        if let Some(rate_limit) = &self.rate_limit {
+            loop {
+                let wait = match rate_limit.lock().unwrap().take() {
+                    Ok(()) => break,
+                    Err(wait) => wait,
+                };
+                tokio::time::sleep(wait).await;
+            }
+        }
+
+        let _permit = self
+            .semaphore
+            .acquire()
+            .await
+            .map_err(|err| DKIMError::UnknownInternalError(err.to_string()))?;
+        lookup.await
+    }
+}
+
+impl<L: Lookup> Lookup for ThrottledLookup<L> {
+    fn lookup_txt<'a>(&'a self, name: &'a str) -> BoxFuture<'a, Result<Vec<String>, DKIMError>> {
+        Box::pin(self.throttle(self.inner.lookup_txt(name)))
+    }
+
+    fn lookup_txt_answer<'a>(
+        &'a self,
+        name: &'a str,
+    ) -> BoxFuture<'a, Result<TxtAnswer, LookupError>> {
+        Box::pin(self.throttle(self.inner.lookup_txt_answer(name)))
+    }
+
+    fn lookup_cname<'a>(
+        &'a self,
+        name: &'a str,
+    ) -> BoxFuture<'a, Result<Option<String>, DKIMError>> {
+        Box::pin(self.throttle(self.inner.lookup_cname(name)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::dns::RetryingLookup;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+
+    /// Records the maximum number of concurrent lookups, each taking 10ms
+    #[derive(Default)]
+    struct InstrumentedLookup {
+        in_flight: AtomicUsize,
+        max_in_flight: AtomicUsize,
+        calls: AtomicUsize,
+    }
+
+    impl Lookup for InstrumentedLookup {
+        fn lookup_txt<'a>(
+            &'a self,
+            _name: &'a str,
+        ) -> BoxFuture<'a, Result<Vec<String>, DKIMError>> {
+            Box::pin(async move {
+                let in_flight = self.in_flight.fetch_add(1, Ordering::SeqCst) + 1;
+                self.max_in_flight.fetch_max(in_flight, Ordering::SeqCst);
+                self.calls.fetch_add(1, Ordering::SeqCst);
+                tokio::time::sleep(Duration::from_millis(10)).await;
+                self.in_flight.fetch_sub(1, Ordering::SeqCst);
+                Ok(vec!["v=DKIM1; p=".to_owned()])
+            })
+        }
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_throttled_lookup_concurrency() {
+        let lookup = Arc::new(ThrottledLookup::new(InstrumentedLookup::default(), 3));
+        let lookups = (0..20).map(|_| {
+            let lookup = Arc::clone(&lookup);
+            tokio::spawn(async move { lookup.lookup_txt("a.example.com").await })
+        });
+        for res in futures::future::join_all(lookups).await {
+            assert!(res.unwrap().is_ok());
+        }
+        assert_eq!(lookup.inner.calls.load(Ordering::SeqCst), 20);
+        assert_eq!(lookup.inner.max_in_flight.load(Ordering::SeqCst), 3);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_throttled_lookup_rate_limit() {
+        let lookup = Arc::new(RetryingLookup::new(
+            ThrottledLookup::new(InstrumentedLookup::default(), 100).with_rate_limit(10),
+        ));
+        let start = Instant::now();
+        let lookups = (0..25).map(|_| {
+            let lookup = Arc::clone(&lookup);
+            tokio::spawn(async move { lookup.lookup_txt("a.example.com").await })
+        });
+        for res in futures::future::join_all(lookups).await {
+            assert!(res.unwrap().is_ok());
+        }
+
+        // A burst of 10 queries, then 10 per second
+        let elapsed = start.elapsed();
+        assert!(elapsed >= Duration::from_millis(1500), "{:?}", elapsed);
+        assert!(elapsed < Duration::from_millis(1600), "{:?}", elapsed);
+    }
+}
@@ -1,12 +1,27 @@
-/// DKIM error status
-pub enum Status {
+/// Classification of a DKIM error (RFC 6376 section 3.9)
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "serde", serde(rename_all = "snake_case"))]
+pub enum ErrorClass {
+    /// The signature will never verify, reported as `permerror` or `fail`
     Permfail,
+    /// Verifying again later may succeed, reported as `temperror`
     Tempfail,
 }
 
+/// DKIM error status
+pub type Status = ErrorClass;
+
 quick_error! {
     #[derive(Debug, PartialEq, Clone)]
-    /// DKIM errors
+    #[cfg_attr(feature = "serde", derive(serde::Serialize))]
+    #[cfg_attr(
+        feature = "serde",
+        serde(tag = "code", content = "detail", rename_all = "snake_case")
+    )]
+    /// DKIM errors, serialized with the snake_case name of the variant as
+    /// `code` and its fields as `detail`. A [DKIMError::BuilderError] can't be
+    /// deserialized.
     pub enum DKIMError {
         UnsupportedHashAlgorithm(value: String) {
             display("unsupported hash algorithm: {}", value)
@@ -17,9 +32,18 @@ quick_error! {
         SignatureSyntaxError(err: String) {
             display("signature syntax error: {}", err)
         }
+        SignatureTagListError(err: crate::parser::TagListError) {
+            display("signature syntax error: {}", err)
+        }
         SignatureMissingRequiredTag(name: &'static str) {
             display("signature missing required tag ({})", name)
         }
+        SignatureNotFound(selector: String) {
+            display("no signature matching {}", selector)
+        }
+        DuplicateTag(name: String) {
+            display("duplicate tag in signature ({})", name)
+        }
         IncompatibleVersion {
             display("incompatible version")
         }
@@ -29,9 +53,18 @@ quick_error! {
         FromFieldNotSigned {
             display("From field not signed")
         }
+        FromDomainNotFound(err: String) {
+            display("no From domain: {}", err)
+        }
+        MultipleFromDomains(domains: Vec<String>) {
+            display("multiple From domains: {}", domains.join(", "))
+        }
         SignatureExpired {
             display("signature expired")
         }
+        SignatureTimestampInFuture {
+            display("signature timestamp in the future")
+        }
         UnacceptableSignatureHeader {
             display("unacceptable signature header")
         }
@@ -41,15 +74,48 @@ quick_error! {
         KeyUnavailable(err: String) {
             display("key unavailable: {}", err)
         }
+        TemporaryDnsFailure(err: String) {
+            display("temporary DNS failure: {}", err)
+        }
+        PermanentDnsFailure(err: String) {
+            display("permanent DNS failure: {}", err)
+        }
         UnknownInternalError(err: String) {
             display("internal error: {}", err)
         }
         NoKeyForSignature {
             display("no key for signature")
         }
+        InvalidDnsName(err: String) {
+            display("invalid DNS name: {}", err)
+        }
+        KeyRecordTooLarge(size: usize) {
+            display("key record of {} bytes is too large", size)
+        }
+        LimitExceeded { limit: &'static str, max: usize, actual: usize } {
+            display("message exceeds {} of {}: {}", limit, max, actual)
+        }
         KeySyntaxError {
             display("key syntax error")
         }
+        KeyUnauthenticated {
+            display("key record without the authentic data bit of the resolver")
+        }
+        HashAlgorithmNotPermittedByKey(value: String) {
+            display("hash algorithm not permitted by the key: {}", value)
+        }
+        InappropriateServiceType(value: String) {
+            display("inappropriate key service type: {}", value)
+        }
+        /// The selector and domain are empty for a record parsed on its own,
+        /// by [crate::DkimPublicKey::from_dns_txt]
+        KeyRevoked { selector: String, domain: String } {
+            display("{}", if selector.is_empty() && domain.is_empty() {
+                "key revoked".to_owned()
+            } else {
+                format!("key {} of {} revoked", selector, domain)
+            })
+        }
         KeyIncompatibleVersion {
             display("key incompatible version")
         }
@@ -59,6 +125,9 @@ quick_error! {
         SignatureDidNotVerify {
             display("signature did not verify")
         }
+        InvalidDigestLength { expected: usize, actual: usize } {
+            display("digest of {} bytes instead of {}", actual, expected)
+        }
         BodyHashDidNotVerify {
             display("body hash did not verify")
         }
@@ -71,32 +140,120 @@ quick_error! {
         BuilderError(err: &'static str) {
             display("failed to build object: {}", err)
         }
+        PrivateKeyLoadError(err: String) {
+            display("failed to load private key: {}", err)
+        }
+        SelfCheckFailed(err: Box<DKIMError>) {
+            display("signature failed the self check: {}", err)
+        }
+        SignatureFailed { index: usize, domain: String, selector: String, err: Box<DKIMError> } {
+            display("signature {} (d={}, s={}): {}", index, domain, selector, err)
+        }
     }
 }
 
 impl DKIMError {
-    pub fn status(self) -> Status {
+    /// Whether the failure is permanent or verifying again later may succeed
+    pub fn classification(&self) -> ErrorClass {
         use DKIMError::*;
         match self {
             SignatureSyntaxError(_)
+            | SignatureTagListError(_)
             | SignatureMissingRequiredTag(_)
+            | DuplicateTag(_)
+            | SignatureNotFound(_)
             | IncompatibleVersion
             | DomainMismatch
             | FromFieldNotSigned
+            | FromDomainNotFound(_)
+            | MultipleFromDomains(_)
             | SignatureExpired
+            | SignatureTimestampInFuture
             | UnacceptableSignatureHeader
             | UnsupportedQueryMethod
+            | PermanentDnsFailure(_)
             | NoKeyForSignature
+            | InvalidDnsName(_)
+            | KeyRecordTooLarge(_)
+            | LimitExceeded { .. }
             | KeySyntaxError
+            | KeyUnauthenticated
+            | KeyRevoked { .. }
+            | HashAlgorithmNotPermittedByKey(_)
+            | InappropriateServiceType(_)
             | KeyIncompatibleVersion
             | InappropriateKeyAlgorithm
             | SignatureDidNotVerify
+            | InvalidDigestLength { .. }
             | BodyHashDidNotVerify
             | MalformedBody
             | UnsupportedCanonicalizationType(_)
-            | UnsupportedHashAlgorithm(_) => Status::Permfail,
-            KeyUnavailable(_) | UnknownInternalError(_) => Status::Tempfail,
-            BuilderError(_) | FailedToSign(_) => unreachable!(),
+            | UnsupportedHashAlgorithm(_)
+            | BuilderError(_)
+            | FailedToSign(_)
+            | PrivateKeyLoadError(_) => ErrorClass::Permfail,
+            KeyUnavailable(_) | TemporaryDnsFailure(_) | UnknownInternalError(_) => {
+                ErrorClass::Tempfail
+            }
+            SelfCheckFailed(err) | SignatureFailed { err, .. } => err.classification(),
+        }
+    }
+
+    /// The error without the context of [DKIMError::SignatureFailed]
+    pub fn root_cause(&self) -> &DKIMError {
+        match self {
+            DKIMError::SignatureFailed { err, .. } => err.root_cause(),
+            err => err,
         }
     }
+
+    pub fn status(self) -> Status {
+        self.classification()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_classification() {
+        assert_eq!(
+            DKIMError::TemporaryDnsFailure("SERVFAIL".to_owned()).classification(),
+            ErrorClass::Tempfail
+        );
+        assert_eq!(
+            DKIMError::NoKeyForSignature.classification(),
+            ErrorClass::Permfail
+        );
+        assert_eq!(
+            DKIMError::PermanentDnsFailure("CNAME loop".to_owned()).classification(),
+            ErrorClass::Permfail
+        );
+        assert_eq!(
+            DKIMError::SelfCheckFailed(Box::new(DKIMError::KeyUnavailable(String::new())))
+                .classification(),
+            ErrorClass::Tempfail
+        );
+        assert_eq!(DKIMError::BodyHashDidNotVerify.status(), Status::Permfail);
+    }
+
+    #[test]
+    fn test_signature_failed() {
+        let err = DKIMError::SignatureFailed {
+            index: 2,
+            domain: "example.com".to_owned(),
+            selector: "s2".to_owned(),
+            err: Box::new(DKIMError::TemporaryDnsFailure("SERVFAIL".to_owned())),
+        };
+        assert_eq!(
+            err.to_string(),
+            "signature 2 (d=example.com, s=s2): temporary DNS failure: SERVFAIL"
+        );
+        assert_eq!(err.classification(), ErrorClass::Tempfail);
+        assert_eq!(
+            err.root_cause(),
+            &DKIMError::TemporaryDnsFailure("SERVFAIL".to_owned())
+        );
+    }
 }
@@ -0,0 +1,197 @@
+//! Golden hashes of the messages of test/fixtures: each `<name>.eml` comes
+//! with a `<name>.expected.json` computed independently of cfdkim:
+//!
+//! ```json
+//! {
+//!   "from_domain": "example.com",
+//!   "records": { "s1._domainkey.example.com": "v=DKIM1; p=..." },
+//!   "signatures": [
+//!     {
+//!       "outcome": "pass",
+//!       "header_data": "from:...\r\ndkim-signature:...; b=",
+//!       "header_sha256": "<hex>",
+//!       "body_sha256": { "simple": "<hex>", "relaxed": "<hex>" }
+//!     }
+//!   ]
+//! }
+//! ```
+//!
+//! One entry per DKIM-Signature header, in header order. `header_data` is
+//! the input of the header hash with the canonicalization of the signature,
+//! the body hashes are the ones of the whole body with each canonicalization
+//! and `outcome` is the summary of its verification against the key records.
+
+#[cfg(test)]
+mod tests {
+    use crate::json::Value;
+    use crate::{
+        canonicalization, canonicalize_signed_email_detailed, dns, get_canonicalized_body, to_hex,
+        verify_all_signatures, SignatureSelector, VerificationOptions,
+    };
+    use sha2::{Digest, Sha256};
+    use std::path::{Path, PathBuf};
+    use std::sync::Arc;
+
+    const FIXTURES: &str = "./test/fixtures";
+
+    fn sha256_hex(data: &[u8]) -> String {
+        to_hex(&Sha256::digest(data))
+    }
+
+    fn str_member<'a>(value: &'a Value, key: &str) -> &'a str {
+        value
+            .get(key)
+            .and_then(Value::as_str)
+            .unwrap_or_else(|| panic!("missing {:?} string", key))
+    }
+
+    /// Where two byte strings start differing, with some context, for the
+    /// failure messages
+    fn first_divergence(expected: &[u8], actual: &[u8]) -> Option<String> {
+        let offset = expected
+            .iter()
+            .zip(actual)
+            .position(|(a, b)| a != b)
+            .or_else(|| {
+                (expected.len() != actual.len()).then(|| expected.len().min(actual.len()))
+            })?;
+        let context = |data: &[u8]| {
+            let start = offset.saturating_sub(16);
+            let end = (offset + 16).min(data.len());
+            format!("{:?}", String::from_utf8_lossy(&data[start.min(end)..end]))
+        };
+        Some(format!(
+            "first divergent byte at offset {}: expected {} got {}",
+            offset,
+            context(expected),
+            context(actual)
+        ))
+    }
+
+    /// Divergences of a fixture from its golden values, empty when it
+    /// matches them all
+    async fn run_fixture(path: &Path) -> Vec<String> {
+        let email_bytes = std::fs::read(path).unwrap();
+        let json = std::fs::read_to_string(path.with_extension("expected.json")).unwrap();
+        let expected = crate::json::parse(&json).unwrap();
+
+        let mut lookup = dns::StaticLookup::default();
+        if let Some(Value::Object(records)) = expected.get("records") {
+            for (name, record) in records {
+                lookup.insert(name, record.as_str().expect("record is not a string"));
+            }
+        }
+        let email = mailparse::parse_mail(&email_bytes).unwrap();
+        let result = verify_all_signatures(
+            str_member(&expected, "from_domain"),
+            &email,
+            Arc::new(lookup),
+            &VerificationOptions::default(),
+        )
+        .await
+        .unwrap();
+
+        let signatures = expected
+            .get("signatures")
+            .and_then(Value::as_array)
+            .unwrap();
+        let mut divergences = vec![];
+        if signatures.len() != result.signatures().len() {
+            divergences.push(format!(
+                "{} signatures instead of {}",
+                result.signatures().len(),
+                signatures.len()
+            ));
+        }
+        for (index, (golden, signature)) in signatures.iter().zip(result.signatures()).enumerate() {
+            let mut diverge = |what: &str, detail: String| {
+                divergences.push(format!("signature {}: {}: {}", index, what, detail))
+            };
+
+            let outcome = signature.result.summary();
+            if outcome != str_member(golden, "outcome") {
+                diverge(
+                    "outcome",
+                    format!(
+                        "expected {} got {} ({:?})",
+                        str_member(golden, "outcome"),
+                        outcome,
+                        signature.result.error()
+                    ),
+                );
+            }
+
+            let canonicalized =
+                canonicalize_signed_email_detailed(&email_bytes, SignatureSelector::Index(index))
+                    .unwrap();
+            let header_data = str_member(golden, "header_data").as_bytes();
+            if let Some(detail) = first_divergence(header_data, &canonicalized.headers) {
+                diverge("header data", detail);
+            }
+            let header_sha256 = sha256_hex(&canonicalized.headers);
+            if header_sha256 != str_member(golden, "header_sha256") {
+                diverge(
+                    "header hash",
+                    format!(
+                        "expected {} got {}",
+                        str_member(golden, "header_sha256"),
+                        header_sha256
+                    ),
+                );
+            }
+
+            let body_hashes = golden.get("body_sha256").unwrap();
+            for (name, canonicalization_type) in [
+                ("simple", canonicalization::Type::Simple),
+                ("relaxed", canonicalization::Type::Relaxed),
+            ] {
+                let body = get_canonicalized_body(&email_bytes, canonicalization_type).unwrap();
+                let body_sha256 = sha256_hex(&body);
+                if body_sha256 != str_member(body_hashes, name) {
+                    diverge(
+                        &format!("{} body hash", name),
+                        format!(
+                            "expected {} got {} for {} bytes",
+                            str_member(body_hashes, name),
+                            body_sha256,
+                            body.len()
+                        ),
+                    );
+                }
+            }
+        }
+        divergences
+    }
+
+    #[tokio::test]
+    async fn test_fixtures() {
+        let mut paths: Vec<PathBuf> = std::fs::read_dir(FIXTURES)
+            .unwrap()
+            .map(|entry| entry.unwrap().path())
+            .filter(|path| path.extension().is_some_and(|ext| ext == "eml"))
+            .collect();
+        paths.sort();
+        assert!(!paths.is_empty(), "no fixture in {}", FIXTURES);
+
+        let mut failures = vec![];
+        for path in &paths {
+            for divergence in run_fixture(path).await {
+                failures.push(format!("{}: {}", path.display(), divergence));
+            }
+        }
+        assert!(failures.is_empty(), "{}", failures.join("\n"));
+    }
+
+    #[test]
+    fn test_first_divergence() {
+        assert_eq!(first_divergence(b"abc", b"abc"), None);
+        assert_eq!(
+            first_divergence(b"from:a\r\n", b"from: a\r\n").unwrap(),
+            r#"first divergent byte at offset 5: expected "from:a\r\n" got "from: a\r\n""#
+        );
+        assert_eq!(
+            first_divergence(b"abc", b"ab").unwrap(),
+            r#"first divergent byte at offset 2: expected "abc" got "ab""#
+        );
+    }
+}
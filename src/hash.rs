@@ -1,26 +1,76 @@
+//! Body and header hashes of signatures, as computed by the signer and the
+//! verifier.
+//!
+//! The functions take the parsed raw message, header included, like
+//! [mailparse::parse_mail] returns it: the body is everything after the empty
+//! line ending the header. For verification the line endings of the body are
+//! normalized to CRLF first, see [compute_body_hash].
+
+use std::borrow::Cow;
 use std::collections::HashMap;
 
+use crate::log::Logger;
 use base64::engine::general_purpose;
 use base64::Engine;
-use slog::debug;
 
-use crate::canonicalization::{
-    self, canonicalize_body_relaxed, canonicalize_body_simple, canonicalize_header_relaxed,
-    canonicalize_header_simple,
-};
+use crate::canonicalization::{self, canonicalize_header};
 use crate::header::HEADER;
 use crate::{bytes, DKIMError, DKIMHeader};
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "serde", serde(rename_all = "snake_case"))]
 pub enum HashAlgo {
     RsaSha1,
     RsaSha256,
     Ed25519Sha256,
 }
 
-/// Get the body part of an email
-pub(crate) fn get_body<'a>(email: &'a mailparse::ParsedMail<'a>) -> Result<Vec<u8>, DKIMError> {
-    Ok(bytes::get_all_after(email.raw_bytes, b"\r\n\r\n").to_vec())
+impl HashAlgo {
+    /// Name of the algorithm, as in the a= tag of signatures
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            HashAlgo::RsaSha1 => "rsa-sha1",
+            HashAlgo::RsaSha256 => "rsa-sha256",
+            HashAlgo::Ed25519Sha256 => "ed25519-sha256",
+        }
+    }
+
+    /// Name of the hash algorithm, as listed in the h= tag of key records
+    pub fn hash_name(&self) -> &'static str {
+        match self {
+            HashAlgo::RsaSha1 => "sha1",
+            HashAlgo::RsaSha256 | HashAlgo::Ed25519Sha256 => "sha256",
+        }
+    }
+}
+
+/// Bodies larger than this are hashed with a [BodyHasher] instead of being
+/// canonicalized in memory first
+const STREAMING_BODY_THRESHOLD: usize = 1024 * 1024;
+
+/// Size of the chunks given to the [BodyHasher] of large bodies
+const STREAMING_CHUNK_SIZE: usize = 64 * 1024;
+
+/// Get the body part of an email, everything after the empty line ending
+/// the header
+pub(crate) fn get_body<'a>(email: &'a mailparse::ParsedMail<'a>) -> Result<&'a [u8], DKIMError> {
+    let (_, ix) = mailparse::parse_headers(email.raw_bytes)
+        .map_err(|err| DKIMError::SignatureSyntaxError(err.to_string()))?;
+    Ok(&email.raw_bytes[ix..])
+}
+
+/// Get the body part of an email with its line endings normalized to CRLF
+/// like the signer did, see [bytes::to_crlf]
+pub(crate) fn get_normalized_body<'a>(
+    email: &'a mailparse::ParsedMail<'a>,
+) -> Result<Cow<'a, [u8]>, DKIMError> {
+    let body = get_body(email)?;
+    Ok(if bytes::has_bare_lf(body) {
+        Cow::Owned(bytes::to_crlf(body))
+    } else {
+        Cow::Borrowed(body)
+    })
 }
 
 fn hash_sha1<T: AsRef<[u8]>>(data: T) -> Vec<u8> {
@@ -39,41 +89,282 @@ fn hash_sha256<T: AsRef<[u8]>>(data: T) -> Vec<u8> {
     hasher.finalize().to_vec()
 }
 
-/// Returns the hash of message's body
-/// https://datatracker.ietf.org/doc/html/rfc6376#section-3.7
-pub(crate) fn compute_body_hash<'a>(
+enum Hasher {
+    Sha1(sha1::Sha1),
+    Sha256(sha2::Sha256),
+}
+
+/// Incremental body hash, canonicalizing the body given in chunks with a
+/// [canonicalization::BodyCanonicalizer] so that large bodies are hashed
+/// without being copied in memory.
+///
+/// ```
+/// use cfdkim::{canonicalization::Type, BodyHasher, HashAlgo};
+///
+/// let mut hasher = BodyHasher::new(Type::Relaxed, HashAlgo::RsaSha256, None);
+/// hasher.update(b"Hello  ");
+/// hasher.update(b"Alice\r\n\r\n");
+/// let body_hash = hasher.finalize();
+/// assert_eq!(body_hash.len(), 32);
+/// ```
+pub struct BodyHasher {
+    canonicalizer: canonicalization::BodyCanonicalizer,
+    hasher: Hasher,
+    /// Number of canonicalized bytes still to hash, with l=
+    remaining: Option<u64>,
+    /// Length of the canonicalized body so far, l= aside
+    canonicalized_len: u64,
+    buffer: Vec<u8>,
+}
+
+impl BodyHasher {
+    /// New hasher for the body of a signature, hashing at most `length`
+    /// bytes of the canonicalized body when set (l= tag)
+    pub fn new(
+        canonicalization_type: canonicalization::Type,
+        hash_algo: HashAlgo,
+        length: Option<u64>,
+    ) -> Self {
+        use sha1::Digest as _;
+        let hasher = match hash_algo {
+            HashAlgo::RsaSha1 => Hasher::Sha1(sha1::Sha1::new()),
+            HashAlgo::RsaSha256 | HashAlgo::Ed25519Sha256 => Hasher::Sha256(sha2::Sha256::new()),
+        };
+        Self {
+            canonicalizer: canonicalization::BodyCanonicalizer::new(canonicalization_type),
+            hasher,
+            remaining: length,
+            canonicalized_len: 0,
+            buffer: vec![],
+        }
+    }
+
+    /// Hash the next chunk of the raw body
+    pub fn update(&mut self, chunk: &[u8]) {
+        self.canonicalizer.update(chunk, &mut self.buffer);
+        self.canonicalized_len += self.buffer.len() as u64;
+        self.hasher.update(&self.buffer, &mut self.remaining);
+        self.buffer.clear();
+    }
+
+    /// Hash the end of the body and return the hash
+    pub fn finalize(self) -> Vec<u8> {
+        self.finalize_with_length().0
+    }
+
+    /// Same as [BodyHasher::finalize], also returning the length of the
+    /// whole canonicalized body
+    pub(crate) fn finalize_with_length(self) -> (Vec<u8>, u64) {
+        let Self {
+            canonicalizer,
+            mut hasher,
+            mut remaining,
+            canonicalized_len,
+            mut buffer,
+        } = self;
+        canonicalizer.finalize(&mut buffer);
+        hasher.update(&buffer, &mut remaining);
+        (hasher.finalize(), canonicalized_len + buffer.len() as u64)
+    }
+}
+
+impl Hasher {
+    /// Hash the data, up to `remaining` bytes when set
+    fn update(&mut self, mut data: &[u8], remaining: &mut Option<u64>) {
+        use sha1::Digest as _;
+        if let Some(remaining) = remaining {
+            let length = data
+                .len()
+                .min(usize::try_from(*remaining).unwrap_or(usize::MAX));
+            data = &data[..length];
+            *remaining -= length as u64;
+        }
+        match self {
+            Hasher::Sha1(hasher) => hasher.update(data),
+            Hasher::Sha256(hasher) => hasher.update(data),
+        }
+    }
+
+    fn finalize(self) -> Vec<u8> {
+        use sha1::Digest as _;
+        match self {
+            Hasher::Sha1(hasher) => hasher.finalize().to_vec(),
+            Hasher::Sha256(hasher) => hasher.finalize().to_vec(),
+        }
+    }
+}
+
+/// Initial state of SHA-256 (FIPS 180-4 section 5.3.3)
+const SHA256_INITIAL_STATE: [u32; 8] = [
+    0x6a09e667, 0xbb67ae85, 0x3c6ef372, 0xa54ff53a, 0x510e527f, 0x9b05688c, 0x1f83d9ab, 0x5be0cd19,
+];
+
+/// SHA-256 of the beginning of some data, to finish the hash elsewhere (in a
+/// zk circuit for example), see [partial_body_hash]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PartialSha256State {
+    /// Internal state after hashing the first `hashed_len` bytes
+    pub state: [u32; 8],
+    /// Bytes after the first `hashed_len` bytes, still to hash
+    pub remaining: Vec<u8>,
+    /// Number of bytes hashed, a multiple of 64
+    pub hashed_len: u64,
+}
+
+impl PartialSha256State {
+    /// Finish the hash over the remaining bytes, returning the same digest
+    /// as hashing all the data at once
+    pub fn finalize(&self) -> [u8; 32] {
+        let total_len = self.hashed_len + self.remaining.len() as u64;
+        let mut data = self.remaining.clone();
+        // Padding: a 1 bit, zeros and the length in bits, to a block boundary
+        data.push(0x80);
+        while data.len() % 64 != 56 {
+            data.push(0);
+        }
+        data.extend_from_slice(&(total_len * 8).to_be_bytes());
+
+        let mut state = self.state;
+        compress_sha256(&mut state, &data);
+        let mut digest = [0; 32];
+        for (bytes, word) in digest.chunks_mut(4).zip(state) {
+            bytes.copy_from_slice(&word.to_be_bytes());
+        }
+        digest
+    }
+}
+
+/// Hash whole 64 bytes blocks into the SHA-256 state
+fn compress_sha256(state: &mut [u32; 8], data: &[u8]) {
+    use sha2::digest::generic_array::GenericArray;
+
+    let blocks: Vec<_> = data
+        .chunks_exact(64)
+        .map(GenericArray::clone_from_slice)
+        .collect();
+    sha2::compress256(state, &blocks);
+}
+
+/// SHA-256 of a canonicalized body (truncated to l= if needed, like the
+/// `body` of [crate::CanonicalizedEmail]) up to the last 64 bytes block
+/// boundary before `cut_offset`. Finishing the hash over the remaining bytes
+/// gives the bh= of the signature.
+pub fn partial_body_hash(canonicalized_body: &[u8], cut_offset: usize) -> PartialSha256State {
+    let hashed_len = cut_offset.min(canonicalized_body.len()) / 64 * 64;
+    let mut state = SHA256_INITIAL_STATE;
+    compress_sha256(&mut state, &canonicalized_body[..hashed_len]);
+
+    PartialSha256State {
+        state,
+        remaining: canonicalized_body[hashed_len..].to_vec(),
+        hashed_len: hashed_len as u64,
+    }
+}
+
+/// Returns the canonicalized body of the message
+pub(crate) fn canonicalize_body<'a>(
     canonicalization_type: canonicalization::Type,
-    length: Option<String>,
-    hash_algo: HashAlgo,
     email: &'a mailparse::ParsedMail<'a>,
-) -> Result<String, DKIMError> {
+) -> Result<Vec<u8>, DKIMError> {
     let body = get_body(email)?;
 
-    let mut canonicalized_body = if canonicalization_type == canonicalization::Type::Simple {
-        canonicalize_body_simple(&body)
-    } else {
-        canonicalize_body_relaxed(&body)
+    Ok(canonicalization::canonicalize_body(
+        body,
+        canonicalization_type,
+    ))
+}
+
+/// Returns the base64 encoded hash of an already canonicalized body
+pub fn hash_canonicalized_body(hash_algo: HashAlgo, canonicalized_body: &[u8]) -> String {
+    let hash = match hash_algo {
+        HashAlgo::RsaSha1 => hash_sha1(canonicalized_body),
+        HashAlgo::RsaSha256 => hash_sha256(canonicalized_body),
+        HashAlgo::Ed25519Sha256 => hash_sha256(canonicalized_body),
     };
+    general_purpose::STANDARD.encode(hash)
+}
+
+/// Returns the base64 encoded hash of message's body, to compare with the
+/// bh= tag of a signature.
+///
+/// The body of `email` (the whole raw message) has its bare LFs converted to
+/// CRLF, is canonicalized, truncated to `length` bytes when set (l= tag) and
+/// hashed.
+/// <https://datatracker.ietf.org/doc/html/rfc6376#section-3.7>
+pub fn compute_body_hash<'a>(
+    canonicalization_type: canonicalization::Type,
+    length: Option<u64>,
+    hash_algo: HashAlgo,
+    email: &'a mailparse::ParsedMail<'a>,
+) -> Result<String, DKIMError> {
+    compute_body_hash_and_length(canonicalization_type, length, hash_algo, email)
+        .map(|(body_hash, _)| body_hash)
+}
+
+/// Same as [compute_body_hash], also returning the length of the whole
+/// canonicalized body
+fn compute_body_hash_and_length<'a>(
+    canonicalization_type: canonicalization::Type,
+    length: Option<u64>,
+    hash_algo: HashAlgo,
+    email: &'a mailparse::ParsedMail<'a>,
+) -> Result<(String, u64), DKIMError> {
+    let body = get_normalized_body(email)?;
+    if body.len() > STREAMING_BODY_THRESHOLD {
+        let mut hasher = BodyHasher::new(canonicalization_type, hash_algo, length);
+        for chunk in body.chunks(STREAMING_CHUNK_SIZE) {
+            hasher.update(chunk);
+        }
+        let (body_hash, canonicalized_len) = hasher.finalize_with_length();
+        return Ok((
+            general_purpose::STANDARD.encode(body_hash),
+            canonicalized_len,
+        ));
+    }
+
+    let mut canonicalized_body = canonicalization::canonicalize_body(&body, canonicalization_type);
+    let canonicalized_len = canonicalized_body.len() as u64;
     if let Some(length) = length {
-        let length = length
-            .parse::<usize>()
-            .map_err(|err| DKIMError::SignatureSyntaxError(format!("invalid length: {}", err)))?;
-        canonicalized_body.truncate(length);
+        canonicalized_body.truncate(usize::try_from(length).unwrap_or(usize::MAX));
     };
 
-    let hash = match hash_algo {
-        HashAlgo::RsaSha1 => hash_sha1(&canonicalized_body),
-        HashAlgo::RsaSha256 => hash_sha256(&canonicalized_body),
-        HashAlgo::Ed25519Sha256 => hash_sha256(&canonicalized_body),
-    };
-    Ok(general_purpose::STANDARD.encode(hash))
+    Ok((
+        hash_canonicalized_body(hash_algo, &canonicalized_body),
+        canonicalized_len,
+    ))
 }
 
-fn select_headers<'a>(
+pub(crate) fn select_headers<'a>(
     dkim_header: &str,
     email: &'a mailparse::ParsedMail<'a>,
 ) -> Result<Vec<(String, &'a [u8])>, DKIMError> {
-    let mut signed_headers = vec![];
+    select_headers_excluding(dkim_header, email, None)
+}
+
+/// Select the headers listed in `h=`, bottom-up, ignoring the header at index
+/// `excluded` (the DKIM-Signature header being verified)
+fn select_headers_excluding<'a>(
+    dkim_header: &str,
+    email: &'a mailparse::ParsedMail<'a>,
+    excluded: Option<usize>,
+) -> Result<Vec<(String, &'a [u8])>, DKIMError> {
+    Ok(select_header_indexes(dkim_header, email, excluded)
+        .into_iter()
+        .filter_map(|(_, index)| {
+            let header = &email.headers[index?];
+            Some((header.get_key(), header.get_value_raw()))
+        })
+        .collect())
+}
+
+/// Index in the message of the header selected for each name listed in
+/// `h=`, if any, see [select_headers_excluding]
+fn select_header_indexes(
+    dkim_header: &str,
+    email: &mailparse::ParsedMail,
+    excluded: Option<usize>,
+) -> Vec<(String, Option<usize>)> {
+    let mut selection = vec![];
 
     let email_headers = &email.headers;
     let num_headers = email_headers.len();
@@ -90,20 +381,108 @@ fn select_headers<'a>(
             .rev()
             .skip(num_headers - index)
         {
+            if Some(header.0) == excluded {
+                continue;
+            }
             if header.1.get_key_ref().eq_ignore_ascii_case(&name) {
-                signed_headers.push((header.1.get_key(), header.1.get_value_raw()));
+                selection.push((name.clone(), Some(header.0)));
                 last_index.insert(name, header.0);
                 continue 'outer;
             }
         }
 
+        selection.push((name.clone(), None));
         last_index.insert(name, 0);
     }
 
-    Ok(signed_headers)
+    selection
 }
 
-pub(crate) fn canonicalize_header_email<'a, 'b>(
+/// Index of the DKIM-Signature header of `dkim_header` in the message, which
+/// is hashed separately and can't be selected by h=
+fn own_header_index(dkim_header: &DKIMHeader, email: &mailparse::ParsedMail) -> Option<usize> {
+    email.headers.iter().position(|header| {
+        header.get_key_ref().eq_ignore_ascii_case(HEADER)
+            && String::from_utf8_lossy(header.get_value_raw()) == dkim_header.raw_bytes
+    })
+}
+
+/// Exact bytes of a header field of `email`, from the first byte of its name
+/// to the end of its value without the final line ending, folding and the
+/// whitespace around the colon included. `None` when the header doesn't
+/// point into `email.raw_bytes`.
+fn raw_header_field<'a>(
+    email: &mailparse::ParsedMail<'a>,
+    header: &mailparse::MailHeader<'a>,
+) -> Option<&'a [u8]> {
+    let base = email.raw_bytes.as_ptr() as usize;
+    let value = header.get_value_raw();
+    let start = (header.get_key_raw().as_ptr() as usize).checked_sub(base)?;
+    let end = (value.as_ptr() as usize + value.len()).checked_sub(base)?;
+    if start > end || end > email.raw_bytes.len() {
+        return None;
+    }
+    let raw_bytes: &'a [u8] = email.raw_bytes;
+    Some(&raw_bytes[start..end])
+}
+
+/// Canonicalize a selected header field. The simple canonicalization uses
+/// the header as it appears in the message, see [raw_header_field], and
+/// only rebuilds it from its name and value when that's unavailable.
+fn canonicalize_selected_header(
+    email: &mailparse::ParsedMail,
+    header: &mailparse::MailHeader,
+    canonicalization_type: canonicalization::Type,
+) -> Vec<u8> {
+    match (&canonicalization_type, raw_header_field(email, header)) {
+        (canonicalization::Type::Simple, Some(field)) => [field, b"\r\n"].concat(),
+        _ => canonicalize_header(
+            &header.get_key(),
+            header.get_value_raw(),
+            canonicalization_type,
+        ),
+    }
+}
+
+/// Header field hashed for a name listed in the h= tag of a signature, see
+/// [selected_headers_for_signature]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SelectedHeader {
+    /// Name as listed in h=, lowercased
+    pub name: String,
+    /// Index of the selected header field in the header of the message,
+    /// starting at 0 for the first one. `None` when no occurrence was left,
+    /// the name then hashes as nothing.
+    pub index: Option<usize>,
+    /// Raw value of the selected header field, as given to the
+    /// canonicalization
+    pub value: Option<Vec<u8>>,
+}
+
+/// Returns the header fields that went into the header hash of a signature,
+/// in the order of its h= tag: occurrences of a name are selected from the
+/// bottom of the header up (RFC 6376, section 5.4.2).
+pub fn selected_headers_for_signature(
+    dkim_header: &DKIMHeader,
+    email: &mailparse::ParsedMail,
+) -> Vec<SelectedHeader> {
+    let excluded = own_header_index(dkim_header, email);
+    select_header_indexes(&dkim_header.get_required_tag("h"), email, excluded)
+        .into_iter()
+        .map(|(name, index)| SelectedHeader {
+            name,
+            index,
+            value: index.map(|index| email.headers[index].get_value_raw().to_vec()),
+        })
+        .collect()
+}
+
+/// Returns the input of the header hash: the header fields of `email` listed
+/// in `headers` (h= tag, colon separated) selected bottom-up and
+/// canonicalized, followed by the canonicalized DKIM-Signature header with
+/// an empty b= tag and without its final CRLF.
+/// <https://datatracker.ietf.org/doc/html/rfc6376#section-3.7>
+pub fn canonicalize_header_email<'a, 'b>(
     canonicalization_type: canonicalization::Type,
     headers: &'b str,
     dkim_header: &'b DKIMHeader,
@@ -111,25 +490,39 @@ pub(crate) fn canonicalize_header_email<'a, 'b>(
 ) -> Result<Vec<u8>, DKIMError> {
     let mut input = Vec::new();
 
+    // https://datatracker.ietf.org/doc/html/rfc6376#section-5.4
+    // The DKIM-Signature header being verified is hashed separately below, so
+    // it can't be one of the selected headers when h= lists other signatures.
+    let own_header = own_header_index(dkim_header, email);
+
     // Add the headers defined in `h=` in the hash
-    for (key, value) in select_headers(headers, email)? {
-        let canonicalized_value = if canonicalization_type == canonicalization::Type::Simple {
-            canonicalize_header_simple(&key, value)
-        } else {
-            canonicalize_header_relaxed(&key, value)
-        };
-        input.extend_from_slice(&canonicalized_value);
+    for (_, index) in select_header_indexes(headers, email, own_header) {
+        if let Some(index) = index {
+            let canonicalized_value = canonicalize_selected_header(
+                email,
+                &email.headers[index],
+                canonicalization_type.clone(),
+            );
+            input.extend_from_slice(&canonicalized_value);
+        }
     }
 
     // Add the DKIM-Signature header in the hash. Remove the value of the
     // signature (b) first.
     {
-        let sign = dkim_header.get_raw_tag("b").unwrap();
-        let value = dkim_header.raw_bytes.replace(&sign, "");
-        let mut canonicalized_value = if canonicalization_type == canonicalization::Type::Simple {
-            canonicalize_header_simple(HEADER, value.as_bytes())
-        } else {
-            canonicalize_header_relaxed(HEADER, value.as_bytes())
+        let value = dkim_header.with_empty_signature();
+        // With simple canonicalization, keep the name and the whitespace
+        // after the colon of the header in the message, when verifying
+        let raw_name = own_header.and_then(|index| {
+            let header = &email.headers[index];
+            let field = raw_header_field(email, header)?;
+            Some(&field[..field.len() - header.get_value_raw().len()])
+        });
+        let mut canonicalized_value = match (&canonicalization_type, raw_name) {
+            (canonicalization::Type::Simple, Some(raw_name)) => {
+                [raw_name, value.as_bytes(), b"\r\n"].concat()
+            }
+            _ => canonicalize_header(HEADER, value.as_bytes(), canonicalization_type),
         };
 
         // remove trailing "\r\n"
@@ -140,43 +533,51 @@ pub(crate) fn canonicalize_header_email<'a, 'b>(
     Ok(input)
 }
 
-pub(crate) fn compute_headers_hash<'a, 'b>(
-    logger: &slog::Logger,
+/// Returns the data to be signed of a signature, the input of the header
+/// hash, as logged by the signer and the verifier.
+///
+/// This is [canonicalize_header_email]: every selected header field ends with
+/// a CRLF, but the DKIM-Signature header comes last without its CRLF, as
+/// required by RFC 6376 section 3.7, so the data never ends with a CRLF.
+pub fn compute_headers_message<'a, 'b>(
+    canonicalization_type: canonicalization::Type,
+    headers: &'b str,
+    dkim_header: &'b DKIMHeader,
+    email: &'a mailparse::ParsedMail<'a>,
+) -> Result<Vec<u8>, DKIMError> {
+    canonicalize_header_email(canonicalization_type, headers, dkim_header, email)
+}
+
+/// Returns the hash of [compute_headers_message]. With RSA this digest is
+/// what PKCS#1 v1.5 signs in b=, with Ed25519 it is the signed message
+/// itself (RFC 8463).
+pub fn compute_headers_hash<'a, 'b>(
     canonicalization_type: canonicalization::Type,
     headers: &'b str,
     hash_algo: HashAlgo,
     dkim_header: &'b DKIMHeader,
     email: &'a mailparse::ParsedMail<'a>,
 ) -> Result<Vec<u8>, DKIMError> {
-    // let mut input = Vec::new();
-
-    // // Add the headers defined in `h=` in the hash
-    // for (key, value) in select_headers(headers, email)? {
-    //     let canonicalized_value = if canonicalization_type == canonicalization::Type::Simple {
-    //         canonicalize_header_simple(&key, value)
-    //     } else {
-    //         canonicalize_header_relaxed(&key, value)
-    //     };
-    //     input.extend_from_slice(&canonicalized_value);
-    // }
-
-    // // Add the DKIM-Signature header in the hash. Remove the value of the
-    // // signature (b) first.
-    // {
-    //     let sign = dkim_header.get_raw_tag("b").unwrap();
-    //     let value = dkim_header.raw_bytes.replace(&sign, "");
-    //     let mut canonicalized_value = if canonicalization_type == canonicalization::Type::Simple {
-    //         canonicalize_header_simple(HEADER, value.as_bytes())
-    //     } else {
-    //         canonicalize_header_relaxed(HEADER, value.as_bytes())
-    //     };
-
-    //     // remove trailing "\r\n"
-    //     canonicalized_value.truncate(canonicalized_value.len() - 2);
-
-    //     input.extend_from_slice(&canonicalized_value);
-    // }
-    let input = canonicalize_header_email(canonicalization_type, headers, dkim_header, email)?;
+    compute_headers_hash_with_logger(
+        &Logger::default(),
+        canonicalization_type,
+        headers,
+        hash_algo,
+        dkim_header,
+        email,
+    )
+}
+
+/// [compute_headers_hash] logging the hashed data
+pub(crate) fn compute_headers_hash_with_logger<'a, 'b>(
+    logger: &Logger,
+    canonicalization_type: canonicalization::Type,
+    headers: &'b str,
+    hash_algo: HashAlgo,
+    dkim_header: &'b DKIMHeader,
+    email: &'a mailparse::ParsedMail<'a>,
+) -> Result<Vec<u8>, DKIMError> {
+    let input = compute_headers_message(canonicalization_type, headers, dkim_header, email)?;
     debug!(logger, "headers to hash: {:?}", input);
 
     let hash = match hash_algo {
@@ -187,6 +588,122 @@ pub(crate) fn compute_headers_hash<'a, 'b>(
     Ok(hash)
 }
 
+/// Returns the base64 encoded body hash of a signature, as computed by the
+/// verifier with its c=, l= and a= tags, see [compute_body_hash]
+pub fn compute_body_hash_for_signature<'a>(
+    dkim_header: &DKIMHeader,
+    email: &'a mailparse::ParsedMail<'a>,
+) -> Result<String, DKIMError> {
+    let (_, body_canonicalization_type) = dkim_header.canonicalization()?;
+    compute_body_hash(
+        body_canonicalization_type,
+        dkim_header.body_length()?,
+        dkim_header.algorithm()?,
+        email,
+    )
+}
+
+/// Body hashes computed while verifying the signatures of a message, so that
+/// the signatures with the same body canonicalization, hash algorithm and
+/// l= share them
+#[derive(Default)]
+pub(crate) struct BodyHashCache {
+    hashes: Vec<(BodyHashKey, String)>,
+    /// Length of the whole canonicalized body with each canonicalization
+    lengths: Vec<(canonicalization::Type, u64)>,
+    /// Number of body hashes actually computed
+    #[cfg(test)]
+    computed: usize,
+}
+
+/// Body canonicalization, whether the hash is SHA-1 and the signed length
+type BodyHashKey = (canonicalization::Type, bool, Option<u64>);
+
+impl BodyHashCache {
+    /// Same as [compute_body_hash_for_signature], reusing the hash of a
+    /// previous signature when possible
+    pub(crate) fn body_hash_for_signature<'a>(
+        &mut self,
+        dkim_header: &DKIMHeader,
+        email: &'a mailparse::ParsedMail<'a>,
+    ) -> Result<String, DKIMError> {
+        let (_, body_canonicalization_type) = dkim_header.canonicalization()?;
+        let hash_algo = dkim_header.algorithm()?;
+        let key = (
+            body_canonicalization_type,
+            hash_algo == HashAlgo::RsaSha1,
+            dkim_header.body_length()?,
+        );
+        if let Some((_, body_hash)) = self.hashes.iter().find(|(k, _)| *k == key) {
+            return Ok(body_hash.clone());
+        }
+
+        let (body_hash, canonicalized_len) =
+            compute_body_hash_and_length(key.0.clone(), key.2, hash_algo, email)?;
+        #[cfg(test)]
+        {
+            self.computed += 1;
+        }
+        if !self.lengths.iter().any(|(t, _)| *t == key.0) {
+            self.lengths.push((key.0.clone(), canonicalized_len));
+        }
+        self.hashes.push((key, body_hash.clone()));
+        Ok(body_hash)
+    }
+
+    /// Length of the whole canonicalized body with the c= of the signature,
+    /// known once a body hash was computed with it
+    pub(crate) fn canonicalized_body_length<'a>(
+        &mut self,
+        dkim_header: &DKIMHeader,
+        email: &'a mailparse::ParsedMail<'a>,
+    ) -> Result<u64, DKIMError> {
+        let (_, body_canonicalization_type) = dkim_header.canonicalization()?;
+        let known = |cache: &Self| {
+            cache
+                .lengths
+                .iter()
+                .find(|(t, _)| *t == body_canonicalization_type)
+                .map(|(_, length)| *length)
+        };
+        if let Some(length) = known(self) {
+            return Ok(length);
+        }
+        self.body_hash_for_signature(dkim_header, email)?;
+        Ok(known(self).expect("length recorded with the body hash"))
+    }
+
+    #[cfg(test)]
+    pub(crate) fn computed(&self) -> usize {
+        self.computed
+    }
+}
+
+/// Returns the header hash of a signature, as computed by the verifier with
+/// its c=, h= and a= tags, see [compute_headers_hash]
+pub fn compute_headers_hash_for_signature<'a>(
+    dkim_header: &DKIMHeader,
+    email: &'a mailparse::ParsedMail<'a>,
+) -> Result<Vec<u8>, DKIMError> {
+    compute_headers_hash_for_signature_with_logger(&Logger::default(), dkim_header, email)
+}
+
+pub(crate) fn compute_headers_hash_for_signature_with_logger<'a>(
+    logger: &Logger,
+    dkim_header: &DKIMHeader,
+    email: &'a mailparse::ParsedMail<'a>,
+) -> Result<Vec<u8>, DKIMError> {
+    let (header_canonicalization_type, _) = dkim_header.canonicalization()?;
+    compute_headers_hash_with_logger(
+        logger,
+        header_canonicalization_type,
+        &dkim_header.get_required_tag("h"),
+        dkim_header.algorithm()?,
+        dkim_header,
+        email,
+    )
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -212,19 +729,13 @@ Hello Alice
         let length = None;
         let hash_algo = HashAlgo::RsaSha1;
         assert_eq!(
-            compute_body_hash(
-                canonicalization_type.clone(),
-                length.clone(),
-                hash_algo,
-                &email
-            )
-            .unwrap(),
-            "uoq1oCgLlTqpdDX/iUbLy7J1Wic="
+            compute_body_hash(canonicalization_type.clone(), length, hash_algo, &email).unwrap(),
+            "ya82MJvChLGBNSxeRvrSat5LliQ="
         );
         let hash_algo = HashAlgo::RsaSha256;
         assert_eq!(
             compute_body_hash(canonicalization_type, length, hash_algo, &email).unwrap(),
-            "frcCV1k9oG9oKj3dpUqdJg1PxRT2RSN/XKdLCPjaYaY="
+            "KXQwQpX2zFwgixPbV6Dd18ZMJU04lLeRnwqzUp8uGwI="
         )
     }
 
@@ -245,19 +756,13 @@ Hello Alice
         let length = None;
         let hash_algo = HashAlgo::RsaSha1;
         assert_eq!(
-            compute_body_hash(
-                canonicalization_type.clone(),
-                length.clone(),
-                hash_algo,
-                &email
-            )
-            .unwrap(),
-            "2jmj7l5rSw0yVb/vlWAYkK/YBwk="
+            compute_body_hash(canonicalization_type.clone(), length, hash_algo, &email).unwrap(),
+            "QKvft7OqaNbRT/nH0Qmc/7mSK7w="
         );
         let hash_algo = HashAlgo::RsaSha256;
         assert_eq!(
             compute_body_hash(canonicalization_type, length, hash_algo, &email).unwrap(),
-            "47DEQpj8HBSa+/TImW+5JCeuQeRkm5NMpJWZG3hSuFU="
+            "+kuxulZ7MkxvrZj1LNFkEtOUvi0M2/80KBPP0duHSfw="
         )
     }
 
@@ -275,22 +780,16 @@ Hello Alice
         .unwrap();
 
         let canonicalization_type = canonicalization::Type::Relaxed;
-        let length = Some("3".to_owned());
+        let length = Some(3);
         let hash_algo = HashAlgo::RsaSha1;
         assert_eq!(
-            compute_body_hash(
-                canonicalization_type.clone(),
-                length.clone(),
-                hash_algo,
-                &email
-            )
-            .unwrap(),
-            "2jmj7l5rSw0yVb/vlWAYkK/YBwk="
+            compute_body_hash(canonicalization_type.clone(), length, hash_algo, &email).unwrap(),
+            "28LR/tDcN6cK6g83aVjIAu3cBVk="
         );
         let hash_algo = HashAlgo::RsaSha256;
         assert_eq!(
             compute_body_hash(canonicalization_type, length, hash_algo, &email).unwrap(),
-            "47DEQpj8HBSa+/TImW+5JCeuQeRkm5NMpJWZG3hSuFU="
+            "t4nCTc22jEQ3sEwYa/I5pyB+dXP7GyKnSf4ae42W0pI="
         )
     }
 
@@ -302,13 +801,7 @@ Hello Alice
         let length = None;
         let hash_algo = HashAlgo::RsaSha1;
         assert_eq!(
-            compute_body_hash(
-                canonicalization_type.clone(),
-                length.clone(),
-                hash_algo,
-                &email
-            )
-            .unwrap(),
+            compute_body_hash(canonicalization_type.clone(), length, hash_algo, &email).unwrap(),
             "uoq1oCgLlTqpdDX/iUbLy7J1Wic="
         );
         let hash_algo = HashAlgo::RsaSha256;
@@ -326,13 +819,7 @@ Hello Alice
         let length = None;
         let hash_algo = HashAlgo::RsaSha1;
         assert_eq!(
-            compute_body_hash(
-                canonicalization_type.clone(),
-                length.clone(),
-                hash_algo,
-                &email
-            )
-            .unwrap(),
+            compute_body_hash(canonicalization_type.clone(), length, hash_algo, &email).unwrap(),
             "2jmj7l5rSw0yVb/vlWAYkK/YBwk="
         );
         let hash_algo = HashAlgo::RsaSha256;
@@ -358,10 +845,8 @@ Hello Alice
         let canonicalization_type = canonicalization::Type::Simple;
         let hash_algo = HashAlgo::RsaSha1;
         let headers = "To: Subject".to_owned();
-        let logger = slog::Logger::root(slog::Discard, slog::o!());
         assert_eq!(
             compute_headers_hash(
-                &logger,
                 canonicalization_type.clone(),
                 &headers,
                 hash_algo,
@@ -377,7 +862,6 @@ Hello Alice
         let hash_algo = HashAlgo::RsaSha256;
         assert_eq!(
             compute_headers_hash(
-                &logger,
                 canonicalization_type,
                 &headers,
                 hash_algo,
@@ -392,6 +876,147 @@ Hello Alice
         )
     }
 
+    #[test]
+    fn test_compute_headers_message() {
+        let email = mailparse::parse_mail(
+            b"From: a@test.com\r\nTo: test@sauleau.com\r\nSubject:  A\r\n  subject\r\n\r\nHello Alice\r\n",
+        )
+        .unwrap();
+        let dkim_header = crate::validate_header(
+            "v=1; a=rsa-sha256; c=relaxed/relaxed; d=test.com; s=smtp;\r\n h=from:subject:to; bh=; b=ABC\r\n DEF",
+        )
+        .unwrap();
+        let message = compute_headers_message(
+            canonicalization::Type::Relaxed,
+            "from:subject:to",
+            &dkim_header,
+            &email,
+        )
+        .unwrap();
+        // The DKIM-Signature header comes last, without b= value nor CRLF
+        assert_eq!(
+            String::from_utf8_lossy(&message),
+            "from:a@test.com\r\nsubject:A subject\r\nto:test@sauleau.com\r\ndkim-signature:v=1; a=rsa-sha256; c=relaxed/relaxed; d=test.com; s=smtp; h=from:subject:to; bh=; b="
+        );
+        assert_eq!(
+            compute_headers_hash(
+                canonicalization::Type::Relaxed,
+                "from:subject:to",
+                HashAlgo::RsaSha256,
+                &dkim_header,
+                &email
+            )
+            .unwrap(),
+            hash_sha256(&message)
+        );
+    }
+
+    #[test]
+    fn test_compute_headers_message_simple_raw() {
+        let unsigned =
+            "v=1; a=rsa-sha256; c=simple/simple; d=test.com; s=smtp;\r\n\th=from:to; bh=; b=";
+        let raw_email = format!(
+            "DKIM-Signature:{}ABC\r\nFrom:a@test.com\r\nTo:\t<a@test.com>,\r\n\t<b@test.com>\r\n\r\nHello\r\n",
+            unsigned
+        );
+        let email = mailparse::parse_mail(raw_email.as_bytes()).unwrap();
+        let dkim_header =
+            crate::validate_header(&String::from_utf8_lossy(email.headers[0].get_value_raw()))
+                .unwrap();
+        // The headers are hashed as they appear in the message, the
+        // DKIM-Signature header included
+        let message = compute_headers_message(
+            canonicalization::Type::Simple,
+            "from:to",
+            &dkim_header,
+            &email,
+        )
+        .unwrap();
+        assert_eq!(
+            String::from_utf8_lossy(&message),
+            format!(
+                "From:a@test.com\r\nTo:\t<a@test.com>,\r\n\t<b@test.com>\r\nDKIM-Signature:{}",
+                unsigned
+            )
+        );
+
+        // Rebuilt from the name and value of headers not from the message
+        let header = mailparse::parse_header(b"Subject:no-space").unwrap().0;
+        assert_eq!(
+            canonicalize_selected_header(&email, &header, canonicalization::Type::Simple),
+            b"Subject: no-space\r\n"
+        );
+    }
+
+    #[test]
+    fn test_selected_headers_for_signature() {
+        let email = mailparse::parse_mail(
+            b"Received: 1\r\nFrom: a@test.com\r\nReceived: 2\r\nSubject: A\r\n\r\nHello\r\n",
+        )
+        .unwrap();
+        let dkim_header = crate::validate_header(
+            "v=1; a=rsa-sha256; d=test.com; s=smtp; h=received:received:received:from:x-missing; bh=; b=",
+        )
+        .unwrap();
+
+        let selected = |name: &str, index: Option<usize>, value: Option<&[u8]>| SelectedHeader {
+            name: name.to_owned(),
+            index,
+            value: value.map(<[u8]>::to_vec),
+        };
+        assert_eq!(
+            selected_headers_for_signature(&dkim_header, &email),
+            vec![
+                selected("received", Some(2), Some(b"2")),
+                selected("received", Some(0), Some(b"1")),
+                selected("received", None, None),
+                selected("from", Some(1), Some(b"a@test.com")),
+                selected("x-missing", None, None),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_compute_headers_message_repeated_names() {
+        let dkim_header = crate::validate_header(
+            "v=1; a=rsa-sha256; c=relaxed/relaxed; d=test.com; s=smtp; h=received:received:received:from; bh=; b=",
+        )
+        .unwrap();
+        let signature = "dkim-signature:v=1; a=rsa-sha256; c=relaxed/relaxed; d=test.com; s=smtp; h=received:received:received:from; bh=; b=";
+
+        // Occurrences are taken from the bottom up, the references left once
+        // they are exhausted add nothing
+        for (received, expected) in [
+            (0, "from:a@test.com\r\n"),
+            (1, "received:r0\r\nfrom:a@test.com\r\n"),
+            (
+                3,
+                "received:r2\r\nreceived:r1\r\nreceived:r0\r\nfrom:a@test.com\r\n",
+            ),
+        ] {
+            let mut raw_email = String::new();
+            for i in 0..received {
+                raw_email += &format!("Received: r{}\r\n", i);
+            }
+            raw_email += "From: a@test.com\r\n\r\nHello\r\n";
+            let email = mailparse::parse_mail(raw_email.as_bytes()).unwrap();
+
+            let message = compute_headers_message(
+                canonicalization::Type::Relaxed,
+                &dkim_header.get_required_tag("h"),
+                &dkim_header,
+                &email,
+            )
+            .unwrap();
+            assert_eq!(
+                String::from_utf8_lossy(&message),
+                format!("{}{}", expected, signature),
+                "{} Received headers",
+                received
+            );
+        }
+    }
+
     #[test]
     fn test_compute_headers_hash_relaxed() {
         let email = mailparse::parse_mail(
@@ -408,10 +1033,8 @@ Hello Alice
         let canonicalization_type = canonicalization::Type::Relaxed;
         let hash_algo = HashAlgo::RsaSha1;
         let headers = "To: Subject".to_owned();
-        let logger = slog::Logger::root(slog::Discard, slog::o!());
         assert_eq!(
             compute_headers_hash(
-                &logger,
                 canonicalization_type.clone(),
                 &headers,
                 hash_algo,
@@ -427,7 +1050,6 @@ Hello Alice
         let hash_algo = HashAlgo::RsaSha256;
         assert_eq!(
             compute_headers_hash(
-                &logger,
                 canonicalization_type,
                 &headers,
                 hash_algo,
@@ -442,14 +1064,87 @@ Hello Alice
         )
     }
 
+    #[test]
+    fn test_body_hasher() {
+        let large_body = "Some  text\t \r\n\r\n".repeat(STREAMING_BODY_THRESHOLD / 8);
+        let corpus = [
+            "Subject: A\r\n\r\n".to_owned(),
+            "Subject: A\r\n\r\n\r\n\r\n".to_owned(),
+            "Subject: A\r\n\r\nHello  Alice \r\n\r\n \r\n".to_owned(),
+            "Subject: A\r\n\r\nno line ending\t".to_owned(),
+            "Subject: A\r\n\r\nbare \n and \r line endings\r".to_owned(),
+            format!("Subject: A\r\n\r\n{}", large_body),
+        ];
+        for raw_email in &corpus {
+            let email = mailparse::parse_mail(raw_email.as_bytes()).unwrap();
+            for canonicalization_type in [
+                canonicalization::Type::Simple,
+                canonicalization::Type::Relaxed,
+            ] {
+                for hash_algo in [HashAlgo::RsaSha1, HashAlgo::RsaSha256] {
+                    for length in [None, Some(0), Some(7), Some(u64::MAX)] {
+                        let mut canonicalized_body = canonicalization::canonicalize_body(
+                            &get_normalized_body(&email).unwrap(),
+                            canonicalization_type.clone(),
+                        );
+                        let canonicalized_len = canonicalized_body.len() as u64;
+                        if let Some(length) = length {
+                            canonicalized_body
+                                .truncate(usize::try_from(length).unwrap_or(usize::MAX));
+                        }
+                        let expected =
+                            hash_canonicalized_body(hash_algo.clone(), &canonicalized_body);
+
+                        let mut hasher = BodyHasher::new(
+                            canonicalization_type.clone(),
+                            hash_algo.clone(),
+                            length,
+                        );
+                        for chunk in get_normalized_body(&email).unwrap().chunks(4093) {
+                            hasher.update(chunk);
+                        }
+                        assert_eq!(
+                            general_purpose::STANDARD.encode(hasher.finalize()),
+                            expected
+                        );
+                        // The length is the one of the whole body, l= aside
+                        assert_eq!(
+                            compute_body_hash_and_length(
+                                canonicalization_type.clone(),
+                                length,
+                                hash_algo.clone(),
+                                &email
+                            )
+                            .unwrap(),
+                            (expected, canonicalized_len)
+                        );
+                    }
+                }
+            }
+        }
+    }
+
     #[test]
     fn test_get_body() {
         let email =
             mailparse::parse_mail("Subject: A\r\n\r\nContent\n.hi\n.hello..".as_bytes()).unwrap();
         assert_eq!(
-            String::from_utf8_lossy(&get_body(&email).unwrap()),
+            String::from_utf8_lossy(get_body(&email).unwrap()),
             "Content\n.hi\n.hello..".to_owned()
         );
+        assert_eq!(
+            get_normalized_body(&email).unwrap(),
+            &b"Content\r\n.hi\r\n.hello.."[..]
+        );
+
+        // The header of a message with LF line endings ends with an empty line
+        // too, and a lone CR is kept
+        let email = mailparse::parse_mail(b"Subject: A\n\nline\rwith CR\n\n").unwrap();
+        assert_eq!(get_body(&email).unwrap(), b"line\rwith CR\n\n");
+        assert_eq!(
+            get_normalized_body(&email).unwrap(),
+            &b"line\rwith CR\r\n\r\n"[..]
+        );
     }
 
     #[test]
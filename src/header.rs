@@ -1,8 +1,29 @@
-use crate::{parser, DKIMError};
+use crate::{canonicalization, hash, parser, DKIMError};
+use base64::engine::{general_purpose, DecodePaddingMode, GeneralPurpose, GeneralPurposeConfig};
+use base64::Engine;
 use indexmap::map::IndexMap;
+use std::fmt;
 
 pub(crate) const HEADER: &str = "DKIM-Signature";
 pub(crate) const REQUIRED_TAGS: &[&str] = &["v", "a", "b", "bh", "d", "h", "s"];
+/// Line length used by [DKIMHeader::to_header_value] when folding
+const DEFAULT_LINE_LENGTH: usize = 78;
+
+/// Base64 accepting values with or without their padding
+const LENIENT_BASE64: GeneralPurpose = GeneralPurpose::new(
+    &base64::alphabet::STANDARD,
+    GeneralPurposeConfig::new().with_decode_padding_mode(DecodePaddingMode::Indifferent),
+);
+
+/// Decode a base64 tag value, ignoring the folding whitespace the tag grammar
+/// allows inside it and a missing padding
+pub(crate) fn decode_base64_value(value: &str) -> Result<Vec<u8>, base64::DecodeError> {
+    let value: String = value
+        .chars()
+        .filter(|c| !matches!(c, ' ' | '\t' | '\r' | '\n'))
+        .collect();
+    LENIENT_BASE64.decode(value)
+}
 
 #[derive(Debug, Clone)]
 pub struct DKIMHeader {
@@ -11,11 +32,13 @@ pub struct DKIMHeader {
 }
 
 impl DKIMHeader {
-    pub(crate) fn get_tag(&self, name: &str) -> Option<String> {
+    /// Value of a tag with the whitespace removed
+    pub fn get_tag(&self, name: &str) -> Option<String> {
         self.tags.get(name).map(|v| v.value.clone())
     }
 
-    pub(crate) fn get_raw_tag(&self, name: &str) -> Option<String> {
+    /// Value of a tag as it appears in the header
+    pub fn get_raw_tag(&self, name: &str) -> Option<String> {
         self.tags.get(name).map(|v| v.raw_value.clone())
     }
 
@@ -25,14 +48,185 @@ impl DKIMHeader {
         debug_assert!(REQUIRED_TAGS.contains(&name));
         self.tags.get(name).unwrap().value.clone()
     }
+
+    /// Value of a required tag, empty if the header wasn't validated and
+    /// doesn't have it
+    fn required_tag(&self, name: &str) -> &str {
+        debug_assert!(REQUIRED_TAGS.contains(&name));
+        self.tags.get(name).map_or("", |tag| tag.value.as_str())
+    }
+
+    /// Signing domain (d=)
+    pub fn domain(&self) -> &str {
+        self.required_tag("d")
+    }
+
+    /// Selector of the key (s=)
+    pub fn selector(&self) -> &str {
+        self.required_tag("s")
+    }
+
+    /// Agent or user identifier (i=), decoded from dkim-quoted-printable
+    pub fn identity(&self) -> Result<Option<String>, DKIMError> {
+        self.get_tag("i")
+            .map(|value| {
+                let value = parser::decode_dkim_quoted_printable(&value)?;
+                Ok(String::from_utf8_lossy(&value).into_owned())
+            })
+            .transpose()
+    }
+
+    /// Signature timestamp (t=), in seconds since the epoch
+    pub fn timestamp(&self) -> Option<i64> {
+        self.get_tag("t").and_then(|value| value.parse().ok())
+    }
+
+    /// Signature expiration (x=), in seconds since the epoch
+    pub fn expiration(&self) -> Option<i64> {
+        self.get_tag("x").and_then(|value| value.parse().ok())
+    }
+
+    /// Number of bytes of the canonicalized body covered by the signature
+    /// (l=), or nothing if it covers the whole body
+    pub fn body_length(&self) -> Result<Option<u64>, DKIMError> {
+        self.get_tag("l")
+            .map(|value| {
+                value.parse().map_err(|err| {
+                    DKIMError::SignatureSyntaxError(format!("invalid length: {}", err))
+                })
+            })
+            .transpose()
+    }
+
+    /// Signing algorithm (a=)
+    pub fn algorithm(&self) -> Result<hash::HashAlgo, DKIMError> {
+        parser::parse_hash_algo(self.required_tag("a"))
+    }
+
+    /// Header and body canonicalizations (c=), simple/simple by default
+    pub fn canonicalization(
+        &self,
+    ) -> Result<(canonicalization::Type, canonicalization::Type), DKIMError> {
+        parser::parse_canonicalization(self.get_tag("c"))
+    }
+
+    /// Names of the signed header fields (h=), lowercased and in order
+    pub fn signed_headers(&self) -> Vec<String> {
+        self.required_tag("h")
+            .split(':')
+            .map(|name| name.trim().to_ascii_lowercase())
+            .collect()
+    }
+
+    /// Decoded hash of the body (bh=), see [decode_base64_value]
+    pub fn body_hash_bytes(&self) -> Result<Vec<u8>, DKIMError> {
+        decode_base64_value(self.required_tag("bh")).map_err(|err| {
+            DKIMError::SignatureSyntaxError(format!("failed to decode body hash: {}", err))
+        })
+    }
+
+    /// Decoded signature (b=), see [decode_base64_value]
+    pub fn signature_bytes(&self) -> Result<Vec<u8>, DKIMError> {
+        decode_base64_value(self.required_tag("b")).map_err(|err| {
+            DKIMError::SignatureSyntaxError(format!("failed to decode signature: {}", err))
+        })
+    }
+
+    /// Returns the decoded header fields copied into the z= tag, or nothing if
+    /// the signature doesn't have one. Values that aren't UTF-8 are converted
+    /// lossily.
+    pub fn copied_headers(&self) -> Result<Vec<(String, String)>, DKIMError> {
+        match self.get_tag("z") {
+            Some(value) => parser::parse_copied_headers(&value),
+            None => Ok(vec![]),
+        }
+    }
+
+    /// Generate the header value from the tags, in their stored order. With
+    /// `folding`, lines are kept under 78 characters (including the header
+    /// name) where possible.
+    ///
+    /// Parsing the result gives back the same tag values, but not
+    /// necessarily the original bytes: use [DKIMHeader::with_empty_signature]
+    /// for the form covered by the signature.
+    pub fn to_header_value(&self, folding: bool) -> String {
+        if folding {
+            serialize_folded(self, DEFAULT_LINE_LENGTH, None)
+        } else {
+            serialize(self, None)
+        }
+    }
+
+    /// The header value as received with the value of the b= tag removed,
+    /// which is the form included in the header hash
+    /// <https://datatracker.ietf.org/doc/html/rfc6376#section-3.7>
+    pub fn with_empty_signature(&self) -> String {
+        let mut out = String::with_capacity(self.raw_bytes.len());
+        for spec in self.raw_bytes.split_inclusive(';') {
+            match spec.split_once('=') {
+                Some((name, value)) if name.trim() == "b" => {
+                    // Keep the whitespace around the value
+                    let trimmed = value.trim_start();
+                    let leading = &value[..value.len() - trimmed.len()];
+                    let trailing =
+                        &trimmed[trimmed.trim_end_matches([';', ' ', '\t', '\r', '\n']).len()..];
+                    out.push_str(name);
+                    out.push('=');
+                    out.push_str(leading);
+                    out.push_str(trailing);
+                }
+                _ => out.push_str(spec),
+            }
+        }
+        out
+    }
+}
+
+impl fmt::Display for DKIMHeader {
+    /// The unfolded header value, see [DKIMHeader::to_header_value]
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.to_header_value(false))
+    }
+}
+
+/// Layout of the base64 values of the b= and bh= tags
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Wrapping {
+    /// Keep the value on a single line
+    None,
+    /// Split the value in chunks of the given number of characters, each on
+    /// its own line
+    Columns(usize),
+}
+
+impl Wrapping {
+    /// Chunks of the base64 value
+    fn chunks(&self, value: &str) -> Vec<String> {
+        match self {
+            Wrapping::Columns(columns) if *columns > 0 && !value.is_empty() => value
+                .as_bytes()
+                .chunks(*columns)
+                .map(|chunk| String::from_utf8_lossy(chunk).into_owned())
+                .collect(),
+            _ => vec![value.to_owned()],
+        }
+    }
+}
+
+fn is_base64_tag(key: &str) -> bool {
+    key == "b" || key == "bh"
 }
 
 /// Generate the DKIM-Signature header from the tags
-fn serialize(header: DKIMHeader) -> String {
+fn serialize(header: &DKIMHeader, wrapping: Option<&Wrapping>) -> String {
     let mut out = "".to_owned();
 
     for (key, tag) in &header.tags {
-        out += &format!("{}={};", key, tag.value);
+        let value = match wrapping {
+            Some(wrapping) if is_base64_tag(key) => wrapping.chunks(&tag.value).join("\r\n "),
+            _ => tag.value.clone(),
+        };
+        out += &format!("{}={};", key, value);
         out += " ";
     }
 
@@ -40,23 +234,153 @@ fn serialize(header: DKIMHeader) -> String {
     out
 }
 
+/// Generate the DKIM-Signature header from the tags, folded with CRLF + WSP
+/// to keep the lines (including the header name) under `line_length`
+/// characters where possible.
+///
+/// Lines are broken between tags, after the `:` in h=, after the `|` in z=
+/// and inside the base64 of b= and bh=. A value is never folded right after
+/// its `=` or right before its `;`, which guarantees that removing the value
+/// of b= gives the same bytes as serializing with an empty b=.
+///
+/// With an explicit `wrapping`, b= and bh= always start on a new line and
+/// their values are laid out as specified instead.
+fn serialize_folded(
+    header: &DKIMHeader,
+    line_length: usize,
+    wrapping: Option<&Wrapping>,
+) -> String {
+    let mut out = "".to_owned();
+    // The value starts after "DKIM-Signature: "
+    let mut column = HEADER.len() + 2;
+
+    for (i, (key, tag)) in header.tags.iter().enumerate() {
+        if let Some(wrapping) = wrapping.filter(|_| is_base64_tag(key)) {
+            for (j, chunk) in wrapping.chunks(&tag.value).iter().enumerate() {
+                let piece = if j == 0 {
+                    format!("{}={}", key, chunk)
+                } else {
+                    chunk.to_owned()
+                };
+                if i > 0 || j > 0 {
+                    out += "\r\n ";
+                }
+                out += &piece;
+                column = 1 + piece.len();
+            }
+            out.push(';');
+            column += 1;
+            continue;
+        }
+
+        let mut pieces: Vec<String> = match key.as_str() {
+            "b" | "bh" => tag.value.chars().map(|c| c.to_string()).collect(),
+            "h" => tag.value.split_inclusive(':').map(str::to_owned).collect(),
+            "z" => tag.value.split_inclusive('|').map(str::to_owned).collect(),
+            _ => vec![tag.value.clone()],
+        };
+        if pieces.is_empty() {
+            pieces.push("".to_owned());
+        }
+        pieces[0] = format!("{}={}", key, pieces[0]);
+        if let Some(last) = pieces.last_mut() {
+            last.push(';');
+        }
+
+        // Prefer moving a whole tag to the next line over breaking it. This
+        // isn't done for b= since its value is empty when computing the
+        // signature.
+        let tag_length: usize = pieces.iter().map(|p| p.len()).sum();
+        let next_line = key != "b" && tag_length < line_length;
+
+        for (j, piece) in pieces.iter().enumerate() {
+            if j == 0 && i > 0 {
+                let length = if next_line { tag_length } else { piece.len() };
+                if column + 1 + length > line_length {
+                    out += "\r\n";
+                    column = 0;
+                }
+                out += " ";
+                column += 1;
+            } else if j > 0 && column + piece.len() > line_length {
+                out += "\r\n ";
+                column = 1;
+            }
+            out += piece;
+            column += piece.len();
+        }
+    }
+
+    out
+}
+
+/// Builder of DKIM-Signature headers, for example to assemble a signature
+/// computed by an external signer.
+///
+/// ```
+/// use cfdkim::canonicalization::Type;
+/// use cfdkim::{DKIMHeaderBuilder, HashAlgo};
+///
+/// let builder = DKIMHeaderBuilder::new()
+///     .set_algorithm(HashAlgo::RsaSha256)
+///     .set_domain("example.com")
+///     .set_selector("2022")
+///     .set_canonicalization(Type::Relaxed, Type::Simple)
+///     .set_signed_headers(&["From", "Subject"])
+///     .set_body_hash(&[0; 32]);
+///
+/// // Form of the header included in the header hash
+/// let unsigned = builder.build_unsigned().unwrap();
+/// assert!(unsigned.to_header_value(false).ends_with("; b=;"));
+///
+/// let signature = [1, 2, 3];
+/// let header = builder.set_signature(&signature).build().unwrap();
+/// assert_eq!(header.signature_bytes().unwrap(), signature);
+/// ```
 #[derive(Clone)]
-pub(crate) struct DKIMHeaderBuilder {
+pub struct DKIMHeaderBuilder {
     header: DKIMHeader,
     time: Option<chrono::DateTime<chrono::offset::Utc>>,
+    line_length: Option<usize>,
+    wrapping: Option<Wrapping>,
 }
+
+impl Default for DKIMHeaderBuilder {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl DKIMHeaderBuilder {
-    pub(crate) fn new() -> Self {
+    /// New builder with the v=1 tag
+    pub fn new() -> Self {
         DKIMHeaderBuilder {
             header: DKIMHeader {
                 tags: IndexMap::new(),
                 raw_bytes: "".to_owned(),
             },
             time: None,
+            line_length: None,
+            wrapping: None,
         }
+        .add_tag("v", "1")
     }
 
-    pub(crate) fn add_tag(mut self, name: &str, value: &str) -> Self {
+    /// Fold the generated header at the given line length
+    pub fn set_line_length(mut self, line_length: Option<usize>) -> Self {
+        self.line_length = line_length;
+        self
+    }
+
+    /// Lay out the values of b= and bh= as specified instead of following the
+    /// folding of the header
+    pub fn set_wrapping(mut self, wrapping: Option<Wrapping>) -> Self {
+        self.wrapping = wrapping;
+        self
+    }
+
+    /// Set a tag, replacing its previous value but keeping its position
+    pub fn add_tag(mut self, name: &str, value: &str) -> Self {
         let tag = parser::Tag {
             name: name.to_owned(),
             value: value.to_owned(),
@@ -67,28 +391,130 @@ impl DKIMHeaderBuilder {
         self
     }
 
-    pub(crate) fn set_signed_headers(self, headers: &[&str]) -> Self {
+    /// Signing algorithm (a=)
+    pub fn set_algorithm(self, algorithm: hash::HashAlgo) -> Self {
+        self.add_tag("a", algorithm.as_str())
+    }
+
+    /// Signing domain (d=)
+    pub fn set_domain(self, domain: &str) -> Self {
+        self.add_tag("d", domain)
+    }
+
+    /// Selector of the key (s=)
+    pub fn set_selector(self, selector: &str) -> Self {
+        self.add_tag("s", selector)
+    }
+
+    /// Header and body canonicalizations (c=)
+    pub fn set_canonicalization(
+        self,
+        header: canonicalization::Type,
+        body: canonicalization::Type,
+    ) -> Self {
+        self.add_tag("c", &format!("{}/{}", header, body))
+    }
+
+    /// Names of the signed header fields (h=)
+    pub fn set_signed_headers(self, headers: &[&str]) -> Self {
         let headers: Vec<String> = headers.iter().map(|h| h.to_lowercase()).collect();
         let value = headers.join(":");
         self.add_tag("h", &value)
     }
 
-    pub(crate) fn set_expiry(self, duration: chrono::Duration) -> Result<Self, DKIMError> {
+    /// Agent or user identifier (i=), encoded in dkim-quoted-printable
+    pub fn set_identity(self, identity: &str) -> Self {
+        self.add_tag(
+            "i",
+            &parser::encode_dkim_quoted_printable(identity.as_bytes()),
+        )
+    }
+
+    /// Query method of the key (q=)
+    pub fn set_query_method(self, method: &str) -> Self {
+        self.add_tag("q", method)
+    }
+
+    /// Number of bytes of the canonicalized body covered by the signature (l=)
+    pub fn set_body_length(self, length: u64) -> Self {
+        self.add_tag("l", &length.to_string())
+    }
+
+    /// Header fields copied into the z= tag, as (name, value) pairs
+    pub fn set_copied_headers(self, headers: &[(&str, &[u8])]) -> Self {
+        let copies: Vec<String> = headers
+            .iter()
+            .map(|(name, value)| {
+                format!("{}:{}", name, parser::encode_dkim_quoted_printable(value))
+            })
+            .collect();
+        self.add_tag("z", &copies.join("|"))
+    }
+
+    /// Hash of the canonicalized body (bh=)
+    pub fn set_body_hash(self, hash: &[u8]) -> Self {
+        self.add_tag("bh", &general_purpose::STANDARD.encode(hash))
+    }
+
+    /// Signature (b=)
+    pub fn set_signature(self, signature: &[u8]) -> Self {
+        self.add_tag("b", &general_purpose::STANDARD.encode(signature))
+    }
+
+    /// Expiration (x=) at the given duration after the time set by
+    /// [DKIMHeaderBuilder::set_time] or
+    /// [DKIMHeaderBuilder::set_reference_time]
+    pub fn set_expiry(self, duration: chrono::Duration) -> Result<Self, DKIMError> {
         let time = self
             .time
             .ok_or(DKIMError::BuilderError("missing require time"))?;
         let expiry = (time + duration).timestamp();
-        Ok(self.add_tag("x", &expiry.to_string()))
+        Ok(self.set_expiration(expiry))
+    }
+
+    /// Expiration (x=), in seconds since the epoch
+    pub fn set_expiration(self, timestamp: i64) -> Self {
+        self.add_tag("x", &timestamp.to_string())
     }
 
-    pub(crate) fn set_time(mut self, time: chrono::DateTime<chrono::offset::Utc>) -> Self {
+    /// Signature timestamp (t=)
+    pub fn set_time(self, time: chrono::DateTime<chrono::offset::Utc>) -> Self {
+        self.set_reference_time(time)
+            .add_tag("t", &time.timestamp().to_string())
+    }
+
+    /// Set the time used to compute the expiry without emitting the t= tag
+    pub fn set_reference_time(mut self, time: chrono::DateTime<chrono::offset::Utc>) -> Self {
         self.time = Some(time);
-        self.add_tag("t", &time.timestamp().to_string())
+        self
+    }
+
+    /// Generate the header, failing if a required tag is missing
+    pub fn build(self) -> Result<DKIMHeader, DKIMError> {
+        if let Some(name) = REQUIRED_TAGS
+            .iter()
+            .find(|name| !self.header.tags.contains_key(**name))
+        {
+            return Err(DKIMError::SignatureMissingRequiredTag(name));
+        }
+        Ok(self.render())
     }
 
-    pub(crate) fn build(mut self) -> Result<DKIMHeader, DKIMError> {
-        self.header.raw_bytes = serialize(self.header.clone());
-        Ok(self.header)
+    /// Generate the header with an empty b= tag, whose
+    /// [DKIMHeader::with_empty_signature] is the form included in the
+    /// header hash
+    pub fn build_unsigned(&self) -> Result<DKIMHeader, DKIMError> {
+        self.clone().add_tag("b", "").build()
+    }
+
+    fn render(mut self) -> DKIMHeader {
+        self.header.raw_bytes = match self.line_length {
+            Some(line_length) => {
+                serialize_folded(&self.header, line_length, self.wrapping.as_ref())
+            }
+            None => serialize(&self.header, self.wrapping.as_ref()),
+        };
+        self.header
     }
 }
 
@@ -101,8 +527,7 @@ mod tests {
         let header = DKIMHeaderBuilder::new()
             .add_tag("v", "1")
             .add_tag("a", "something")
-            .build()
-            .unwrap();
+            .render();
         assert_eq!(header.raw_bytes, "v=1; a=something;".to_owned());
     }
 
@@ -111,8 +536,7 @@ mod tests {
         let header = DKIMHeaderBuilder::new()
             .add_tag("v", "2")
             .set_signed_headers(&["header1", "header2", "header3"])
-            .build()
-            .unwrap();
+            .render();
         assert_eq!(
             header.raw_bytes,
             "v=2; h=header1:header2:header3;".to_owned()
@@ -129,8 +553,237 @@ mod tests {
             .set_time(time)
             .set_expiry(chrono::Duration::hours(3))
             .unwrap()
-            .build()
-            .unwrap();
-        assert_eq!(header.raw_bytes, "t=1609459201; x=1609470001;".to_owned());
+            .render();
+        assert_eq!(
+            header.raw_bytes,
+            "v=1; t=1609459201; x=1609470001;".to_owned()
+        );
+    }
+
+    #[test]
+    fn test_dkim_header_accessors() {
+        use canonicalization::Type::{Relaxed, Simple};
+
+        let header = crate::validate_header_with_options(
+            "v=1; a=ed25519-sha256; d=example.net; s=brisbane; c=relaxed; l=42;\r\n t=1528637909; x=1528637999; h=From : To:\r\n SUBJECT; bh=2jUSOH9NhtVGCQWNr9BrIAPre\r\n KQjO6Sn7XIkfJVOzv8=; b=YWJj\r\n ZA==",
+            &crate::ValidationOptions {
+                check_expiration: false,
+                ..Default::default()
+            },
+        )
+        .unwrap();
+        assert_eq!(header.domain(), "example.net");
+        assert_eq!(header.selector(), "brisbane");
+        assert_eq!(header.timestamp(), Some(1528637909));
+        assert_eq!(header.expiration(), Some(1528637999));
+        assert_eq!(header.body_length(), Ok(Some(42)));
+        assert_eq!(header.algorithm(), Ok(hash::HashAlgo::Ed25519Sha256));
+        assert_eq!(header.canonicalization(), Ok((Relaxed, Simple)));
+        assert_eq!(header.signed_headers(), vec!["from", "to", "subject"]);
+        assert_eq!(header.body_hash_bytes().unwrap().len(), 32);
+        assert_eq!(header.signature_bytes(), Ok(b"abcd".to_vec()));
+
+        let header = crate::validate_header(
+            "v=1; a=rsa-sha512; d=example.net; s=brisbane; c=fancy; l=-1; h=from; bh=!; b=",
+        )
+        .unwrap();
+        assert_eq!(header.timestamp(), None);
+        assert!(header.body_length().is_err());
+        assert!(header.algorithm().is_err());
+        assert!(header.canonicalization().is_err());
+        assert!(header.body_hash_bytes().is_err());
+        assert_eq!(header.signature_bytes(), Ok(vec![]));
+    }
+
+    #[test]
+    fn test_dkim_header_to_header_value() {
+        let value = "v=1; a=rsa-sha256; d=example.net; s=brisbane;\r\n c=relaxed/simple; q=dns/txt; t=1117574938;\r\n h=from : to:subject; z=From:foo=20bar|To:a@b;\r\n bh=MTIzNDU2Nzg5MDEyMzQ1Njc4OTAxMjM0NTY3ODkwMTI=; b=dzdVyOfAKCdLX\r\n dJOc9G2q8LoX ;";
+        let header = crate::validate_header(value).unwrap();
+
+        assert_eq!(
+            header.to_string(),
+            "v=1; a=rsa-sha256; d=example.net; s=brisbane; c=relaxed/simple; q=dns/txt; t=1117574938; h=from:to:subject; z=From:foo=20bar|To:a@b; bh=MTIzNDU2Nzg5MDEyMzQ1Njc4OTAxMjM0NTY3ODkwMTI=; b=dzdVyOfAKCdLXdJOc9G2q8LoX;"
+        );
+        for folding in [false, true] {
+            let serialized = header.to_header_value(folding);
+            let parsed = crate::validate_header(&serialized).unwrap();
+            assert_eq!(parsed.tags.len(), header.tags.len());
+            for (name, tag) in &header.tags {
+                assert_eq!(parsed.get_tag(name).as_ref(), Some(&tag.value));
+            }
+        }
+        for line in format!("{}: {}", HEADER, header.to_header_value(true)).split("\r\n") {
+            assert!(line.len() <= DEFAULT_LINE_LENGTH);
+        }
+
+        assert_eq!(
+            header.with_empty_signature(),
+            "v=1; a=rsa-sha256; d=example.net; s=brisbane;\r\n c=relaxed/simple; q=dns/txt; t=1117574938;\r\n h=from : to:subject; z=From:foo=20bar|To:a@b;\r\n bh=MTIzNDU2Nzg5MDEyMzQ1Njc4OTAxMjM0NTY3ODkwMTI=; b= ;"
+        );
+        // Only the b= tag is emptied, even if another one has the same value
+        let header =
+            crate::validate_header("v=1; a=rsa-sha256; d=a; s=b; h=from; bh=YQ==; b=YQ==").unwrap();
+        assert_eq!(
+            header.with_empty_signature(),
+            "v=1; a=rsa-sha256; d=a; s=b; h=from; bh=YQ==; b="
+        );
+    }
+
+    #[test]
+    fn test_dkim_header_builder_setters() {
+        use chrono::TimeZone;
+        use hash::HashAlgo;
+
+        let time = chrono::Utc.with_ymd_and_hms(2021, 1, 1, 0, 0, 1).unwrap();
+        let builder = DKIMHeaderBuilder::new()
+            .set_algorithm(HashAlgo::Ed25519Sha256)
+            .set_domain("example.com")
+            .set_selector("s1")
+            .set_canonicalization(
+                canonicalization::Type::Relaxed,
+                canonicalization::Type::Simple,
+            )
+            .set_signed_headers(&["From", "To"])
+            .set_identity("joe; jr@example.com")
+            .set_query_method("dns/txt")
+            .set_body_length(10)
+            .set_time(time)
+            .set_expiry(chrono::Duration::hours(1))
+            .unwrap()
+            .set_copied_headers(&[("From", b"joe@example.com"), ("To", b"a|b")])
+            .set_body_hash(b"hash");
+
+        let unsigned = builder.build_unsigned().unwrap();
+        assert_eq!(
+            unsigned.raw_bytes,
+            "v=1; a=ed25519-sha256; d=example.com; s=s1; c=relaxed/simple; h=from:to; i=joe=3B=20jr@example.com; q=dns/txt; l=10; t=1609459201; x=1609462801; z=From:joe@example.com|To:a=7Cb; bh=aGFzaA==; b=;"
+        );
+        assert_eq!(unsigned.with_empty_signature(), unsigned.raw_bytes);
+
+        let header = builder.clone().set_signature(b"signature").build().unwrap();
+        let parsed = crate::validate_header_with_options(
+            &header.raw_bytes,
+            &crate::ValidationOptions {
+                check_expiration: false,
+                ..Default::default()
+            },
+        )
+        .unwrap();
+        assert_eq!(parsed.algorithm(), Ok(HashAlgo::Ed25519Sha256));
+        assert_eq!(parsed.body_length(), Ok(Some(10)));
+        assert_eq!(parsed.body_hash_bytes(), Ok(b"hash".to_vec()));
+        assert_eq!(parsed.signature_bytes(), Ok(b"signature".to_vec()));
+        assert_eq!(
+            parsed.copied_headers().unwrap()[1],
+            ("To".to_owned(), "a|b".to_owned())
+        );
+        assert_eq!(parsed.with_empty_signature(), unsigned.raw_bytes);
+
+        assert_eq!(
+            builder.build().unwrap_err(),
+            DKIMError::SignatureMissingRequiredTag("b")
+        );
+        assert_eq!(
+            DKIMHeaderBuilder::new()
+                .set_domain("example.com")
+                .build_unsigned()
+                .unwrap_err(),
+            DKIMError::SignatureMissingRequiredTag("a")
+        );
+    }
+
+    #[test]
+    fn test_dkim_header_builder_folded() {
+        let builder = DKIMHeaderBuilder::new()
+            .add_tag("v", "1")
+            .add_tag("a", "rsa-sha256")
+            .add_tag("d", "example.com")
+            .add_tag("s", "selector")
+            .add_tag("c", "simple/simple")
+            .set_signed_headers(&[
+                "From",
+                "To",
+                "Subject",
+                "Date",
+                "Message-ID",
+                "MIME-Version",
+                "Content-Type",
+            ])
+            .add_tag("bh", "2jUSOH9NhtVGCQWNr9BrIAPreKQjO6Sn7XIkfJVOzv8=")
+            .set_line_length(Some(78));
+        let signature =
+            "dzdVyOfAKCdLXdJOc9G2q8LoXSlEniSbav+yuU4zGeeruD00lszZVoG4ZHRNiYzR".repeat(4);
+
+        let header = builder.clone().add_tag("b", &signature).render();
+        assert_eq!(
+            format!("{}: {}", HEADER, header.raw_bytes),
+            "DKIM-Signature: v=1; a=rsa-sha256; d=example.com; s=selector; c=simple/simple;\r\n h=from:to:subject:date:message-id:mime-version:content-type;\r\n bh=2jUSOH9NhtVGCQWNr9BrIAPreKQjO6Sn7XIkfJVOzv8=; b=dzdVyOfAKCdLXdJOc9G2q8LoXS\r\n lEniSbav+yuU4zGeeruD00lszZVoG4ZHRNiYzRdzdVyOfAKCdLXdJOc9G2q8LoXSlEniSbav+yuU4\r\n zGeeruD00lszZVoG4ZHRNiYzRdzdVyOfAKCdLXdJOc9G2q8LoXSlEniSbav+yuU4zGeeruD00lszZ\r\n VoG4ZHRNiYzRdzdVyOfAKCdLXdJOc9G2q8LoXSlEniSbav+yuU4zGeeruD00lszZVoG4ZHRNiYzR;"
+        );
+        for line in format!("{}: {}", HEADER, header.raw_bytes).split("\r\n") {
+            assert!(line.len() <= 78);
+        }
+
+        // Parsing the folded header gives back the same values
+        let parsed = crate::validate_header(&header.raw_bytes).unwrap();
+        assert_eq!(parsed.get_required_tag("b"), signature);
+        assert_eq!(
+            parsed.get_required_tag("h"),
+            "from:to:subject:date:message-id:mime-version:content-type"
+        );
+
+        // Removing the signature gives the form used to compute it
+        let unsigned = builder.add_tag("b", "").render();
+        assert_eq!(
+            header
+                .raw_bytes
+                .replace(&parsed.get_raw_tag("b").unwrap(), ""),
+            unsigned.raw_bytes
+        );
+    }
+
+    #[test]
+    fn test_dkim_header_builder_wrapping() {
+        let builder = DKIMHeaderBuilder::new()
+            .add_tag("v", "1")
+            .add_tag("a", "rsa-sha256")
+            .add_tag("bh", "2jUSOH9NhtVGCQWNr9BrIAPreKQjO6Sn7XIkfJVOzv8=")
+            .set_wrapping(Some(Wrapping::Columns(20)));
+        let signature = "dzdVyOfAKCdLXdJOc9G2q8LoXSlEniSbav+yuU4zGeeruD00lszZVoG4ZHRNiYzR";
+
+        let header = builder.clone().add_tag("b", signature).render();
+        assert_eq!(
+            header.raw_bytes,
+            "v=1; a=rsa-sha256; bh=2jUSOH9NhtVGCQWNr9Br\r\n IAPreKQjO6Sn7XIkfJVO\r\n zv8=; b=dzdVyOfAKCdLXdJOc9G2\r\n q8LoXSlEniSbav+yuU4z\r\n GeeruD00lszZVoG4ZHRN\r\n iYzR;"
+        );
+
+        let folded = builder.clone().set_line_length(Some(78));
+        let header = folded.clone().add_tag("b", signature).render();
+        assert_eq!(
+            header.raw_bytes,
+            "v=1; a=rsa-sha256;\r\n bh=2jUSOH9NhtVGCQWNr9Br\r\n IAPreKQjO6Sn7XIkfJVO\r\n zv8=;\r\n b=dzdVyOfAKCdLXdJOc9G2\r\n q8LoXSlEniSbav+yuU4z\r\n GeeruD00lszZVoG4ZHRN\r\n iYzR;"
+        );
+
+        // Removing the signature gives the form used to compute it
+        let tags = parser::tag_list(&header.raw_bytes).unwrap();
+        let b = tags.iter().find(|tag| tag.name == "b").unwrap();
+        assert_eq!(b.value, signature);
+        let unsigned = folded.add_tag("b", "").render();
+        assert_eq!(
+            header.raw_bytes.replace(&b.raw_value, ""),
+            unsigned.raw_bytes
+        );
+
+        let header = builder
+            .set_wrapping(Some(Wrapping::None))
+            .set_line_length(Some(40))
+            .add_tag("b", signature)
+            .render();
+        assert_eq!(
+            header.raw_bytes,
+            format!(
+                "v=1; a=rsa-sha256;\r\n bh=2jUSOH9NhtVGCQWNr9BrIAPreKQjO6Sn7XIkfJVOzv8=;\r\n b={};",
+                signature
+            )
+        );
     }
 }
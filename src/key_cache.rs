@@ -0,0 +1,191 @@
+//! Caching the parsed keys of the signing domains
+
+use crate::public_key::{self, DkimKeyRecord, RetrievedKey};
+use crate::{dns, Clock, DKIMError, DkimPublicKey, SystemClock};
+use std::collections::HashMap;
+use std::fmt;
+use std::sync::{Arc, Mutex};
+
+const DEFAULT_TTL: chrono::Duration = chrono::Duration::hours(1);
+
+/// Keys retrieved for a domain and selector, with their parsed record, kept
+/// so that the verifications of the messages of the same senders don't look
+/// them up and parse them again.
+///
+/// Share it between tasks with an `Arc`, see
+/// [VerificationOptions::key_cache](crate::VerificationOptions::key_cache).
+/// Failed lookups aren't cached, and the keys retrieved with different
+/// [max_cname_chain](crate::VerificationOptions::max_cname_chain) or
+/// [lenient_key_records](crate::VerificationOptions::lenient_key_records)
+/// options are kept apart.
+pub struct KeyCache {
+    capacity: usize,
+    ttl: chrono::Duration,
+    clock: Arc<dyn Clock>,
+    entries: Mutex<HashMap<CacheKey, Entry>>,
+}
+
+/// Domain and selector of a key, with the options it was retrieved with
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+struct CacheKey {
+    domain: String,
+    selector: String,
+    max_cname_chain: usize,
+    lenient: bool,
+}
+
+struct Entry {
+    key: RetrievedKey,
+    expires_at: chrono::NaiveDateTime,
+}
+
+impl fmt::Debug for KeyCache {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("KeyCache")
+            .field("capacity", &self.capacity)
+            .field("ttl", &self.ttl)
+            .field("len", &self.len())
+            .finish()
+    }
+}
+
+impl CacheKey {
+    fn new(domain: &str, selector: &str, max_cname_chain: usize, lenient: bool) -> Self {
+        Self {
+            domain: crate::normalize_domain(domain),
+            selector: crate::normalize_domain(selector),
+            max_cname_chain,
+            lenient,
+        }
+    }
+}
+
+impl KeyCache {
+    /// New cache keeping up to `capacity` keys for an hour
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            capacity: capacity.max(1),
+            ttl: DEFAULT_TTL,
+            clock: Arc::new(SystemClock),
+            entries: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Keep the keys for this long
+    pub fn with_ttl(mut self, ttl: chrono::Duration) -> Self {
+        self.ttl = ttl;
+        self
+    }
+
+    /// Clock used to expire the keys. Defaults to the system clock.
+    pub fn with_clock(mut self, clock: Arc<dyn Clock>) -> Self {
+        self.clock = clock;
+        self
+    }
+
+    /// Drop the key of a domain and selector, after its rotation
+    pub fn invalidate(&self, domain: &str, selector: &str) {
+        let invalidated = CacheKey::new(domain, selector, 0, false);
+        self.entries.lock().unwrap().retain(|key, _| {
+            key.domain != invalidated.domain || key.selector != invalidated.selector
+        });
+    }
+
+    /// Drop all the keys
+    pub fn clear(&self) {
+        self.entries.lock().unwrap().clear();
+    }
+
+    /// Number of keys kept, including the expired ones not evicted yet
+    pub fn len(&self) -> usize {
+        self.entries.lock().unwrap().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Same as [public_key::retrieve_public_key], answering from the cache
+    /// when possible
+    pub async fn retrieve_public_key(
+        &self,
+        resolver: Arc<dyn dns::Lookup>,
+        domain: &str,
+        selector: &str,
+    ) -> Result<(DkimPublicKey, DkimKeyRecord), DKIMError> {
+        let retrieved = self
+            .get_or_retrieve(domain, selector, dns::DEFAULT_MAX_CNAME_CHAIN, true, || {
+                public_key::retrieve_key(
+                    resolver,
+                    domain,
+                    selector,
+                    dns::DEFAULT_MAX_CNAME_CHAIN,
+                    true,
+                )
+            })
+            .await?;
+        Ok((retrieved.key, retrieved.record))
+    }
+
+    /// Key of the domain and selector from the cache, or else from
+    /// `retrieve` following up to `max_cname_chain` CNAMEs and parsing the
+    /// record leniently or not
+    pub(crate) async fn get_or_retrieve<F, Fut>(
+        &self,
+        domain: &str,
+        selector: &str,
+        max_cname_chain: usize,
+        lenient: bool,
+        retrieve: F,
+    ) -> Result<RetrievedKey, DKIMError>
+    where
+        F: FnOnce() -> Fut,
+        Fut: std::future::Future<Output = Result<RetrievedKey, DKIMError>>,
+    {
+        let key = CacheKey::new(domain, selector, max_cname_chain, lenient);
+        if let Some(retrieved) = self.get(&key) {
+            return Ok(retrieved);
+        }
+        let retrieved = retrieve().await?;
+        self.insert(key, retrieved.clone());
+        Ok(retrieved)
+    }
+
+    fn get(&self, key: &CacheKey) -> Option<RetrievedKey> {
+        let now = self.clock.now();
+        let mut entries = self.entries.lock().unwrap();
+        match entries.get(key) {
+            Some(entry) if entry.expires_at > now => Some(entry.key.clone()),
+            Some(_) => {
+                entries.remove(key);
+                None
+            }
+            None => None,
+        }
+    }
+
+    fn insert(&self, key: CacheKey, retrieved: RetrievedKey) {
+        let now = self.clock.now();
+        let mut entries = self.entries.lock().unwrap();
+        if entries.len() >= self.capacity {
+            entries.retain(|_, entry| entry.expires_at > now);
+        }
+        if entries.len() >= self.capacity {
+            // Evict the key expiring first
+            if let Some(oldest) = entries
+                .iter()
+                .min_by_key(|(_, entry)| entry.expires_at)
+                .map(|(key, _)| key.clone())
+            {
+                entries.remove(&oldest);
+            }
+        }
+        entries.insert(
+            key,
+            Entry {
+                key: retrieved,
+                expires_at: now + self.ttl,
+            },
+        );
+    }
+}
@@ -0,0 +1,154 @@
+//! Size limits of the verified messages
+
+use crate::DKIMError;
+
+/// Sizes above which a message isn't verified, checked on its raw bytes
+/// before it is parsed or canonicalized, see
+/// [VerificationOptions::limits](crate::VerificationOptions::limits).
+///
+/// Each limit fails with [DKIMError::LimitExceeded] naming it, `None`
+/// disables it.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Limits {
+    /// Maximum size of the whole message, 100 MB by default
+    pub max_message_size: Option<usize>,
+    /// Maximum size of a single header field, its folding and line endings
+    /// included, 64 KB by default
+    pub max_header_length: Option<usize>,
+    /// Maximum size of the header block, up to the empty line ending it,
+    /// 1 MB by default
+    pub max_header_block_size: Option<usize>,
+    /// Maximum number of header fields, 1000 by default
+    pub max_headers: Option<usize>,
+}
+
+impl Default for Limits {
+    fn default() -> Self {
+        Self {
+            max_message_size: Some(100 * 1024 * 1024),
+            max_header_length: Some(64 * 1024),
+            max_header_block_size: Some(1024 * 1024),
+            max_headers: Some(1000),
+        }
+    }
+}
+
+/// Names of the limits, as in [DKIMError::LimitExceeded]
+pub(crate) const LIMIT_NAMES: &[&str] = &[
+    "max_message_size",
+    "max_header_length",
+    "max_header_block_size",
+    "max_headers",
+];
+
+fn check(limit: &'static str, max: Option<usize>, actual: usize) -> Result<(), DKIMError> {
+    debug_assert!(LIMIT_NAMES.contains(&limit));
+    match max {
+        Some(max) if actual > max => Err(DKIMError::LimitExceeded { limit, max, actual }),
+        _ => Ok(()),
+    }
+}
+
+impl Limits {
+    /// No limit at all
+    pub fn unlimited() -> Self {
+        Self {
+            max_message_size: None,
+            max_header_length: None,
+            max_header_block_size: None,
+            max_headers: None,
+        }
+    }
+
+    /// Check the raw bytes of a message against the limits, scanning its
+    /// header block line by line up to the first limit exceeded
+    pub fn check(&self, email_bytes: &[u8]) -> Result<(), DKIMError> {
+        check("max_message_size", self.max_message_size, email_bytes.len())?;
+
+        let mut block_size = 0;
+        let mut headers = 0;
+        let mut header_length = 0;
+        for line in email_bytes.split_inclusive(|&byte| byte == b'\n') {
+            if line == b"\n" || line == b"\r\n" {
+                break;
+            }
+            block_size += line.len();
+            check(
+                "max_header_block_size",
+                self.max_header_block_size,
+                block_size,
+            )?;
+            if line.starts_with(b" ") || line.starts_with(b"\t") {
+                header_length += line.len();
+            } else {
+                headers += 1;
+                check("max_headers", self.max_headers, headers)?;
+                header_length = line.len();
+            }
+            check("max_header_length", self.max_header_length, header_length)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_limits() {
+        let email = b"From: a@example.com\r\nSubject: A\r\n  folded\r\n\r\nBody\r\n";
+        let limits = Limits {
+            max_message_size: Some(email.len()),
+            max_header_length: Some(22),
+            max_header_block_size: Some(43),
+            max_headers: Some(2),
+        };
+        assert_eq!(limits.check(email), Ok(()));
+        assert_eq!(Limits::default().check(email), Ok(()));
+        assert_eq!(Limits::unlimited().check(email), Ok(()));
+
+        let exceeded = |limits: Limits| match limits.check(email).unwrap_err() {
+            DKIMError::LimitExceeded { limit, max, actual } => (limit, max, actual),
+            err => panic!("unexpected error: {}", err),
+        };
+        assert_eq!(
+            exceeded(Limits {
+                max_message_size: Some(10),
+                ..limits.clone()
+            }),
+            ("max_message_size", 10, email.len())
+        );
+        // The folded Subject header counts its continuation line
+        assert_eq!(
+            exceeded(Limits {
+                max_header_length: Some(21),
+                ..limits.clone()
+            }),
+            ("max_header_length", 21, 22)
+        );
+        assert_eq!(
+            exceeded(Limits {
+                max_header_block_size: Some(42),
+                ..limits.clone()
+            }),
+            ("max_header_block_size", 42, 43)
+        );
+        assert_eq!(
+            exceeded(Limits {
+                max_headers: Some(1),
+                ..limits
+            }),
+            ("max_headers", 1, 2)
+        );
+
+        // The body isn't part of the header block
+        let body = format!("From: a@example.com\n\n{}\n", "a".repeat(100));
+        let limits = Limits {
+            max_header_length: Some(20),
+            max_header_block_size: Some(20),
+            ..Limits::default()
+        };
+        assert_eq!(limits.check(body.as_bytes()), Ok(()));
+    }
+}
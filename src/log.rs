@@ -0,0 +1,118 @@
+//! Logging of the verification and signing steps through the optional
+//! backends: a slog logger with the `slog` feature and the `tracing` crate
+//! with the `tracing` feature. Without them nothing is logged.
+
+use std::fmt;
+
+/// Destination of the debug messages of a verification or signature, given
+/// with [VerificationOptions::logger](crate::VerificationOptions::logger) or
+/// [SignerBuilder::with_logger](crate::SignerBuilder::with_logger).
+///
+/// The default one only logs through `tracing` when the feature is enabled.
+/// With the `slog` feature, a `slog::Logger` converts into one.
+#[derive(Clone, Default)]
+pub struct Logger {
+    #[cfg(feature = "slog")]
+    pub(crate) slog: Option<slog::Logger>,
+}
+
+impl fmt::Debug for Logger {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut backends = vec![];
+        #[cfg(feature = "slog")]
+        if self.slog.is_some() {
+            backends.push("slog");
+        }
+        if cfg!(feature = "tracing") {
+            backends.push("tracing");
+        }
+        f.debug_tuple("Logger").field(&backends).finish()
+    }
+}
+
+#[cfg(feature = "slog")]
+impl From<slog::Logger> for Logger {
+    fn from(logger: slog::Logger) -> Self {
+        Self { slog: Some(logger) }
+    }
+}
+
+#[cfg(feature = "slog")]
+impl From<&slog::Logger> for Logger {
+    fn from(logger: &slog::Logger) -> Self {
+        Self::from(logger.clone())
+    }
+}
+
+macro_rules! log {
+    ($level:ident, $logger:expr, $($arg:tt)+) => {{
+        let _logger: &$crate::log::Logger = $logger;
+        #[cfg(feature = "slog")]
+        if let Some(logger) = &_logger.slog {
+            slog::$level!(logger, $($arg)+);
+        }
+        #[cfg(feature = "tracing")]
+        tracing::$level!($($arg)+);
+        // Arguments only used by the messages
+        #[cfg(not(any(feature = "slog", feature = "tracing")))]
+        let _ = format_args!($($arg)+);
+    }};
+}
+
+macro_rules! debug {
+    ($logger:expr, $($arg:tt)+) => {
+        log!(debug, $logger, $($arg)+)
+    };
+}
+
+macro_rules! warn {
+    ($logger:expr, $($arg:tt)+) => {
+        log!(warn, $logger, $($arg)+)
+    };
+}
+
+#[cfg(all(test, feature = "slog"))]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_slog_logger() {
+        use std::sync::{Arc, Mutex};
+
+        /// Drain keeping the formatted messages
+        struct Messages(Arc<Mutex<Vec<String>>>);
+
+        impl slog::Drain for Messages {
+            type Ok = ();
+            type Err = slog::Never;
+
+            fn log(
+                &self,
+                record: &slog::Record,
+                _: &slog::OwnedKVList,
+            ) -> Result<Self::Ok, Self::Err> {
+                self.0
+                    .lock()
+                    .unwrap()
+                    .push(format!("{} {}", record.level(), record.msg()));
+                Ok(())
+            }
+        }
+
+        let messages = Arc::new(Mutex::new(vec![]));
+        let logger = Logger::from(slog::Logger::root(
+            Messages(Arc::clone(&messages)),
+            slog::o!(),
+        ));
+        debug!(&logger, "checking {:?}", "s1");
+        warn!(&logger, "invalid key record: {}", "syntax error");
+        debug!(&Logger::default(), "not logged");
+        assert_eq!(
+            *messages.lock().unwrap(),
+            [
+                "DEBG checking \"s1\"",
+                "WARN invalid key record: syntax error"
+            ]
+        );
+    }
+}
@@ -0,0 +1,128 @@
+//! Splitting mbox files into messages, for [verify_batch](crate::verify_batch)
+
+use std::io::{self, BufRead};
+
+/// Messages of an mbox file, read one at a time, see [messages]
+pub struct Messages<R> {
+    reader: R,
+    /// Whether the next From_ line starts a message: at the start of the
+    /// file or after an empty line
+    separator: bool,
+    done: bool,
+}
+
+/// Iterate over the messages of an mbox file. Each message starts with a
+/// From_ line (`From sender date`) at the start of the file or after an empty
+/// line, which isn't part of the message, and the empty line before the next
+/// one is dropped. Lines quoted as in mboxrd (`>From `) are unquoted and the
+/// line endings are converted to CRLF, as signed. Lines before the first
+/// From_ line are returned as a message.
+pub fn messages<R: BufRead>(reader: R) -> Messages<R> {
+    Messages {
+        reader,
+        separator: true,
+        done: false,
+    }
+}
+
+impl<R: BufRead> Iterator for Messages<R> {
+    type Item = io::Result<Vec<u8>>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let mut message = vec![];
+        let mut line = vec![];
+        let mut empty_line = false;
+        while !self.done {
+            line.clear();
+            match self.reader.read_until(b'\n', &mut line) {
+                Ok(0) => self.done = true,
+                Ok(_) => {}
+                Err(err) => {
+                    self.done = true;
+                    return Some(Err(err));
+                }
+            }
+            if self.done {
+                break;
+            }
+
+            let has_newline = line.ends_with(b"\n");
+            let mut content = line.strip_suffix(b"\n").unwrap_or(&line);
+            content = content.strip_suffix(b"\r").unwrap_or(content);
+
+            if self.separator && content.starts_with(b"From ") {
+                self.separator = false;
+                if message.is_empty() {
+                    continue;
+                }
+                if empty_line {
+                    message.truncate(message.len() - 2);
+                }
+                return Some(Ok(message));
+            }
+
+            empty_line = content.is_empty();
+            self.separator = empty_line;
+            if is_quoted_from(content) {
+                content = &content[1..];
+            }
+            message.extend_from_slice(content);
+            if has_newline {
+                message.extend_from_slice(b"\r\n");
+            }
+        }
+
+        if message.is_empty() {
+            None
+        } else {
+            Some(Ok(message))
+        }
+    }
+}
+
+/// Whether the line is `>From `, `>>From `, ...
+fn is_quoted_from(line: &[u8]) -> bool {
+    let unquoted = line.iter().position(|&byte| byte != b'>').unwrap_or(0);
+    unquoted > 0 && line[unquoted..].starts_with(b"From ")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn split(mbox: &str) -> Vec<String> {
+        messages(mbox.as_bytes())
+            .map(|message| String::from_utf8(message.unwrap()).unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn test_messages() {
+        let mbox = "From alice@example.com Thu Jan  1 00:00:00 2021\n\
+                    From: alice@example.com\n\
+                    \n\
+                    Hello\n\
+                    >From the start\n\
+                    >>From quoted\n\
+                    From inside a paragraph\n\
+                    \n\
+                    From bob@example.com Thu Jan  1 00:00:00 2021\r\n\
+                    From: bob@example.com\r\n\
+                    \r\n\
+                    Hi\r\n";
+        assert_eq!(
+            split(mbox),
+            [
+                "From: alice@example.com\r\n\r\nHello\r\nFrom the start\r\n>From quoted\r\n\
+                 From inside a paragraph\r\n",
+                "From: bob@example.com\r\n\r\nHi\r\n",
+            ]
+        );
+
+        assert_eq!(
+            split("From: alice@example.com\n\nHello"),
+            ["From: alice@example.com\r\n\r\nHello"]
+        );
+        assert!(split("").is_empty());
+    }
+}
@@ -1,14 +1,15 @@
 use crate::{canonicalization, hash, DKIMError};
-use nom::bytes::complete::tag;
+use nom::bytes::complete::take_while;
 use nom::bytes::complete::take_while1;
 use nom::character::complete::alpha1;
 use nom::combinator::opt;
+use nom::combinator::recognize;
 use nom::multi::fold_many0;
 use nom::sequence::delimited;
 use nom::sequence::pair;
 use nom::sequence::preceded;
-use nom::sequence::terminated;
 use nom::IResult;
+use std::fmt;
 
 #[derive(Clone, Debug, PartialEq)]
 /// DKIM signature tag
@@ -21,37 +22,98 @@ pub struct Tag {
     pub raw_value: String,
 }
 
+/// Number of bytes of the input shown in a [TagListError]
+const SNIPPET_LENGTH: usize = 40;
+
+/// Syntax error in a tag-list
+#[derive(Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+pub struct TagListError {
+    /// Byte offset of the error in the input
+    pub offset: usize,
+    /// Input starting at the offset, truncated to 40 bytes
+    pub snippet: String,
+    /// What was expected at the offset
+    pub description: &'static str,
+}
+
+impl TagListError {
+    fn new(input: &str, rest: &str, description: &'static str) -> Self {
+        let mut end = rest.len().min(SNIPPET_LENGTH);
+        while !rest.is_char_boundary(end) {
+            end -= 1;
+        }
+        Self {
+            offset: input.len() - rest.len(),
+            snippet: rest[..end].to_owned(),
+            description,
+        }
+    }
+}
+
+impl fmt::Display for TagListError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} at offset {} ({:?})",
+            self.description, self.offset, self.snippet
+        )
+    }
+}
+
+impl std::error::Error for TagListError {}
+
 /// Main entrypoint of the parser. Parses the DKIM signature tag list
 /// as specified <https://datatracker.ietf.org/doc/html/rfc6376#section-3.6.1>.
 /// tag-list  =  tag-spec *( ";" tag-spec ) [ ";" ]
-pub fn tag_list(input: &str) -> IResult<&str, Vec<Tag>> {
-    let (input, start) = tag_spec(input)?;
-
-    terminated(
-        fold_many0(
-            preceded(tag(";"), tag_spec),
-            move || vec![start.clone()],
-            |mut acc: Vec<Tag>, item| {
-                acc.push(item);
-                acc
-            },
-        ),
-        opt(tag(";")),
-    )(input)
+///
+/// The whole input must be a tag-list, only folding whitespace is allowed
+/// after the last `;`.
+pub fn tag_list(input: &str) -> Result<Vec<Tag>, TagListError> {
+    let mut tags = vec![];
+    let mut rest = input;
+    loop {
+        let (next, tag) = tag_spec(input, rest)?;
+        tags.push(tag);
+        rest = match next.strip_prefix(';') {
+            Some(next) => next,
+            None if next.is_empty() => return Ok(tags),
+            None => {
+                return Err(TagListError::new(
+                    input,
+                    next,
+                    "invalid character in tag value",
+                ))
+            }
+        };
+        if rest.trim_start_matches(is_fws).is_empty() {
+            return Ok(tags);
+        }
+    }
 }
 
 /// tag-spec  =  [FWS] tag-name [FWS] "=" [FWS] tag-value [FWS]
-fn tag_spec(input: &str) -> IResult<&str, Tag> {
-    let (input, name) = delimited(opt(fws), tag_name, opt(fws))(input)?;
-    let (input, _) = tag("=")(input)?;
+fn tag_spec<'a>(input: &str, rest: &'a str) -> Result<(&'a str, Tag), TagListError> {
+    let rest = rest.trim_start_matches(is_fws);
+    let (rest, name) = tag_name(rest).map_err(|_| match rest.chars().next() {
+        None => TagListError::new(input, rest, "expected tag name"),
+        Some(';') => TagListError::new(input, rest, "empty tag"),
+        Some(_) => TagListError::new(input, rest, "invalid tag name"),
+    })?;
+    let rest = rest.trim_start_matches(is_fws);
+    let value_input = rest
+        .strip_prefix('=')
+        .ok_or_else(|| TagListError::new(input, rest, "expected '=' after tag name"))?;
 
-    // Parse the twice to keep the original text
-    let value_input = input;
-    let (_, raw_value) = delimited(opt(fws), raw_tag_value, opt(fws))(value_input)?;
-    let (input, value) = delimited(opt(fws), tag_value, opt(fws))(value_input)?;
+    // Parse the twice to keep the original text. The value parsers can't
+    // fail as they accept an empty value.
+    let invalid = |_| TagListError::new(input, value_input, "invalid tag value");
+    let (_, raw_value) =
+        delimited(opt(fws), raw_tag_value, opt(fws))(value_input).map_err(invalid)?;
+    let (rest, value) = delimited(opt(fws), tag_value, opt(fws))(value_input).map_err(invalid)?;
 
     Ok((
-        input,
+        rest,
         Tag {
             name: name.to_owned(),
             value,
@@ -60,17 +122,61 @@ fn tag_spec(input: &str) -> IResult<&str, Tag> {
     ))
 }
 
+fn is_fws(c: char) -> bool {
+    c == ' ' || c == '\t' || c == '\r' || c == '\n'
+}
+
 /// tag-name  =  ALPHA *ALNUMPUNC
 /// ALNUMPUNC =  ALPHA / DIGIT / "_"
 fn tag_name(input: &str) -> IResult<&str, &str> {
-    alpha1(input)
+    recognize(pair(
+        alpha1,
+        take_while(|c: char| c.is_ascii_alphanumeric() || c == '_'),
+    ))(input)
+}
+
+/// Whether the name matches the tag-name grammar
+pub(crate) fn is_valid_tag_name(name: &str) -> bool {
+    matches!(tag_name(name), Ok(("", _)))
+}
+
+/// Whether the value matches the ASCII tag-value grammar, without any folding
+pub(crate) fn is_valid_tag_value(value: &str) -> bool {
+    value.is_ascii() && !value.contains(['\r', '\n']) && matches!(tag_value(value), Ok(("", _)))
+}
+
+/// Whether the value is a dot-separated list of sub-domains, the grammar of
+/// the selectors (s=) and ASCII domain names (d=)
+/// sub-domain =  Let-dig [Ldh-str]
+/// Ldh-str    =  *( ALPHA / DIGIT / "-" ) Let-dig
+pub(crate) fn is_valid_domain_name(value: &str) -> bool {
+    value.split('.').all(|label| {
+        !label.is_empty()
+            && !label.starts_with('-')
+            && !label.ends_with('-')
+            && label.chars().all(|c| c.is_ascii_alphanumeric() || c == '-')
+    })
+}
+
+/// Whether the name matches the field-name grammar of RFC 5322, printable
+/// ASCII characters except the colon
+pub(crate) fn is_valid_field_name(name: &str) -> bool {
+    !name.is_empty() && name.chars().all(|c| ('!'..='~').contains(&c) && c != ':')
+}
+
+/// VALCHAR   =  %x21-3A / %x3C-7E / UTF8-non-ascii
+///
+/// Non-ASCII characters are allowed as in RFC 8616, section 4, for the
+/// U-labels of internationalized domains, except whitespace and controls.
+fn is_valchar(c: char) -> bool {
+    ('!'..=':').contains(&c)
+        || ('<'..='~').contains(&c)
+        || (!c.is_ascii() && !c.is_whitespace() && !c.is_control())
 }
 
 /// tag-value =  [ tval *( 1*(WSP / FWS) tval ) ]
 /// tval      =  1*VALCHAR
-/// VALCHAR   =  %x21-3A / %x3C-7E
 fn tag_value(input: &str) -> IResult<&str, String> {
-    let is_valchar = |c| ('!'..=':').contains(&c) || ('<'..='~').contains(&c);
     match opt(take_while1(is_valchar))(input)? {
         (input, Some(start)) => fold_many0(
             preceded(fws, take_while1(is_valchar)),
@@ -85,7 +191,6 @@ fn tag_value(input: &str) -> IResult<&str, String> {
 }
 
 fn raw_tag_value(input: &str) -> IResult<&str, String> {
-    let is_valchar = |c| ('!'..=':').contains(&c) || ('<'..='~').contains(&c);
     match opt(take_while1(is_valchar))(input)? {
         (input, Some(start)) => fold_many0(
             pair(fws, take_while1(is_valchar)),
@@ -101,7 +206,76 @@ fn raw_tag_value(input: &str) -> IResult<&str, String> {
 
 /// FWS is folding whitespace.  It allows multiple lines separated by CRLF followed by at least one whitespace, to be joined.
 fn fws(input: &str) -> IResult<&str, &str> {
-    take_while1(|c| c == ' ' || c == '\t' || c == '\r' || c == '\n')(input)
+    take_while1(is_fws)(input)
+}
+
+/// Decodes a dkim-quoted-printable value as specified in
+/// <https://datatracker.ietf.org/doc/html/rfc6376#section-2.11>.
+/// Folding whitespace is ignored and `=XX` sequences are replaced by the
+/// corresponding octet.
+pub fn decode_dkim_quoted_printable(value: &str) -> Result<Vec<u8>, DKIMError> {
+    let mut out = Vec::with_capacity(value.len());
+    let mut bytes = value.bytes();
+
+    while let Some(byte) = bytes.next() {
+        match byte {
+            b' ' | b'\t' | b'\r' | b'\n' => {}
+            b'=' => {
+                let hex = [bytes.next(), bytes.next()];
+                let octet = match hex {
+                    [Some(high), Some(low)] => std::str::from_utf8(&[high, low])
+                        .ok()
+                        .and_then(|hex| u8::from_str_radix(hex, 16).ok()),
+                    _ => None,
+                };
+                let octet = octet.ok_or_else(|| {
+                    DKIMError::SignatureSyntaxError(format!(
+                        "invalid quoted-printable sequence in: {}",
+                        value
+                    ))
+                })?;
+                out.push(octet);
+            }
+            byte => out.push(byte),
+        }
+    }
+
+    Ok(out)
+}
+
+/// Encodes a value in dkim-quoted-printable, as used in the z= tag. Every byte
+/// that isn't a dkim-safe-char, as well as `|`, is encoded as `=XX`.
+pub fn encode_dkim_quoted_printable(value: &[u8]) -> String {
+    let mut out = String::with_capacity(value.len());
+    for &byte in value {
+        // dkim-safe-char = %x21-3A / %x3C / %x3E-7E
+        let is_safe = matches!(byte, 0x21..=0x3A | 0x3C | 0x3E..=0x7E) && byte != b'|';
+        if is_safe {
+            out.push(byte as char);
+        } else {
+            out += &format!("={:02X}", byte);
+        }
+    }
+    out
+}
+
+/// Parses the copied header fields of the z= tag as (name, value) pairs
+/// <https://datatracker.ietf.org/doc/html/rfc6376#section-3.5>. Values that
+/// aren't UTF-8 once decoded are converted lossily.
+pub(crate) fn parse_copied_headers(value: &str) -> Result<Vec<(String, String)>, DKIMError> {
+    value
+        .split('|')
+        .map(|copy| {
+            let (name, value) = copy.split_once(':').ok_or_else(|| {
+                DKIMError::SignatureSyntaxError(format!("invalid copied header: {}", copy))
+            })?;
+            let value = decode_dkim_quoted_printable(value)?;
+            Ok((
+                name.trim().to_owned(),
+                String::from_utf8_lossy(&value).into_owned(),
+            ))
+        })
+        .collect()
 }
 
 pub(crate) fn parse_hash_algo(value: &str) -> Result<hash::HashAlgo, DKIMError> {
@@ -115,22 +289,26 @@ pub(crate) fn parse_hash_algo(value: &str) -> Result<hash::HashAlgo, DKIMError>
 }
 
 /// Parses the canonicalization value (passed in c=) and returns canonicalization
-/// for (Header, Body)
+/// for (Header, Body). Both are case-insensitive and a sole value is the
+/// header canonicalization, with simple for the body.
 pub(crate) fn parse_canonicalization(
     value: Option<String>,
 ) -> Result<(canonicalization::Type, canonicalization::Type), DKIMError> {
     use canonicalization::Type::{Relaxed, Simple};
-    if value.is_none() {
-        return Ok((Simple, Simple));
-    }
-    match value.unwrap().as_str() {
-        "simple/simple" => Ok((Simple, Simple)),
-        "relaxed/simple" => Ok((Relaxed, Simple)),
-        "simple/relaxed" => Ok((Simple, Relaxed)),
-        "relaxed/relaxed" => Ok((Relaxed, Relaxed)),
-        "relaxed" => Ok((Relaxed, Simple)),
-        "simple" => Ok((Simple, Simple)),
-        v => Err(DKIMError::UnsupportedCanonicalizationType(v.to_owned())),
+    let value = match value {
+        Some(value) => value,
+        None => return Ok((Simple, Simple)),
+    };
+    let parse = |token: &str| match token.trim().to_ascii_lowercase().as_str() {
+        "simple" => Ok(Simple),
+        "relaxed" => Ok(Relaxed),
+        _ => Err(DKIMError::UnsupportedCanonicalizationType(
+            token.trim().to_owned(),
+        )),
+    };
+    match value.split_once('/') {
+        Some((header, body)) => Ok((parse(header)?, parse(body)?)),
+        None => Ok((parse(&value)?, Simple)),
     }
 }
 
@@ -158,43 +336,178 @@ mod tests {
         );
     }
 
+    #[test]
+    fn test_canonicalization_tolerant() {
+        use canonicalization::Type::{Relaxed, Simple};
+
+        let parse = |value: &str| parse_canonicalization(Some(value.to_owned()));
+        assert_eq!(parse("Relaxed/Simple").unwrap(), (Relaxed, Simple));
+        assert_eq!(parse("relaxed / RELAXED").unwrap(), (Relaxed, Relaxed));
+        assert_eq!(parse("simple/relaxed \t").unwrap(), (Simple, Relaxed));
+        assert_eq!(parse(" Relaxed ").unwrap(), (Relaxed, Simple));
+        assert_eq!(
+            parse("strict/relaxed").unwrap_err(),
+            DKIMError::UnsupportedCanonicalizationType("strict".to_owned())
+        );
+        assert_eq!(
+            parse("relaxed/relaxed/simple").unwrap_err(),
+            DKIMError::UnsupportedCanonicalizationType("relaxed/simple".to_owned())
+        );
+        assert_eq!(
+            parse("").unwrap_err(),
+            DKIMError::UnsupportedCanonicalizationType("".to_owned())
+        );
+    }
+
+    #[test]
+    fn test_decode_dkim_quoted_printable() {
+        assert_eq!(
+            decode_dkim_quoted_printable("demo=20run").unwrap(),
+            b"demo run"
+        );
+        assert_eq!(
+            decode_dkim_quoted_printable("foo=40bar@example.net").unwrap(),
+            b"foo@bar@example.net"
+        );
+        assert_eq!(
+            decode_dkim_quoted_printable("July=205,\r\n =202005").unwrap(),
+            b"July 5, 2005"
+        );
+        assert!(decode_dkim_quoted_printable("a=2").is_err());
+        assert!(decode_dkim_quoted_printable("a=ZZ").is_err());
+    }
+
+    #[test]
+    fn test_encode_dkim_quoted_printable() {
+        assert_eq!(
+            encode_dkim_quoted_printable(b"July 5, 2005 3:44:08 PM -0700"),
+            "July=205,=202005=203:44:08=20PM=20-0700"
+        );
+        assert_eq!(
+            encode_dkim_quoted_printable("a|b;c=d\te\r\n f\u{e9}".as_bytes()),
+            "a=7Cb=3Bc=3Dd=09e=0D=0A=20f=C3=A9"
+        );
+
+        let value = "Spaces | pipes; and \u{fc}nicode".as_bytes();
+        assert_eq!(
+            decode_dkim_quoted_printable(&encode_dkim_quoted_printable(value)).unwrap(),
+            value
+        );
+    }
+
+    #[test]
+    fn test_parse_copied_headers() {
+        assert_eq!(
+            parse_copied_headers(
+                "From:foo@eng.example.net|To:joe@example.com|Subject:demo=20run|Date:July=205,=202005=203:44:08=20PM=20-0700"
+            )
+            .unwrap(),
+            vec![
+                ("From".to_owned(), "foo@eng.example.net".to_owned()),
+                ("To".to_owned(), "joe@example.com".to_owned()),
+                ("Subject".to_owned(), "demo run".to_owned()),
+                (
+                    "Date".to_owned(),
+                    "July 5, 2005 3:44:08 PM -0700".to_owned()
+                ),
+            ]
+        );
+        // A | in a value is encoded
+        assert_eq!(
+            parse_copied_headers("Subject:a=7Cb|X-Tag:=3D").unwrap(),
+            vec![
+                ("Subject".to_owned(), "a|b".to_owned()),
+                ("X-Tag".to_owned(), "=".to_owned()),
+            ]
+        );
+        // Invalid UTF-8 is replaced
+        assert_eq!(
+            parse_copied_headers("X-Tag:caf=E9").unwrap(),
+            vec![("X-Tag".to_owned(), "caf\u{fffd}".to_owned())]
+        );
+        assert!(parse_copied_headers("From").is_err());
+    }
+
+    #[test]
+    fn test_tag_name() {
+        assert_eq!(
+            tag_list("zk_1=a; v=1").unwrap()[0],
+            Tag {
+                name: "zk_1".to_string(),
+                value: "a".to_string(),
+                raw_value: "a".to_string()
+            }
+        );
+
+        assert!(is_valid_tag_name("zk"));
+        assert!(is_valid_tag_name("x_1"));
+        assert!(!is_valid_tag_name("1x"));
+        assert!(!is_valid_tag_name("_x"));
+        assert!(!is_valid_tag_name("x-y"));
+        assert!(!is_valid_tag_name(""));
+    }
+
+    #[test]
+    fn test_is_valid_tag_value() {
+        assert!(is_valid_tag_value(""));
+        assert!(is_valid_tag_value("1"));
+        assert!(is_valid_tag_value("a b\tc"));
+        assert!(!is_valid_tag_value("a;b"));
+        assert!(!is_valid_tag_value(" a"));
+        assert!(!is_valid_tag_value("a "));
+        assert!(!is_valid_tag_value("a\r\n b"));
+        assert!(!is_valid_tag_value("caf\u{e9}"));
+    }
+
+    #[test]
+    fn test_is_valid_domain_name() {
+        assert!(is_valid_domain_name("s1"));
+        assert!(is_valid_domain_name("mail-2024.example.com"));
+        assert!(is_valid_domain_name("xn--bcher-kva.example"));
+        assert!(!is_valid_domain_name(""));
+        assert!(!is_valid_domain_name("example.com."));
+        assert!(!is_valid_domain_name("-s1.example.com"));
+        assert!(!is_valid_domain_name("s_1"));
+        assert!(!is_valid_domain_name("example.com; h=to"));
+        assert!(!is_valid_domain_name("example.com\r\nBcc: eve@example.net"));
+
+        assert!(is_valid_field_name("X-Mailer"));
+        assert!(!is_valid_field_name("From:"));
+        assert!(!is_valid_field_name("From\r\nBcc"));
+        assert!(!is_valid_field_name(""));
+    }
+
     #[test]
     fn test_tag_list() {
         assert_eq!(
             tag_list("a = a/1@.-:= ").unwrap(),
-            (
-                "",
-                vec![Tag {
-                    name: "a".to_string(),
-                    value: "a/1@.-:=".to_string(),
-                    raw_value: "a/1@.-:=".to_string()
-                }]
-            )
+            vec![Tag {
+                name: "a".to_string(),
+                value: "a/1@.-:=".to_string(),
+                raw_value: "a/1@.-:=".to_string()
+            }]
         );
         assert_eq!(
             tag_list("a= a ; b = a\n    bc").unwrap(),
-            (
-                "",
-                vec![
-                    Tag {
-                        name: "a".to_string(),
-                        value: "a".to_string(),
-                        raw_value: "a".to_string()
-                    },
-                    Tag {
-                        name: "b".to_string(),
-                        value: "abc".to_string(),
-                        raw_value: "a\n    bc".to_string()
-                    }
-                ]
-            )
+            vec![
+                Tag {
+                    name: "a".to_string(),
+                    value: "a".to_string(),
+                    raw_value: "a".to_string()
+                },
+                Tag {
+                    name: "b".to_string(),
+                    value: "abc".to_string(),
+                    raw_value: "a\n    bc".to_string()
+                }
+            ]
         );
     }
 
     #[test]
     fn test_tag_spec() {
         assert_eq!(
-            tag_spec("a=b").unwrap(),
+            tag_spec("a=b", "a=b").unwrap(),
             (
                 "",
                 Tag {
@@ -205,7 +518,7 @@ mod tests {
             )
         );
         assert_eq!(
-            tag_spec("a=b c d e f").unwrap(),
+            tag_spec("a=b c d e f", "a=b c d e f").unwrap(),
             (
                 "",
                 Tag {
@@ -217,25 +530,67 @@ mod tests {
         );
     }
 
+    #[test]
+    fn test_tag_list_errors() {
+        fn error(input: &str) -> TagListError {
+            tag_list(input).unwrap_err()
+        }
+        assert_eq!(
+            error("v=1; a=rsa-sha256; d example.com; s=a"),
+            TagListError {
+                offset: 21,
+                snippet: "example.com; s=a".to_owned(),
+                description: "expected '=' after tag name"
+            }
+        );
+        assert_eq!(
+            error("v=1; a=rsa-sha256; d=caf\u{7f}.example; s=a"),
+            TagListError {
+                offset: 24,
+                snippet: "\u{7f}.example; s=a".to_owned(),
+                description: "invalid character in tag value"
+            }
+        );
+        // U-labels of internationalized domains are allowed
+        assert!(tag_list("v=1; a=rsa-sha256; d=caf\u{e9}.example; s=a").is_ok());
+        assert_eq!(error("v=1;; a=rsa-sha256").offset, 4);
+        assert_eq!(error("v=1;; a=rsa-sha256").description, "empty tag");
+        assert_eq!(error("v=1; 1a=b").description, "invalid tag name");
+        assert_eq!(error("").description, "expected tag name");
+        assert_eq!(
+            error(&format!("v=1; b={} {}", "a".repeat(50), "\u{a0}")),
+            TagListError {
+                offset: 58,
+                snippet: "\u{a0}".to_owned(),
+                description: "invalid character in tag value"
+            }
+        );
+        assert_eq!(error(&format!("v {}", "a".repeat(50))).snippet.len(), 40);
+        assert_eq!(
+            error("v=1 \u{a0}; a=b").to_string(),
+            "invalid character in tag value at offset 4 (\"\\u{a0}; a=b\")"
+        );
+
+        // Only whitespace can follow the last separator
+        assert_eq!(tag_list("v=1; a=b;\r\n ").unwrap().len(), 2);
+    }
+
     #[test]
     fn test_tag_list_dns() {
         assert_eq!(
             tag_list("k=rsa; p=kEy+/").unwrap(),
-            (
-                "",
-                vec![
-                    Tag {
-                        name: "k".to_string(),
-                        value: "rsa".to_string(),
-                        raw_value: "rsa".to_string()
-                    },
-                    Tag {
-                        name: "p".to_string(),
-                        value: "kEy+/".to_string(),
-                        raw_value: "kEy+/".to_string()
-                    }
-                ]
-            )
+            vec![
+                Tag {
+                    name: "k".to_string(),
+                    value: "rsa".to_string(),
+                    raw_value: "rsa".to_string()
+                },
+                Tag {
+                    name: "p".to_string(),
+                    value: "kEy+/".to_string(),
+                    raw_value: "kEy+/".to_string()
+                }
+            ]
         );
     }
 }
@@ -1,87 +1,429 @@
-#[cfg(all(feature = "dns", not(target_arch = "wasm32")))]
 use crate::dns;
-use crate::{parser, DKIMError, DkimPublicKey, DNS_NAMESPACE};
+use crate::log::Logger;
+use crate::{parser, DKIMError, DkimPublicKey, HashAlgo, DNS_NAMESPACE};
 use base64::{engine::general_purpose, Engine};
-use rsa::{pkcs1, pkcs8};
-use slog::{debug, warn};
 use std::collections::HashMap;
 use std::sync::Arc;
 
 const RSA_KEY_TYPE: &str = "rsa";
 const ED25519_KEY_TYPE: &str = "ed25519";
 
+/// Type of a key (k=)
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum KeyType {
+    #[default]
+    Rsa,
+    Ed25519,
+}
+
+impl KeyType {
+    /// Name of the type in key records
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            KeyType::Rsa => RSA_KEY_TYPE,
+            KeyType::Ed25519 => ED25519_KEY_TYPE,
+        }
+    }
+}
+
+/// Flags of a key record (t=)
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct KeyFlags(u8);
+
+impl KeyFlags {
+    /// The domain is testing DKIM (y)
+    pub const TESTING: KeyFlags = KeyFlags(1);
+    /// The i= domain must be d= and not a subdomain (s)
+    pub const STRICT: KeyFlags = KeyFlags(1 << 1);
+
+    pub fn empty() -> Self {
+        KeyFlags(0)
+    }
+
+    pub fn contains(&self, flags: KeyFlags) -> bool {
+        self.0 & flags.0 == flags.0
+    }
+
+    pub fn insert(&mut self, flags: KeyFlags) {
+        self.0 |= flags.0;
+    }
+}
+
+impl std::ops::BitOr for KeyFlags {
+    type Output = KeyFlags;
+
+    fn bitor(self, rhs: KeyFlags) -> KeyFlags {
+        KeyFlags(self.0 | rhs.0)
+    }
+}
+
+/// DKIM key record
+/// <https://datatracker.ietf.org/doc/html/rfc6376#section-3.6.1>
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DkimKeyRecord {
+    /// Version (v=), always DKIM1
+    pub version: String,
+    /// Key type (k=), RSA by default
+    pub key_type: KeyType,
+    /// Acceptable hash algorithms (h=) in lowercase, `None` when all are
+    pub hash_algorithms: Option<Vec<String>>,
+    /// Service types (s=) in lowercase, `*` by default
+    pub service_types: Vec<String>,
+    /// Flags (t=), unknown flags are ignored
+    pub flags: KeyFlags,
+    /// Notes for humans (n=)
+    pub notes: Option<String>,
+    /// Decoded key data (p=), empty when the key was revoked
+    pub public_key: Vec<u8>,
+}
+
+impl DkimKeyRecord {
+    /// Whether the key was revoked, with an empty p= tag
+    pub fn is_revoked(&self) -> bool {
+        self.public_key.is_empty()
+    }
+
+    /// Whether the key may be used for email, with `*` or `email` in s=
+    pub fn permits_email(&self) -> bool {
+        self.service_types
+            .iter()
+            .any(|service| service == "*" || service == "email")
+    }
+
+    /// Check that the key can be used to verify signatures: it isn't
+    /// revoked and its service types include email
+    pub(crate) fn check_usable(&self, selector: &str, domain: &str) -> Result<(), DKIMError> {
+        if self.is_revoked() {
+            return Err(DKIMError::KeyRevoked {
+                selector: selector.to_owned(),
+                domain: domain.to_owned(),
+            });
+        }
+        if !self.permits_email() {
+            return Err(DKIMError::InappropriateServiceType(
+                self.service_types.join(":"),
+            ));
+        }
+        Ok(())
+    }
+
+    /// Whether signatures may use the hash algorithm with this key. Names
+    /// in h= other than sha1 and sha256 are never matched.
+    pub fn permits_hash(&self, hash_algo: &HashAlgo) -> bool {
+        match &self.hash_algorithms {
+            Some(algorithms) => algorithms.iter().any(|name| name == hash_algo.hash_name()),
+            None => true,
+        }
+    }
+
+    /// Public key of the record
+    pub fn public_key(&self) -> Result<DkimPublicKey, DKIMError> {
+        match self.key_type {
+            KeyType::Rsa => DkimPublicKey::parse_rsa_key(&self.public_key),
+            KeyType::Ed25519 => DkimPublicKey::parse_ed25519_key(&self.public_key),
+        }
+    }
+}
+
+/// Public key retrieved from DNS with information about its record
+#[derive(Debug, Clone)]
+pub struct RetrievedKey {
+    pub key: DkimPublicKey,
+    pub record: DkimKeyRecord,
+    /// Name of the TXT record, which differs from the queried one when CNAMEs
+    /// were followed
+    pub name: String,
+    /// Whether the resolver set the authentic data bit of the record, see
+    /// [TxtAnswer::authentic_data](crate::dns::TxtAnswer::authentic_data)
+    pub authentic_data: bool,
+}
+
+/// Largest key record parsed, in bytes. Records with a 16384-bit RSA key
+/// stay well under it.
+pub const MAX_KEY_RECORD_SIZE: usize = 64 * 1024;
+
 // https://datatracker.ietf.org/doc/html/rfc6376#section-6.1.2
-#[cfg(all(feature = "dns", not(target_arch = "wasm32")))]
 pub async fn retrieve_public_key(
-    logger: &slog::Logger,
     resolver: Arc<dyn dns::Lookup>,
     domain: String,
     subdomain: String,
-) -> Result<DkimPublicKey, DKIMError> {
-    let dns_name = format!("{}.{}.{}", subdomain, DNS_NAMESPACE, domain);
-    let res = resolver.lookup_txt(&dns_name).await?;
+) -> Result<(DkimPublicKey, DkimKeyRecord), DKIMError> {
+    let retrieved = retrieve_key(
+        resolver,
+        &domain,
+        &subdomain,
+        dns::DEFAULT_MAX_CNAME_CHAIN,
+        true,
+    )
+    .await?;
+    Ok((retrieved.key, retrieved.record))
+}
+
+/// Same as [retrieve_public_key] with information about the key record,
+/// following up to `max_cname_chain` CNAMEs. The record is parsed with
+/// [parse_key_record_lenient] if `lenient` is set, [parse_key_record]
+/// otherwise.
+pub async fn retrieve_key(
+    resolver: Arc<dyn dns::Lookup>,
+    domain: &str,
+    subdomain: &str,
+    max_cname_chain: usize,
+    lenient: bool,
+) -> Result<RetrievedKey, DKIMError> {
+    retrieve_key_with_logger(
+        &Logger::default(),
+        resolver,
+        domain,
+        subdomain,
+        max_cname_chain,
+        lenient,
+    )
+    .await
+}
+
+/// [retrieve_key] logging the record and recoveries to the logger of the
+/// verification
+pub(crate) async fn retrieve_key_with_logger(
+    logger: &Logger,
+    resolver: Arc<dyn dns::Lookup>,
+    domain: &str,
+    subdomain: &str,
+    max_cname_chain: usize,
+    lenient: bool,
+) -> Result<RetrievedKey, DKIMError> {
+    // Internationalized selectors and domains are queried with their A-labels
+    let dns_name = format!(
+        "{}.{}.{}",
+        crate::ascii_domain(subdomain),
+        DNS_NAMESPACE,
+        crate::ascii_domain(domain)
+    );
+    dns::check_name(&dns_name)?;
+    let (name, answer) =
+        dns::lookup_txt_following_cnames(resolver.as_ref(), &dns_name, max_cname_chain).await?;
+    if name != dns_name {
+        debug!(logger, "{} resolved to {} through CNAMEs", dns_name, name);
+    }
+    let res = answer.records;
     // TODO: Return multiple keys for when verifiying the signatures. During key
     // rotation they are often multiple keys to consider.
+    // The character-strings of a record are concatenated without separator
+    // (https://datatracker.ietf.org/doc/html/rfc6376#section-3.6.2.2). Some
+    // resolvers join them themselves, either directly, with spaces (ignored by
+    // the tag parser) or with the quotes left in.
     let txt = res
         .first()
         .ok_or(DKIMError::NoKeyForSignature)?
+        .text()
         .replace("\" \"", "");
+    if txt.len() > MAX_KEY_RECORD_SIZE {
+        return Err(DKIMError::KeyRecordTooLarge(txt.len()));
+    }
     debug!(logger, "DKIM TXT: {:?}", txt);
 
-    // Parse the tags inside the DKIM TXT DNS record
-    let (_, tags) = parser::tag_list(&txt).map_err(|err| {
-        warn!(logger, "key syntax error: {}", err);
-        DKIMError::KeySyntaxError
+    let record = if lenient {
+        parse_key_record_with_logger(logger, &txt)
+    } else {
+        parse_key_record(&txt)
+    };
+    let record = record.map_err(|err| {
+        warn!(logger, "invalid key record: {}", err);
+        err
     })?;
+    record.check_usable(subdomain, domain)?;
+    let key = record.public_key()?;
+    Ok(RetrievedKey {
+        key,
+        record,
+        name,
+        authentic_data: answer.authentic_data,
+    })
+}
+
+/// Parse a DKIM key record. Unknown tags, key flags and service types are
+/// ignored.
+pub fn parse_key_record(txt: &str) -> Result<DkimKeyRecord, DKIMError> {
+    let tags = parser::tag_list(txt).map_err(|_| DKIMError::KeySyntaxError)?;
 
     let mut tags_map = HashMap::new();
     for tag in &tags {
-        tags_map.insert(tag.name.clone(), tag.clone());
+        tags_map.insert(tag.name.as_str(), tag);
     }
+    let list = |name: &str| {
+        tags_map.get(name).map(|tag| {
+            tag.value
+                .split(':')
+                .map(|value| value.trim().to_lowercase())
+                .filter(|value| !value.is_empty())
+                .collect::<Vec<_>>()
+        })
+    };
 
     // Check version
-    if let Some(version) = tags_map.get("v") {
-        if version.value != "DKIM1" {
-            return Err(DKIMError::KeyIncompatibleVersion);
-        }
-    }
+    let version = match tags_map.get("v") {
+        Some(version) if version.value != "DKIM1" => return Err(DKIMError::KeyIncompatibleVersion),
+        _ => "DKIM1".to_owned(),
+    };
 
     // Get key type
-    let key_type = match tags_map.get("k") {
-        Some(v) => {
-            if v.value != RSA_KEY_TYPE && v.value != ED25519_KEY_TYPE {
-                return Err(DKIMError::InappropriateKeyAlgorithm);
-            }
-            v.value.clone()
-        }
-        None => RSA_KEY_TYPE.to_string(),
+    let key_type = match tags_map.get("k").map(|tag| tag.value.as_str()) {
+        None | Some(RSA_KEY_TYPE) => KeyType::Rsa,
+        Some(ED25519_KEY_TYPE) => KeyType::Ed25519,
+        Some(_) => return Err(DKIMError::InappropriateKeyAlgorithm),
     };
 
+    let mut flags = KeyFlags::empty();
+    for flag in list("t").unwrap_or_default() {
+        match flag.as_str() {
+            "y" => flags.insert(KeyFlags::TESTING),
+            "s" => flags.insert(KeyFlags::STRICT),
+            _ => {}
+        }
+    }
+
+    // Notes are only informational, keep them as is if they aren't valid
+    // quoted-printable
+    let notes = tags_map.get("n").map(|tag| {
+        parser::decode_dkim_quoted_printable(&tag.value)
+            .map(|notes| String::from_utf8_lossy(&notes).into_owned())
+            .unwrap_or_else(|_| tag.raw_value.clone())
+    });
+
     let tag = tags_map.get("p").ok_or(DKIMError::NoKeyForSignature)?;
-    let bytes = general_purpose::STANDARD
+    let public_key = general_purpose::STANDARD
         .decode(&tag.value)
-        .map_err(|err| {
-            DKIMError::KeyUnavailable(format!("failed to decode public key: {}", err))
-        })?;
-    let key = if key_type == RSA_KEY_TYPE {
-        DkimPublicKey::Rsa(
-            pkcs8::DecodePublicKey::from_public_key_der(&bytes)
-                .or_else(|_| pkcs1::DecodeRsaPublicKey::from_pkcs1_der(&bytes))
-                .map_err(|err| {
-                    DKIMError::KeyUnavailable(format!("failed to parse public key: {}", err))
-                })?,
-        )
-    } else {
-        DkimPublicKey::Ed25519(
-            ed25519_dalek::VerifyingKey::from_bytes((&bytes as &[u8]).try_into().map_err(
-                |err| DKIMError::KeyUnavailable(format!("failed to convert public key: {}", err)),
-            )?)
-            .map_err(|err| {
-                DKIMError::KeyUnavailable(format!("failed to parse public key: {}", err))
-            })?,
-        )
-    };
-    Ok(key)
+        .map_err(|_| DKIMError::KeySyntaxError)?;
+
+    Ok(DkimKeyRecord {
+        version,
+        key_type,
+        hash_algorithms: list("h"),
+        service_types: list("s").unwrap_or_else(|| vec!["*".to_owned()]),
+        flags,
+        notes,
+        public_key,
+    })
+}
+
+/// Same as [parse_key_record], recovering from the mistakes seen in
+/// published records: quotes left from zone files, empty tags (`;;`),
+/// whitespace other than spaces and tabs around tags, and whitespace or
+/// escaped whitespace (`\t`) inside the key data. Each recovery is logged at
+/// the debug level, see [Logger].
+pub fn parse_key_record_lenient(txt: &str) -> Result<DkimKeyRecord, DKIMError> {
+    parse_key_record_with_logger(&Logger::default(), txt)
+}
+
+pub(crate) fn parse_key_record_with_logger(
+    logger: &Logger,
+    txt: &str,
+) -> Result<DkimKeyRecord, DKIMError> {
+    parse_key_record(&normalize_key_record(logger, txt))
+}
+
+fn normalize_key_record(logger: &Logger, txt: &str) -> String {
+    let is_fws = |c: char| matches!(c, ' ' | '\t' | '\r' | '\n');
+
+    let segments: Vec<&str> = txt.split(';').collect();
+    let mut tags = vec![];
+    for (i, segment) in segments.iter().enumerate() {
+        let mut segment = segment.to_string();
+        if segment.contains('"') {
+            debug!(logger, "removing quotes from key record tag {:?}", segment);
+            segment = segment.replace('"', "");
+        }
+        let trimmed = segment.trim();
+        if trimmed.is_empty() {
+            // A single trailing semicolon is allowed
+            if i + 1 != segments.len() {
+                debug!(logger, "ignoring empty key record tag");
+            }
+            continue;
+        }
+        if trimmed != segment.trim_matches(is_fws) {
+            debug!(
+                logger,
+                "removing whitespace around key record tag {:?}", trimmed
+            );
+        }
+
+        let (name, value) = match trimmed.split_once('=') {
+            Some((name, value)) => (name.trim(), value.trim()),
+            None => {
+                tags.push(trimmed.to_owned());
+                continue;
+            }
+        };
+        let value = if name == "p" {
+            let data: String = value
+                .replace("\\t", "")
+                .replace("\\r", "")
+                .replace("\\n", "")
+                .chars()
+                .filter(|c| !c.is_whitespace())
+                .collect();
+            if data != value.replace(is_fws, "") {
+                debug!(logger, "removing whitespace from the key data");
+            }
+            data
+        } else {
+            value.to_owned()
+        };
+        tags.push(format!("{}={}", name, value));
+    }
+    tags.join("; ")
+}
+
+/// Optional tags of the DKIM key record generated by [to_dns_record]
+/// <https://datatracker.ietf.org/doc/html/rfc6376#section-3.6.1>
+#[derive(Debug, Clone, Default)]
+pub struct DnsRecordOptions {
+    /// Acceptable hash algorithms (h=), for example `sha256`
+    pub hash_algorithms: Vec<String>,
+    /// Service types the key applies to (s=), for example `email`
+    pub service_types: Vec<String>,
+    /// The domain is testing DKIM (t=y)
+    pub testing: bool,
+    /// The i= domain must be d= and not a subdomain (t=s)
+    pub strict: bool,
+    /// Notes for humans (n=)
+    pub notes: Option<String>,
+}
+
+/// Generate the value of the DKIM key TXT record for a public key, to be
+/// published at `<selector>._domainkey.<domain>`. RSA keys are encoded as a
+/// SubjectPublicKeyInfo and Ed25519 keys as their raw 32 bytes.
+///
+/// The record can be longer than 255 characters, in which case it has to be
+/// split in multiple strings when published.
+pub fn to_dns_record(public_key: &DkimPublicKey, options: &DnsRecordOptions) -> String {
+    let key_type = public_key.key_type().as_str();
+    let key = general_purpose::STANDARD.encode(public_key.to_der());
+
+    let mut tags = vec![("v", "DKIM1".to_owned())];
+    if !options.hash_algorithms.is_empty() {
+        tags.push(("h", options.hash_algorithms.join(":")));
+    }
+    tags.push(("k", key_type.to_owned()));
+    if !options.service_types.is_empty() {
+        tags.push(("s", options.service_types.join(":")));
+    }
+    let flags: Vec<&str> = [(options.testing, "y"), (options.strict, "s")]
+        .into_iter()
+        .filter_map(|(enabled, flag)| enabled.then_some(flag))
+        .collect();
+    if !flags.is_empty() {
+        tags.push(("t", flags.join(":")));
+    }
+    if let Some(notes) = &options.notes {
+        tags.push(("n", parser::encode_dkim_quoted_printable(notes.as_bytes())));
+    }
+    tags.push(("p", key));
+
+    tags.iter()
+        .map(|(name, value)| format!("{}={}", name, value))
+        .collect::<Vec<_>>()
+        .join("; ")
 }
 
 #[cfg(test)]
@@ -104,16 +446,9 @@ mod tests {
             }
         }
         let resolver = Arc::new(TestResolver {});
-        let logger = slog::Logger::root(slog::Discard, slog::o!());
-
-        retrieve_public_key(
-            &logger,
-            resolver,
-            "cloudflare.com".to_string(),
-            "dkim".to_string(),
-        )
-        .await
-        .unwrap();
+        retrieve_public_key(resolver, "cloudflare.com".to_string(), "dkim".to_string())
+            .await
+            .unwrap();
     }
 
     #[tokio::test]
@@ -131,16 +466,236 @@ mod tests {
             }
         }
         let resolver = Arc::new(TestResolver {});
-        let logger = slog::Logger::root(slog::Discard, slog::o!());
+        retrieve_public_key(resolver, "cloudflare.com".to_string(), "dkim".to_string())
+            .await
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_retrieve_public_key_multiple_strings() {
+        /// Returns the record with its character-strings in the given form
+        struct TestResolver {
+            strings: Vec<String>,
+            joined: Option<&'static str>,
+        }
+        impl dns::Lookup for TestResolver {
+            fn lookup_txt<'a>(
+                &'a self,
+                _name: &'a str,
+            ) -> BoxFuture<'a, Result<Vec<String>, DKIMError>> {
+                let joined = self.strings.join(self.joined.unwrap_or(""));
+                Box::pin(async move { Ok(vec![joined]) })
+            }
+
+            fn lookup_txt_answer<'a>(
+                &'a self,
+                _name: &'a str,
+            ) -> BoxFuture<'a, Result<dns::TxtAnswer, dns::LookupError>> {
+                let strings = match self.joined {
+                    Some(separator) => vec![self.strings.join(separator)],
+                    None => self.strings.clone(),
+                };
+                Box::pin(async move {
+                    Ok(dns::TxtAnswer {
+                        records: vec![dns::TxtRecord::new(&strings, 300)],
+                        authentic_data: false,
+                    })
+                })
+            }
+        }
+
+        // A 4096-bit key doesn't fit in less than three strings of 255 bytes
+        let key = std::fs::read_to_string("./test/keys/rsa4096.public").unwrap();
+        let record = format!("v=DKIM1; k=rsa; p={}", key.trim());
+        let strings: Vec<String> = record
+            .as_bytes()
+            .chunks(255)
+            .map(|chunk| String::from_utf8(chunk.to_vec()).unwrap())
+            .collect();
+        assert_eq!(strings.len(), 3);
+
+        let mut keys = vec![];
+        for joined in [None, Some(""), Some(" "), Some("\" \"")] {
+            let resolver = Arc::new(TestResolver {
+                strings: strings.clone(),
+                joined,
+            });
+            let key =
+                retrieve_public_key(resolver, "cloudflare.com".to_string(), "dkim".to_string())
+                    .await
+                    .unwrap();
+            keys.push(key.0);
+        }
+
+        match &keys[0] {
+            DkimPublicKey::Rsa(key) => assert_eq!(rsa::traits::PublicKeyParts::size(key), 512),
+            _ => panic!("expected an RSA key"),
+        }
+        for key in &keys {
+            assert_eq!(to_dns_record(key, &DnsRecordOptions::default()), record);
+        }
+    }
 
-        retrieve_public_key(
-            &logger,
-            resolver,
-            "cloudflare.com".to_string(),
-            "dkim".to_string(),
+    #[tokio::test]
+    async fn test_retrieve_key_cname_chain() {
+        let record = std::fs::read_to_string("./test/keys/ed.public").unwrap();
+        let mut resolver = dns::StaticLookup::default();
+        resolver.insert_cname(
+            "selector1._domainkey.example.com",
+            "selector1-example-com._domainkey.provider.net",
+        );
+        resolver.insert_cname(
+            "selector1-example-com._domainkey.provider.net",
+            "key1.keys.provider.net",
+        );
+        resolver.insert(
+            "key1.keys.provider.net",
+            &format!("v=DKIM1; k=ed25519; p={}", record.trim()),
+        );
+        let resolver: Arc<dyn dns::Lookup> = Arc::new(resolver);
+        let retrieved = retrieve_key(
+            Arc::clone(&resolver),
+            "example.com",
+            "selector1",
+            dns::DEFAULT_MAX_CNAME_CHAIN,
+            true,
         )
         .await
         .unwrap();
+        assert_eq!(retrieved.name, "key1.keys.provider.net");
+        assert!(matches!(retrieved.key, DkimPublicKey::Ed25519(_)));
+        assert!(!retrieved.authentic_data);
+
+        // The chain is longer than allowed
+        let err = retrieve_key(resolver, "example.com", "selector1", 1, true)
+            .await
+            .unwrap_err();
+        assert_eq!(
+            err,
+            DKIMError::PermanentDnsFailure(
+                "more than 1 CNAMEs for selector1._domainkey.example.com".to_owned()
+            )
+        );
+    }
+
+    #[tokio::test]
+    async fn test_retrieve_key_idna() {
+        let record = std::fs::read_to_string("./test/keys/ed.public").unwrap();
+        let mut resolver = dns::StaticLookup::default();
+        resolver.insert(
+            "s1._domainkey.xn--bcher-kva.example",
+            &format!("v=DKIM1; k=ed25519; p={}", record.trim()),
+        );
+        let resolver: Arc<dyn dns::Lookup> = Arc::new(resolver);
+        // d= as a U-label, queried with its A-label
+        for domain in ["b\u{fc}cher.example", "xn--bcher-kva.example"] {
+            let retrieved = retrieve_key(
+                Arc::clone(&resolver),
+                domain,
+                "s1",
+                dns::DEFAULT_MAX_CNAME_CHAIN,
+                true,
+            )
+            .await
+            .unwrap();
+            assert_eq!(retrieved.name, "s1._domainkey.xn--bcher-kva.example");
+        }
+    }
+
+    #[tokio::test]
+    async fn test_retrieve_key_limits() {
+        let record = std::fs::read_to_string("./test/keys/ed.public").unwrap();
+        let record = format!("v=DKIM1; k=ed25519; p={}", record.trim());
+        let selector = "s".repeat(64);
+        let domain = format!("{}.example.com", "a.".repeat(120));
+        let mut resolver = dns::StaticLookup::default();
+        resolver.insert(&format!("{}._domainkey.example.com", selector), &record);
+        resolver.insert(
+            &format!("{}._domainkey.example.com", &selector[1..]),
+            &record,
+        );
+        resolver.insert(&format!("s1._domainkey.{}", domain), &record);
+        resolver.insert(
+            "large._domainkey.example.com",
+            &format!("{}; n={}", record, "x".repeat(MAX_KEY_RECORD_SIZE)),
+        );
+        let resolver: Arc<dyn dns::Lookup> = Arc::new(resolver);
+        let retrieve = |domain: String, selector: String| {
+            let resolver = Arc::clone(&resolver);
+            async move {
+                retrieve_key(
+                    resolver,
+                    &domain,
+                    &selector,
+                    dns::DEFAULT_MAX_CNAME_CHAIN,
+                    true,
+                )
+                .await
+            }
+        };
+
+        // Rejected before querying, the records being published
+        assert_eq!(
+            retrieve("example.com".to_owned(), selector.clone())
+                .await
+                .unwrap_err(),
+            DKIMError::InvalidDnsName("label of 64 octets".to_owned())
+        );
+        assert_eq!(
+            retrieve(domain, "s1".to_owned()).await.unwrap_err(),
+            DKIMError::InvalidDnsName("name of 266 octets".to_owned())
+        );
+        assert_eq!(
+            retrieve("example..com".to_owned(), "s1".to_owned())
+                .await
+                .unwrap_err(),
+            DKIMError::InvalidDnsName("empty label".to_owned())
+        );
+        assert!(retrieve("example.com".to_owned(), selector[1..].to_owned())
+            .await
+            .is_ok());
+
+        assert_eq!(
+            retrieve("example.com".to_owned(), "large".to_owned())
+                .await
+                .unwrap_err(),
+            DKIMError::KeyRecordTooLarge(record.len() + 4 + MAX_KEY_RECORD_SIZE)
+        );
+    }
+
+    #[tokio::test]
+    async fn test_retrieve_public_key_service_types() {
+        let key = std::fs::read_to_string("./test/keys/ed.public").unwrap();
+        let mut resolver = dns::StaticLookup::default();
+        for (selector, service_types) in [
+            ("email", "email"),
+            ("any", "*"),
+            ("tlsrpt", "tlsrpt"),
+            ("both", "email:tlsrpt"),
+        ] {
+            resolver.insert(
+                &format!("{}._domainkey.example.com", selector),
+                &format!("v=DKIM1; k=ed25519; s={}; p={}", service_types, key.trim()),
+            );
+        }
+        let resolver: Arc<dyn dns::Lookup> = Arc::new(resolver);
+        for selector in ["email", "any", "both"] {
+            let (_, record) = retrieve_public_key(
+                Arc::clone(&resolver),
+                "example.com".to_string(),
+                selector.to_string(),
+            )
+            .await
+            .unwrap();
+            assert!(record.permits_email());
+        }
+        let err = retrieve_public_key(resolver, "example.com".to_string(), "tlsrpt".to_string())
+            .await
+            .unwrap_err();
+        assert_eq!(
+            err,
+            DKIMError::InappropriateServiceType("tlsrpt".to_owned())
+        );
     }
 
     #[tokio::test]
@@ -158,16 +713,9 @@ mod tests {
             }
         }
         let resolver = Arc::new(TestResolver {});
-        let logger = slog::Logger::root(slog::Discard, slog::o!());
-
-        let key = retrieve_public_key(
-            &logger,
-            resolver,
-            "cloudflare.com".to_string(),
-            "dkim".to_string(),
-        )
-        .await
-        .unwrap_err();
+        let key = retrieve_public_key(resolver, "cloudflare.com".to_string(), "dkim".to_string())
+            .await
+            .unwrap_err();
         assert_eq!(key, DKIMError::KeyIncompatibleVersion);
     }
 
@@ -186,16 +734,138 @@ mod tests {
             }
         }
         let resolver = Arc::new(TestResolver {});
-        let logger = slog::Logger::root(slog::Discard, slog::o!());
+        let key = retrieve_public_key(resolver, "cloudflare.com".to_string(), "dkim".to_string())
+            .await
+            .unwrap_err();
+        assert_eq!(key, DKIMError::InappropriateKeyAlgorithm);
+    }
+
+    #[test]
+    fn test_parse_key_record() {
+        let data = std::fs::read_to_string("./test/keys/2022.txt").unwrap();
+        let txt: String = data.split('"').skip(1).step_by(2).collect();
+        let record = parse_key_record(&txt).unwrap();
+        assert_eq!(record.version, "DKIM1");
+        assert_eq!(record.key_type, KeyType::Rsa);
+        assert_eq!(record.hash_algorithms, Some(vec!["sha256".to_owned()]));
+        assert_eq!(record.service_types, vec!["*"]);
+        assert_eq!(record.flags, KeyFlags::TESTING | KeyFlags::STRICT);
+        assert_eq!(record.notes, None);
+        assert!(matches!(record.public_key(), Ok(DkimPublicKey::Rsa(_))));
 
-        let key = retrieve_public_key(
-            &logger,
-            resolver,
-            "cloudflare.com".to_string(),
-            "dkim".to_string(),
+        // Unknown tags, flags and services are kept out
+        let record = parse_key_record(
+            "k=ed25519; x=unknown; h=SHA256 : foo; s=email:tlsrpt; t=x:y; n=rotated=3B=202018; \
+             p=11qYAYKxCrfVS/7TyWQHOg7hcvPapiMlrwIaaPcHURo=",
         )
-        .await
-        .unwrap_err();
-        assert_eq!(key, DKIMError::InappropriateKeyAlgorithm);
+        .unwrap();
+        assert_eq!(record.key_type, KeyType::Ed25519);
+        assert_eq!(
+            record.hash_algorithms,
+            Some(vec!["sha256".to_owned(), "foo".to_owned()])
+        );
+        assert_eq!(record.service_types, vec!["email", "tlsrpt"]);
+        assert!(record.permits_hash(&HashAlgo::Ed25519Sha256));
+        assert!(!record.permits_hash(&HashAlgo::RsaSha1));
+        assert!(record.flags.contains(KeyFlags::TESTING));
+        assert!(!record.flags.contains(KeyFlags::STRICT));
+        assert_eq!(record.notes.as_deref(), Some("rotated; 2018"));
+        assert!(matches!(record.public_key(), Ok(DkimPublicKey::Ed25519(_))));
+
+        // A revoked key has no data
+        let record = parse_key_record("v=DKIM1; p=").unwrap();
+        assert!(record.is_revoked());
+        assert!(record.permits_hash(&HashAlgo::RsaSha1));
+        assert!(record.public_key().is_err());
+
+        assert_eq!(
+            parse_key_record("v=DKIM1; k=rsa"),
+            Err(DKIMError::NoKeyForSignature)
+        );
+        assert_eq!(
+            parse_key_record("=DKIM1; p=YQ=="),
+            Err(DKIMError::KeySyntaxError)
+        );
+
+        // Malformed keys are permanent failures, unlike the DNS ones
+        let err = parse_key_record("v=DKIM1; p=!!!").unwrap_err();
+        assert_eq!(err, DKIMError::KeySyntaxError);
+        assert_eq!(err.classification(), crate::ErrorClass::Permfail);
+        let record = parse_key_record("v=DKIM1; k=ed25519; p=YQ==").unwrap();
+        assert_eq!(record.public_key(), Err(DKIMError::KeySyntaxError));
+        assert_eq!(
+            parse_key_record("v=DKIM1; k=dsa; p=YQ=="),
+            Err(DKIMError::InappropriateKeyAlgorithm)
+        );
+    }
+
+    #[test]
+    fn test_parse_key_record_lenient() {
+        let public_key = std::fs::read_to_string("./test/keys/ed.public").unwrap();
+        let expected = parse_key_record(&format!("v=DKIM1; k=ed25519; p={}", public_key.trim()))
+            .unwrap()
+            .public_key()
+            .unwrap();
+
+        let fixtures = std::fs::read_to_string("./test/keys/recoverable-records.txt").unwrap();
+        for txt in fixtures.lines().filter(|line| !line.starts_with('#')) {
+            assert!(parse_key_record(txt).is_err(), "{:?}", txt);
+            let record = parse_key_record_lenient(txt).unwrap();
+            assert_eq!(record.public_key().unwrap(), expected, "{:?}", txt);
+        }
+
+        // Spaces and tabs around tags and inside values are folding
+        // whitespace, accepted in both modes
+        let txt = "v=DKIM1 ;\tk = ed25519; p = 11qYAYKxCrfVS/7TyWQHOg 7hcvPapiMlrwIaaPcHURo=\t;";
+        assert_eq!(
+            parse_key_record(txt).unwrap().public_key().unwrap(),
+            expected
+        );
+        assert_eq!(
+            parse_key_record_lenient(txt).unwrap(),
+            parse_key_record(txt).unwrap()
+        );
+
+        // Still not a key record
+        assert_eq!(
+            parse_key_record_lenient("v=DKIM1; k=ed25519"),
+            Err(DKIMError::NoKeyForSignature)
+        );
+        assert_eq!(
+            parse_key_record_lenient("v=DKIM1; p"),
+            Err(DKIMError::KeySyntaxError)
+        );
+    }
+
+    #[test]
+    fn test_to_dns_record() {
+        use rsa::pkcs1::DecodeRsaPrivateKey;
+
+        let private_key =
+            rsa::RsaPrivateKey::read_pkcs1_pem_file("./test/keys/2022.private").unwrap();
+        let public_key = DkimPublicKey::Rsa(private_key.to_public_key());
+        let options = DnsRecordOptions {
+            hash_algorithms: vec!["sha256".to_owned()],
+            testing: true,
+            strict: true,
+            ..Default::default()
+        };
+        // Same as the record generated by opendkim-genkey
+        let data = std::fs::read_to_string("./test/keys/2022.txt").unwrap();
+        let expected: String = data.split('"').skip(1).step_by(2).collect();
+        assert_eq!(to_dns_record(&public_key, &options), expected);
+
+        let public_key = std::fs::read_to_string("./test/keys/ed.public").unwrap();
+        let bytes = general_purpose::STANDARD.decode(public_key.trim()).unwrap();
+        let public_key = DkimPublicKey::try_from_bytes(&bytes, "ed25519").unwrap();
+        let options = DnsRecordOptions {
+            service_types: vec!["email".to_owned()],
+            notes: Some("rotated; 2018".to_owned()),
+            ..Default::default()
+        };
+        assert_eq!(
+            to_dns_record(&public_key, &options),
+            "v=DKIM1; k=ed25519; s=email; n=rotated=3B=202018; p=11qYAYKxCrfVS/7TyWQHOg7hcvPapiMlrwIaaPcHURo="
+        );
     }
 }
@@ -1,13 +1,192 @@
-use crate::{canonicalization, DKIMError};
+use crate::{canonicalization, AlignmentMode, DKIMError, DKIMHeader, ErrorClass};
+use std::collections::HashMap;
+use std::fmt;
+
+/// Header fields reported by [DKIMResult::unsigned_headers] by default
+pub const DEFAULT_IMPORTANT_HEADERS: &[&str] = &[
+    "from",
+    "sender",
+    "reply-to",
+    "to",
+    "cc",
+    "subject",
+    "date",
+    "message-id",
+    "mime-version",
+    "content-type",
+    "content-transfer-encoding",
+];
+
+/// Important header field present in the message but not, or not in all its
+/// occurrences, covered by the signature. An intermediary can change it
+/// without breaking the signature.
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct UnsignedHeaderWarning {
+    /// Name of the header field, as in the message
+    pub name: String,
+    /// Number of occurrences in the message
+    pub occurrences: usize,
+    /// Number of occurrences covered by the signature
+    pub signed: usize,
+}
+
+impl fmt::Display for UnsignedHeaderWarning {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.signed == 0 {
+            write!(f, "{} was not covered by the DKIM signature", self.name)
+        } else {
+            write!(
+                f,
+                "{} of {} {} headers were not covered by the DKIM signature",
+                self.occurrences - self.signed,
+                self.occurrences,
+                self.name
+            )
+        }
+    }
+}
+
+/// Condition found while verifying a signature which doesn't fail it but may
+/// matter for policy, see [DKIMResult::warnings]
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "serde", serde(tag = "code", rename_all = "snake_case"))]
+pub enum DkimWarning {
+    /// The signature uses rsa-sha1, deprecated by RFC 8301
+    Sha1Signature,
+    /// The RSA key is shorter than 2048 bits
+    WeakRsaKey { bits: usize },
+    /// The signature only covers the first `length` bytes of the
+    /// canonicalized body (l=), `unsigned` bytes follow
+    BodyLengthLimit { length: u64, unsigned: u64 },
+    /// The key record is in testing mode (t=y)
+    TestingKey,
+    /// The signature timestamp (t=) is older than
+    /// [VerificationOptions::signature_age_warning], by `age` seconds
+    ///
+    /// [VerificationOptions::signature_age_warning]: crate::VerificationOptions::signature_age_warning
+    OldSignature { age: i64 },
+    /// h= doesn't list From more times than it occurs, so a From header can
+    /// be added without breaking the signature
+    FromNotOversigned,
+}
+
+impl fmt::Display for DkimWarning {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DkimWarning::Sha1Signature => write!(f, "signature uses rsa-sha1"),
+            DkimWarning::WeakRsaKey { bits } => write!(f, "RSA key of {} bits", bits),
+            DkimWarning::BodyLengthLimit { length, unsigned } => write!(
+                f,
+                "signature covers {} bytes of the body, {} bytes are unsigned",
+                length, unsigned
+            ),
+            DkimWarning::TestingKey => write!(f, "key record in testing mode"),
+            DkimWarning::OldSignature { age } => {
+                write!(f, "signature is {} days old", age / 86400)
+            }
+            DkimWarning::FromNotOversigned => write!(f, "From header is not oversigned"),
+        }
+    }
+}
+
+/// Compare the header fields of the message with the ones listed in h=,
+/// returning the important ones not fully signed and the oversigned ones
+/// (listed more times than they occur, so that adding one breaks the
+/// signature).
+pub(crate) fn header_coverage<S: AsRef<str>>(
+    email: &mailparse::ParsedMail,
+    signed_headers: &[String],
+    important_headers: &[S],
+) -> (Vec<UnsignedHeaderWarning>, Vec<String>) {
+    // Occurrences by lowercase name, with the name of the first one
+    let mut present: HashMap<String, (String, usize)> = HashMap::new();
+    for header in &email.headers {
+        let key = header.get_key_ref();
+        present
+            .entry(key.to_ascii_lowercase())
+            .or_insert_with(|| (key.to_string(), 0))
+            .1 += 1;
+    }
+    let mut signed: HashMap<&str, usize> = HashMap::new();
+    for name in signed_headers {
+        *signed.entry(name.as_str()).or_default() += 1;
+    }
+
+    let mut unsigned = vec![];
+    for name in important_headers {
+        let name = name.as_ref().to_ascii_lowercase();
+        if let Some((key, occurrences)) = present.get(&name) {
+            let signed = signed.get(name.as_str()).copied().unwrap_or_default();
+            if signed < *occurrences {
+                unsigned.push(UnsignedHeaderWarning {
+                    name: key.clone(),
+                    occurrences: *occurrences,
+                    signed,
+                });
+            }
+        }
+    }
+
+    let mut oversigned = vec![];
+    for name in signed_headers {
+        let occurrences = present.get(name).map_or(0, |(_, count)| *count);
+        if signed[name.as_str()] > occurrences && !oversigned.contains(name) {
+            oversigned.push(name.clone());
+        }
+    }
+
+    (unsigned, oversigned)
+}
+
+/// Outcome of the verification, see [DKIMResult::summary]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "serde", serde(rename_all = "snake_case"))]
+pub enum Summary {
+    Pass,
+    /// No signature could be verified
+    Neutral,
+    Fail,
+}
+
+impl fmt::Display for Summary {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+impl Summary {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Summary::Pass => "pass",
+            Summary::Neutral => "neutral",
+            Summary::Fail => "fail",
+        }
+    }
+}
 
 #[derive(Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 /// Result of the DKIM verification
 pub struct DKIMResult {
-    value: &'static str,
+    #[cfg_attr(feature = "serde", serde(rename = "summary"))]
+    value: Summary,
     error: Option<DKIMError>,
     domain_used: String,
+    selector: Option<String>,
+    signature_index: Option<usize>,
+    identity: Option<String>,
+    signature_b: Option<String>,
     header_canonicalization_type: Option<canonicalization::Type>,
     body_canonicalization_type: Option<canonicalization::Type>,
+    alignment_mode: AlignmentMode,
+    key_authentic_data: bool,
+    key_fingerprint: Option<[u8; 32]>,
+    unsigned_headers: Vec<UnsignedHeaderWarning>,
+    oversigned_headers: Vec<String>,
+    warnings: Vec<DkimWarning>,
 }
 impl DKIMResult {
     /// Constructs a `pass` result
@@ -17,45 +196,156 @@ impl DKIMResult {
         body_canonicalization_type: canonicalization::Type,
     ) -> Self {
         DKIMResult {
-            value: "pass",
+            value: Summary::Pass,
             error: None,
             domain_used,
+            selector: None,
+            signature_index: None,
+            identity: None,
+            signature_b: None,
             header_canonicalization_type: Some(header_canonicalization_type),
             body_canonicalization_type: Some(body_canonicalization_type),
+            alignment_mode: AlignmentMode::default(),
+            key_authentic_data: false,
+            key_fingerprint: None,
+            unsigned_headers: vec![],
+            oversigned_headers: vec![],
+            warnings: vec![],
         }
     }
     /// Constructs a `neutral` result
     pub fn neutral(domain_used: String) -> Self {
         DKIMResult {
-            value: "neutral",
+            value: Summary::Neutral,
             error: None,
             domain_used,
+            selector: None,
+            signature_index: None,
+            identity: None,
+            signature_b: None,
             header_canonicalization_type: None,
             body_canonicalization_type: None,
+            alignment_mode: AlignmentMode::default(),
+            key_authentic_data: false,
+            key_fingerprint: None,
+            unsigned_headers: vec![],
+            oversigned_headers: vec![],
+            warnings: vec![],
         }
     }
-    /// Constructs a `fail` result with a reason
+    /// Constructs a `fail` result with a reason. The signature is the one of
+    /// a [DKIMError::SignatureFailed] reason.
     pub fn fail(reason: DKIMError, domain_used: String) -> Self {
+        let (selector, signature_index) = match &reason {
+            DKIMError::SignatureFailed {
+                index, selector, ..
+            } => (Some(selector.clone()), Some(*index)),
+            _ => (None, None),
+        };
         DKIMResult {
-            value: "fail",
+            value: Summary::Fail,
             error: Some(reason),
             domain_used,
+            selector,
+            signature_index,
+            identity: None,
+            signature_b: None,
             header_canonicalization_type: None,
             body_canonicalization_type: None,
+            alignment_mode: AlignmentMode::default(),
+            key_authentic_data: false,
+            key_fingerprint: None,
+            unsigned_headers: vec![],
+            oversigned_headers: vec![],
+            warnings: vec![],
         }
     }
 
+    /// Sets the signature which passed or failed the verification
+    pub(crate) fn with_signature(mut self, index: usize, header: &DKIMHeader) -> Self {
+        self.signature_index = Some(index);
+        self.selector = Some(header.selector().to_owned());
+        self.identity = header.identity().ok().flatten();
+        self.signature_b = header.get_tag("b");
+        self
+    }
+
+    /// Sets the alignment mode used to select the signature
+    pub(crate) fn with_alignment_mode(mut self, alignment_mode: AlignmentMode) -> Self {
+        self.alignment_mode = alignment_mode;
+        self
+    }
+
+    /// Sets whether the resolver set the authentic data bit of the key record
+    pub(crate) fn with_key_authentic_data(mut self, key_authentic_data: bool) -> Self {
+        self.key_authentic_data = key_authentic_data;
+        self
+    }
+
+    /// Sets the fingerprint of the key which validated the signature
+    pub(crate) fn with_key_fingerprint(mut self, key_fingerprint: [u8; 32]) -> Self {
+        self.key_fingerprint = Some(key_fingerprint);
+        self
+    }
+
+    /// Sets the coverage of the header fields by the signature, see
+    /// [header_coverage]
+    pub(crate) fn with_header_coverage(
+        mut self,
+        (unsigned_headers, oversigned_headers): (Vec<UnsignedHeaderWarning>, Vec<String>),
+    ) -> Self {
+        self.unsigned_headers = unsigned_headers;
+        self.oversigned_headers = oversigned_headers;
+        self
+    }
+
+    /// Sets the warnings raised while verifying the signature
+    pub(crate) fn with_warnings(mut self, warnings: Vec<DkimWarning>) -> Self {
+        self.warnings = warnings;
+        self
+    }
+
     pub fn error(&self) -> Option<DKIMError> {
         self.error.clone()
     }
 
+    /// Returns the classification of the error of a `fail` result: a
+    /// [ErrorClass::Tempfail] maps to `temperror` in an Authentication-Results
+    /// header (RFC 8601), a [ErrorClass::Permfail] to `fail` or `permerror`
+    pub fn error_class(&self) -> Option<ErrorClass> {
+        self.error.as_ref().map(DKIMError::classification)
+    }
+
     /// Returns the domain used to pass the DKIM verification
     pub fn domain_used(&self) -> String {
         self.domain_used.to_lowercase()
     }
 
+    /// Returns the selector (s=) of the signature which passed or failed
+    /// the verification
+    pub fn selector(&self) -> Option<&str> {
+        self.selector.as_deref()
+    }
+
+    /// Returns the position of the signature which passed or failed the
+    /// verification among the DKIM-Signature headers
+    pub fn signature_index(&self) -> Option<usize> {
+        self.signature_index
+    }
+
+    /// Returns the agent or user identifier (i=) of the signature which passed
+    /// or failed the verification
+    pub fn identity(&self) -> Option<&str> {
+        self.identity.as_deref()
+    }
+
     /// Returns the verification result as a summary: fail, neutral or pass.
     pub fn summary(&self) -> &'static str {
+        self.value.as_str()
+    }
+
+    /// Returns the verification result as a [Summary]
+    pub fn outcome(&self) -> Summary {
         self.value
     }
 
@@ -69,13 +359,465 @@ impl DKIMResult {
         self.body_canonicalization_type.clone()
     }
 
+    /// Returns the alignment mode used to select the signature
+    pub fn alignment_mode(&self) -> AlignmentMode {
+        self.alignment_mode
+    }
+
+    /// Returns whether the resolver set the authentic data (AD) bit of the key
+    /// record used to pass the verification, see
+    /// [TxtAnswer::authentic_data](crate::dns::TxtAnswer::authentic_data)
+    pub fn key_authentic_data(&self) -> bool {
+        self.key_authentic_data
+    }
+
+    /// Returns the [DkimPublicKey::sha256_fingerprint] of the key used to
+    /// pass the verification
+    ///
+    /// [DkimPublicKey::sha256_fingerprint]: crate::DkimPublicKey::sha256_fingerprint
+    pub fn key_fingerprint(&self) -> Option<[u8; 32]> {
+        self.key_fingerprint
+    }
+
+    /// Returns the important header fields of the message not covered by the
+    /// signature used to pass the verification, see
+    /// [VerificationOptions::important_headers]
+    ///
+    /// [VerificationOptions::important_headers]: crate::VerificationOptions::important_headers
+    pub fn unsigned_headers(&self) -> &[UnsignedHeaderWarning] {
+        &self.unsigned_headers
+    }
+
+    /// Returns the lowercase names of the header fields oversigned by the
+    /// signature used to pass the verification: they can't be added to the
+    /// message without breaking it
+    pub fn oversigned_headers(&self) -> &[String] {
+        &self.oversigned_headers
+    }
+
+    /// Returns the warnings raised while verifying the signature used to
+    /// pass the verification
+    pub fn warnings(&self) -> &[DkimWarning] {
+        &self.warnings
+    }
+
+    /// Returns the result as reported in an Authentication-Results header
+    /// (RFC 8601 section 2.7.1): pass, fail, neutral (the signature couldn't
+    /// be processed), permerror, temperror, or none without signature
+    pub fn authentication_result(&self) -> &'static str {
+        match (&self.value, &self.error) {
+            (Summary::Pass, _) => "pass",
+            (Summary::Fail, Some(err)) => failure_result(err),
+            _ => "none",
+        }
+    }
+
+    /// Formats the result as an Authentication-Results header, see
+    /// [authentication_results]
+    pub fn to_authentication_results(&self, authserv_id: &str) -> String {
+        authentication_results(authserv_id, std::slice::from_ref(self))
+    }
+
+    /// Signing domain (d=) of the signature which passed or failed the
+    /// verification
+    fn signature_domain(&self) -> Option<&str> {
+        match (&self.value, &self.error) {
+            (Summary::Pass, _) => Some(&self.domain_used),
+            (_, Some(DKIMError::SignatureFailed { domain, .. })) => Some(domain),
+            _ => None,
+        }
+    }
+
+    /// The resinfo of an Authentication-Results header: the result, the
+    /// reason of a failure as comment and the properties of the signature
+    fn resinfo(&self) -> String {
+        let mut resinfo = format!("dkim={}", self.authentication_result());
+        if let Some(err) = &self.error {
+            resinfo.push(' ');
+            resinfo.push_str(&comment(&err.root_cause().to_string()));
+        }
+        let identity = self.identity.as_deref();
+        let b = self
+            .signature_b
+            .as_deref()
+            .map(|b| b.get(..HEADER_B_LENGTH).unwrap_or(b));
+        for (property, value) in [
+            ("header.d", self.signature_domain()),
+            ("header.i", identity),
+            ("header.s", self.selector.as_deref()),
+            ("header.b", b),
+        ] {
+            if let Some(value) = value {
+                resinfo.push_str(&format!(" {}={}", property, pvalue(value)));
+            }
+        }
+        resinfo
+    }
+
     /// Similar to `summary` but with detail on fail. Typically used for the
     /// `Authentication-Results` header.
     pub fn with_detail(&self) -> String {
         if let Some(err) = self.error() {
-            format!("{} ({})", self.value, err)
+            format!("{} ({})", self.value.as_str(), err)
         } else {
-            self.value.to_owned()
+            self.value.as_str().to_owned()
         }
     }
 }
+
+/// Number of characters of the signature in header.b (RFC 6008 section 4)
+const HEADER_B_LENGTH: usize = 8;
+
+/// Format an Authentication-Results header with the results of the
+/// signatures of a message:
+///
+/// ```text
+/// Authentication-Results: mx.example.com; dkim=pass header.d=example.com header.s=sel1 header.b=AbCdEfGh
+/// ```
+pub fn authentication_results(authserv_id: &str, results: &[DKIMResult]) -> String {
+    let mut header = format!("Authentication-Results: {}", pvalue(authserv_id));
+    if results.is_empty() {
+        header.push_str("; none");
+    }
+    for result in results {
+        header.push_str("; ");
+        header.push_str(&result.resinfo());
+    }
+    header
+}
+
+/// Result of a failed verification in an Authentication-Results header
+fn failure_result(err: &DKIMError) -> &'static str {
+    use DKIMError::*;
+    match err {
+        SignatureSyntaxError(_)
+        | SignatureTagListError(_)
+        | SignatureMissingRequiredTag(_)
+        | SignatureNotFound(_)
+        | DuplicateTag(_)
+        | IncompatibleVersion
+        | FromFieldNotSigned
+        | UnacceptableSignatureHeader
+        | UnsupportedQueryMethod
+        | UnsupportedCanonicalizationType(_)
+        | UnsupportedHashAlgorithm(_) => "neutral",
+        NoKeyForSignature
+        | InvalidDnsName(_)
+        | KeyRecordTooLarge(_)
+        | LimitExceeded { .. }
+        | KeySyntaxError
+        | KeyIncompatibleVersion
+        | PermanentDnsFailure(_)
+        | MalformedBody
+        | InvalidDigestLength { .. }
+        | FromDomainNotFound(_)
+        | MultipleFromDomains(_)
+        | BuilderError(_)
+        | FailedToSign(_)
+        | PrivateKeyLoadError(_) => "permerror",
+        DomainMismatch
+        | SignatureExpired
+        | SignatureTimestampInFuture
+        | KeyUnauthenticated
+        | HashAlgorithmNotPermittedByKey(_)
+        | InappropriateServiceType(_)
+        | KeyRevoked { .. }
+        | InappropriateKeyAlgorithm
+        | SignatureDidNotVerify
+        | BodyHashDidNotVerify => "fail",
+        KeyUnavailable(_) | TemporaryDnsFailure(_) | UnknownInternalError(_) => "temperror",
+        SelfCheckFailed(err) | SignatureFailed { err, .. } => failure_result(err),
+    }
+}
+
+/// Comment (RFC 5322 section 3.2.2) with the text, quoting the parentheses
+/// and backslashes and replacing the control characters by spaces
+fn comment(text: &str) -> String {
+    let mut out = String::with_capacity(text.len() + 2);
+    out.push('(');
+    for c in text.chars() {
+        match c {
+            '(' | ')' | '\\' => {
+                out.push('\\');
+                out.push(c);
+            }
+            c if c.is_control() => out.push(' '),
+            c => out.push(c),
+        }
+    }
+    out.push(')');
+    out
+}
+
+/// Property value (RFC 8601 section 2.2): a token, an address, or else a
+/// quoted-string
+fn pvalue(value: &str) -> String {
+    // RFC 2045 section 5.1
+    let is_token = |value: &str| {
+        !value.is_empty()
+            && value
+                .chars()
+                .all(|c| c.is_ascii_graphic() && !"()<>@,;:\\\"/[]?=".contains(c))
+    };
+    // dot-atom of RFC 5322 section 3.2.3
+    let is_local_part = |value: &str| {
+        value
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || "!#$%&'*+-/=?^_`{|}~.".contains(c))
+    };
+    let bare = match value.rsplit_once('@') {
+        Some((local_part, domain)) => is_local_part(local_part) && is_token(domain),
+        None => is_token(value),
+    };
+    if bare {
+        return value.to_owned();
+    }
+
+    let mut out = String::with_capacity(value.len() + 2);
+    out.push('"');
+    for c in value.chars() {
+        match c {
+            '"' | '\\' => {
+                out.push('\\');
+                out.push(c);
+            }
+            c if c.is_control() => out.push(' '),
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+    out
+}
+
+/// Rule deciding the outcome of a message from the results of all its
+/// signatures, see [AggregateResult::policy]
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "serde", serde(rename_all = "snake_case"))]
+pub enum PassPolicy {
+    /// Pass if a signature aligned with the From domain passes, fail if one
+    /// failed. Signatures of other domains are ignored.
+    #[default]
+    AnyAligned,
+    /// Pass only if every signature passes
+    All,
+    /// The outcome of the most recent signature (t=). Signatures without
+    /// timestamp are the oldest, the first header wins a tie.
+    Newest,
+}
+
+/// Result of one of the signatures of a message
+#[derive(Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct SignatureResult {
+    pub result: DKIMResult,
+    /// Whether the signing domain is aligned with the From domain, false if
+    /// the signature couldn't be parsed
+    pub aligned: bool,
+    /// Signature timestamp (t=)
+    pub timestamp: Option<i64>,
+}
+
+/// Results of all the signatures of a message, in header order
+#[derive(Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct AggregateResult {
+    signatures: Vec<SignatureResult>,
+}
+
+impl AggregateResult {
+    pub(crate) fn new(signatures: Vec<SignatureResult>) -> Self {
+        AggregateResult { signatures }
+    }
+
+    /// Returns the result of each signature, in header order
+    pub fn signatures(&self) -> &[SignatureResult] {
+        &self.signatures
+    }
+
+    /// Returns the outcome of the message under the given policy, neutral
+    /// without signature to evaluate
+    pub fn policy(&self, policy: PassPolicy) -> Summary {
+        let outcomes = |aligned_only: bool| {
+            self.signatures
+                .iter()
+                .filter(move |s| s.aligned || !aligned_only)
+                .map(|s| s.result.value)
+        };
+        match policy {
+            PassPolicy::AnyAligned => {
+                let mut summary = Summary::Neutral;
+                for outcome in outcomes(true) {
+                    match outcome {
+                        Summary::Pass => return Summary::Pass,
+                        Summary::Fail => summary = Summary::Fail,
+                        Summary::Neutral => {}
+                    }
+                }
+                summary
+            }
+            PassPolicy::All if self.signatures.is_empty() => Summary::Neutral,
+            PassPolicy::All if outcomes(false).all(|o| o == Summary::Pass) => Summary::Pass,
+            PassPolicy::All => Summary::Fail,
+            PassPolicy::Newest => self
+                .signatures
+                .iter()
+                .rev()
+                .max_by_key(|s| s.timestamp)
+                .map_or(Summary::Neutral, |s| s.result.value),
+        }
+    }
+
+    /// Returns the lowercase signing domains of the passing signatures,
+    /// without duplicates
+    pub fn passing_domains(&self) -> Vec<String> {
+        let mut domains = vec![];
+        for result in self.passing() {
+            let domain = result.domain_used();
+            if !domains.contains(&domain) {
+                domains.push(domain);
+            }
+        }
+        domains
+    }
+
+    /// Returns the first passing signature in header order
+    pub fn first_pass(&self) -> Option<&DKIMResult> {
+        self.passing().next()
+    }
+
+    fn passing(&self) -> impl Iterator<Item = &DKIMResult> {
+        self.signatures
+            .iter()
+            .map(|s| &s.result)
+            .filter(|r| r.value == Summary::Pass)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::canonicalization::Type;
+
+    fn header(value: &str) -> DKIMHeader {
+        crate::validate_header(value).unwrap()
+    }
+
+    #[test]
+    fn test_authentication_results() {
+        let signature = header(
+            "v=1; a=rsa-sha256; d=example.com; s=sel1; i=j=3Doe@mail.example.com; h=from; bh=YWJj; b=AbCd/+Ef GhIj",
+        );
+        let pass = DKIMResult::pass("example.com".to_owned(), Type::Relaxed, Type::Relaxed)
+            .with_signature(0, &signature);
+        assert_eq!(
+            pass.to_authentication_results("mx.example.com"),
+            "Authentication-Results: mx.example.com; dkim=pass header.d=example.com header.i=j=oe@mail.example.com header.s=sel1 header.b=\"AbCd/+Ef\""
+        );
+
+        let err = |index, err| DKIMError::SignatureFailed {
+            index,
+            domain: "example.com".to_owned(),
+            selector: "sel1".to_owned(),
+            err: Box::new(err),
+        };
+        let temperror = DKIMResult::fail(
+            err(
+                0,
+                DKIMError::TemporaryDnsFailure("SERVFAIL (code 2)".to_owned()),
+            ),
+            "example.com".to_owned(),
+        );
+        let fail = DKIMResult::fail(
+            err(1, DKIMError::BodyHashDidNotVerify),
+            "example.com".to_owned(),
+        );
+        let neutral = DKIMResult::fail(
+            DKIMError::SignatureSyntaxError("a\\b)\r\n(c".to_owned()),
+            "example.com".to_owned(),
+        );
+        let permerror = DKIMResult::fail(
+            err(2, DKIMError::NoKeyForSignature),
+            "example.com".to_owned(),
+        );
+        assert_eq!(
+            authentication_results("mx.example.com", &[temperror, fail, neutral, permerror]),
+            "Authentication-Results: mx.example.com; \
+             dkim=temperror (temporary DNS failure: SERVFAIL \\(code 2\\)) header.d=example.com header.s=sel1; \
+             dkim=fail (body hash did not verify) header.d=example.com header.s=sel1; \
+             dkim=neutral (signature syntax error: a\\\\b\\)  \\(c); \
+             dkim=permerror (no key for signature) header.d=example.com header.s=sel1"
+        );
+
+        assert_eq!(
+            DKIMResult::neutral("example.com".to_owned()).to_authentication_results("mx"),
+            "Authentication-Results: mx; dkim=none"
+        );
+        assert_eq!(
+            authentication_results("mx example", &[]),
+            "Authentication-Results: \"mx example\"; none"
+        );
+    }
+
+    #[test]
+    fn test_pvalue() {
+        assert_eq!(pvalue("example.com"), "example.com");
+        assert_eq!(pvalue("@example.com"), "@example.com");
+        assert_eq!(pvalue("a.b+c@example.com"), "a.b+c@example.com");
+        assert_eq!(pvalue("a b@example.com"), "\"a b@example.com\"");
+        assert_eq!(pvalue("ab/c="), "\"ab/c=\"");
+        assert_eq!(pvalue("a\"b\\c\n"), "\"a\\\"b\\\\c \"");
+        assert_eq!(pvalue(""), "\"\"");
+    }
+
+    #[test]
+    fn test_aggregate_policy() {
+        let signature = |value: Summary, aligned, timestamp| SignatureResult {
+            result: match value {
+                Summary::Pass => {
+                    DKIMResult::pass("Example.com".to_owned(), Type::Simple, Type::Simple)
+                }
+                Summary::Neutral => DKIMResult::neutral("example.com".to_owned()),
+                Summary::Fail => {
+                    DKIMResult::fail(DKIMError::BodyHashDidNotVerify, "example.com".to_owned())
+                }
+            },
+            aligned,
+            timestamp,
+        };
+
+        let empty = AggregateResult::new(vec![]);
+        for policy in [PassPolicy::AnyAligned, PassPolicy::All, PassPolicy::Newest] {
+            assert_eq!(empty.policy(policy), Summary::Neutral);
+        }
+        assert!(empty.first_pass().is_none());
+
+        let aggregate = AggregateResult::new(vec![
+            signature(Summary::Fail, true, Some(20)),
+            signature(Summary::Pass, false, Some(30)),
+            signature(Summary::Pass, true, None),
+        ]);
+        assert_eq!(aggregate.policy(PassPolicy::AnyAligned), Summary::Pass);
+        assert_eq!(aggregate.policy(PassPolicy::All), Summary::Fail);
+        assert_eq!(aggregate.policy(PassPolicy::Newest), Summary::Pass);
+        assert_eq!(aggregate.passing_domains(), vec!["example.com"]);
+
+        let aggregate = AggregateResult::new(vec![
+            signature(Summary::Pass, false, None),
+            signature(Summary::Fail, true, Some(10)),
+            signature(Summary::Pass, true, Some(10)),
+        ]);
+        assert_eq!(aggregate.policy(PassPolicy::AnyAligned), Summary::Pass);
+        assert_eq!(aggregate.policy(PassPolicy::Newest), Summary::Fail);
+
+        let aggregate = AggregateResult::new(vec![
+            signature(Summary::Pass, false, None),
+            signature(Summary::Fail, true, None),
+        ]);
+        assert_eq!(aggregate.policy(PassPolicy::AnyAligned), Summary::Fail);
+        assert_eq!(aggregate.policy(PassPolicy::Newest), Summary::Pass);
+        let aggregate = AggregateResult::new(vec![signature(Summary::Pass, false, None)]);
+        assert_eq!(aggregate.policy(PassPolicy::AnyAligned), Summary::Neutral);
+        assert_eq!(aggregate.policy(PassPolicy::All), Summary::Pass);
+    }
+}
@@ -1,8 +1,15 @@
 #[cfg(test)]
 mod tests {
     use crate::{
-        dns, verify_email_with_resolver, DKIMError, DKIMResult, DkimPrivateKey, SignerBuilder,
+        canonicalization, dns, public_key, testing, validate_header, verify_all_signatures,
+        verify_batch, verify_email_auto, verify_email_with_options, verify_email_with_resolver,
+        verify_raw_email, AlignmentMode, BatchOptions, DKIMError, DKIMResult, DkimPrivateKey,
+        DkimWarning, FixedClock, HashAlgo, HeaderSelection, KeyCache, MultiSigner, PassPolicy,
+        SignatureProvider, SignerBuilder, Summary, UnsignedHeaderWarning, VerificationOptions,
+        Wrapping,
     };
+    use base64::engine::general_purpose;
+    use base64::Engine;
     use chrono::TimeZone;
     use futures::future::BoxFuture;
     use regex::Regex;
@@ -11,10 +18,6 @@ mod tests {
     use std::path::Path;
     use std::sync::Arc;
 
-    fn test_logger() -> slog::Logger {
-        slog::Logger::root(slog::Discard, slog::o!())
-    }
-
     fn dkim_record() -> String {
         let data = std::fs::read_to_string("./test/keys/2022.txt").unwrap();
         let re = Regex::new(r#"".*""#).unwrap();
@@ -26,27 +29,63 @@ mod tests {
         out
     }
 
+    fn rsa_private_key() -> DkimPrivateKey {
+        DkimPrivateKey::Rsa(
+            rsa::RsaPrivateKey::read_pkcs1_pem_file(Path::new("./test/keys/2022.private")).unwrap(),
+        )
+    }
+
     fn sign(domain: &str, raw_email: &str) -> String {
         let email = mailparse::parse_mail(raw_email.as_bytes()).unwrap();
 
-        let private_key =
-            rsa::RsaPrivateKey::read_pkcs1_pem_file(Path::new("./test/keys/2022.private")).unwrap();
-        let logger = test_logger();
         let time = chrono::Utc.with_ymd_and_hms(2021, 1, 1, 0, 0, 1).unwrap();
 
         let signer = SignerBuilder::new()
             .with_signed_headers(&["From", "Subject"])
             .unwrap()
-            .with_private_key(DkimPrivateKey::Rsa(private_key))
+            .with_private_key(rsa_private_key())
             .with_selector("2022")
-            .with_logger(&logger)
             .with_signing_domain(domain)
             .with_time(time)
             .build()
             .unwrap();
         let header = signer.sign(&email).unwrap();
 
-        format!("{}\n{}", header, raw_email)
+        // The line endings are converted to CRLF on the wire
+        format!("{}\r\n{}", header, raw_email)
+            .replace("\r\n", "\n")
+            .replace('\n', "\r\n")
+    }
+
+    fn ed25519_dkim_record() -> String {
+        let public_key = std::fs::read_to_string("./test/keys/ed.public").unwrap();
+        format!("v=DKIM1; k=ed25519; p={}", public_key.trim())
+    }
+
+    fn sign_ed25519(domain: &str, raw_email: &str) -> String {
+        let email = mailparse::parse_mail(raw_email.as_bytes()).unwrap();
+
+        let file_content = std::fs::read("./test/keys/ed.private").unwrap();
+        let file_decoded = general_purpose::STANDARD.decode(file_content).unwrap();
+        let secret_key = ed25519_dalek::SecretKey::try_from(file_decoded).unwrap();
+        let signing_key = ed25519_dalek::SigningKey::from_bytes(&secret_key);
+
+        let time = chrono::Utc.with_ymd_and_hms(2021, 1, 1, 0, 0, 1).unwrap();
+
+        let signer = SignerBuilder::new()
+            .with_signed_headers(&["From", "Subject"])
+            .unwrap()
+            .with_private_key(DkimPrivateKey::Ed25519(signing_key))
+            .with_header_canonicalization(canonicalization::Type::Relaxed)
+            .with_body_canonicalization(canonicalization::Type::Relaxed)
+            .with_selector("brisbane")
+            .with_signing_domain(domain)
+            .with_time(time)
+            .build()
+            .unwrap();
+        let header = signer.sign(&email).unwrap();
+
+        format!("{}\r\n{}", header, raw_email)
     }
 
     async fn verify(
@@ -54,10 +93,22 @@ mod tests {
         from_domain: &str,
         raw_email: &str,
     ) -> DKIMResult {
-        let logger = test_logger();
         let email = mailparse::parse_mail(raw_email.as_bytes()).unwrap();
 
-        verify_email_with_resolver(&logger, from_domain, &email, resolver)
+        verify_email_with_resolver(from_domain, &email, resolver)
+            .await
+            .unwrap()
+    }
+
+    async fn verify_with_options(
+        resolver: Arc<dyn dns::Lookup>,
+        from_domain: &str,
+        raw_email: &str,
+        options: &VerificationOptions,
+    ) -> DKIMResult {
+        let email = mailparse::parse_mail(raw_email.as_bytes()).unwrap();
+
+        verify_email_with_options(from_domain, &email, resolver, options)
             .await
             .unwrap()
     }
@@ -181,4 +232,2036 @@ sentation" style=3D"width:100%;">
             assert_eq!(res.with_detail(), "pass")
         }
     }
+
+    #[tokio::test]
+    async fn test_roundtrip_relaxed_alignment() {
+        let (private_key, public_key) = testing::generate_keypair(public_key::KeyType::Ed25519);
+        let resolver: Arc<dyn dns::Lookup> = Arc::new(testing::static_lookup(&[(
+            "s1",
+            "cloudflare.com",
+            &public_key,
+        )]));
+        let signed_email = testing::sign_test_message(
+            "Hello Alice\r\n",
+            &[
+                ("Subject", "subject"),
+                ("From", "Sven Sauleau <sven@mail.cloudflare.com>"),
+            ],
+            &private_key,
+            "s1",
+            "cloudflare.com",
+        );
+        let signed_email = String::from_utf8(signed_email).unwrap();
+
+        let res = verify(Arc::clone(&resolver), "mail.cloudflare.com", &signed_email).await;
+        assert_eq!(res.with_detail(), "neutral");
+        assert_eq!(res.alignment_mode(), AlignmentMode::Strict);
+
+        let options = VerificationOptions {
+            alignment: AlignmentMode::Relaxed,
+            ..Default::default()
+        };
+        let res = verify_with_options(
+            Arc::clone(&resolver),
+            "mail.cloudflare.com",
+            &signed_email,
+            &options,
+        )
+        .await;
+        assert_eq!(res.with_detail(), "pass");
+        assert_eq!(res.domain_used(), "cloudflare.com");
+        assert_eq!(res.alignment_mode(), AlignmentMode::Relaxed);
+
+        // A sibling domain sharing the suffix is not aligned
+        let res = verify_with_options(
+            Arc::clone(&resolver),
+            "evilcloudflare.com",
+            &signed_email,
+            &options,
+        )
+        .await;
+        assert_eq!(res.with_detail(), "neutral");
+
+        // The candidate selection agrees with check_alignment, for a parent
+        // From domain and a single label signing domain
+        let resolver = Arc::new(testing::static_lookup(&[
+            ("s1", "mail.cloudflare.com", &public_key),
+            ("s1", "com", &public_key),
+        ]));
+        for (domain, aligned) in [("mail.cloudflare.com", true), ("com", false)] {
+            let signed_email = testing::sign_test_message(
+                "Hello Alice\r\n",
+                &[("From", "Sven Sauleau <sven@cloudflare.com>")],
+                &private_key,
+                "s1",
+                domain,
+            );
+            let email = mailparse::parse_mail(&signed_email).unwrap();
+            let res = verify_all_signatures(
+                "cloudflare.com",
+                &email,
+                Arc::clone(&resolver) as Arc<dyn dns::Lookup>,
+                &options,
+            )
+            .await
+            .unwrap();
+            let signature = &res.signatures()[0];
+            assert_eq!(signature.aligned, aligned, "{}", domain);
+            assert_eq!(
+                crate::check_alignment("cloudflare.com", &signature.result, AlignmentMode::Relaxed)
+                    .is_aligned(),
+                aligned,
+                "{}",
+                domain
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn test_roundtrip_authentic_data() {
+        /// Answers like `test_resolver`, with the authentic data bit if the name
+        /// is signed
+        struct DnssecResolver {
+            inner: Arc<dyn dns::Lookup>,
+            signed: bool,
+        }
+        impl dns::Lookup for DnssecResolver {
+            fn lookup_txt<'a>(
+                &'a self,
+                name: &'a str,
+            ) -> BoxFuture<'a, Result<Vec<String>, DKIMError>> {
+                self.inner.lookup_txt(name)
+            }
+
+            fn lookup_txt_answer<'a>(
+                &'a self,
+                name: &'a str,
+            ) -> BoxFuture<'a, Result<dns::TxtAnswer, dns::LookupError>> {
+                Box::pin(async move {
+                    let answer = self.inner.lookup_txt_answer(name).await?;
+                    Ok(dns::TxtAnswer {
+                        authentic_data: self.signed,
+                        ..answer
+                    })
+                })
+            }
+        }
+
+        let email = r#"Subject: subject
+From: Sven Sauleau <sven@cloudflare.com>
+
+Hello Alice
+"#;
+        let signed_email = sign("cloudflare.com", email);
+        let strict = VerificationOptions {
+            require_authentic_data: true,
+            ..Default::default()
+        };
+
+        for signed in [true, false] {
+            let resolver: Arc<dyn dns::Lookup> = Arc::new(DnssecResolver {
+                inner: test_resolver(map! {
+                    "2022._domainkey.cloudflare.com" => dkim_record()
+                }),
+                signed,
+            });
+
+            let res = verify(Arc::clone(&resolver), "cloudflare.com", &signed_email).await;
+            assert_eq!(res.with_detail(), "pass");
+            assert_eq!(res.key_authentic_data(), signed);
+
+            let res = verify_with_options(resolver, "cloudflare.com", &signed_email, &strict).await;
+            if signed {
+                assert_eq!(res.with_detail(), "pass");
+            } else {
+                assert_eq!(
+                    res.error().as_ref().map(DKIMError::root_cause),
+                    Some(&DKIMError::KeyUnauthenticated)
+                );
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn test_roundtrip_ed25519() {
+        let resolver = test_resolver(map! {
+            "brisbane._domainkey.football.example.com" => ed25519_dkim_record()
+        });
+        let from_domain = "football.example.com";
+        let email = "From: Joe SixPack <joe@football.example.com>\r\nSubject: Is dinner ready?\r\n\r\nHi.\r\n\r\nWe lost the game.  Are you hungry yet?\r\n\r\nJoe.\r\n";
+
+        let signed_email = sign_ed25519(from_domain, email);
+        assert!(signed_email.starts_with("DKIM-Signature: v=1; a=ed25519-sha256;"));
+
+        let res = verify(Arc::clone(&resolver), from_domain, &signed_email).await;
+        assert_eq!(res.with_detail(), "pass");
+
+        let tampered_email = signed_email.replace("hungry", "thirsty");
+        let res = verify(Arc::clone(&resolver), from_domain, &tampered_email).await;
+        assert_eq!(
+            res.with_detail(),
+            "fail (signature 0 (d=football.example.com, s=brisbane): body hash did not verify)"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_roundtrip_expiry() {
+        let resolver = test_resolver(map! {
+            "2022._domainkey.cloudflare.com" => dkim_record()
+        });
+        let from_domain = "cloudflare.com";
+        let raw_email =
+            "Subject: subject\r\nFrom: Sven Sauleau <sven@cloudflare.com>\r\n\r\nHello Alice\r\n";
+        let email = mailparse::parse_mail(raw_email.as_bytes()).unwrap();
+        let time = chrono::Utc.with_ymd_and_hms(2021, 1, 1, 0, 0, 1).unwrap();
+
+        let signer = SignerBuilder::new()
+            .with_signed_headers(&["From", "Subject"])
+            .unwrap()
+            .with_private_key(rsa_private_key())
+            .with_selector("2022")
+            .with_signing_domain(from_domain)
+            .with_time(time)
+            .with_expiry(chrono::Duration::days(7))
+            .build()
+            .unwrap();
+        let header = signer.sign(&email).unwrap();
+        assert!(header.contains("; t=1609459201; x=1610064001;"));
+        let signed_email = format!("{}\r\n{}", header, raw_email);
+
+        let mut options = VerificationOptions::default();
+        options.validation.check_expiration = true;
+
+        options.validation.clock =
+            Arc::new(FixedClock((time + chrono::Duration::days(1)).naive_utc()));
+        let res =
+            verify_with_options(Arc::clone(&resolver), from_domain, &signed_email, &options).await;
+        assert_eq!(res.with_detail(), "pass");
+
+        options.validation.clock =
+            Arc::new(FixedClock((time + chrono::Duration::days(8)).naive_utc()));
+        let res =
+            verify_with_options(Arc::clone(&resolver), from_domain, &signed_email, &options).await;
+        assert_eq!(res.with_detail(), "fail (signature expired)");
+
+        // The x= tag is covered by the signature
+        let tampered_email = signed_email.replace("x=1610064001", "x=1620064001");
+        let res = verify_with_options(
+            Arc::clone(&resolver),
+            from_domain,
+            &tampered_email,
+            &options,
+        )
+        .await;
+        assert_eq!(
+            res.with_detail(),
+            "fail (signature 0 (d=cloudflare.com, s=2022): signature did not verify)"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_roundtrip_without_timestamp() {
+        let resolver = test_resolver(map! {
+            "2022._domainkey.cloudflare.com" => dkim_record()
+        });
+        let from_domain = "cloudflare.com";
+        let raw_email =
+            "Subject: subject\r\nFrom: Sven Sauleau <sven@cloudflare.com>\r\n\r\nHello Alice\r\n";
+        let email = mailparse::parse_mail(raw_email.as_bytes()).unwrap();
+        let signer = SignerBuilder::new()
+            .with_signed_headers(&["From", "Subject"])
+            .unwrap()
+            .with_private_key(rsa_private_key())
+            .with_selector("2022")
+            .with_signing_domain(from_domain)
+            .without_timestamp()
+            .build()
+            .unwrap();
+        let header = signer.sign(&email).unwrap();
+        assert!(!header.contains("; t="));
+        let signed_email = format!("{}\r\n{}", header, raw_email);
+
+        let res = verify(Arc::clone(&resolver), from_domain, &signed_email).await;
+        assert_eq!(res.with_detail(), "pass");
+    }
+
+    #[tokio::test]
+    async fn test_roundtrip_body_length() {
+        let resolver = test_resolver(map! {
+            "2022._domainkey.cloudflare.com" => dkim_record()
+        });
+        let from_domain = "cloudflare.com";
+        let raw_email =
+            "Subject: subject\r\nFrom: Sven Sauleau <sven@cloudflare.com>\r\n\r\nHello Alice\r\n";
+        let email = mailparse::parse_mail(raw_email.as_bytes()).unwrap();
+        let signer = SignerBuilder::new()
+            .with_signed_headers(&["From", "Subject"])
+            .unwrap()
+            .with_private_key(rsa_private_key())
+            .with_selector("2022")
+            .with_signing_domain(from_domain)
+            .with_body_length()
+            .build()
+            .unwrap();
+        let header = signer.sign(&email).unwrap();
+        let signed_email = format!("{}\r\n{}", header, raw_email);
+
+        // A mailing list appends a footer after signing
+        let forwarded_email = format!("{}--\r\nmailing list footer\r\n", signed_email);
+        let res = verify(Arc::clone(&resolver), from_domain, &forwarded_email).await;
+        assert_eq!(res.with_detail(), "pass");
+
+        let tampered_email = signed_email.replace("Hello", "Hallo");
+        let res = verify(Arc::clone(&resolver), from_domain, &tampered_email).await;
+        assert_eq!(
+            res.with_detail(),
+            "fail (signature 0 (d=cloudflare.com, s=2022): body hash did not verify)"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_roundtrip_copied_headers() {
+        let resolver = test_resolver(map! {
+            "2022._domainkey.cloudflare.com" => dkim_record()
+        });
+        let from_domain = "cloudflare.com";
+        let raw_email = "Subject: caf\u{e9} | menu; today\r\nFrom: Sven Sauleau <sven@cloudflare.com>\r\n\r\nHello Alice\r\n";
+        let email = mailparse::parse_mail(raw_email.as_bytes()).unwrap();
+        let signer = SignerBuilder::new()
+            .with_signed_headers(&["From", "Subject"])
+            .unwrap()
+            .with_private_key(rsa_private_key())
+            .with_selector("2022")
+            .with_signing_domain(from_domain)
+            .with_copied_headers(true)
+            .build()
+            .unwrap();
+        let header = signer.sign(&email).unwrap();
+        let signed_email = format!("{}\r\n{}", header, raw_email);
+
+        let res = verify(Arc::clone(&resolver), from_domain, &signed_email).await;
+        assert_eq!(res.with_detail(), "pass");
+
+        let dkim_header = validate_header(header.trim_start_matches("DKIM-Signature: ")).unwrap();
+        assert_eq!(
+            dkim_header.copied_headers().unwrap(),
+            vec![
+                (
+                    "From".to_owned(),
+                    "Sven Sauleau <sven@cloudflare.com>".to_owned()
+                ),
+                ("Subject".to_owned(), "caf\u{e9} | menu; today".to_owned()),
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_roundtrip_oversigning() {
+        let resolver = test_resolver(map! {
+            "2022._domainkey.cloudflare.com" => dkim_record()
+        });
+        let from_domain = "cloudflare.com";
+        let raw_email =
+            "Subject: Hello\r\nFrom: Sven Sauleau <sven@cloudflare.com>\r\n\r\nHello Alice\r\n";
+        let email = mailparse::parse_mail(raw_email.as_bytes()).unwrap();
+        let sign = |oversign: bool| {
+            let mut builder = SignerBuilder::new()
+                .with_signed_headers(&["From", "Subject"])
+                .unwrap()
+                .with_private_key(rsa_private_key())
+                .with_selector("2022")
+                .with_signing_domain(from_domain);
+            if oversign {
+                builder = builder.oversign_signed_headers();
+            }
+            builder.build().unwrap().sign(&email).unwrap()
+        };
+
+        for oversign in [false, true] {
+            let header = sign(oversign);
+            let signed_email = format!("{}\r\n{}", header, raw_email);
+            let res = verify(Arc::clone(&resolver), from_domain, &signed_email).await;
+            assert_eq!(res.with_detail(), "pass");
+            assert!(res.unsigned_headers().is_empty());
+            if oversign {
+                assert_eq!(res.oversigned_headers(), ["from", "subject"]);
+            } else {
+                assert!(res.oversigned_headers().is_empty());
+            }
+
+            // An attacker adds another Subject header after signing
+            let injected_email = format!("Subject: Urgent\r\n{}", signed_email);
+            let res = verify(Arc::clone(&resolver), from_domain, &injected_email).await;
+            if oversign {
+                assert_eq!(res.summary(), "fail");
+            } else {
+                assert_eq!(res.with_detail(), "pass");
+                assert_eq!(
+                    res.unsigned_headers(),
+                    [UnsignedHeaderWarning {
+                        name: "Subject".to_owned(),
+                        occurrences: 2,
+                        signed: 1
+                    }]
+                );
+                assert_eq!(
+                    res.unsigned_headers()[0].to_string(),
+                    "1 of 2 Subject headers were not covered by the DKIM signature"
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn test_canonicalize_signed_email_body() {
+        use canonicalization::Type::{Relaxed, Simple};
+        use sha2::{Digest, Sha256};
+
+        let raw_email = "Subject: Hello\r\nFrom: Sven Sauleau <sven@cloudflare.com>\r\n\r\n  ASCII  art\r\n\tcode  block  \r\n\r\n";
+        let email = mailparse::parse_mail(raw_email.as_bytes()).unwrap();
+
+        for header_canonicalization in [Simple, Relaxed] {
+            for (body_canonicalization, expected) in [
+                (Simple, "  ASCII  art\r\n\tcode  block  \r\n"),
+                (Relaxed, " ASCII art\r\n code block\r\n"),
+            ] {
+                for body_length in [None, Some(5)] {
+                    let mut builder = SignerBuilder::new()
+                        .with_signed_headers(&["From", "Subject"])
+                        .unwrap()
+                        .with_private_key(rsa_private_key())
+                        .with_selector("2022")
+                        .with_signing_domain("cloudflare.com")
+                        .with_header_canonicalization(header_canonicalization.clone())
+                        .with_body_canonicalization(body_canonicalization.clone());
+                    if let Some(length) = body_length {
+                        builder = builder.with_body_length_limit(length);
+                    }
+                    let header = builder.build().unwrap().sign(&email).unwrap();
+                    let signed_email = format!("{}\r\n{}", header, raw_email);
+
+                    let (_, body, _) =
+                        crate::canonicalize_signed_email(signed_email.as_bytes()).unwrap();
+                    let expected = &expected[..body_length.unwrap_or(expected.len())];
+                    assert_eq!(String::from_utf8_lossy(&body), expected);
+
+                    // The body matches the body hash of the signature
+                    let dkim_header = validate_header(header.split_once(": ").unwrap().1).unwrap();
+                    assert_eq!(
+                        dkim_header.body_hash_bytes().unwrap(),
+                        Sha256::digest(&body).to_vec()
+                    );
+
+                    let canonicalized = crate::canonicalize_signed_email_detailed(
+                        signed_email.as_bytes(),
+                        crate::SignatureSelector::Index(0),
+                    )
+                    .unwrap();
+                    assert_eq!(canonicalized.body, body);
+                    assert_eq!(
+                        canonicalized.body_hash,
+                        dkim_header.body_hash_bytes().unwrap()
+                    );
+                    assert_eq!(
+                        canonicalized.signature,
+                        dkim_header.signature_bytes().unwrap()
+                    );
+                    assert_eq!(canonicalized.hash_algo, HashAlgo::RsaSha256);
+                    assert_eq!(canonicalized.header.domain(), "cloudflare.com");
+                    assert_eq!(canonicalized.body_length, body_length.map(|_| body.len()));
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_public_hashes() {
+        use crate::hash::{compute_body_hash_for_signature, compute_headers_hash_for_signature};
+        use sha2::{Digest, Sha256};
+
+        let raw_email = "Subject: Hello\r\nFrom: Sven Sauleau <sven@cloudflare.com>\r\n\r\nHello  Alice\r\n\r\n";
+        let signed_email = sign("cloudflare.com", raw_email);
+        let email = mailparse::parse_mail(signed_email.as_bytes()).unwrap();
+        let canonicalized = crate::canonicalize_signed_email_detailed(
+            signed_email.as_bytes(),
+            crate::SignatureSelector::Index(0),
+        )
+        .unwrap();
+        let dkim_header = &canonicalized.header;
+
+        assert_eq!(
+            compute_body_hash_for_signature(dkim_header, &email).unwrap(),
+            dkim_header.get_required_tag("bh")
+        );
+        let headers_hash = compute_headers_hash_for_signature(dkim_header, &email).unwrap();
+        assert_eq!(
+            headers_hash,
+            Sha256::digest(&canonicalized.headers).to_vec()
+        );
+        let public_key = rsa_private_key().public_key();
+        public_key
+            .verify(HashAlgo::RsaSha256, &headers_hash, &canonicalized.signature)
+            .unwrap();
+    }
+
+    #[test]
+    fn test_partial_body_hash() {
+        let raw_email = format!(
+            "Subject: Hello\r\nFrom: Sven Sauleau <sven@cloudflare.com>\r\n\r\n{}",
+            "Hello Alice, this is a line of the body\r\n".repeat(5)
+        );
+        let signed_email = sign("cloudflare.com", &raw_email);
+        let canonicalized = crate::canonicalize_signed_email_detailed(
+            signed_email.as_bytes(),
+            crate::SignatureSelector::Index(0),
+        )
+        .unwrap();
+        assert_eq!(canonicalized.body.len(), 205);
+
+        for (cut_offset, hashed_len) in [
+            (0, 0),
+            (63, 0),
+            (64, 64),
+            (150, 128),
+            (205, 192),
+            (1000, 192),
+        ] {
+            let partial = crate::hash::partial_body_hash(&canonicalized.body, cut_offset);
+            assert_eq!(partial.hashed_len, hashed_len);
+            assert_eq!(
+                partial.remaining,
+                &canonicalized.body[hashed_len as usize..]
+            );
+            assert_eq!(partial.finalize().to_vec(), canonicalized.body_hash);
+        }
+    }
+
+    #[test]
+    fn test_canonicalize_signed_email_for() {
+        use crate::{canonicalize_signed_email_for, list_signatures, SignatureSelector};
+
+        let raw_email =
+            "Subject: Hello\r\nFrom: Sven Sauleau <sven@cloudflare.com>\r\n\r\nHello Alice\r\n";
+        let signed_email = sign_ed25519("football.example.com", &sign("cloudflare.com", raw_email));
+        let signed_email = format!("DKIM-Signature: invalid\r\n{}", signed_email);
+
+        assert_eq!(
+            list_signatures(signed_email.as_bytes()).unwrap(),
+            vec![
+                crate::SignatureSummary {
+                    index: 1,
+                    domain: "football.example.com".to_owned(),
+                    selector: "brisbane".to_owned(),
+                    algorithm: Some(HashAlgo::Ed25519Sha256),
+                },
+                crate::SignatureSummary {
+                    index: 2,
+                    domain: "cloudflare.com".to_owned(),
+                    selector: "2022".to_owned(),
+                    algorithm: Some(HashAlgo::RsaSha256),
+                },
+            ]
+        );
+
+        let canonicalize =
+            |selector| canonicalize_signed_email_for(signed_email.as_bytes(), selector);
+        for selector in [
+            SignatureSelector::Index(2),
+            SignatureSelector::Domain("Cloudflare.com"),
+            SignatureSelector::Selector("2022"),
+            SignatureSelector::DomainAndSelector("cloudflare.com", "2022"),
+        ] {
+            let (header, _, signature, dkim_header) = canonicalize(selector).unwrap();
+            assert_eq!(dkim_header.domain(), "cloudflare.com");
+            assert_eq!(signature, dkim_header.signature_bytes().unwrap());
+            assert!(header.starts_with(b"From: Sven Sauleau <sven@cloudflare.com>\r\n"));
+        }
+        let (_, body, _, dkim_header) = canonicalize(SignatureSelector::Index(1)).unwrap();
+        assert_eq!(dkim_header.selector(), "brisbane");
+        assert_eq!(body, b"Hello Alice\r\n");
+
+        assert_eq!(
+            canonicalize(SignatureSelector::DomainAndSelector(
+                "cloudflare.com",
+                "brisbane"
+            ))
+            .unwrap_err(),
+            DKIMError::SignatureNotFound("domain cloudflare.com and selector brisbane".to_owned())
+        );
+        assert_eq!(
+            canonicalize(SignatureSelector::Index(3)).unwrap_err(),
+            DKIMError::SignatureNotFound("index 3".to_owned())
+        );
+        // The invalid signature is reported when asked for
+        assert!(matches!(
+            canonicalize(SignatureSelector::Index(0)),
+            Err(DKIMError::SignatureTagListError(_))
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_resolve_public_keys() {
+        use crate::resolve_public_keys;
+
+        let raw_email =
+            "Subject: Hello\r\nFrom: Sven Sauleau <sven@cloudflare.com>\r\n\r\nHello Alice\r\n";
+        let signed_email = sign_ed25519("football.example.com", &sign("cloudflare.com", raw_email));
+        let signed_email = format!("DKIM-Signature: invalid\r\n{}", signed_email);
+        let mut lookup = dns::StaticLookup::default();
+        lookup.insert("2022._domainkey.cloudflare.com", &dkim_record());
+
+        let keys = resolve_public_keys(signed_email.as_bytes(), Arc::new(lookup))
+            .await
+            .unwrap();
+        assert_eq!(keys.len(), 2);
+        assert_eq!(
+            (keys[0].0.index, keys[0].0.domain.as_str()),
+            (1, "football.example.com")
+        );
+        assert_eq!(keys[0].1, Err(DKIMError::NoKeyForSignature));
+        assert_eq!(keys[1].0.domain, "cloudflare.com");
+        assert_eq!(
+            keys[1].1,
+            Ok(crate::DkimPublicKey::from_dns_txt(&dkim_record()).unwrap())
+        );
+
+        // No DKIM-Signature header
+        let keys = resolve_public_keys(raw_email.as_bytes(), test_resolver(HashMap::new()))
+            .await
+            .unwrap();
+        assert!(keys.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_roundtrip_unsigned_headers() {
+        let resolver = test_resolver(map! {
+            "2022._domainkey.cloudflare.com" => dkim_record()
+        });
+        let from_domain = "cloudflare.com";
+        let raw_email = "Subject: Hello\r\nFrom: Sven Sauleau <sven@cloudflare.com>\r\nTo: Alice <alice@example.com>\r\nX-Mailer: test\r\n\r\nHello Alice\r\n";
+
+        let signed_email = sign(from_domain, raw_email);
+        let res = verify(Arc::clone(&resolver), from_domain, &signed_email).await;
+        assert_eq!(res.with_detail(), "pass");
+        assert_eq!(
+            res.unsigned_headers(),
+            [UnsignedHeaderWarning {
+                name: "To".to_owned(),
+                occurrences: 1,
+                signed: 0
+            }]
+        );
+        assert_eq!(
+            res.unsigned_headers()[0].to_string(),
+            "To was not covered by the DKIM signature"
+        );
+
+        let options = VerificationOptions {
+            important_headers: vec!["X-Mailer".to_owned(), "Subject".to_owned()],
+            ..Default::default()
+        };
+        let res = verify_with_options(resolver, from_domain, &signed_email, &options).await;
+        assert_eq!(res.with_detail(), "pass");
+        assert_eq!(res.unsigned_headers().len(), 1);
+        assert_eq!(res.unsigned_headers()[0].name, "X-Mailer");
+    }
+
+    #[tokio::test]
+    async fn test_roundtrip_extra_tag() {
+        let resolver = test_resolver(map! {
+            "2022._domainkey.cloudflare.com" => dkim_record()
+        });
+        let from_domain = "cloudflare.com";
+        let raw_email =
+            "Subject: Hello\r\nFrom: Sven Sauleau <sven@cloudflare.com>\r\n\r\nHello Alice\r\n";
+        let email = mailparse::parse_mail(raw_email.as_bytes()).unwrap();
+        let header = SignerBuilder::new()
+            .with_signed_headers(&["From", "Subject"])
+            .unwrap()
+            .with_private_key(rsa_private_key())
+            .with_selector("2022")
+            .with_signing_domain(from_domain)
+            .with_extra_tag("zk", "1")
+            .unwrap()
+            .build()
+            .unwrap()
+            .sign(&email)
+            .unwrap();
+        let signed_email = format!("{}\r\n{}", header, raw_email);
+        let res = verify(Arc::clone(&resolver), from_domain, &signed_email).await;
+        assert_eq!(res.with_detail(), "pass");
+
+        // The extra tag is covered by the signature
+        let tampered_email = signed_email.replace("; zk=1;", "; zk=2;");
+        let res = verify(Arc::clone(&resolver), from_domain, &tampered_email).await;
+        assert_eq!(res.summary(), "fail");
+    }
+
+    #[tokio::test]
+    async fn test_roundtrip_folding() {
+        let resolver = test_resolver(map! {
+            "2022._domainkey.cloudflare.com" => dkim_record()
+        });
+        let from_domain = "cloudflare.com";
+        let raw_email =
+            "Subject: Hello\r\nFrom: Sven Sauleau <sven@cloudflare.com>\r\n\r\nHello Alice\r\n";
+        let email = mailparse::parse_mail(raw_email.as_bytes()).unwrap();
+        for canonicalization in [
+            canonicalization::Type::Simple,
+            canonicalization::Type::Relaxed,
+        ] {
+            for line_length in [30, 78] {
+                let header = SignerBuilder::new()
+                    .with_signed_headers(&["From", "Subject", "To", "Date", "Message-ID"])
+                    .unwrap()
+                    .with_private_key(rsa_private_key())
+                    .with_selector("2022")
+                    .with_signing_domain(from_domain)
+                    .with_header_canonicalization(canonicalization.clone())
+                    .with_copied_headers(true)
+                    .with_line_length(line_length)
+                    .build()
+                    .unwrap()
+                    .sign(&email)
+                    .unwrap();
+                assert!(header.contains("\r\n "));
+
+                let signed_email = format!("{}\r\n{}", header, raw_email);
+                let res = verify(Arc::clone(&resolver), from_domain, &signed_email).await;
+                assert_eq!(res.with_detail(), "pass");
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn test_roundtrip_signature_wrapping() {
+        let resolver = test_resolver(map! {
+            "2022._domainkey.cloudflare.com" => dkim_record()
+        });
+        let from_domain = "cloudflare.com";
+        let raw_email =
+            "Subject: Hello\r\nFrom: Sven Sauleau <sven@cloudflare.com>\r\n\r\nHello Alice\r\n";
+        let email = mailparse::parse_mail(raw_email.as_bytes()).unwrap();
+        for canonicalization in [
+            canonicalization::Type::Simple,
+            canonicalization::Type::Relaxed,
+        ] {
+            for wrapping in [Wrapping::None, Wrapping::Columns(72), Wrapping::Columns(10)] {
+                for folding in [true, false] {
+                    let builder = SignerBuilder::new()
+                        .with_signed_headers(&["From", "Subject"])
+                        .unwrap()
+                        .with_private_key(rsa_private_key())
+                        .with_selector("2022")
+                        .with_signing_domain(from_domain)
+                        .with_header_canonicalization(canonicalization.clone())
+                        .with_signature_wrapping(wrapping.clone());
+                    let builder = if folding {
+                        builder
+                    } else {
+                        builder.without_folding()
+                    };
+                    let header = builder.build().unwrap().sign(&email).unwrap();
+
+                    let b_start = header.find(" b=").unwrap();
+                    let b_lines = header[b_start..].split("\r\n").count();
+                    match wrapping {
+                        Wrapping::None => assert_eq!(b_lines, 1),
+                        // 344 characters for a 2048 bits RSA signature
+                        Wrapping::Columns(72) => assert_eq!(b_lines, 5),
+                        _ => assert_eq!(b_lines, 35),
+                    }
+                    if folding {
+                        assert!(header.contains(";\r\n bh="));
+                        assert!(header.contains(";\r\n b="));
+                    }
+
+                    let signed_email = format!("{}\r\n{}", header, raw_email);
+                    let res = verify(Arc::clone(&resolver), from_domain, &signed_email).await;
+                    assert_eq!(res.with_detail(), "pass");
+                }
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn test_roundtrip_recommended_headers() {
+        let resolver = test_resolver(map! {
+            "2022._domainkey.cloudflare.com" => dkim_record()
+        });
+        let from_domain = "cloudflare.com";
+        let raw_email = "Received: from a\r\nTo: alice@example.com\r\nTo: bob@example.com\r\nSubject: Hello\r\nFrom: Sven Sauleau <sven@cloudflare.com>\r\n\r\nHello Alice\r\n";
+        let email = mailparse::parse_mail(raw_email.as_bytes()).unwrap();
+        let header = SignerBuilder::new()
+            .with_header_selection(HeaderSelection::RecommendedPresent)
+            .with_private_key(rsa_private_key())
+            .with_selector("2022")
+            .with_signing_domain(from_domain)
+            .build()
+            .unwrap()
+            .sign(&email)
+            .unwrap();
+        let signed_email = format!("{}\r\n{}", header, raw_email);
+        let res = verify(Arc::clone(&resolver), from_domain, &signed_email).await;
+        assert_eq!(res.with_detail(), "pass");
+
+        // Both To headers are signed
+        let tampered_email = signed_email.replace("bob@", "eve@");
+        let res = verify(Arc::clone(&resolver), from_domain, &tampered_email).await;
+        assert_eq!(res.summary(), "fail");
+    }
+
+    #[tokio::test]
+    async fn test_roundtrip_openssl_keys() {
+        let from_domain = "cloudflare.com";
+        let raw_email =
+            "Subject: Hello\r\nFrom: Sven Sauleau <sven@cloudflare.com>\r\n\r\nHello Alice\r\n";
+        let email = mailparse::parse_mail(raw_email.as_bytes()).unwrap();
+        for path in [
+            "./test/keys/openssl-rsa.pem",
+            "./test/keys/openssl-ed25519.pem",
+        ] {
+            let private_key = DkimPrivateKey::from_file(path).unwrap();
+            let resolver = test_resolver(map! {
+                "openssl._domainkey.cloudflare.com" => private_key.to_dns_record()
+            });
+
+            let header = SignerBuilder::new()
+                .with_signed_headers(&["From", "Subject"])
+                .unwrap()
+                .with_private_key(private_key)
+                .with_selector("openssl")
+                .with_signing_domain(from_domain)
+                .build()
+                .unwrap()
+                .sign(&email)
+                .unwrap();
+            let signed_email = format!("{}\r\n{}", header, raw_email);
+            let res = verify(Arc::clone(&resolver), from_domain, &signed_email).await;
+            assert_eq!(res.with_detail(), "pass");
+        }
+    }
+
+    /// Signs with a local key, like a KMS would
+    struct TestSignatureProvider {
+        private_key: DkimPrivateKey,
+        algorithm: HashAlgo,
+        calls: std::sync::atomic::AtomicUsize,
+    }
+
+    impl SignatureProvider for TestSignatureProvider {
+        fn algorithm(&self) -> HashAlgo {
+            self.algorithm.clone()
+        }
+
+        fn sign<'a>(
+            &'a self,
+            algo: HashAlgo,
+            digest: &'a [u8],
+        ) -> BoxFuture<'a, Result<Vec<u8>, DKIMError>> {
+            self.calls.fetch_add(1, std::sync::atomic::Ordering::SeqCst);
+            Box::pin(async move { self.private_key.sign(algo, digest).await })
+        }
+    }
+
+    #[tokio::test]
+    async fn test_roundtrip_signature_provider() {
+        let from_domain = "cloudflare.com";
+        let raw_email =
+            "Subject: Hello\r\nFrom: Sven Sauleau <sven@cloudflare.com>\r\n\r\nHello Alice\r\n";
+        let email = mailparse::parse_mail(raw_email.as_bytes()).unwrap();
+        let providers = [
+            (rsa_private_key(), HashAlgo::RsaSha256),
+            (rsa_private_key(), HashAlgo::RsaSha1),
+            (
+                DkimPrivateKey::from_file("./test/keys/openssl-ed25519.pem").unwrap(),
+                HashAlgo::Ed25519Sha256,
+            ),
+        ];
+        for (private_key, algorithm) in providers {
+            let resolver = test_resolver(map! {
+                "kms._domainkey.cloudflare.com" => private_key.to_dns_record()
+            });
+            let provider = Arc::new(TestSignatureProvider {
+                private_key,
+                algorithm,
+                calls: std::sync::atomic::AtomicUsize::new(0),
+            });
+
+            let signer = SignerBuilder::new()
+                .with_signed_headers(&["From", "Subject"])
+                .unwrap()
+                .with_signature_provider(Arc::clone(&provider) as Arc<dyn SignatureProvider>)
+                .with_selector("kms")
+                .with_signing_domain(from_domain)
+                .build()
+                .unwrap();
+            assert_eq!(
+                signer.sign(&email).unwrap_err(),
+                DKIMError::FailedToSign(
+                    "signing with a signature provider requires sign_async".to_owned()
+                )
+            );
+
+            let header = signer.sign_async(&email).await.unwrap();
+            assert_eq!(provider.calls.load(std::sync::atomic::Ordering::SeqCst), 1);
+
+            let signed_email = format!("{}\r\n{}", header, raw_email);
+            let res = verify(Arc::clone(&resolver), from_domain, &signed_email).await;
+            assert_eq!(res.with_detail(), "pass");
+        }
+    }
+
+    #[tokio::test]
+    async fn test_roundtrip_key_hash_algorithms() {
+        let from_domain = "cloudflare.com";
+        let raw_email =
+            "Subject: Hello\r\nFrom: Sven Sauleau <sven@cloudflare.com>\r\n\r\nHello Alice\r\n";
+        let email = mailparse::parse_mail(raw_email.as_bytes()).unwrap();
+        let provider = Arc::new(TestSignatureProvider {
+            private_key: rsa_private_key(),
+            algorithm: HashAlgo::RsaSha1,
+            calls: std::sync::atomic::AtomicUsize::new(0),
+        });
+        let header = SignerBuilder::new()
+            .with_signed_headers(&["From", "Subject"])
+            .unwrap()
+            .with_signature_provider(provider)
+            .with_selector("kms")
+            .with_signing_domain(from_domain)
+            .build()
+            .unwrap()
+            .sign_async(&email)
+            .await
+            .unwrap();
+        let signed_email = format!("{}\r\n{}", header, raw_email);
+
+        let record = rsa_private_key().to_dns_record();
+        for (hash_algorithms, expected) in [
+            ("sha1:sha256", "pass"),
+            ("foo:SHA1", "pass"),
+            ("sha256", "fail"),
+            ("foo", "fail"),
+        ] {
+            let resolver = test_resolver(map! {
+                "kms._domainkey.cloudflare.com" => record.replace("k=rsa", &format!("h={}; k=rsa", hash_algorithms))
+            });
+            let res = verify(resolver, from_domain, &signed_email).await;
+            assert_eq!(res.summary(), expected, "h={}", hash_algorithms);
+            if expected == "fail" {
+                assert_eq!(
+                    res.error().as_ref().map(DKIMError::root_cause),
+                    Some(&DKIMError::HashAlgorithmNotPermittedByKey(
+                        "sha1".to_owned()
+                    ))
+                );
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn test_roundtrip_sign_and_attach() {
+        let resolver = test_resolver(map! {
+            "2022._domainkey.cloudflare.com" => dkim_record()
+        });
+        let from_domain = "cloudflare.com";
+        let signer = SignerBuilder::new()
+            .with_signed_headers(&["From", "Subject"])
+            .unwrap()
+            .with_private_key(rsa_private_key())
+            .with_selector("2022")
+            .with_signing_domain(from_domain)
+            .build()
+            .unwrap();
+
+        let raw_email = "Subject: Hello\r\nFrom: Sven Sauleau <sven@cloudflare.com>\r\n\r\nHello Alice  \r\n\r\n\r\n";
+        let signed_email = signer.sign_and_attach(raw_email.as_bytes()).unwrap();
+        let signed_email = String::from_utf8(signed_email).unwrap();
+        assert!(signed_email.starts_with("DKIM-Signature: "));
+        // The original message is kept as is
+        assert!(signed_email.ends_with(&format!("\r\n{}", raw_email)));
+        let res = verify(Arc::clone(&resolver), from_domain, &signed_email).await;
+        assert_eq!(res.with_detail(), "pass");
+
+        let lf_email = "Subject: Hello\nFrom: Sven Sauleau <sven@cloudflare.com>\n\nHello Alice\n";
+        let signed_email = signer.sign_and_attach(lf_email.as_bytes()).unwrap();
+        let signed_email = String::from_utf8(signed_email).unwrap();
+        assert!(!signed_email.replace("\r\n", "").contains('\n'));
+        let res = verify(Arc::clone(&resolver), from_domain, &signed_email).await;
+        assert_eq!(res.with_detail(), "pass");
+    }
+
+    #[tokio::test]
+    async fn test_roundtrip_lf_line_endings() {
+        let resolver = test_resolver(map! {
+            "2022._domainkey.cloudflare.com" => dkim_record()
+        });
+        let from_domain = "cloudflare.com";
+        let lf_email = "Subject: Hello\nFrom: Sven Sauleau <sven@cloudflare.com>\n\nHello Alice\nHow are you?\n";
+        let crlf_email = lf_email.replace('\n', "\r\n");
+        let email = mailparse::parse_mail(lf_email.as_bytes()).unwrap();
+        let builder = || {
+            SignerBuilder::new()
+                .with_signed_headers(&["From", "Subject"])
+                .unwrap()
+                .with_private_key(rsa_private_key())
+                .with_selector("2022")
+                .with_signing_domain(from_domain)
+        };
+
+        // The signature covers the message as converted to CRLF by the MTA
+        let header = builder().build().unwrap().sign(&email).unwrap();
+        let signed_email = format!("{}\r\n{}", header, crlf_email);
+        let res = verify(Arc::clone(&resolver), from_domain, &signed_email).await;
+        assert_eq!(res.with_detail(), "pass");
+
+        let header = builder()
+            .with_line_ending_normalization(false)
+            .build()
+            .unwrap()
+            .sign(&email)
+            .unwrap();
+        let signed_email = format!("{}\r\n{}", header, crlf_email);
+        let res = verify(resolver, from_domain, &signed_email).await;
+        assert_eq!(res.summary(), "fail");
+    }
+
+    #[tokio::test]
+    async fn test_verify_non_utf8_fixtures() {
+        let zone = std::fs::read_to_string("./test/keys/2022.txt").unwrap();
+        let lookup: Arc<dyn dns::Lookup> = Arc::new(
+            dns::StaticLookup::from_zone(&format!("$ORIGIN cloudflare.com.\n{}", zone)).unwrap(),
+        );
+        let public_key = public_key::parse_key_record(&dkim_record())
+            .unwrap()
+            .public_key()
+            .unwrap();
+
+        // Signed by another implementation, with 8-bit headers and bodies
+        for fixture in ["iso-8859-1.eml", "shift-jis.eml"] {
+            let raw_email = std::fs::read(format!("./test/emails/{}", fixture)).unwrap();
+            assert!(std::str::from_utf8(&raw_email).is_err());
+            let email = mailparse::parse_mail(&raw_email).unwrap();
+
+            let res = verify_email_with_resolver("cloudflare.com", &email, Arc::clone(&lookup))
+                .await
+                .unwrap();
+            assert_eq!(res.with_detail(), "pass", "{}", fixture);
+
+            let res =
+                crate::verify_email_with_key("cloudflare.com", &email, public_key.clone(), false)
+                    .unwrap();
+            assert_eq!(res.with_detail(), "pass", "{}", fixture);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_verify_folded_signature() {
+        // Folded as by Gmail, with the b= value continued over indented lines,
+        // signed with the test key
+        let raw_email = std::fs::read("./test/emails/gmail-folded.eml").unwrap();
+        let email = mailparse::parse_mail(&raw_email).unwrap();
+        let resolver = test_resolver(map! {
+            "2022._domainkey.gmail.com" => dkim_record()
+        });
+        let res = verify_email_with_resolver("gmail.com", &email, resolver)
+            .await
+            .unwrap();
+        assert_eq!(res.with_detail(), "pass");
+
+        let public_key = rsa_private_key().public_key();
+        let res = crate::verify_email_with_key("gmail.com", &email, public_key, false).unwrap();
+        assert_eq!(res.with_detail(), "pass");
+
+        let (_, _, signature) = crate::canonicalize_signed_email(&raw_email).unwrap();
+        assert_eq!(signature.len(), 256);
+    }
+
+    #[tokio::test]
+    async fn test_verify_simple_unusual_folding() {
+        // Signed with c=simple/simple outside of cfdkim, with headers without
+        // space after the colon, with a tab after it and folded with tabs and
+        // spaces, hashed as they appear in the message
+        let raw_email = std::fs::read("./test/emails/simple-unusual-folding.eml").unwrap();
+        let email = mailparse::parse_mail(&raw_email).unwrap();
+        let resolver = test_resolver(map! {
+            "2022._domainkey.example.com" => dkim_record()
+        });
+        let res = verify_email_with_resolver("example.com", &email, resolver)
+            .await
+            .unwrap();
+        assert_eq!(res.with_detail(), "pass");
+
+        // Respacing a header breaks the signature
+        let respaced = String::from_utf8(raw_email)
+            .unwrap()
+            .replace("Subject:no-space", "Subject: no-space");
+        let email = mailparse::parse_mail(respaced.as_bytes()).unwrap();
+        let public_key = rsa_private_key().public_key();
+        let res = crate::verify_email_with_key("example.com", &email, public_key, false).unwrap();
+        assert_eq!(res.summary(), "fail");
+    }
+
+    #[tokio::test]
+    async fn test_verify_limits() {
+        use crate::Limits;
+        use futures::StreamExt;
+
+        let raw_email = std::fs::read("./test/emails/simple-unusual-folding.eml").unwrap();
+        let email = mailparse::parse_mail(&raw_email).unwrap();
+        let resolver = test_resolver(map! {
+            "2022._domainkey.example.com" => dkim_record()
+        });
+        let options = VerificationOptions {
+            limits: Limits {
+                max_headers: Some(3),
+                ..Limits::default()
+            },
+            ..Default::default()
+        };
+        let exceeded = DKIMError::LimitExceeded {
+            limit: "max_headers",
+            max: 3,
+            actual: 4,
+        };
+        assert_eq!(
+            verify_email_with_options("example.com", &email, Arc::clone(&resolver), &options)
+                .await
+                .err(),
+            Some(exceeded.clone())
+        );
+        assert_eq!(
+            verify_all_signatures("example.com", &email, Arc::clone(&resolver), &options)
+                .await
+                .err(),
+            Some(exceeded.clone())
+        );
+        let batch_options = BatchOptions {
+            verification: options,
+            ..Default::default()
+        };
+        let results: Vec<_> = verify_batch(
+            futures::stream::iter(vec![raw_email.clone()]),
+            Arc::clone(&resolver),
+            &batch_options,
+        )
+        .collect()
+        .await;
+        assert_eq!(results[0].1.as_ref().err(), Some(&exceeded));
+
+        let options = VerificationOptions {
+            limits: Limits::unlimited(),
+            ..Default::default()
+        };
+        let res = verify_email_with_options("example.com", &email, Arc::clone(&resolver), &options)
+            .await
+            .unwrap();
+        assert_eq!(res.with_detail(), "pass");
+
+        // The default limits apply without options
+        let long_header = format!(
+            "X-Long: {}\r\n{}",
+            "a".repeat(64 * 1024),
+            String::from_utf8(raw_email).unwrap()
+        );
+        let email = mailparse::parse_mail(long_header.as_bytes()).unwrap();
+        let public_key = rsa_private_key().public_key();
+        assert!(matches!(
+            crate::verify_email_with_key("example.com", &email, public_key.clone(), false),
+            Err(DKIMError::LimitExceeded {
+                limit: "max_header_length",
+                ..
+            })
+        ));
+        assert!(matches!(
+            verify_raw_email("example.com", long_header.as_bytes(), Arc::clone(&resolver)).await,
+            Err(DKIMError::LimitExceeded {
+                limit: "max_header_length",
+                ..
+            })
+        ));
+
+        // but can be disabled
+        let res = crate::verify_email_with_key_and_limits(
+            "example.com",
+            &email,
+            public_key,
+            false,
+            &Limits::unlimited(),
+        )
+        .unwrap();
+        assert_eq!(res.with_detail(), "pass");
+        let res = crate::verify_raw_email_with_options(
+            "example.com",
+            long_header.as_bytes(),
+            resolver,
+            &options,
+        )
+        .await
+        .unwrap();
+        assert_eq!(res.with_detail(), "pass");
+    }
+
+    #[tokio::test]
+    async fn test_verify_body_hash() {
+        use crate::{verify_body_hash, SignatureSelector};
+
+        let raw_email = std::fs::read("./test/emails/gmail-folded.eml").unwrap();
+        let check = verify_body_hash(&raw_email, SignatureSelector::Index(0)).unwrap();
+        assert!(check.matches);
+        assert_eq!(check.expected, check.computed);
+        assert_eq!(check.expected.len(), 32);
+
+        // The fixture with a word of its body changed
+        let tampered = std::fs::read("./test/emails/gmail-tampered-body.eml").unwrap();
+        let check = verify_body_hash(&tampered, SignatureSelector::Domain("gmail.com")).unwrap();
+        assert!(!check.matches);
+        assert_ne!(check.expected, check.computed);
+        let email = mailparse::parse_mail(&tampered).unwrap();
+        let resolver = test_resolver(map! {
+            "2022._domainkey.gmail.com" => dkim_record()
+        });
+        let res = verify_email_with_resolver("gmail.com", &email, resolver)
+            .await
+            .unwrap();
+        assert_eq!(
+            res.error().map(|err| err.root_cause().clone()),
+            Some(DKIMError::BodyHashDidNotVerify)
+        );
+
+        assert_eq!(
+            verify_body_hash(&tampered, SignatureSelector::Index(1)).unwrap_err(),
+            DKIMError::SignatureNotFound("index 1".to_owned())
+        );
+    }
+
+    #[tokio::test]
+    async fn test_roundtrip_idna() {
+        let (private_key, public_key) = testing::generate_keypair(public_key::KeyType::Ed25519);
+        let lookup: Arc<dyn dns::Lookup> = Arc::new(testing::static_lookup(&[(
+            "s1",
+            "xn--bcher-kva.example",
+            &public_key,
+        )]));
+        for from in ["alice@b\u{fc}cher.example", "alice@xn--bcher-kva.example"] {
+            let signed_email = testing::sign_test_message(
+                "Hello Bob\r\n",
+                &[("From", from), ("Subject", "Hello")],
+                &private_key,
+                "s1",
+                "b\u{fc}cher.example",
+            );
+            assert!(String::from_utf8_lossy(&signed_email).contains("d=xn--bcher-kva.example;"));
+            let email = mailparse::parse_mail(&signed_email).unwrap();
+            assert_eq!(
+                crate::extract_from_domain(&email).unwrap(),
+                "xn--bcher-kva.example"
+            );
+            // The From domain given in either form
+            for from_domain in ["B\u{fc}cher.example", "xn--bcher-kva.example"] {
+                let res = verify_email_with_resolver(from_domain, &email, Arc::clone(&lookup))
+                    .await
+                    .unwrap();
+                assert_eq!(res.with_detail(), "pass", "{} {}", from, from_domain);
+            }
+        }
+    }
+
+    #[test]
+    fn test_roundtrip_canonicalizations() {
+        use canonicalization::Type::{Relaxed, Simple};
+
+        // Folded and with trailing whitespace, so that the canonicalizations
+        // differ
+        let raw_email = "Subject:  Hello\r\n  again \r\nFrom: Sven Sauleau <sven@cloudflare.com>\r\n\r\nHello  Alice \r\n\r\n";
+        let email = mailparse::parse_mail(raw_email.as_bytes()).unwrap();
+        for private_key in [
+            rsa_private_key(),
+            DkimPrivateKey::from_file("./test/keys/openssl-ed25519.pem").unwrap(),
+        ] {
+            for (header, body) in [
+                (Simple, Simple),
+                (Simple, Relaxed),
+                (Relaxed, Simple),
+                (Relaxed, Relaxed),
+            ] {
+                let signer = SignerBuilder::new()
+                    .with_signed_headers(&["From", "Subject"])
+                    .unwrap()
+                    .with_private_key(private_key.clone())
+                    .with_selector("s1")
+                    .with_signing_domain("cloudflare.com")
+                    .with_canonicalization(header.clone(), body.clone())
+                    .build()
+                    .unwrap();
+                let signature = signer.sign(&email).unwrap();
+                assert!(signature.contains(&format!("c={}/{};", header, body)));
+
+                let signed_email = format!("{}\r\n{}", signature, raw_email);
+                let signed_email = mailparse::parse_mail(signed_email.as_bytes()).unwrap();
+                let res = crate::verify_email_with_key(
+                    "cloudflare.com",
+                    &signed_email,
+                    private_key.public_key(),
+                    false,
+                )
+                .unwrap();
+                assert_eq!(res.with_detail(), "pass", "{}/{}", header, body);
+                assert_eq!(res.header_canonicalization_type(), Some(header));
+                assert_eq!(res.body_canonicalization_type(), Some(body));
+            }
+        }
+    }
+
+    #[test]
+    fn test_roundtrip_prepared_signature() {
+        use futures::FutureExt;
+
+        let raw_email =
+            "Subject: Hello\r\nFrom: Sven Sauleau <sven@cloudflare.com>\r\n\r\nHello Alice\r\n";
+        let email = mailparse::parse_mail(raw_email.as_bytes()).unwrap();
+        for private_key in [
+            rsa_private_key(),
+            DkimPrivateKey::from_file("./test/keys/openssl-ed25519.pem").unwrap(),
+        ] {
+            let signer = SignerBuilder::new()
+                .with_signed_headers(&["From", "Subject"])
+                .unwrap()
+                .with_private_key(private_key.clone())
+                .with_selector("2022")
+                .with_signing_domain("cloudflare.com")
+                .with_timestamp(1609459201)
+                .unwrap()
+                .with_self_check(true)
+                .build()
+                .unwrap();
+
+            let prepared = signer.prepare(&email).unwrap();
+            assert!(prepared.header_value_without_signature().ends_with(" b=;"));
+            assert_eq!(
+                prepared.hash_algo(),
+                SignatureProvider::algorithm(&private_key)
+            );
+            // Signed as a KMS would
+            let signature = SignatureProvider::sign(
+                &private_key,
+                prepared.hash_algo(),
+                prepared.data_to_sign(),
+            )
+            .now_or_never()
+            .unwrap()
+            .unwrap();
+            let header = prepared.finalize(&signature).unwrap();
+            assert_eq!(header, signer.sign(&email).unwrap());
+
+            let signed_email = format!("{}\r\n{}", header, raw_email);
+            let signed_email = mailparse::parse_mail(signed_email.as_bytes()).unwrap();
+            let res = crate::verify_email_with_key(
+                "cloudflare.com",
+                &signed_email,
+                private_key.public_key(),
+                false,
+            )
+            .unwrap();
+            assert_eq!(res.with_detail(), "pass");
+
+            let mut wrong_signature = signature;
+            wrong_signature[0] ^= 1;
+            assert!(matches!(
+                signer.prepare(&email).unwrap().finalize(&wrong_signature),
+                Err(DKIMError::SelfCheckFailed(_))
+            ));
+        }
+    }
+
+    #[tokio::test]
+    async fn test_roundtrip_static_lookup() {
+        let zone = std::fs::read_to_string("./test/keys/2022.txt").unwrap();
+        let lookup =
+            dns::StaticLookup::from_zone(&format!("$ORIGIN cloudflare.com.\n{}", zone)).unwrap();
+        let from_domain = "cloudflare.com";
+        let raw_email =
+            "Subject: Hello\r\nFrom: Sven Sauleau <sven@cloudflare.com>\r\n\r\nHello Alice\r\n";
+
+        let signed_email = sign(from_domain, raw_email);
+        let lookup: Arc<dyn dns::Lookup> = Arc::new(lookup);
+        let res = verify(Arc::clone(&lookup), from_domain, &signed_email).await;
+        assert_eq!(res.with_detail(), "pass");
+        assert_eq!(
+            res.key_fingerprint(),
+            Some(rsa_private_key().public_key().sha256_fingerprint())
+        );
+
+        // From the raw bytes, as on wasm32
+        let res = verify_raw_email(from_domain, signed_email.as_bytes(), lookup)
+            .await
+            .unwrap();
+        assert_eq!(res.with_detail(), "pass");
+    }
+
+    #[tokio::test]
+    async fn test_roundtrip_from_domain() {
+        let resolver = test_resolver(map! {
+            "2022._domainkey.cloudflare.com" => dkim_record()
+        });
+        let raw_email = "Subject: Hello\r\nFrom: \"sven@example.com\" <sven@Cloudflare.com>\r\n\r\nHello Alice\r\n";
+
+        let signed_email = sign("cloudflare.com", raw_email);
+        let email = mailparse::parse_mail(signed_email.as_bytes()).unwrap();
+        let res = verify_email_auto(&email, Arc::clone(&resolver))
+            .await
+            .unwrap();
+        assert_eq!(res.with_detail(), "pass");
+
+        let signed_email = sign(
+            "cloudflare.com",
+            &raw_email.replace(
+                "<sven@Cloudflare.com>",
+                "<sven@cloudflare.com>, bob@example.com",
+            ),
+        );
+        let email = mailparse::parse_mail(signed_email.as_bytes()).unwrap();
+        assert!(matches!(
+            verify_email_auto(&email, resolver).await,
+            Err(DKIMError::MultipleFromDomains(_))
+        ));
+    }
+
+    /// [test_resolver] counting the queries
+    fn counting_resolver(
+        db: HashMap<&'static str, String>,
+    ) -> (Arc<dyn dns::Lookup>, Arc<std::sync::atomic::AtomicUsize>) {
+        struct CountingResolver {
+            inner: Arc<dyn dns::Lookup>,
+            queries: Arc<std::sync::atomic::AtomicUsize>,
+        }
+        impl dns::Lookup for CountingResolver {
+            fn lookup_txt<'a>(
+                &'a self,
+                name: &'a str,
+            ) -> BoxFuture<'a, Result<Vec<String>, DKIMError>> {
+                self.queries
+                    .fetch_add(1, std::sync::atomic::Ordering::SeqCst);
+                self.inner.lookup_txt(name)
+            }
+        }
+        let queries = Arc::new(std::sync::atomic::AtomicUsize::new(0));
+        let resolver = Arc::new(CountingResolver {
+            inner: test_resolver(db),
+            queries: Arc::clone(&queries),
+        });
+        (resolver, queries)
+    }
+
+    #[tokio::test]
+    async fn test_roundtrip_key_cache() {
+        use std::sync::atomic::Ordering;
+
+        let (resolver, queries) = counting_resolver(map! {
+            "2022._domainkey.cloudflare.com" => dkim_record()
+        });
+        let from_domain = "cloudflare.com";
+        let raw_email =
+            "Subject: Hello\r\nFrom: Sven Sauleau <sven@cloudflare.com>\r\n\r\nHello Alice\r\n";
+        let signed_email = sign(from_domain, raw_email);
+        let cache = Arc::new(KeyCache::new(16));
+        let options = VerificationOptions {
+            key_cache: Some(Arc::clone(&cache)),
+            ..Default::default()
+        };
+
+        for _ in 0..3 {
+            let res =
+                verify_with_options(Arc::clone(&resolver), from_domain, &signed_email, &options)
+                    .await;
+            assert_eq!(res.with_detail(), "pass");
+        }
+        assert_eq!(queries.load(Ordering::SeqCst), 1);
+        assert_eq!(cache.len(), 1);
+        let (public_key, _) = cache
+            .retrieve_public_key(Arc::clone(&resolver), "Cloudflare.com", "2022")
+            .await
+            .unwrap();
+        assert_eq!(public_key, rsa_private_key().public_key());
+        assert_eq!(queries.load(Ordering::SeqCst), 1);
+
+        // Keys retrieved with other options aren't shared
+        let strict_options = VerificationOptions {
+            lenient_key_records: false,
+            ..options.clone()
+        };
+        verify_with_options(
+            Arc::clone(&resolver),
+            from_domain,
+            &signed_email,
+            &strict_options,
+        )
+        .await;
+        assert_eq!(queries.load(Ordering::SeqCst), 2);
+        assert_eq!(cache.len(), 2);
+
+        // Looked up again after a rotation
+        cache.invalidate("cloudflare.com", "2022");
+        assert!(cache.is_empty());
+        verify_with_options(Arc::clone(&resolver), from_domain, &signed_email, &options).await;
+        assert_eq!(queries.load(Ordering::SeqCst), 3);
+
+        // And once expired
+        let options = VerificationOptions {
+            key_cache: Some(Arc::new(
+                KeyCache::new(16).with_ttl(chrono::Duration::zero()),
+            )),
+            ..Default::default()
+        };
+        for _ in 0..2 {
+            verify_with_options(Arc::clone(&resolver), from_domain, &signed_email, &options).await;
+        }
+        assert_eq!(queries.load(Ordering::SeqCst), 5);
+    }
+
+    #[tokio::test]
+    async fn test_roundtrip_batch() {
+        use futures::StreamExt;
+
+        let (resolver, queries) = counting_resolver(map! {
+            "2022._domainkey.cloudflare.com" => dkim_record()
+        });
+        let signed = |subject: &str| {
+            sign(
+                "cloudflare.com",
+                &format!(
+                    "Subject: {}\r\nFrom: sven@cloudflare.com\r\n\r\nHello\r\n",
+                    subject
+                ),
+            )
+            .into_bytes()
+        };
+        let messages = vec![
+            signed("First"),
+            b"not an email".to_vec(),
+            signed("Second"),
+            signed("Third"),
+        ];
+        let options = BatchOptions {
+            concurrency: 2,
+            ..Default::default()
+        };
+
+        let mut results: Vec<_> = verify_batch(futures::stream::iter(messages), resolver, &options)
+            .collect()
+            .await;
+        results.sort_by_key(|(index, _)| *index);
+        let summaries: Vec<_> = results
+            .iter()
+            .map(|(index, res)| match res {
+                Ok(res) => (*index, res.with_detail()),
+                Err(err) => (*index, err.to_string()),
+            })
+            .collect();
+        assert_eq!(
+            summaries,
+            [
+                (0, "pass".to_owned()),
+                (
+                    1,
+                    "no From domain: no address in the From header".to_owned()
+                ),
+                (2, "pass".to_owned()),
+                (3, "pass".to_owned()),
+            ]
+        );
+        // The key is looked up once for the batch
+        assert_eq!(queries.load(std::sync::atomic::Ordering::SeqCst), 1);
+    }
+
+    #[tokio::test]
+    #[cfg(feature = "mbox")]
+    async fn test_roundtrip_mbox() {
+        use futures::StreamExt;
+
+        let resolver = test_resolver(map! {
+            "2022._domainkey.cloudflare.com" => dkim_record()
+        });
+        let mbox = std::fs::File::open("./test/emails/batch.mbox").unwrap();
+        let messages = crate::mbox::messages(std::io::BufReader::new(mbox)).map(Result::unwrap);
+
+        let options = BatchOptions::default();
+        let mut results: Vec<_> = verify_batch(futures::stream::iter(messages), resolver, &options)
+            .collect()
+            .await;
+        results.sort_by_key(|(index, _)| *index);
+        let summaries: Vec<_> = results
+            .iter()
+            .map(|(_, res)| match res {
+                Ok(res) => res.summary().to_owned(),
+                Err(err) => err.to_string(),
+            })
+            .collect();
+        assert_eq!(
+            summaries,
+            [
+                "pass",
+                "fail",
+                "neutral",
+                "no From domain: no address in the From header",
+                "pass"
+            ]
+        );
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_roundtrip_concurrent_lookups() {
+        /// Answers like `test_resolver` after a delay depending on the name
+        struct SlowResolver {
+            inner: Arc<dyn dns::Lookup>,
+        }
+        impl dns::Lookup for SlowResolver {
+            fn lookup_txt<'a>(
+                &'a self,
+                name: &'a str,
+            ) -> BoxFuture<'a, Result<Vec<String>, DKIMError>> {
+                let delay = if name.ends_with("example.com") {
+                    300
+                } else {
+                    200
+                };
+                Box::pin(async move {
+                    tokio::time::sleep(std::time::Duration::from_millis(delay)).await;
+                    self.inner.lookup_txt(name).await
+                })
+            }
+        }
+        let (private_key, public_key) = testing::generate_keypair(public_key::KeyType::Rsa);
+        let resolver: Arc<dyn dns::Lookup> = Arc::new(SlowResolver {
+            inner: Arc::new(testing::static_lookup(&[
+                ("s1", "example.com", &public_key),
+                ("s1", "example.net", &public_key),
+            ])),
+        });
+        let headers = [("Subject", "Hello"), ("From", "Alice <alice@example.com>")];
+        let signed_email =
+            testing::sign_test_message("Hello\r\n", &headers, &private_key, "s1", "example.com");
+        let signed_email = SignerBuilder::new()
+            .with_signed_headers(&["From", "Subject"])
+            .unwrap()
+            .with_private_key(private_key)
+            .with_selector("s1")
+            .with_signing_domain("example.net")
+            .build()
+            .unwrap()
+            .sign_and_attach(&signed_email)
+            .unwrap();
+        let email = mailparse::parse_mail(&signed_email).unwrap();
+        let options = VerificationOptions::default();
+
+        // The lookups overlap, taking as long as the slowest one
+        let start = tokio::time::Instant::now();
+        let aggregate = verify_all_signatures("example.com", &email, resolver, &options)
+            .await
+            .unwrap();
+        assert_eq!(start.elapsed(), std::time::Duration::from_millis(300));
+
+        // In the order of the headers
+        let domains: Vec<_> = aggregate
+            .signatures()
+            .iter()
+            .map(|signature| (signature.result.domain_used(), signature.result.summary()))
+            .collect();
+        assert_eq!(
+            domains,
+            [
+                ("example.net".to_owned(), "pass"),
+                ("example.com".to_owned(), "pass")
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_roundtrip_revoked_key() {
+        let resolver = test_resolver(map! {
+            "2022._domainkey.cloudflare.com" => "v=DKIM1; p=".to_owned()
+        });
+        let from_domain = "cloudflare.com";
+        let (private_key, _) = testing::generate_keypair(public_key::KeyType::Rsa);
+        let signed_email = testing::sign_test_message(
+            "Hello Alice\r\n",
+            &[("From", "Sven Sauleau <sven@cloudflare.com>")],
+            &private_key,
+            "2022",
+            from_domain,
+        );
+        let signed_email = String::from_utf8(signed_email).unwrap();
+        let res = verify(resolver, from_domain, &signed_email).await;
+        assert_eq!(res.summary(), "fail");
+        let err = res.error().unwrap().root_cause().clone();
+        assert_eq!(
+            err,
+            DKIMError::KeyRevoked {
+                selector: "2022".to_owned(),
+                domain: "cloudflare.com".to_owned()
+            }
+        );
+        assert!(matches!(err.status(), crate::errors::Status::Permfail));
+        assert_eq!(res.error_class(), Some(crate::ErrorClass::Permfail));
+    }
+
+    #[tokio::test]
+    async fn test_roundtrip_resigning() {
+        let original_resolver = test_resolver(map! {
+            "2022._domainkey.cloudflare.com" => dkim_record()
+        });
+        let forwarder_resolver = test_resolver(map! {
+            "brisbane._domainkey.football.example.com" => ed25519_dkim_record()
+        });
+        let raw_email =
+            "Subject: Hello\r\nFrom: Sven Sauleau <sven@cloudflare.com>\r\n\r\nHello Alice\r\n";
+        let original_signer = SignerBuilder::new()
+            .with_signed_headers(&["From", "Subject"])
+            .unwrap()
+            .with_private_key(rsa_private_key())
+            .with_selector("2022")
+            .with_signing_domain("cloudflare.com")
+            .build()
+            .unwrap();
+        let signed_email = original_signer
+            .sign_and_attach(raw_email.as_bytes())
+            .unwrap();
+
+        let file_content = std::fs::read("./test/keys/ed.private").unwrap();
+        let file_decoded = general_purpose::STANDARD.decode(file_content).unwrap();
+        let secret_key = ed25519_dalek::SecretKey::try_from(file_decoded).unwrap();
+        let forwarder_signer = SignerBuilder::new()
+            .with_signed_headers(&["From", "Subject"])
+            .unwrap()
+            .with_private_key(DkimPrivateKey::Ed25519(
+                ed25519_dalek::SigningKey::from_bytes(&secret_key),
+            ))
+            .with_selector("brisbane")
+            .with_signing_domain("football.example.com")
+            .seal_existing_signatures()
+            .with_oversigning(vec!["DKIM-Signature".to_owned()])
+            .build()
+            .unwrap();
+        let email = mailparse::parse_mail(&signed_email).unwrap();
+        assert_eq!(
+            forwarder_signer.selected_headers(&email).unwrap(),
+            vec!["from", "subject", "dkim-signature", "dkim-signature"]
+        );
+        let resigned_email = forwarder_signer.sign_and_attach(&signed_email).unwrap();
+        let resigned_email = String::from_utf8(resigned_email).unwrap();
+
+        // The existing signature is left untouched
+        let signed_email = String::from_utf8(signed_email).unwrap();
+        assert!(resigned_email.ends_with(&signed_email));
+
+        // Both signatures verify independently
+        let res = verify(original_resolver, "cloudflare.com", &resigned_email).await;
+        assert_eq!(res.with_detail(), "pass");
+        assert_eq!(res.domain_used(), "cloudflare.com");
+        let res = verify(
+            Arc::clone(&forwarder_resolver),
+            "football.example.com",
+            &resigned_email,
+        )
+        .await;
+        assert_eq!(res.with_detail(), "pass");
+        assert_eq!(res.domain_used(), "football.example.com");
+
+        // Removing the sealed signature breaks the new one
+        let forwarder_header = &resigned_email[..resigned_email.len() - signed_email.len()];
+        let stripped_email = format!("{}{}", forwarder_header, raw_email);
+        let res = verify(forwarder_resolver, "football.example.com", &stripped_email).await;
+        assert_eq!(res.summary(), "fail");
+    }
+
+    #[tokio::test]
+    async fn test_roundtrip_generated_dns_record() {
+        let from_domain = "cloudflare.com";
+        let raw_email =
+            "Subject: Hello\r\nFrom: Sven Sauleau <sven@cloudflare.com>\r\n\r\nHello Alice\r\n";
+        let email = mailparse::parse_mail(raw_email.as_bytes()).unwrap();
+        let options = public_key::DnsRecordOptions {
+            hash_algorithms: vec!["sha256".to_owned()],
+            service_types: vec!["email".to_owned()],
+            testing: true,
+            notes: Some("generated".to_owned()),
+            ..Default::default()
+        };
+        for key_type in [public_key::KeyType::Rsa, public_key::KeyType::Ed25519] {
+            let (private_key, _) = testing::generate_keypair(key_type);
+            let record = public_key::to_dns_record(&private_key.public_key(), &options);
+            let resolver = test_resolver(map! {
+                "generated._domainkey.cloudflare.com" => record
+            });
+
+            let header = SignerBuilder::new()
+                .with_signed_headers(&["From", "Subject"])
+                .unwrap()
+                .with_private_key(private_key)
+                .with_selector("generated")
+                .with_signing_domain(from_domain)
+                .build()
+                .unwrap()
+                .sign(&email)
+                .unwrap();
+            let signed_email = format!("{}\r\n{}", header, raw_email);
+            let res = verify(resolver, from_domain, &signed_email).await;
+            assert_eq!(res.with_detail(), "pass");
+        }
+    }
+
+    #[tokio::test]
+    async fn test_roundtrip_multi_signer() {
+        let from_domain = "cloudflare.com";
+        let raw_email =
+            "Subject: Hello\r\nFrom: Sven Sauleau <sven@cloudflare.com>\r\n\r\nHello Alice\r\n";
+        let email = mailparse::parse_mail(raw_email.as_bytes()).unwrap();
+        let time = chrono::Utc.with_ymd_and_hms(2021, 1, 1, 0, 0, 1).unwrap();
+
+        let file_content = std::fs::read("./test/keys/ed.private").unwrap();
+        let file_decoded = general_purpose::STANDARD.decode(file_content).unwrap();
+        let secret_key = ed25519_dalek::SecretKey::try_from(file_decoded).unwrap();
+        let signers = vec![
+            SignerBuilder::new()
+                .with_signed_headers(&["From", "Subject"])
+                .unwrap()
+                .with_private_key(rsa_private_key())
+                .with_selector("2022")
+                .with_signing_domain(from_domain)
+                .with_time(time)
+                .build()
+                .unwrap(),
+            SignerBuilder::new()
+                .with_signed_headers(&["From", "Subject"])
+                .unwrap()
+                .with_private_key(DkimPrivateKey::Ed25519(
+                    ed25519_dalek::SigningKey::from_bytes(&secret_key),
+                ))
+                .with_selector("brisbane")
+                .with_signing_domain(from_domain)
+                .with_time(time)
+                .build()
+                .unwrap(),
+        ];
+        assert!(MultiSigner::new(vec![]).is_err());
+        let multi_signer = MultiSigner::new(signers).unwrap();
+
+        let headers = multi_signer.sign(&email).unwrap();
+        assert_eq!(headers, multi_signer.sign_async(&email).await.unwrap());
+        assert_eq!(headers.len(), 2);
+        assert!(headers[0].contains("a=rsa-sha256;"));
+        assert!(headers[1].contains("a=ed25519-sha256;"));
+
+        // The body hash is shared between the signatures
+        let body_hash = |header: &str| {
+            let header = header.replace("\r\n ", "");
+            let start = header.find("bh=").unwrap();
+            header[start..start + header[start..].find(';').unwrap()].to_owned()
+        };
+        assert_eq!(body_hash(&headers[0]), body_hash(&headers[1]));
+
+        // Each signature verifies against its own key
+        for (header, selector, record) in [
+            (&headers[0], "2022._domainkey.cloudflare.com", dkim_record()),
+            (
+                &headers[1],
+                "brisbane._domainkey.cloudflare.com",
+                ed25519_dkim_record(),
+            ),
+        ] {
+            let resolver = test_resolver(map! { selector => record });
+            let signed_email = format!("{}\r\n{}", header, raw_email);
+            let res = verify(resolver, from_domain, &signed_email).await;
+            assert_eq!(res.with_detail(), "pass");
+        }
+
+        // And together, in order
+        let resolver = test_resolver(map! {
+            "2022._domainkey.cloudflare.com" => dkim_record(),
+            "brisbane._domainkey.cloudflare.com" => ed25519_dkim_record()
+        });
+        let signed_email = format!("{}\r\n{}\r\n{}", headers[0], headers[1], raw_email);
+        let res = verify(resolver, from_domain, &signed_email).await;
+        assert_eq!(res.with_detail(), "pass");
+        assert_eq!(res.selector(), Some("2022"));
+        assert_eq!(res.signature_index(), Some(0));
+        assert!(res
+            .to_authentication_results("mx.cloudflare.com")
+            .starts_with(
+                "Authentication-Results: mx.cloudflare.com; dkim=pass header.d=cloudflare.com header.s=2022 header.b="
+            ));
+
+        // The failure is reported with the signature it was raised for
+        let resolver = test_resolver(map! {
+            "2022._domainkey.cloudflare.com" => "v=DKIM1; k=rsa; p=".to_owned(),
+            "brisbane._domainkey.cloudflare.com" => "v=DKIM1; k=ed25519; p=AAAA".to_owned()
+        });
+        let res = verify(resolver, from_domain, &signed_email).await;
+        assert_eq!(
+            res.with_detail(),
+            "fail (signature 1 (d=cloudflare.com, s=brisbane): key syntax error)"
+        );
+        assert_eq!(res.selector(), Some("brisbane"));
+        assert_eq!(res.signature_index(), Some(1));
+    }
+
+    #[tokio::test]
+    async fn test_roundtrip_aggregate() {
+        let resolver = test_resolver(map! {
+            "2022._domainkey.example.com" => dkim_record(),
+            "brisbane._domainkey.example.net" => "v=DKIM1; k=ed25519; p=".to_owned()
+        });
+        let raw_email = "Subject: Hello\r\nFrom: Alice <alice@example.com>\r\n\r\nHello\r\n";
+        let signed_email = format!(
+            "DKIM-Signature: v=1; a=rsa-sha256\r\n{}",
+            sign_ed25519("example.net", &sign("example.com", raw_email))
+        );
+        let email = mailparse::parse_mail(signed_email.as_bytes()).unwrap();
+        let options = VerificationOptions::default();
+
+        let aggregate = verify_all_signatures("example.com", &email, resolver.clone(), &options)
+            .await
+            .unwrap();
+        let signatures = aggregate.signatures();
+        assert_eq!(signatures.len(), 3);
+        // The malformed header is reported without being attributed to a domain
+        assert_eq!(signatures[0].result.summary(), "fail");
+        assert!(!signatures[0].aligned);
+        assert_eq!(signatures[1].result.selector(), Some("brisbane"));
+        assert!(matches!(
+            signatures[1].result.error().unwrap().root_cause(),
+            DKIMError::KeyRevoked { .. }
+        ));
+        assert!(!signatures[1].aligned);
+        assert_eq!(signatures[2].result.summary(), "pass");
+        assert!(signatures[2].aligned);
+        assert_eq!(signatures[2].timestamp, Some(1609459201));
+
+        assert_eq!(aggregate.policy(PassPolicy::AnyAligned), Summary::Pass);
+        assert_eq!(aggregate.policy(PassPolicy::All), Summary::Fail);
+        // Both signatures have the same timestamp, the first one wins
+        assert_eq!(aggregate.policy(PassPolicy::Newest), Summary::Fail);
+        assert_eq!(aggregate.passing_domains(), vec!["example.com"]);
+        let first_pass = aggregate.first_pass().unwrap();
+        assert_eq!(first_pass.selector(), Some("2022"));
+        assert_eq!(first_pass.signature_index(), Some(2));
+
+        // Only the failing signature is aligned with example.net
+        let aggregate = verify_all_signatures("example.net", &email, resolver, &options)
+            .await
+            .unwrap();
+        assert_eq!(aggregate.policy(PassPolicy::AnyAligned), Summary::Fail);
+        assert_eq!(aggregate.passing_domains(), vec!["example.com"]);
+    }
+
+    #[tokio::test]
+    async fn test_roundtrip_warnings() {
+        let from_domain = "cloudflare.com";
+        let raw_email =
+            "Subject: Hello\r\nFrom: Sven Sauleau <sven@cloudflare.com>\r\n\r\nHello Alice\r\n";
+        let email = mailparse::parse_mail(raw_email.as_bytes()).unwrap();
+        let time = chrono::Utc.with_ymd_and_hms(2021, 1, 1, 0, 0, 1).unwrap();
+        let builder = || {
+            SignerBuilder::new()
+                .with_signed_headers(&["From", "Subject"])
+                .unwrap()
+                .with_selector("2022")
+                .with_signing_domain(from_domain)
+                .with_time(time)
+        };
+
+        // rsa-sha1
+        let provider = Arc::new(TestSignatureProvider {
+            private_key: rsa_private_key(),
+            algorithm: HashAlgo::RsaSha1,
+            calls: std::sync::atomic::AtomicUsize::new(0),
+        });
+        let header = builder()
+            .with_signature_provider(provider)
+            .build()
+            .unwrap()
+            .sign_async(&email)
+            .await
+            .unwrap();
+        let resolver = test_resolver(
+            map! { "2022._domainkey.cloudflare.com" => rsa_private_key().to_dns_record() },
+        );
+        let res = verify(
+            resolver,
+            from_domain,
+            &format!("{}\r\n{}", header, raw_email),
+        )
+        .await;
+        assert_eq!(res.summary(), "pass");
+        assert_eq!(
+            res.warnings(),
+            [DkimWarning::Sha1Signature, DkimWarning::FromNotOversigned]
+        );
+
+        // 1024 bits RSA key
+        let private_key = DkimPrivateKey::from_file("./test/keys/rsa1024.private").unwrap();
+        let resolver = test_resolver(map! {
+            "2022._domainkey.cloudflare.com" => private_key.to_dns_record()
+        });
+        let header = builder()
+            .with_private_key(private_key)
+            .build()
+            .unwrap()
+            .sign(&email)
+            .unwrap();
+        let res = verify(
+            resolver,
+            from_domain,
+            &format!("{}\r\n{}", header, raw_email),
+        )
+        .await;
+        assert_eq!(res.summary(), "pass");
+        assert_eq!(
+            res.warnings(),
+            [
+                DkimWarning::WeakRsaKey { bits: 1024 },
+                DkimWarning::FromNotOversigned
+            ]
+        );
+
+        // Body length, testing key and old signature, with From oversigned
+        let header = builder()
+            .with_private_key(rsa_private_key())
+            .with_body_length()
+            .with_oversigning(vec!["From".to_owned()])
+            .build()
+            .unwrap()
+            .sign(&email)
+            .unwrap();
+        let signed_email = format!("{}\r\n{}Footer\r\n", header, raw_email);
+        let resolver = test_resolver(map! {
+            "2022._domainkey.cloudflare.com" => format!("{}; t=y", dkim_record())
+        });
+        let mut options = VerificationOptions::default();
+        options.validation.clock =
+            Arc::new(FixedClock((time + chrono::Duration::days(40)).naive_utc()));
+        let res =
+            verify_with_options(Arc::clone(&resolver), from_domain, &signed_email, &options).await;
+        assert_eq!(res.summary(), "pass");
+        assert_eq!(
+            res.warnings(),
+            [
+                DkimWarning::BodyLengthLimit {
+                    length: 13,
+                    unsigned: 8
+                },
+                DkimWarning::TestingKey
+            ]
+        );
+
+        options.signature_age_warning = Some(chrono::Duration::days(30));
+        let res = verify_with_options(resolver, from_domain, &signed_email, &options).await;
+        assert_eq!(
+            res.warnings()[2],
+            DkimWarning::OldSignature {
+                age: 40 * 24 * 3600
+            }
+        );
+        assert_eq!(res.warnings()[2].to_string(), "signature is 40 days old");
+    }
 }
@@ -0,0 +1,192 @@
+//! Deserialization of the static strings of the serialized types
+
+use crate::{DKIMError, TagListError};
+use serde::de::{Deserialize, Deserializer, Error};
+
+/// Descriptions of the errors raised by [crate::parse_tag_list]
+const TAG_LIST_ERROR_DESCRIPTIONS: &[&str] = &[
+    "invalid character in tag value",
+    "expected tag name",
+    "empty tag",
+    "invalid tag name",
+    "expected '=' after tag name",
+    "invalid tag value",
+];
+
+/// The static string equal to a value
+fn known(value: &str, known: &[&'static str]) -> Option<&'static str> {
+    known.iter().find(|known| **known == value).copied()
+}
+
+/// The serialized types with static strings can't derive Deserialize, which
+/// would only borrow them from a `'static` input. They're deserialized as a
+/// representation with owned strings, then converted.
+macro_rules! deserialize_from_repr {
+    ($type:ty, $repr:ty) => {
+        impl<'de> Deserialize<'de> for $type {
+            fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+                <$repr>::deserialize(deserializer)?
+                    .try_into()
+                    .map_err(D::Error::custom)
+            }
+        }
+    };
+}
+
+deserialize_from_repr!(TagListError, TagListErrorRepr);
+deserialize_from_repr!(DKIMError, DKIMErrorRepr);
+
+/// [TagListError] as deserialized, with an owned description
+#[derive(serde::Deserialize)]
+struct TagListErrorRepr {
+    offset: usize,
+    snippet: String,
+    description: String,
+}
+
+impl TryFrom<TagListErrorRepr> for TagListError {
+    type Error = String;
+
+    fn try_from(repr: TagListErrorRepr) -> Result<Self, Self::Error> {
+        Ok(TagListError {
+            offset: repr.offset,
+            snippet: repr.snippet,
+            description: known(&repr.description, TAG_LIST_ERROR_DESCRIPTIONS)
+                .ok_or_else(|| format!("unknown tag-list error: {}", repr.description))?,
+        })
+    }
+}
+
+/// [DKIMError] as deserialized, with owned strings in place of the static
+/// ones
+#[derive(serde::Deserialize)]
+#[serde(tag = "code", content = "detail", rename_all = "snake_case")]
+enum DKIMErrorRepr {
+    UnsupportedHashAlgorithm(String),
+    UnsupportedCanonicalizationType(String),
+    SignatureSyntaxError(String),
+    SignatureTagListError(crate::parser::TagListError),
+    SignatureMissingRequiredTag(String),
+    SignatureNotFound(String),
+    DuplicateTag(String),
+    IncompatibleVersion,
+    DomainMismatch,
+    FromFieldNotSigned,
+    FromDomainNotFound(String),
+    MultipleFromDomains(Vec<String>),
+    SignatureExpired,
+    SignatureTimestampInFuture,
+    UnacceptableSignatureHeader,
+    UnsupportedQueryMethod,
+    KeyUnavailable(String),
+    TemporaryDnsFailure(String),
+    PermanentDnsFailure(String),
+    UnknownInternalError(String),
+    NoKeyForSignature,
+    InvalidDnsName(String),
+    KeyRecordTooLarge(usize),
+    LimitExceeded {
+        limit: String,
+        max: usize,
+        actual: usize,
+    },
+    KeySyntaxError,
+    KeyUnauthenticated,
+    HashAlgorithmNotPermittedByKey(String),
+    InappropriateServiceType(String),
+    KeyRevoked {
+        selector: String,
+        domain: String,
+    },
+    KeyIncompatibleVersion,
+    InappropriateKeyAlgorithm,
+    SignatureDidNotVerify,
+    InvalidDigestLength {
+        expected: usize,
+        actual: usize,
+    },
+    BodyHashDidNotVerify,
+    MalformedBody,
+    FailedToSign(String),
+    BuilderError(String),
+    PrivateKeyLoadError(String),
+    SelfCheckFailed(Box<DKIMError>),
+    SignatureFailed {
+        index: usize,
+        domain: String,
+        selector: String,
+        err: Box<DKIMError>,
+    },
+}
+
+impl TryFrom<DKIMErrorRepr> for DKIMError {
+    type Error = String;
+
+    fn try_from(repr: DKIMErrorRepr) -> Result<Self, Self::Error> {
+        use DKIMErrorRepr::*;
+        Ok(match repr {
+            UnsupportedHashAlgorithm(v) => DKIMError::UnsupportedHashAlgorithm(v),
+            UnsupportedCanonicalizationType(v) => DKIMError::UnsupportedCanonicalizationType(v),
+            SignatureSyntaxError(v) => DKIMError::SignatureSyntaxError(v),
+            SignatureTagListError(v) => DKIMError::SignatureTagListError(v),
+            SignatureMissingRequiredTag(name) => DKIMError::SignatureMissingRequiredTag(
+                known(&name, crate::header::REQUIRED_TAGS)
+                    .ok_or_else(|| format!("unknown required tag: {}", name))?,
+            ),
+            SignatureNotFound(v) => DKIMError::SignatureNotFound(v),
+            DuplicateTag(v) => DKIMError::DuplicateTag(v),
+            IncompatibleVersion => DKIMError::IncompatibleVersion,
+            DomainMismatch => DKIMError::DomainMismatch,
+            FromFieldNotSigned => DKIMError::FromFieldNotSigned,
+            FromDomainNotFound(v) => DKIMError::FromDomainNotFound(v),
+            MultipleFromDomains(v) => DKIMError::MultipleFromDomains(v),
+            SignatureExpired => DKIMError::SignatureExpired,
+            SignatureTimestampInFuture => DKIMError::SignatureTimestampInFuture,
+            UnacceptableSignatureHeader => DKIMError::UnacceptableSignatureHeader,
+            UnsupportedQueryMethod => DKIMError::UnsupportedQueryMethod,
+            KeyUnavailable(v) => DKIMError::KeyUnavailable(v),
+            TemporaryDnsFailure(v) => DKIMError::TemporaryDnsFailure(v),
+            PermanentDnsFailure(v) => DKIMError::PermanentDnsFailure(v),
+            UnknownInternalError(v) => DKIMError::UnknownInternalError(v),
+            NoKeyForSignature => DKIMError::NoKeyForSignature,
+            InvalidDnsName(v) => DKIMError::InvalidDnsName(v),
+            KeyRecordTooLarge(v) => DKIMError::KeyRecordTooLarge(v),
+            LimitExceeded { limit, max, actual } => DKIMError::LimitExceeded {
+                limit: known(&limit, crate::limits::LIMIT_NAMES)
+                    .ok_or_else(|| format!("unknown limit: {}", limit))?,
+                max,
+                actual,
+            },
+            KeySyntaxError => DKIMError::KeySyntaxError,
+            KeyUnauthenticated => DKIMError::KeyUnauthenticated,
+            HashAlgorithmNotPermittedByKey(v) => DKIMError::HashAlgorithmNotPermittedByKey(v),
+            InappropriateServiceType(v) => DKIMError::InappropriateServiceType(v),
+            KeyRevoked { selector, domain } => DKIMError::KeyRevoked { selector, domain },
+            KeyIncompatibleVersion => DKIMError::KeyIncompatibleVersion,
+            InappropriateKeyAlgorithm => DKIMError::InappropriateKeyAlgorithm,
+            SignatureDidNotVerify => DKIMError::SignatureDidNotVerify,
+            InvalidDigestLength { expected, actual } => {
+                DKIMError::InvalidDigestLength { expected, actual }
+            }
+            BodyHashDidNotVerify => DKIMError::BodyHashDidNotVerify,
+            MalformedBody => DKIMError::MalformedBody,
+            FailedToSign(v) => DKIMError::FailedToSign(v),
+            BuilderError(err) => {
+                return Err(format!("builder error can't be deserialized: {}", err))
+            }
+            PrivateKeyLoadError(v) => DKIMError::PrivateKeyLoadError(v),
+            SelfCheckFailed(v) => DKIMError::SelfCheckFailed(v),
+            SignatureFailed {
+                index,
+                domain,
+                selector,
+                err,
+            } => DKIMError::SignatureFailed {
+                index,
+                domain,
+                selector,
+                err,
+            },
+        })
+    }
+}
@@ -0,0 +1,530 @@
+//! Serialization of the results and errors, through the JSON values of
+//! [crate::json] as there's no serde format crate among the dependencies
+
+use crate::json::Value;
+use serde::de::value::{MapDeserializer, SeqDeserializer};
+use serde::de::{self, IntoDeserializer, Visitor};
+use serde::ser::{self, Serialize};
+use std::fmt;
+
+#[derive(Debug)]
+pub(crate) struct Error(String);
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.0.fmt(f)
+    }
+}
+
+impl std::error::Error for Error {}
+
+impl ser::Error for Error {
+    fn custom<T: fmt::Display>(msg: T) -> Self {
+        Error(msg.to_string())
+    }
+}
+
+impl de::Error for Error {
+    fn custom<T: fmt::Display>(msg: T) -> Self {
+        Error(msg.to_string())
+    }
+}
+
+/// Serializer into a [Value]
+struct ValueSerializer;
+
+/// Elements of a sequence, in a variant if named
+struct SeqBuilder(Vec<Value>, Option<&'static str>);
+
+/// Members of a map or struct, in a variant if named
+struct MapBuilder {
+    members: Vec<(String, Value)>,
+    key: Option<String>,
+    variant: Option<&'static str>,
+}
+
+fn in_variant(variant: Option<&'static str>, value: Value) -> Value {
+    match variant {
+        Some(variant) => Value::Object(vec![(variant.to_owned(), value)]),
+        None => value,
+    }
+}
+
+impl ser::Serializer for ValueSerializer {
+    type Ok = Value;
+    type Error = Error;
+    type SerializeSeq = SeqBuilder;
+    type SerializeTuple = SeqBuilder;
+    type SerializeTupleStruct = SeqBuilder;
+    type SerializeTupleVariant = SeqBuilder;
+    type SerializeMap = MapBuilder;
+    type SerializeStruct = MapBuilder;
+    type SerializeStructVariant = MapBuilder;
+
+    fn serialize_bool(self, v: bool) -> Result<Value, Error> {
+        Ok(Value::Bool(v))
+    }
+    fn serialize_i8(self, v: i8) -> Result<Value, Error> {
+        self.serialize_f64(v.into())
+    }
+    fn serialize_i16(self, v: i16) -> Result<Value, Error> {
+        self.serialize_f64(v.into())
+    }
+    fn serialize_i32(self, v: i32) -> Result<Value, Error> {
+        self.serialize_f64(v.into())
+    }
+    fn serialize_i64(self, v: i64) -> Result<Value, Error> {
+        self.serialize_f64(v as f64)
+    }
+    fn serialize_u8(self, v: u8) -> Result<Value, Error> {
+        self.serialize_f64(v.into())
+    }
+    fn serialize_u16(self, v: u16) -> Result<Value, Error> {
+        self.serialize_f64(v.into())
+    }
+    fn serialize_u32(self, v: u32) -> Result<Value, Error> {
+        self.serialize_f64(v.into())
+    }
+    fn serialize_u64(self, v: u64) -> Result<Value, Error> {
+        self.serialize_f64(v as f64)
+    }
+    fn serialize_f32(self, v: f32) -> Result<Value, Error> {
+        self.serialize_f64(v.into())
+    }
+    fn serialize_f64(self, v: f64) -> Result<Value, Error> {
+        Ok(Value::Number(v))
+    }
+    fn serialize_char(self, v: char) -> Result<Value, Error> {
+        Ok(Value::String(v.to_string()))
+    }
+    fn serialize_str(self, v: &str) -> Result<Value, Error> {
+        Ok(Value::String(v.to_owned()))
+    }
+    fn serialize_bytes(self, v: &[u8]) -> Result<Value, Error> {
+        v.serialize(self)
+    }
+    fn serialize_none(self) -> Result<Value, Error> {
+        Ok(Value::Null)
+    }
+    fn serialize_some<T: ?Sized + Serialize>(self, value: &T) -> Result<Value, Error> {
+        value.serialize(self)
+    }
+    fn serialize_unit(self) -> Result<Value, Error> {
+        Ok(Value::Null)
+    }
+    fn serialize_unit_struct(self, _name: &'static str) -> Result<Value, Error> {
+        Ok(Value::Null)
+    }
+    fn serialize_unit_variant(
+        self,
+        _name: &'static str,
+        _index: u32,
+        variant: &'static str,
+    ) -> Result<Value, Error> {
+        self.serialize_str(variant)
+    }
+    fn serialize_newtype_struct<T: ?Sized + Serialize>(
+        self,
+        _name: &'static str,
+        value: &T,
+    ) -> Result<Value, Error> {
+        value.serialize(self)
+    }
+    fn serialize_newtype_variant<T: ?Sized + Serialize>(
+        self,
+        _name: &'static str,
+        _index: u32,
+        variant: &'static str,
+        value: &T,
+    ) -> Result<Value, Error> {
+        Ok(in_variant(Some(variant), value.serialize(self)?))
+    }
+    fn serialize_seq(self, _len: Option<usize>) -> Result<SeqBuilder, Error> {
+        Ok(SeqBuilder(vec![], None))
+    }
+    fn serialize_tuple(self, _len: usize) -> Result<SeqBuilder, Error> {
+        Ok(SeqBuilder(vec![], None))
+    }
+    fn serialize_tuple_struct(self, _name: &'static str, _len: usize) -> Result<SeqBuilder, Error> {
+        Ok(SeqBuilder(vec![], None))
+    }
+    fn serialize_tuple_variant(
+        self,
+        _name: &'static str,
+        _index: u32,
+        variant: &'static str,
+        _len: usize,
+    ) -> Result<SeqBuilder, Error> {
+        Ok(SeqBuilder(vec![], Some(variant)))
+    }
+    fn serialize_map(self, _len: Option<usize>) -> Result<MapBuilder, Error> {
+        Ok(MapBuilder {
+            members: vec![],
+            key: None,
+            variant: None,
+        })
+    }
+    fn serialize_struct(self, _name: &'static str, _len: usize) -> Result<MapBuilder, Error> {
+        self.serialize_map(None)
+    }
+    fn serialize_struct_variant(
+        self,
+        _name: &'static str,
+        _index: u32,
+        variant: &'static str,
+        _len: usize,
+    ) -> Result<MapBuilder, Error> {
+        Ok(MapBuilder {
+            members: vec![],
+            key: None,
+            variant: Some(variant),
+        })
+    }
+}
+
+impl SeqBuilder {
+    fn push<T: ?Sized + Serialize>(&mut self, value: &T) -> Result<(), Error> {
+        self.0.push(value.serialize(ValueSerializer)?);
+        Ok(())
+    }
+
+    fn finish(self) -> Result<Value, Error> {
+        Ok(in_variant(self.1, Value::Array(self.0)))
+    }
+}
+
+impl ser::SerializeSeq for SeqBuilder {
+    type Ok = Value;
+    type Error = Error;
+    fn serialize_element<T: ?Sized + Serialize>(&mut self, value: &T) -> Result<(), Error> {
+        self.push(value)
+    }
+    fn end(self) -> Result<Value, Error> {
+        self.finish()
+    }
+}
+
+impl ser::SerializeTuple for SeqBuilder {
+    type Ok = Value;
+    type Error = Error;
+    fn serialize_element<T: ?Sized + Serialize>(&mut self, value: &T) -> Result<(), Error> {
+        self.push(value)
+    }
+    fn end(self) -> Result<Value, Error> {
+        self.finish()
+    }
+}
+
+impl ser::SerializeTupleStruct for SeqBuilder {
+    type Ok = Value;
+    type Error = Error;
+    fn serialize_field<T: ?Sized + Serialize>(&mut self, value: &T) -> Result<(), Error> {
+        self.push(value)
+    }
+    fn end(self) -> Result<Value, Error> {
+        self.finish()
+    }
+}
+
+impl ser::SerializeTupleVariant for SeqBuilder {
+    type Ok = Value;
+    type Error = Error;
+    fn serialize_field<T: ?Sized + Serialize>(&mut self, value: &T) -> Result<(), Error> {
+        self.push(value)
+    }
+    fn end(self) -> Result<Value, Error> {
+        self.finish()
+    }
+}
+
+impl MapBuilder {
+    fn member<T: ?Sized + Serialize>(&mut self, key: &str, value: &T) -> Result<(), Error> {
+        let value = value.serialize(ValueSerializer)?;
+        self.members.push((key.to_owned(), value));
+        Ok(())
+    }
+
+    fn finish(self) -> Result<Value, Error> {
+        Ok(in_variant(self.variant, Value::Object(self.members)))
+    }
+}
+
+impl ser::SerializeMap for MapBuilder {
+    type Ok = Value;
+    type Error = Error;
+    fn serialize_key<T: ?Sized + Serialize>(&mut self, key: &T) -> Result<(), Error> {
+        match key.serialize(ValueSerializer)? {
+            Value::String(key) => self.key = Some(key),
+            key => return Err(Error(format!("non-string key: {:?}", key))),
+        }
+        Ok(())
+    }
+    fn serialize_value<T: ?Sized + Serialize>(&mut self, value: &T) -> Result<(), Error> {
+        let key = self.key.take().unwrap_or_default();
+        self.member(&key, value)
+    }
+    fn end(self) -> Result<Value, Error> {
+        self.finish()
+    }
+}
+
+impl ser::SerializeStruct for MapBuilder {
+    type Ok = Value;
+    type Error = Error;
+    fn serialize_field<T: ?Sized + Serialize>(
+        &mut self,
+        key: &'static str,
+        value: &T,
+    ) -> Result<(), Error> {
+        self.member(key, value)
+    }
+    fn end(self) -> Result<Value, Error> {
+        self.finish()
+    }
+}
+
+impl ser::SerializeStructVariant for MapBuilder {
+    type Ok = Value;
+    type Error = Error;
+    fn serialize_field<T: ?Sized + Serialize>(
+        &mut self,
+        key: &'static str,
+        value: &T,
+    ) -> Result<(), Error> {
+        self.member(key, value)
+    }
+    fn end(self) -> Result<Value, Error> {
+        self.finish()
+    }
+}
+
+impl<'de> IntoDeserializer<'de, Error> for Value {
+    type Deserializer = Value;
+    fn into_deserializer(self) -> Value {
+        self
+    }
+}
+
+impl<'de> de::Deserializer<'de> for Value {
+    type Error = Error;
+
+    fn deserialize_any<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Error> {
+        match self {
+            Value::Null => visitor.visit_unit(),
+            Value::Bool(v) => visitor.visit_bool(v),
+            Value::Number(v) if v.fract() == 0.0 && v >= 0.0 => visitor.visit_u64(v as u64),
+            Value::Number(v) if v.fract() == 0.0 => visitor.visit_i64(v as i64),
+            Value::Number(v) => visitor.visit_f64(v),
+            Value::String(v) => visitor.visit_string(v),
+            Value::Array(values) => visitor.visit_seq(SeqDeserializer::new(values.into_iter())),
+            Value::Object(members) => visitor.visit_map(MapDeserializer::new(members.into_iter())),
+        }
+    }
+
+    fn deserialize_option<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Error> {
+        match self {
+            Value::Null => visitor.visit_none(),
+            value => visitor.visit_some(value),
+        }
+    }
+
+    /// Only unit variants, serialized as strings
+    fn deserialize_enum<V: Visitor<'de>>(
+        self,
+        _name: &'static str,
+        _variants: &'static [&'static str],
+        visitor: V,
+    ) -> Result<V::Value, Error> {
+        match self {
+            Value::String(variant) => visitor.visit_enum(variant.into_deserializer()),
+            value => Err(Error(format!("unsupported enum value: {:?}", value))),
+        }
+    }
+
+    serde::forward_to_deserialize_any! {
+        bool i8 i16 i32 i64 i128 u8 u16 u32 u64 u128 f32 f64 char str string
+        bytes byte_buf unit unit_struct newtype_struct seq tuple
+        tuple_struct map struct identifier ignored_any
+    }
+}
+
+/// Write a value as JSON
+fn to_json(value: &Value) -> String {
+    match value {
+        Value::Null => "null".to_owned(),
+        Value::Bool(v) => v.to_string(),
+        Value::Number(v) => v.to_string(),
+        Value::String(v) => format!("{:?}", v),
+        Value::Array(values) => {
+            let values: Vec<_> = values.iter().map(to_json).collect();
+            format!("[{}]", values.join(","))
+        }
+        Value::Object(members) => {
+            let members: Vec<_> = members
+                .iter()
+                .map(|(key, value)| format!("{:?}:{}", key, to_json(value)))
+                .collect();
+            format!("{{{}}}", members.join(","))
+        }
+    }
+}
+
+fn to_value<T: Serialize>(value: &T) -> Value {
+    value.serialize(ValueSerializer).unwrap()
+}
+
+fn from_value<T: de::DeserializeOwned>(value: Value) -> Result<T, Error> {
+    T::deserialize(value)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::canonicalization::Type;
+    use crate::{DKIMError, DKIMResult, DkimWarning, TagListError, UnsignedHeaderWarning};
+
+    /// Serialize, deserialize and serialize again, returning the JSON
+    fn roundtrip<T: Serialize + de::DeserializeOwned>(value: &T) -> String {
+        let serialized = to_value(value);
+        let deserialized: T = from_value(serialized.clone()).unwrap();
+        assert_eq!(to_value(&deserialized), serialized);
+        to_json(&serialized)
+    }
+
+    #[test]
+    fn test_serde_error() {
+        assert_eq!(
+            roundtrip(&DKIMError::BodyHashDidNotVerify),
+            r#"{"code":"body_hash_did_not_verify"}"#
+        );
+        assert_eq!(
+            roundtrip(&DKIMError::SignatureMissingRequiredTag("bh")),
+            r#"{"code":"signature_missing_required_tag","detail":"bh"}"#
+        );
+        assert_eq!(
+            roundtrip(&DKIMError::KeyRevoked {
+                selector: "s1".to_owned(),
+                domain: "example.com".to_owned()
+            }),
+            r#"{"code":"key_revoked","detail":{"selector":"s1","domain":"example.com"}}"#
+        );
+        assert_eq!(
+            roundtrip(&DKIMError::InvalidDigestLength {
+                expected: 32,
+                actual: 20
+            }),
+            r#"{"code":"invalid_digest_length","detail":{"expected":32,"actual":20}}"#
+        );
+        assert_eq!(
+            roundtrip(&DKIMError::LimitExceeded {
+                limit: "max_headers",
+                max: 1000,
+                actual: 1001
+            }),
+            r#"{"code":"limit_exceeded","detail":{"limit":"max_headers","max":1000,"actual":1001}}"#
+        );
+        assert_eq!(
+            roundtrip(&DKIMError::MultipleFromDomains(vec![
+                "example.com".to_owned(),
+                "example.net".to_owned()
+            ])),
+            r#"{"code":"multiple_from_domains","detail":["example.com","example.net"]}"#
+        );
+
+        let err = DKIMError::SignatureFailed {
+            index: 1,
+            domain: "example.com".to_owned(),
+            selector: "s1".to_owned(),
+            err: Box::new(DKIMError::SignatureTagListError(
+                crate::parse_tag_list("v=1; =2").unwrap_err(),
+            )),
+        };
+        assert_eq!(
+            roundtrip(&err),
+            r#"{"code":"signature_failed","detail":{"index":1,"domain":"example.com","selector":"s1","err":{"code":"signature_tag_list_error","detail":{"offset":5,"snippet":"=2","description":"invalid tag name"}}}}"#
+        );
+        assert_eq!(from_value::<DKIMError>(to_value(&err)).unwrap(), err);
+
+        // Static strings are only deserialized if known
+        assert!(
+            from_value::<DKIMError>(to_value(&DKIMError::SignatureMissingRequiredTag("x")))
+                .is_err()
+        );
+        assert!(from_value::<DKIMError>(to_value(&DKIMError::BuilderError("invalid"))).is_err());
+        let mut err = to_value(&TagListError {
+            offset: 0,
+            snippet: String::new(),
+            description: "empty tag",
+        });
+        assert!(from_value::<TagListError>(err.clone()).is_ok());
+        if let Value::Object(members) = &mut err {
+            members[2].1 = Value::String("unknown".to_owned());
+        }
+        assert!(from_value::<TagListError>(err).is_err());
+    }
+
+    #[test]
+    fn test_serde_result() {
+        let pass = DKIMResult::pass("example.com".to_owned(), Type::Relaxed, Type::Simple)
+            .with_signature(
+                0,
+                &crate::validate_header(
+                    "v=1; a=rsa-sha256; d=example.com; s=s1; i=@example.com; h=from; bh=YWJj; b=YWJj",
+                )
+                .unwrap(),
+            )
+            .with_key_authentic_data(true)
+            .with_key_fingerprint([7; 32])
+            .with_header_coverage((
+                vec![UnsignedHeaderWarning {
+                    name: "Subject".to_owned(),
+                    occurrences: 2,
+                    signed: 1,
+                }],
+                vec!["from".to_owned()],
+            ))
+            .with_warnings(vec![
+                DkimWarning::Sha1Signature,
+                DkimWarning::WeakRsaKey { bits: 1024 },
+            ]);
+        let json = roundtrip(&pass);
+        assert!(json.starts_with(
+            r#"{"summary":"pass","error":null,"domain_used":"example.com","selector":"s1","signature_index":0,"identity":"@example.com","signature_b":"YWJj","header_canonicalization_type":"relaxed","body_canonicalization_type":"simple","alignment_mode":"strict","key_authentic_data":true,"key_fingerprint":[7,"#
+        ));
+        assert!(json.ends_with(
+            r#""unsigned_headers":[{"name":"Subject","occurrences":2,"signed":1}],"oversigned_headers":["from"],"warnings":[{"code":"sha1_signature"},{"code":"weak_rsa_key","bits":1024}]}"#
+        ));
+        let deserialized: DKIMResult = from_value(to_value(&pass)).unwrap();
+        assert_eq!(deserialized.summary(), "pass");
+        assert_eq!(deserialized.key_fingerprint(), Some([7; 32]));
+        assert_eq!(deserialized.unsigned_headers(), pass.unsigned_headers());
+        assert_eq!(deserialized.warnings(), pass.warnings());
+
+        let fail = DKIMResult::fail(
+            DKIMError::SignatureFailed {
+                index: 2,
+                domain: "example.com".to_owned(),
+                selector: "s2".to_owned(),
+                err: Box::new(DKIMError::TemporaryDnsFailure("SERVFAIL".to_owned())),
+            },
+            "example.com".to_owned(),
+        );
+        assert!(roundtrip(&fail).starts_with(
+            r#"{"summary":"fail","error":{"code":"signature_failed","detail":{"index":2,"#
+        ));
+        let deserialized: DKIMResult = from_value(to_value(&fail)).unwrap();
+        assert_eq!(deserialized.with_detail(), fail.with_detail());
+        assert_eq!(deserialized.selector(), Some("s2"));
+
+        let neutral = DKIMResult::neutral("example.com".to_owned());
+        assert!(roundtrip(&neutral).starts_with(
+            r#"{"summary":"neutral","error":null,"domain_used":"example.com","selector":null,"#
+        ));
+
+        let mut invalid = to_value(&neutral);
+        if let Value::Object(members) = &mut invalid {
+            members[0].1 = Value::String("unknown".to_owned());
+        }
+        assert!(from_value::<DKIMResult>(invalid).is_err());
+    }
+}
@@ -1,22 +1,208 @@
-use crate::header::DKIMHeaderBuilder;
-use crate::{canonicalization, hash, DKIMError, DkimPrivateKey, HEADER};
-use base64::engine::general_purpose;
-use base64::Engine;
+use crate::header::{DKIMHeader, DKIMHeaderBuilder, Wrapping};
+use crate::log::Logger;
+use crate::{
+    bytes, canonicalization, hash, parser, Clock, DKIMError, DkimPrivateKey, DkimPublicKey,
+    FixedClock, SystemClock, ValidationOptions, HEADER,
+};
 use ed25519_dalek::Signer;
+use futures::future::BoxFuture;
+use mailparse::MailHeaderMap;
 use rsa::traits::SignatureScheme;
 use rsa::Pkcs1v15Sign;
+use std::borrow::Cow;
+use std::sync::Arc;
+
+/// Length of the body covered by the signature (l= tag)
+#[derive(Clone, Copy)]
+enum BodyLength {
+    /// The length of the canonicalized body at signing time
+    Auto,
+    /// A fixed number of bytes of the canonicalized body
+    Limit(usize),
+}
+
+/// Default line length of the generated header
+const DEFAULT_LINE_LENGTH: usize = 78;
+
+/// Tags defined by RFC 6376 which can't be added with
+/// [SignerBuilder::with_extra_tag]
+const RESERVED_TAGS: &[&str] = &[
+    "v", "a", "b", "bh", "c", "d", "h", "i", "l", "q", "s", "t", "x", "z",
+];
+
+/// Headers recommended for signing by
+/// <https://datatracker.ietf.org/doc/html/rfc6376#section-5.4.1>, along with
+/// the MIME headers. Every List-* header is also included.
+const RECOMMENDED_HEADERS: &[&str] = &[
+    "from",
+    "sender",
+    "reply-to",
+    "subject",
+    "date",
+    "message-id",
+    "to",
+    "cc",
+    "mime-version",
+    "content-type",
+    "content-transfer-encoding",
+    "content-id",
+    "content-description",
+    "resent-date",
+    "resent-from",
+    "resent-sender",
+    "resent-to",
+    "resent-cc",
+    "resent-message-id",
+    "in-reply-to",
+    "references",
+];
+
+/// How the signer picks the headers to sign
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum HeaderSelection {
+    /// Sign the headers given to [SignerBuilder::with_signed_headers]
+    #[default]
+    Explicit,
+    /// Sign every header recommended by RFC 6376 that is present in the
+    /// email. Headers like Return-Path or Received, which are commonly added
+    /// or changed in transit, are never signed.
+    RecommendedPresent,
+}
+
+/// What the signer does with the headers given to
+/// [SignerBuilder::with_signed_headers] that are missing from the email
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum MissingHeaderPolicy {
+    /// Fail to sign, listing the missing headers
+    Error,
+    /// Leave them out of the h= tag
+    SkipSilently,
+    /// List them in the h= tag, where they are hashed as the null string so
+    /// that adding them later breaks the signature
+    #[default]
+    SignAsAbsent,
+}
+
+/// Set of headers an option applies to
+#[derive(Clone)]
+enum HeaderSet {
+    /// Every signed header
+    Signed,
+    /// The given headers
+    List(Vec<String>),
+}
+
+/// Produces the signature (b= tag) of the DKIM-Signature header, for example
+/// with a key that lives in an HSM or a KMS and can't be loaded in memory.
+///
+/// [DkimPrivateKey] implements this trait for in-memory keys.
+pub trait SignatureProvider: Send + Sync {
+    /// Signing algorithm, emitted in the a= tag
+    fn algorithm(&self) -> hash::HashAlgo;
+
+    /// Sign the hash of the canonicalized headers. For RSA the hash is signed
+    /// using PKCS#1 v1.5 with the DigestInfo of the hash algorithm (like
+    /// `RSASSA_PKCS1_V1_5_SHA_256` over a digest), for Ed25519 the hash
+    /// itself is the signed message as specified in
+    /// <https://datatracker.ietf.org/doc/html/rfc8463#section-3>.
+    fn sign<'a>(
+        &'a self,
+        algo: hash::HashAlgo,
+        digest: &'a [u8],
+    ) -> BoxFuture<'a, Result<Vec<u8>, DKIMError>>;
+
+    /// Public key corresponding to the signing key, used to verify the
+    /// signatures when [SignerBuilder::with_self_check] is enabled
+    fn public_key(&self) -> Option<DkimPublicKey> {
+        None
+    }
+}
+
+impl SignatureProvider for DkimPrivateKey {
+    fn algorithm(&self) -> hash::HashAlgo {
+        match self {
+            DkimPrivateKey::Rsa(_) => hash::HashAlgo::RsaSha256,
+            DkimPrivateKey::Ed25519(_) => hash::HashAlgo::Ed25519Sha256,
+        }
+    }
+
+    fn sign<'a>(
+        &'a self,
+        algo: hash::HashAlgo,
+        digest: &'a [u8],
+    ) -> BoxFuture<'a, Result<Vec<u8>, DKIMError>> {
+        Box::pin(futures::future::ready(sign_digest(self, algo, digest)))
+    }
+
+    fn public_key(&self) -> Option<DkimPublicKey> {
+        Some(DkimPrivateKey::public_key(self))
+    }
+}
+
+fn sign_digest(
+    private_key: &DkimPrivateKey,
+    algo: hash::HashAlgo,
+    digest: &[u8],
+) -> Result<Vec<u8>, DKIMError> {
+    match (private_key, algo) {
+        (DkimPrivateKey::Rsa(private_key), hash::HashAlgo::RsaSha256) => {
+            Pkcs1v15Sign::new::<rsa::sha2::Sha256>()
+                .sign::<DummyRng>(None, private_key, digest)
+                .map_err(|err| DKIMError::FailedToSign(err.to_string()))
+        }
+        (DkimPrivateKey::Rsa(private_key), hash::HashAlgo::RsaSha1) => {
+            Pkcs1v15Sign::new::<sha1::Sha1>()
+                .sign::<DummyRng>(None, private_key, digest)
+                .map_err(|err| DKIMError::FailedToSign(err.to_string()))
+        }
+        (DkimPrivateKey::Ed25519(keypair), hash::HashAlgo::Ed25519Sha256) => {
+            Ok(keypair.sign(digest).to_bytes().into())
+        }
+        (_, hash) => Err(DKIMError::UnsupportedHashAlgorithm(format!("{:?}", hash))),
+    }
+}
+
+/// Key used by the signer
+enum SigningKey {
+    Local(Box<DkimPrivateKey>),
+    Provider(Arc<dyn SignatureProvider>),
+}
+
+impl SigningKey {
+    fn algorithm(&self) -> hash::HashAlgo {
+        match self {
+            SigningKey::Local(private_key) => private_key.algorithm(),
+            SigningKey::Provider(provider) => provider.algorithm(),
+        }
+    }
+}
 
 /// Builder for the Signer
 pub struct SignerBuilder<'a> {
     signed_headers: Option<&'a [&'a str]>,
+    header_selection: HeaderSelection,
+    missing_header_policy: MissingHeaderPolicy,
     private_key: Option<DkimPrivateKey>,
+    signature_provider: Option<Arc<dyn SignatureProvider>>,
     selector: Option<&'a str>,
     signing_domain: Option<&'a str>,
     time: Option<chrono::DateTime<chrono::offset::Utc>>,
+    clock: Arc<dyn Clock>,
     header_canonicalization: canonicalization::Type,
     body_canonicalization: canonicalization::Type,
-    logger: Option<&'a slog::Logger>,
+    logger: Logger,
     expiry: Option<chrono::Duration>,
+    expiry_timestamp: Option<i64>,
+    timestamp: bool,
+    body_length: Option<BodyLength>,
+    copied_headers: Option<HeaderSet>,
+    oversigned_headers: Option<HeaderSet>,
+    seal_signatures: bool,
+    extra_tags: Vec<(String, String)>,
+    line_length: Option<usize>,
+    signature_wrapping: Option<Wrapping>,
+    self_check: bool,
+    normalize_line_endings: bool,
 }
 
 impl<'a> SignerBuilder<'a> {
@@ -24,12 +210,27 @@ impl<'a> SignerBuilder<'a> {
     pub fn new() -> Self {
         Self {
             signed_headers: None,
+            header_selection: HeaderSelection::Explicit,
+            missing_header_policy: MissingHeaderPolicy::default(),
             private_key: None,
+            signature_provider: None,
             selector: None,
-            logger: None,
+            logger: Logger::default(),
             signing_domain: None,
             expiry: None,
+            expiry_timestamp: None,
             time: None,
+            clock: Arc::new(SystemClock),
+            timestamp: true,
+            body_length: None,
+            copied_headers: None,
+            oversigned_headers: None,
+            seal_signatures: false,
+            extra_tags: vec![],
+            line_length: Some(DEFAULT_LINE_LENGTH),
+            signature_wrapping: None,
+            self_check: false,
+            normalize_line_endings: true,
 
             header_canonicalization: canonicalization::Type::Simple,
             body_canonicalization: canonicalization::Type::Simple,
@@ -48,19 +249,44 @@ impl<'a> SignerBuilder<'a> {
         Ok(self)
     }
 
+    /// Specify how the signed headers are picked. With
+    /// [HeaderSelection::RecommendedPresent] the signed headers don't need to
+    /// be provided.
+    pub fn with_header_selection(mut self, value: HeaderSelection) -> Self {
+        self.header_selection = value;
+        self
+    }
+
+    /// Specify what to do with the signed headers missing from the email.
+    /// Defaults to [MissingHeaderPolicy::SignAsAbsent].
+    pub fn with_missing_header_policy(mut self, value: MissingHeaderPolicy) -> Self {
+        self.missing_header_policy = value;
+        self
+    }
+
     /// Specify the private key used to sign the email
     pub fn with_private_key(mut self, key: DkimPrivateKey) -> Self {
         self.private_key = Some(key);
         self
     }
 
-    /// Specify the private key used to sign the email
+    /// Specify a provider producing the signature instead of a private key,
+    /// see [SignatureProvider]. Emails then have to be signed with
+    /// [DKIMSigner::sign_async].
+    pub fn with_signature_provider(mut self, provider: Arc<dyn SignatureProvider>) -> Self {
+        self.signature_provider = Some(provider);
+        self
+    }
+
+    /// Specify the selector of the key (s=), a dot-separated list of
+    /// sub-domains
     pub fn with_selector(mut self, value: &'a str) -> Self {
         self.selector = Some(value);
         self
     }
 
-    /// Specify for which domain the email should be signed for
+    /// Specify for which domain the email should be signed for. An
+    /// internationalized domain is signed with its A-labels.
     pub fn with_signing_domain(mut self, value: &'a str) -> Self {
         self.signing_domain = Some(value);
         self
@@ -78,9 +304,127 @@ impl<'a> SignerBuilder<'a> {
         self
     }
 
-    /// Specify a logger
-    pub fn with_logger(mut self, logger: &'a slog::Logger) -> Self {
-        self.logger = Some(logger);
+    /// Specify the header and body canonicalizations at once, emitted as
+    /// `c=header/body`
+    pub fn with_canonicalization(
+        self,
+        header: canonicalization::Type,
+        body: canonicalization::Type,
+    ) -> Self {
+        self.with_header_canonicalization(header)
+            .with_body_canonicalization(body)
+    }
+
+    /// Only sign the first `value` bytes of the canonicalized body and emit
+    /// the l= tag. Signing fails if the canonicalized body is shorter.
+    pub fn with_body_length_limit(mut self, value: usize) -> Self {
+        self.body_length = Some(BodyLength::Limit(value));
+        self
+    }
+
+    /// Emit the l= tag with the length of the canonicalized body, so that
+    /// content appended later (like mailing list footers) doesn't break the
+    /// signature.
+    pub fn with_body_length(mut self) -> Self {
+        self.body_length = Some(BodyLength::Auto);
+        self
+    }
+
+    /// Copy the original value of every signed header into the z= tag, which
+    /// is useful for diagnosing signatures that don't verify.
+    pub fn with_copied_headers(mut self, value: bool) -> Self {
+        self.copied_headers = if value { Some(HeaderSet::Signed) } else { None };
+        self
+    }
+
+    /// Copy the original value of the given headers into the z= tag
+    pub fn with_copied_headers_list(mut self, headers: Vec<String>) -> Self {
+        self.copied_headers = Some(HeaderSet::List(headers));
+        self
+    }
+
+    /// Sign the given headers one more time than they occur in the email, so
+    /// that adding another occurrence after signing breaks the signature.
+    /// <https://datatracker.ietf.org/doc/html/rfc6376#section-8.15>
+    pub fn with_oversigning(mut self, headers: Vec<String>) -> Self {
+        self.oversigned_headers = Some(HeaderSet::List(headers));
+        self
+    }
+
+    /// Oversign every signed header, see [SignerBuilder::with_oversigning]
+    pub fn oversign_signed_headers(mut self) -> Self {
+        self.oversigned_headers = Some(HeaderSet::Signed);
+        self
+    }
+
+    /// Sign the DKIM-Signature headers already present in the email, so that
+    /// they can't be removed without breaking the new signature. Existing
+    /// signatures are left untouched either way.
+    pub fn seal_existing_signatures(mut self) -> Self {
+        self.seal_signatures = true;
+        self
+    }
+
+    /// Add a tag that isn't defined by RFC 6376 to the signature, for example
+    /// for private-use. Reserved tag names and values that don't match the
+    /// tag-list grammar are rejected.
+    pub fn with_extra_tag(mut self, name: &str, value: &str) -> Result<Self, DKIMError> {
+        if RESERVED_TAGS.contains(&name) {
+            return Err(DKIMError::BuilderError("reserved tag name"));
+        }
+        if !parser::is_valid_tag_name(name) {
+            return Err(DKIMError::BuilderError("invalid tag name"));
+        }
+        if !parser::is_valid_tag_value(value) {
+            return Err(DKIMError::BuilderError("invalid tag value"));
+        }
+        if self.extra_tags.iter().any(|(n, _)| n == name) {
+            return Err(DKIMError::BuilderError("duplicate tag name"));
+        }
+
+        self.extra_tags.push((name.to_owned(), value.to_owned()));
+        Ok(self)
+    }
+
+    /// Fold the generated DKIM-Signature header so that its lines stay under
+    /// `value` characters where possible. Defaults to 78.
+    pub fn with_line_length(mut self, value: usize) -> Self {
+        self.line_length = Some(value);
+        self
+    }
+
+    /// Generate the DKIM-Signature header on a single line
+    pub fn without_folding(mut self) -> Self {
+        self.line_length = None;
+        self
+    }
+
+    /// Lay out the base64 values of b= and bh= as specified, each starting on
+    /// a new line when the header is folded. By default they are folded with
+    /// the rest of the header.
+    pub fn with_signature_wrapping(mut self, value: Wrapping) -> Self {
+        self.signature_wrapping = Some(value);
+        self
+    }
+
+    /// Convert bare LF line endings of the message to CRLF before signing, so
+    /// that the signature matches the message once sent. Enabled by default.
+    pub fn with_line_ending_normalization(mut self, value: bool) -> Self {
+        self.normalize_line_endings = value;
+        self
+    }
+
+    /// Verify each signature against the public key of the signing key after
+    /// signing, failing with [DKIMError::SelfCheckFailed] if it doesn't pass.
+    /// Signature providers must expose their [SignatureProvider::public_key].
+    pub fn with_self_check(mut self, value: bool) -> Self {
+        self.self_check = value;
+        self
+    }
+
+    /// Specify where the signing steps are logged, see [Logger]
+    pub fn with_logger(mut self, logger: impl Into<Logger>) -> Self {
+        self.logger = logger.into();
         self
     }
 
@@ -90,47 +434,157 @@ impl<'a> SignerBuilder<'a> {
         self
     }
 
-    /// Specify a expiry duration for the signature validity
+    /// Specify the clock giving the signing time, when it isn't set with
+    /// [SignerBuilder::with_time]. Defaults to the system clock.
+    pub fn with_clock(mut self, clock: Arc<dyn Clock>) -> Self {
+        self.clock = clock;
+        self
+    }
+
+    /// Specify the signature timestamp (t=) in seconds since the Unix epoch.
+    /// Together with an Ed25519 key this makes the signature reproducible.
+    pub fn with_timestamp(mut self, value: i64) -> Result<Self, DKIMError> {
+        let time = chrono::DateTime::from_timestamp(value, 0)
+            .ok_or(DKIMError::BuilderError("invalid timestamp"))?;
+        self.time = Some(time);
+        Ok(self)
+    }
+
+    /// Omit the signature timestamp (t=), which is optional
+    pub fn without_timestamp(mut self) -> Self {
+        self.timestamp = false;
+        self
+    }
+
+    /// Specify a expiry duration for the signature validity, relative to the
+    /// signing time. Emitted as the x= tag.
     pub fn with_expiry(mut self, value: chrono::Duration) -> Self {
         self.expiry = Some(value);
         self
     }
 
+    /// Specify an absolute expiry timestamp (seconds since the Unix epoch) for
+    /// the signature validity. Must be after the signing time.
+    pub fn with_expiry_timestamp(mut self, value: i64) -> Self {
+        self.expiry_timestamp = Some(value);
+        self
+    }
+
     /// Build an instance of the Signer
-    /// Must be provided: signed_headers, private_key, selector, logger and
-    /// signing_domain.
+    /// Must be provided: signed_headers (unless another header selection is
+    /// used), private_key, selector and signing_domain.
     pub fn build(self) -> Result<DKIMSigner<'a>, DKIMError> {
         use DKIMError::BuilderError;
 
-        let private_key = self
-            .private_key
-            .ok_or(BuilderError("missing required private key"))?;
-        let hash_algo = match private_key {
-            DkimPrivateKey::Rsa(_) => hash::HashAlgo::RsaSha256,
-            DkimPrivateKey::Ed25519(_) => hash::HashAlgo::Ed25519Sha256,
+        let key = match (self.private_key, self.signature_provider) {
+            (Some(private_key), None) => SigningKey::Local(Box::new(private_key)),
+            (None, Some(provider)) => SigningKey::Provider(provider),
+            (Some(_), Some(_)) => {
+                return Err(BuilderError(
+                    "private key and signature provider are mutually exclusive",
+                ))
+            }
+            (None, None) => return Err(BuilderError("missing required private key")),
         };
+        if let Some(expiry) = self.expiry {
+            if expiry <= chrono::Duration::zero() {
+                return Err(BuilderError("expiry must be a positive duration"));
+            }
+        }
+        if self.expiry.is_some() && self.expiry_timestamp.is_some() {
+            return Err(BuilderError(
+                "expiry and expiry timestamp are mutually exclusive",
+            ));
+        }
 
-        Ok(DKIMSigner {
-            signed_headers: self
+        if let SigningKey::Provider(provider) = &key {
+            if self.self_check && provider.public_key().is_none() {
+                return Err(BuilderError(
+                    "self check requires the public key of the signature provider",
+                ));
+            }
+        }
+
+        let hash_algo = key.algorithm();
+
+        let signed_headers = match self.header_selection {
+            HeaderSelection::Explicit => self
                 .signed_headers
                 .ok_or(BuilderError("missing required signed headers"))?,
-            private_key,
-            selector: self
-                .selector
-                .ok_or(BuilderError("missing required selector"))?,
-            logger: self.logger.ok_or(BuilderError("missing required logger"))?,
-            signing_domain: self
-                .signing_domain
-                .ok_or(BuilderError("missing required logger"))?,
+            HeaderSelection::RecommendedPresent => self.signed_headers.unwrap_or_default(),
+        };
+        let listed_headers = [&self.copied_headers, &self.oversigned_headers]
+            .into_iter()
+            .flat_map(|set| match set {
+                Some(HeaderSet::List(headers)) => headers.as_slice(),
+                _ => &[],
+            });
+        if !signed_headers
+            .iter()
+            .copied()
+            .all(parser::is_valid_field_name)
+            || !listed_headers
+                .into_iter()
+                .all(|name| parser::is_valid_field_name(name))
+        {
+            return Err(BuilderError("invalid header name"));
+        }
+
+        let selector = self
+            .selector
+            .ok_or(BuilderError("missing required selector"))?;
+        if !parser::is_valid_domain_name(selector) {
+            return Err(BuilderError("invalid selector"));
+        }
+        let signing_domain = signing_domain_ascii(
+            self.signing_domain
+                .ok_or(BuilderError("missing required signing domain"))?,
+        )?;
+
+        Ok(DKIMSigner {
+            signed_headers,
+            header_selection: self.header_selection,
+            missing_header_policy: self.missing_header_policy,
+            key,
+            selector,
+            logger: self.logger,
+            signing_domain,
             header_canonicalization: self.header_canonicalization,
             body_canonicalization: self.body_canonicalization,
             expiry: self.expiry,
+            expiry_timestamp: self.expiry_timestamp,
             hash_algo,
             time: self.time,
+            clock: self.clock,
+            timestamp: self.timestamp,
+            body_length: self.body_length,
+            copied_headers: self.copied_headers,
+            oversigned_headers: self.oversigned_headers,
+            seal_signatures: self.seal_signatures,
+            extra_tags: self.extra_tags,
+            line_length: self.line_length,
+            signature_wrapping: self.signature_wrapping,
+            self_check: self.self_check,
+            normalize_line_endings: self.normalize_line_endings,
         })
     }
 }
 
+/// The signing domain in its ASCII form, converting the U-labels of an
+/// internationalized domain to A-labels
+fn signing_domain_ascii(domain: &str) -> Result<String, DKIMError> {
+    let domain = if domain.is_ascii() {
+        domain.to_owned()
+    } else {
+        idna::domain_to_ascii(domain)
+            .map_err(|_| DKIMError::BuilderError("invalid signing domain"))?
+    };
+    if !parser::is_valid_domain_name(&domain) {
+        return Err(DKIMError::BuilderError("invalid signing domain"));
+    }
+    Ok(domain)
+}
+
 impl<'a> Default for SignerBuilder<'a> {
     fn default() -> Self {
         Self::new()
@@ -139,120 +593,618 @@ impl<'a> Default for SignerBuilder<'a> {
 
 pub struct DKIMSigner<'a> {
     signed_headers: &'a [&'a str],
-    private_key: DkimPrivateKey,
+    header_selection: HeaderSelection,
+    missing_header_policy: MissingHeaderPolicy,
+    key: SigningKey,
     selector: &'a str,
-    signing_domain: &'a str,
+    signing_domain: String,
     header_canonicalization: canonicalization::Type,
     body_canonicalization: canonicalization::Type,
-    logger: &'a slog::Logger,
+    logger: Logger,
     expiry: Option<chrono::Duration>,
+    expiry_timestamp: Option<i64>,
     hash_algo: hash::HashAlgo,
     time: Option<chrono::DateTime<chrono::offset::Utc>>,
+    clock: Arc<dyn Clock>,
+    timestamp: bool,
+    body_length: Option<BodyLength>,
+    copied_headers: Option<HeaderSet>,
+    oversigned_headers: Option<HeaderSet>,
+    seal_signatures: bool,
+    extra_tags: Vec<(String, String)>,
+    line_length: Option<usize>,
+    signature_wrapping: Option<Wrapping>,
+    self_check: bool,
+    normalize_line_endings: bool,
 }
 
 /// DKIM signer. Use the [SignerBuilder] to build an instance.
 impl<'a> DKIMSigner<'a> {
     /// Sign a message
     /// As specified in <https://datatracker.ietf.org/doc/html/rfc6376#section-5>
+    ///
+    /// Fails if the signer uses a [SignatureProvider], use
+    /// [DKIMSigner::sign_async] instead.
+    ///
+    /// Unless disabled with [SignerBuilder::with_line_ending_normalization],
+    /// a message with bare LF line endings is signed as if they were CRLF.
     pub fn sign<'b>(&self, email: &'b mailparse::ParsedMail<'b>) -> Result<String, DKIMError> {
-        let body_hash = self.compute_body_hash(email)?;
-        let dkim_header_builder = self.dkim_header_builder(&body_hash)?;
-
-        let header_hash = self.compute_header_hash(email, dkim_header_builder.clone())?;
-
-        let signature = match &self.private_key {
-            DkimPrivateKey::Rsa(private_key) => match &self.hash_algo {
-                hash::HashAlgo::RsaSha256 => {
-                    let scheme = Pkcs1v15Sign::new::<rsa::sha2::Sha256>();
-                    scheme
-                        .sign::<DummyRng>(None, private_key, &header_hash)
-                        .map_err(|err| DKIMError::FailedToSign(err.to_string()))?
-                }
-                hash::HashAlgo::RsaSha1 => {
-                    let scheme = Pkcs1v15Sign::new::<rsa::sha2::Sha256>();
-                    scheme
-                        .sign::<DummyRng>(None, private_key, &header_hash)
-                        .map_err(|err| DKIMError::FailedToSign(err.to_string()))?
-                }
-                hash => return Err(DKIMError::UnsupportedHashAlgorithm(format!("{:?}", hash))),
-            },
-            DkimPrivateKey::Ed25519(keypair) => keypair.sign(&header_hash).to_bytes().into(),
+        let normalized = self.normalized_bytes(email);
+        let normalized_email = normalized.as_deref().map(parse_email).transpose()?;
+        let email = normalized_email.as_ref().unwrap_or(email);
+
+        let private_key = self.local_key()?;
+        let prepared = self.prepare_with_cache(email, &mut BodyCache::default())?;
+        let signature = sign_digest(private_key, self.hash_algo.clone(), &prepared.header_hash)?;
+        prepared.finalize(&signature)
+    }
+
+    /// Generate the DKIM-Signature header of a message without its signature,
+    /// for a signature produced outside of cfdkim like by a KMS: sign
+    /// [PreparedSignature::data_to_sign] and pass the signature to
+    /// [PreparedSignature::finalize]. [DKIMSigner::sign] does the same with
+    /// the private key.
+    ///
+    /// The key configured in the builder is only used for the self check.
+    pub fn prepare<'b>(
+        &self,
+        email: &'b mailparse::ParsedMail<'b>,
+    ) -> Result<PreparedSignature<'_>, DKIMError> {
+        let normalized = self.normalized_bytes(email);
+        let normalized_email = normalized.as_deref().map(parse_email).transpose()?;
+        let email = normalized_email.as_ref().unwrap_or(email);
+
+        self.prepare_with_cache(email, &mut BodyCache::default())
+    }
+
+    /// Sign a raw message and return it with the DKIM-Signature header
+    /// inserted above the existing headers. The message is otherwise left
+    /// untouched, except for bare LF line endings which are converted to CRLF
+    /// before signing, like they would be on the wire.
+    ///
+    /// Like [DKIMSigner::sign], this fails if the signer uses a
+    /// [SignatureProvider].
+    pub fn sign_and_attach(&self, email_bytes: &[u8]) -> Result<Vec<u8>, DKIMError> {
+        let email_bytes = if bytes::has_bare_lf(email_bytes) {
+            Cow::Owned(bytes::to_crlf(email_bytes))
+        } else {
+            Cow::Borrowed(email_bytes)
         };
+        let email = parse_email(&email_bytes)?;
+
+        let header = self.sign(&email)?;
+
+        let mut signed_email = Vec::with_capacity(header.len() + 2 + email_bytes.len());
+        signed_email.extend_from_slice(header.as_bytes());
+        signed_email.extend_from_slice(b"\r\n");
+        signed_email.extend_from_slice(&email_bytes);
+        Ok(signed_email)
+    }
+
+    /// Sign a message, with either the private key or the signature provider
+    /// As specified in <https://datatracker.ietf.org/doc/html/rfc6376#section-5>
+    pub async fn sign_async<'b>(
+        &self,
+        email: &'b mailparse::ParsedMail<'b>,
+    ) -> Result<String, DKIMError> {
+        let prepared = self.prepare(email)?;
+        let signature = self.sign_digest(&prepared.header_hash).await?;
+        prepared.finalize(&signature)
+    }
+
+    /// The message converted to CRLF line endings, if it has bare LFs and
+    /// line ending normalization is enabled
+    fn normalized_bytes(&self, email: &mailparse::ParsedMail) -> Option<Vec<u8>> {
+        if self.normalize_line_endings && bytes::has_bare_lf(email.raw_bytes) {
+            Some(bytes::to_crlf(email.raw_bytes))
+        } else {
+            None
+        }
+    }
+
+    /// The in-memory private key, signing synchronously isn't possible with
+    /// a signature provider
+    fn local_key(&self) -> Result<&DkimPrivateKey, DKIMError> {
+        match &self.key {
+            SigningKey::Local(private_key) => Ok(private_key),
+            SigningKey::Provider(_) => Err(DKIMError::FailedToSign(
+                "signing with a signature provider requires sign_async".to_owned(),
+            )),
+        }
+    }
+
+    /// Sign the hash of the headers with the private key or the provider
+    async fn sign_digest(&self, header_hash: &[u8]) -> Result<Vec<u8>, DKIMError> {
+        match &self.key {
+            SigningKey::Local(private_key) => {
+                SignatureProvider::sign(private_key.as_ref(), self.hash_algo.clone(), header_hash)
+                    .await
+            }
+            SigningKey::Provider(provider) => {
+                provider.sign(self.hash_algo.clone(), header_hash).await
+            }
+        }
+    }
 
-        // add the signature into the DKIM header and generate the header
-        let dkim_header = dkim_header_builder
-            .add_tag("b", &general_purpose::STANDARD.encode(signature))
-            .build()?;
+    /// Generate the DKIM-Signature header without its signature and compute
+    /// the hash to sign, sharing the bodies in the cache
+    fn prepare_with_cache<'b>(
+        &self,
+        email: &'b mailparse::ParsedMail<'b>,
+        cache: &mut BodyCache,
+    ) -> Result<PreparedSignature<'_>, DKIMError> {
+        let (body_hash, body_length) = self.compute_body_hash(email, cache)?;
+        let base_headers = self.base_headers(email)?;
+        let signed_headers = self.oversign(base_headers.clone(), email);
+        debug!(&self.logger, "signed headers: {:?}", signed_headers);
+        let signed_headers: Vec<&str> = signed_headers.iter().map(|h| h.as_str()).collect();
 
-        Ok(format!("{}: {}", HEADER, dkim_header.raw_bytes))
+        let mut dkim_header_builder =
+            self.dkim_header_builder(&signed_headers, &body_hash, body_length)?;
+        if let Some(copied_headers) = self.copied_headers(&base_headers, email)? {
+            dkim_header_builder = dkim_header_builder.add_tag("z", &copied_headers);
+        }
+        for (name, value) in &self.extra_tags {
+            dkim_header_builder = dkim_header_builder.add_tag(name, value);
+        }
+
+        // For signing the DKIM-Signature header the signature needs to be null
+        let unsigned_header = dkim_header_builder.build_unsigned()?;
+        let header_hash = self.compute_header_hash(email, &unsigned_header)?;
+        Ok(PreparedSignature {
+            signer: self,
+            dkim_header_builder,
+            header_value: unsigned_header.raw_bytes,
+            header_hash,
+            email_bytes: self.self_check.then(|| email.raw_bytes.to_vec()),
+        })
     }
 
-    fn dkim_header_builder(&self, body_hash: &str) -> Result<DKIMHeaderBuilder, DKIMError> {
-        let now = chrono::offset::Utc::now();
-        let hash_algo = match self.hash_algo {
-            hash::HashAlgo::RsaSha1 => "rsa-sha1",
-            hash::HashAlgo::RsaSha256 => "rsa-sha256",
-            hash::HashAlgo::Ed25519Sha256 => "ed25519-sha256",
+    /// Verify the generated header against the message with the public key
+    fn check_signature(&self, header: &str, email_bytes: &[u8]) -> Result<(), DKIMError> {
+        let public_key = match &self.key {
+            SigningKey::Local(private_key) => private_key.public_key(),
+            SigningKey::Provider(provider) => {
+                provider.public_key().ok_or(DKIMError::NoKeyForSignature)?
+            }
+        };
+
+        let mut signed_email = Vec::with_capacity(header.len() + 2 + email_bytes.len());
+        signed_email.extend_from_slice(header.as_bytes());
+        signed_email.extend_from_slice(b"\r\n");
+        signed_email.extend_from_slice(email_bytes);
+        let signed_email = mailparse::parse_mail(&signed_email)
+            .map_err(|err| DKIMError::SignatureSyntaxError(err.to_string()))?;
+
+        // Validate the timestamps against the signing time
+        let now = match self.time {
+            Some(time) => time.naive_utc(),
+            None => self.clock.now(),
         };
+        let options = ValidationOptions {
+            clock: Arc::new(FixedClock(now)),
+            ..Default::default()
+        };
+
+        let result = crate::verify_email_with_key_and_options(
+            &self.logger,
+            &self.signing_domain,
+            &signed_email,
+            public_key,
+            false,
+            &options,
+        )?;
+        match result.error() {
+            Some(err) => Err(err.root_cause().clone()),
+            None => Ok(()),
+        }
+    }
+
+    fn dkim_header_builder(
+        &self,
+        signed_headers: &[&str],
+        body_hash: &str,
+        body_length: Option<usize>,
+    ) -> Result<DKIMHeaderBuilder, DKIMError> {
+        let now = self.clock.now().and_utc();
 
         let mut builder = DKIMHeaderBuilder::new()
-            .add_tag("v", "1")
-            .add_tag("a", hash_algo)
-            .add_tag("d", self.signing_domain)
-            .add_tag("s", self.selector)
-            .add_tag(
-                "c",
-                &format!(
-                    "{}/{}",
-                    self.header_canonicalization.to_string(),
-                    self.body_canonicalization.to_string()
-                ),
+            .set_line_length(self.line_length)
+            .set_wrapping(self.signature_wrapping.clone())
+            .set_algorithm(self.hash_algo.clone())
+            .set_domain(&self.signing_domain)
+            .set_selector(self.selector)
+            .set_canonicalization(
+                self.header_canonicalization.clone(),
+                self.body_canonicalization.clone(),
             )
-            .set_signed_headers(self.signed_headers);
+            .set_signed_headers(signed_headers);
+        let time = self.time.unwrap_or(now);
+        builder = if self.timestamp {
+            builder.set_time(time)
+        } else {
+            builder.set_reference_time(time)
+        };
         if let Some(expiry) = self.expiry {
             builder = builder.set_expiry(expiry)?;
         }
-        if let Some(time) = self.time {
-            builder = builder.set_time(time);
-        } else {
-            builder = builder.set_time(now);
+        if let Some(expiry) = self.expiry_timestamp {
+            // https://datatracker.ietf.org/doc/html/rfc6376#section-3.5
+            // The value of the "x=" tag MUST be greater than the value of the
+            // "t=" tag if both are present.
+            if expiry <= time.timestamp() {
+                return Err(DKIMError::BuilderError(
+                    "expiry timestamp must be after the signing time",
+                ));
+            }
+            builder = builder.set_expiration(expiry);
+        }
+        if let Some(body_length) = body_length {
+            builder = builder.set_body_length(body_length as u64);
         }
         builder = builder.add_tag("bh", body_hash);
 
         Ok(builder)
     }
 
+    /// Headers listed in the h= tag when signing the email, including the
+    /// oversigned ones
+    pub fn selected_headers<'b>(
+        &self,
+        email: &'b mailparse::ParsedMail<'b>,
+    ) -> Result<Vec<String>, DKIMError> {
+        let headers = self.oversign(self.base_headers(email)?, email);
+        Ok(headers.iter().map(|h| h.to_lowercase()).collect())
+    }
+
+    /// Headers to sign, before oversigning
+    fn base_headers<'b>(
+        &self,
+        email: &'b mailparse::ParsedMail<'b>,
+    ) -> Result<Vec<String>, DKIMError> {
+        // https://datatracker.ietf.org/doc/html/rfc6376#section-5.4
+        // The From header field MUST be signed
+        if email.headers.get_first_header("From").is_none() {
+            return Err(DKIMError::FailedToSign("missing From header".to_owned()));
+        }
+
+        let mut headers: Vec<String> = match self.header_selection {
+            HeaderSelection::Explicit => self.explicit_headers(email)?,
+            HeaderSelection::RecommendedPresent => email
+                .headers
+                .iter()
+                .map(|h| h.get_key_ref().to_lowercase())
+                .filter(|name| {
+                    RECOMMENDED_HEADERS.contains(&name.as_str()) || name.starts_with("list-")
+                })
+                .collect(),
+        };
+
+        if self.seal_signatures {
+            let listed = headers
+                .iter()
+                .filter(|h| h.eq_ignore_ascii_case(HEADER))
+                .count();
+            let existing = email.headers.get_all_headers(HEADER).len();
+            for _ in listed..existing {
+                headers.push(HEADER.to_owned());
+            }
+        }
+
+        Ok(headers)
+    }
+
+    /// Headers given to [SignerBuilder::with_signed_headers], with the
+    /// missing ones handled by the [MissingHeaderPolicy]
+    fn explicit_headers(&self, email: &mailparse::ParsedMail) -> Result<Vec<String>, DKIMError> {
+        let headers = self.signed_headers.iter().map(|h| h.to_string());
+        if self.missing_header_policy == MissingHeaderPolicy::SignAsAbsent {
+            return Ok(headers.collect());
+        }
+
+        let (present, missing): (Vec<String>, Vec<String>) =
+            headers.partition(|name| email.headers.get_first_header(name).is_some());
+        if self.missing_header_policy == MissingHeaderPolicy::Error && !missing.is_empty() {
+            return Err(DKIMError::FailedToSign(format!(
+                "missing signed headers: {}",
+                missing.join(", ")
+            )));
+        }
+        Ok(present)
+    }
+
+    /// Adds the oversigned headers
+    fn oversign<'b>(
+        &self,
+        mut headers: Vec<String>,
+        email: &'b mailparse::ParsedMail<'b>,
+    ) -> Vec<String> {
+        let oversigned: Vec<String> = match &self.oversigned_headers {
+            None => return headers,
+            Some(HeaderSet::Signed) => headers.clone(),
+            Some(HeaderSet::List(list)) => list.clone(),
+        };
+
+        for name in oversigned {
+            let listed = headers
+                .iter()
+                .filter(|h| h.eq_ignore_ascii_case(&name))
+                .count();
+            let occurrences = email
+                .headers
+                .iter()
+                .filter(|h| h.get_key_ref().eq_ignore_ascii_case(&name))
+                .count();
+            // Per RFC 6376 section 5.4.2 the extra occurrence doesn't exist in
+            // the email and is hashed as the null string.
+            for _ in listed..=occurrences {
+                headers.push(name.clone());
+            }
+        }
+
+        headers
+    }
+
+    /// Value of the z= tag, if enabled
+    fn copied_headers<'b>(
+        &self,
+        signed_headers: &[String],
+        email: &'b mailparse::ParsedMail<'b>,
+    ) -> Result<Option<String>, DKIMError> {
+        let names = match &self.copied_headers {
+            None => return Ok(None),
+            Some(HeaderSet::Signed) => signed_headers.join(":"),
+            Some(HeaderSet::List(headers)) => headers.join(":"),
+        };
+
+        let copies: Vec<String> = hash::select_headers(&names, email)?
+            .into_iter()
+            .map(|(key, value)| format!("{}:{}", key, parser::encode_dkim_quoted_printable(value)))
+            .collect();
+        if copies.is_empty() {
+            return Ok(None);
+        }
+        Ok(Some(copies.join("|")))
+    }
+
     fn compute_body_hash<'b>(
         &self,
         email: &'b mailparse::ParsedMail<'b>,
-    ) -> Result<String, DKIMError> {
-        let length = None;
+        cache: &mut BodyCache,
+    ) -> Result<(String, Option<usize>), DKIMError> {
         let canonicalization = self.body_canonicalization.clone();
-        hash::compute_body_hash(canonicalization, length, self.hash_algo.clone(), email)
+        let canonicalized_body = cache.canonicalized_body(canonicalization.clone(), email)?;
+
+        let length = match self.body_length {
+            None => None,
+            Some(BodyLength::Auto) => Some(canonicalized_body.len()),
+            Some(BodyLength::Limit(length)) => {
+                if length > canonicalized_body.len() {
+                    return Err(DKIMError::FailedToSign(format!(
+                        "body length limit ({}) exceeds the canonicalized body length ({})",
+                        length,
+                        canonicalized_body.len()
+                    )));
+                }
+                Some(length)
+            }
+        };
+
+        let body_hash = cache.body_hash(canonicalization, self.hash_algo.clone(), length);
+        Ok((body_hash, length))
     }
 
     fn compute_header_hash<'b>(
         &self,
         email: &'b mailparse::ParsedMail<'b>,
-        dkim_header_builder: DKIMHeaderBuilder,
+        unsigned_header: &DKIMHeader,
     ) -> Result<Vec<u8>, DKIMError> {
         let canonicalization = self.header_canonicalization.clone();
+        let signed_headers = unsigned_header.get_required_tag("h");
 
-        // For signing the DKIM-Signature header the signature needs to be null
-        let dkim_header = dkim_header_builder.add_tag("b", "").build()?;
-        let signed_headers = dkim_header.get_required_tag("h");
-
-        hash::compute_headers_hash(
-            self.logger,
+        hash::compute_headers_hash_with_logger(
+            &self.logger,
             canonicalization,
             &signed_headers,
             self.hash_algo.clone(),
-            &dkim_header,
+            unsigned_header,
             email,
         )
     }
 }
 
+/// DKIM-Signature header of a message with all its tags but the signature,
+/// see [DKIMSigner::prepare]
+pub struct PreparedSignature<'s> {
+    signer: &'s DKIMSigner<'s>,
+    dkim_header_builder: DKIMHeaderBuilder,
+    header_value: String,
+    header_hash: Vec<u8>,
+    /// Signed message, kept for the self check
+    email_bytes: Option<Vec<u8>>,
+}
+
+impl PreparedSignature<'_> {
+    /// Value of the DKIM-Signature header with an empty b= tag, as included
+    /// in the hash of the headers
+    pub fn header_value_without_signature(&self) -> &str {
+        &self.header_value
+    }
+
+    /// Hash of the canonicalized headers to sign, as expected by
+    /// [SignatureProvider::sign]: for RSA it's signed with PKCS#1 v1.5 and
+    /// the DigestInfo of [PreparedSignature::hash_algo], for Ed25519 it's the
+    /// message signed itself.
+    pub fn data_to_sign(&self) -> &[u8] {
+        &self.header_hash
+    }
+
+    /// Signing algorithm, emitted in the a= tag
+    pub fn hash_algo(&self) -> hash::HashAlgo {
+        self.signer.hash_algo.clone()
+    }
+
+    /// Add the signature of [PreparedSignature::data_to_sign] and generate
+    /// the DKIM-Signature header
+    pub fn finalize(self, signature: &[u8]) -> Result<String, DKIMError> {
+        let dkim_header = self.dkim_header_builder.set_signature(signature).build()?;
+        let header = format!("{}: {}", HEADER, dkim_header.raw_bytes);
+
+        if let Some(email_bytes) = &self.email_bytes {
+            self.signer
+                .check_signature(&header, email_bytes)
+                .map_err(|err| DKIMError::SelfCheckFailed(Box::new(err)))?;
+        }
+        Ok(header)
+    }
+}
+
+/// Canonicalized bodies and body hashes computed while signing, so that they
+/// can be shared between signatures
+#[derive(Default)]
+struct BodyCache {
+    bodies: Vec<(canonicalization::Type, Vec<u8>)>,
+    hashes: Vec<(BodyHashKey, String)>,
+}
+
+/// Body canonicalization, whether the hash is SHA-1 and the signed length
+type BodyHashKey = (canonicalization::Type, bool, Option<usize>);
+
+impl BodyCache {
+    fn canonicalized_body<'b>(
+        &mut self,
+        canonicalization: canonicalization::Type,
+        email: &'b mailparse::ParsedMail<'b>,
+    ) -> Result<&[u8], DKIMError> {
+        let index = match self.bodies.iter().position(|(c, _)| *c == canonicalization) {
+            Some(index) => index,
+            None => {
+                let body = hash::canonicalize_body(canonicalization.clone(), email)?;
+                self.bodies.push((canonicalization, body));
+                self.bodies.len() - 1
+            }
+        };
+        Ok(&self.bodies[index].1)
+    }
+
+    /// Hash of a body previously canonicalized with [BodyCache::canonicalized_body]
+    fn body_hash(
+        &mut self,
+        canonicalization: canonicalization::Type,
+        hash_algo: hash::HashAlgo,
+        length: Option<usize>,
+    ) -> String {
+        let key = (
+            canonicalization,
+            hash_algo == hash::HashAlgo::RsaSha1,
+            length,
+        );
+        if let Some((_, body_hash)) = self.hashes.iter().find(|(k, _)| *k == key) {
+            return body_hash.clone();
+        }
+
+        let body = &self
+            .bodies
+            .iter()
+            .find(|(c, _)| *c == key.0)
+            .expect("body not canonicalized")
+            .1;
+        let body_hash =
+            hash::hash_canonicalized_body(hash_algo, &body[..length.unwrap_or(body.len())]);
+        self.hashes.push((key, body_hash.clone()));
+        body_hash
+    }
+}
+
+/// Signs a message with several keys in one pass, typically an RSA and an
+/// Ed25519 key as recommended by RFC 8463. The body is canonicalized and
+/// hashed once for all the signatures using the same body canonicalization.
+///
+/// Each signature covers the message as given, not the signatures of the
+/// other signers.
+pub struct MultiSigner<'a> {
+    signers: Vec<DKIMSigner<'a>>,
+}
+
+impl<'a> MultiSigner<'a> {
+    /// New multi signer, using the signers in order
+    pub fn new(signers: Vec<DKIMSigner<'a>>) -> Result<Self, DKIMError> {
+        if signers.is_empty() {
+            return Err(DKIMError::BuilderError("missing required signers"));
+        }
+        Ok(Self { signers })
+    }
+
+    /// Sign a message, returning a DKIM-Signature header per signer in the
+    /// order of the signers.
+    ///
+    /// Fails if a signer uses a [SignatureProvider], use
+    /// [MultiSigner::sign_async] instead.
+    pub fn sign<'b>(&self, email: &'b mailparse::ParsedMail<'b>) -> Result<Vec<String>, DKIMError> {
+        let normalized = self.normalized_bytes(email);
+        let normalized_email = normalized.as_deref().map(parse_email).transpose()?;
+
+        let mut cache = BodyCache::default();
+        let mut normalized_cache = BodyCache::default();
+        self.signers
+            .iter()
+            .map(|signer| {
+                let (email, cache) = match &normalized_email {
+                    Some(normalized_email) if signer.normalize_line_endings => {
+                        (normalized_email, &mut normalized_cache)
+                    }
+                    _ => (email, &mut cache),
+                };
+                let private_key = signer.local_key()?;
+                let prepared = signer.prepare_with_cache(email, cache)?;
+                let signature =
+                    sign_digest(private_key, signer.hash_algo.clone(), &prepared.header_hash)?;
+                prepared.finalize(&signature)
+            })
+            .collect()
+    }
+
+    /// Sign a message with either the private keys or the signature providers,
+    /// returning a DKIM-Signature header per signer in the order of the
+    /// signers.
+    pub async fn sign_async<'b>(
+        &self,
+        email: &'b mailparse::ParsedMail<'b>,
+    ) -> Result<Vec<String>, DKIMError> {
+        let normalized = self.normalized_bytes(email);
+        let normalized_email = normalized.as_deref().map(parse_email).transpose()?;
+
+        let mut cache = BodyCache::default();
+        let mut normalized_cache = BodyCache::default();
+        let mut headers = Vec::with_capacity(self.signers.len());
+        for signer in &self.signers {
+            let (email, cache) = match &normalized_email {
+                Some(normalized_email) if signer.normalize_line_endings => {
+                    (normalized_email, &mut normalized_cache)
+                }
+                _ => (email, &mut cache),
+            };
+            let prepared = signer.prepare_with_cache(email, cache)?;
+            let signature = signer.sign_digest(&prepared.header_hash).await?;
+            headers.push(prepared.finalize(&signature)?);
+        }
+        Ok(headers)
+    }
+
+    /// The message converted to CRLF line endings, if it has bare LFs and a
+    /// signer normalizes line endings
+    fn normalized_bytes(&self, email: &mailparse::ParsedMail) -> Option<Vec<u8>> {
+        self.signers
+            .iter()
+            .find_map(|signer| signer.normalized_bytes(email))
+    }
+}
+
+fn parse_email(bytes: &[u8]) -> Result<mailparse::ParsedMail<'_>, DKIMError> {
+    mailparse::parse_mail(bytes)
+        .map_err(|err| DKIMError::FailedToSign(format!("failed to parse email: {}", err)))
+}
+
 use rsa::rand_core::{CryptoRng, RngCore};
 
 /// This is a dummy RNG for cases when we need a concrete RNG type
@@ -283,30 +1235,22 @@ impl CryptoRng for DummyRng {}
 #[cfg(test)]
 mod tests {
     use super::*;
+    use base64::engine::general_purpose;
+    use base64::Engine;
     use chrono::TimeZone;
     use rsa::pkcs1::DecodeRsaPrivateKey;
     use std::{fs, path::Path};
 
-    fn test_logger() -> slog::Logger {
-        slog::Logger::root(slog::Discard, slog::o!())
-    }
-
-    #[ignore]
     #[test]
     fn test_sign_rsa() {
         let email = mailparse::parse_mail(
-            r#"Subject: subject
-From: Sven Sauleau <sven@cloudflare.com>
-
-Hello Alice
-        "#
-            .as_bytes(),
+            "Subject: subject\r\nFrom: Sven Sauleau <sven@cloudflare.com>\r\n\r\nHello Alice\r\n"
+                .as_bytes(),
         )
         .unwrap();
 
         let private_key =
             rsa::RsaPrivateKey::read_pkcs1_pem_file(Path::new("./test/keys/2022.private")).unwrap();
-        let logger = test_logger();
         let time = chrono::Utc.with_ymd_and_hms(2021, 1, 1, 0, 0, 1).unwrap();
 
         let signer = SignerBuilder::new()
@@ -314,17 +1258,579 @@ Hello Alice
             .unwrap()
             .with_private_key(DkimPrivateKey::Rsa(private_key))
             .with_selector("s20")
-            .with_logger(&logger)
             .with_signing_domain("example.com")
             .with_time(time)
             .build()
             .unwrap();
         let header = signer.sign(&email).unwrap();
 
-        assert_eq!(header, "DKIM-Signature: v=1; a=rsa-sha256; d=example.com; s=s20; c=simple/simple; bh=frcCV1k9oG9oKj3dpUqdJg1PxRT2RSN/XKdLCPjaYaY=; h=from:subject; t=1609459201; b=ohfeeUk89mJI/nTb8cViCbOY11tYBkj0xecrpXVwPdkvLMYMZemydr01nUuruhrzaqxFcqgjdEB/alen4NygDo3Kj//GsEUksRO13Hi1aW5lfxLj7Ifux96CbKm3EEcI5rD9tXQ0LaW5nYUdqYdFVIgmU/qTtXRenMxesHhggknm1n6x7K4NsqBS+9leidXtKf8hTSCC7f4XMGFe2YQrCKHfYFBb/MTuzCHbF/CgZHKgMhBAYXMkuEwIGjh4xnR256AmJdxHN+JdrWYzkMdRiuDmYvlnUJdPWq0hD3fR1DxS5/YF6hNHMP9b1yM8eiUQVnqrbzR8C5KWJiM8JhaBcg==;")
+        // bh= and b= as computed by `openssl dgst -sha256 [-sign
+        // test/keys/2022.private]` over the body and over the From and Subject
+        // headers followed by this header with an empty b=
+        assert_eq!(
+            header,
+            concat!(
+                "DKIM-Signature: v=1; a=rsa-sha256; d=example.com; s=s20; c=simple/simple;\r\n",
+                " h=from:subject; t=1609459201;\r\n",
+                " bh=+kuxulZ7MkxvrZj1LNFkEtOUvi0M2/80KBPP0duHSfw=; b=a6q3u6YQrTzrdfAxB2WYo1jEj7\r\n",
+                " ePGfKmrs4Okq6FoAnxn5UgpkfV7kJKIvZi3ip796VG0RHqqGuD5ipfURsuv453sIo6K8wwsCtJV//\r\n",
+                " wrfpwotKcpm5cRJC5E0YJaXTps6VeJyS92P52pq2rwqVUrmgT2RkQeBKOJIeacsfBQ+/ccu/j69Wc\r\n",
+                " mua7RS7Se8jQBw7fatRthLY6UOiTF+vBqxCeEgWbeZLmde+GY0jGflBs1HrXT6LHoUFYnD5ZwokWp\r\n",
+                " zToZaZ/MmCKnaJDw+uN0WGj6fNn7Znn26wgTR/9pdNdVCrucoL+cEOrsNNjeA3R26a5JiQtLvi5/o\r\n",
+                " 1LYWlrIQ==;"
+            )
+        );
+    }
+
+    #[test]
+    fn test_sign_expiry_timestamp() {
+        let email = mailparse::parse_mail(
+            "Subject: subject\r\nFrom: Sven Sauleau <sven@cloudflare.com>\r\n\r\nHello Alice\r\n"
+                .as_bytes(),
+        )
+        .unwrap();
+        let private_key =
+            rsa::RsaPrivateKey::read_pkcs1_pem_file(Path::new("./test/keys/2022.private")).unwrap();
+        let time = chrono::Utc.with_ymd_and_hms(2021, 1, 1, 0, 0, 1).unwrap();
+
+        let builder = || {
+            SignerBuilder::new()
+                .with_signed_headers(&["From", "Subject"])
+                .unwrap()
+                .with_private_key(DkimPrivateKey::Rsa(private_key.clone()))
+                .with_selector("s20")
+                .with_signing_domain("example.com")
+                .with_time(time)
+        };
+
+        let header = builder()
+            .with_expiry_timestamp(1609459202)
+            .build()
+            .unwrap()
+            .sign(&email)
+            .unwrap();
+        assert!(header.contains("; t=1609459201; x=1609459202;"));
+
+        assert_eq!(
+            builder()
+                .with_expiry_timestamp(1609459201)
+                .build()
+                .unwrap()
+                .sign(&email)
+                .unwrap_err(),
+            DKIMError::BuilderError("expiry timestamp must be after the signing time")
+        );
+        assert!(builder()
+            .with_expiry(chrono::Duration::zero())
+            .build()
+            .is_err());
+    }
+
+    #[test]
+    fn test_sign_body_length_limit() {
+        let email = mailparse::parse_mail(
+            "Subject: subject\r\nFrom: Sven Sauleau <sven@cloudflare.com>\r\n\r\nHello Alice\r\n"
+                .as_bytes(),
+        )
+        .unwrap();
+        let private_key =
+            rsa::RsaPrivateKey::read_pkcs1_pem_file(Path::new("./test/keys/2022.private")).unwrap();
+        let builder = || {
+            SignerBuilder::new()
+                .with_signed_headers(&["From", "Subject"])
+                .unwrap()
+                .with_private_key(DkimPrivateKey::Rsa(private_key.clone()))
+                .with_selector("s20")
+                .with_signing_domain("example.com")
+                .without_folding()
+        };
+
+        let header = builder()
+            .with_body_length()
+            .build()
+            .unwrap()
+            .sign(&email)
+            .unwrap();
+        assert!(header.contains("; l=13;"));
+
+        let header = builder()
+            .with_body_length_limit(5)
+            .build()
+            .unwrap()
+            .sign(&email)
+            .unwrap();
+        assert!(header.contains("; l=5;"));
+        // sha256("Hello")
+        assert!(header.contains("; bh=GF+NsyJx/iX1Yab8k4suJkMG7DBO2lGAB9F2SCY4GWk=;"));
+
+        assert_eq!(
+            builder()
+                .with_body_length_limit(14)
+                .build()
+                .unwrap()
+                .sign(&email)
+                .unwrap_err(),
+            DKIMError::FailedToSign(
+                "body length limit (14) exceeds the canonicalized body length (13)".to_owned()
+            )
+        );
+    }
+
+    #[test]
+    fn test_sign_oversigning() {
+        let email = mailparse::parse_mail(
+            "Received: a\r\nReceived: b\r\nSubject: subject\r\nFrom: Sven Sauleau <sven@cloudflare.com>\r\n\r\nHello Alice\r\n"
+                .as_bytes(),
+        )
+        .unwrap();
+        let private_key =
+            rsa::RsaPrivateKey::read_pkcs1_pem_file(Path::new("./test/keys/2022.private")).unwrap();
+        let builder = || {
+            SignerBuilder::new()
+                .with_signed_headers(&["From", "Subject", "Received"])
+                .unwrap()
+                .with_private_key(DkimPrivateKey::Rsa(private_key.clone()))
+                .with_selector("s20")
+                .with_signing_domain("example.com")
+                .without_folding()
+        };
+
+        let header = builder()
+            .oversign_signed_headers()
+            .build()
+            .unwrap()
+            .sign(&email)
+            .unwrap();
+        assert!(header.contains("; h=from:subject:received:from:subject:received:received;"));
+
+        let header = builder()
+            .with_oversigning(vec!["Subject".to_owned(), "To".to_owned()])
+            .build()
+            .unwrap()
+            .sign(&email)
+            .unwrap();
+        assert!(header.contains("; h=from:subject:received:subject:to;"));
+    }
+
+    #[test]
+    fn test_sign_extra_tag() {
+        let email = mailparse::parse_mail(
+            "Subject: subject\r\nFrom: Sven Sauleau <sven@cloudflare.com>\r\n\r\nHello Alice\r\n"
+                .as_bytes(),
+        )
+        .unwrap();
+        let private_key =
+            rsa::RsaPrivateKey::read_pkcs1_pem_file(Path::new("./test/keys/2022.private")).unwrap();
+        let header = SignerBuilder::new()
+            .with_signed_headers(&["From", "Subject"])
+            .unwrap()
+            .with_private_key(DkimPrivateKey::Rsa(private_key))
+            .with_selector("s20")
+            .with_signing_domain("example.com")
+            .with_extra_tag("zk", "1")
+            .unwrap()
+            .without_folding()
+            .build()
+            .unwrap()
+            .sign(&email)
+            .unwrap();
+        assert!(header.contains("; zk=1; b="));
+
+        let err = |name, value| {
+            SignerBuilder::new()
+                .with_extra_tag(name, value)
+                .err()
+                .unwrap()
+        };
+        assert_eq!(err("bh", "1"), DKIMError::BuilderError("reserved tag name"));
+        assert_eq!(err("1zk", "1"), DKIMError::BuilderError("invalid tag name"));
+        assert_eq!(
+            err("zk", "a;b"),
+            DKIMError::BuilderError("invalid tag value")
+        );
+        assert_eq!(
+            SignerBuilder::new()
+                .with_extra_tag("zk", "1")
+                .unwrap()
+                .with_extra_tag("zk", "2")
+                .err()
+                .unwrap(),
+            DKIMError::BuilderError("duplicate tag name")
+        );
+    }
+
+    #[test]
+    fn test_builder_rejects_injection() {
+        let email = mailparse::parse_mail(
+            "Subject: subject\r\nFrom: Sven Sauleau <sven@cloudflare.com>\r\n\r\nHello Alice\r\n"
+                .as_bytes(),
+        )
+        .unwrap();
+        let private_key = DkimPrivateKey::from_file("./test/keys/openssl-ed25519.pem").unwrap();
+        let builder = |selector, domain, headers| {
+            SignerBuilder::new()
+                .with_signed_headers(headers)
+                .unwrap()
+                .with_private_key(private_key.clone())
+                .with_selector(selector)
+                .with_signing_domain(domain)
+                .without_folding()
+        };
+        let err = |builder: SignerBuilder| builder.build().err().unwrap();
+
+        let injection = "\r\nBcc: eve@example.net";
+        let selector = format!("s20{}", injection);
+        assert_eq!(
+            err(builder(&selector, "example.com", &["From"])),
+            DKIMError::BuilderError("invalid selector")
+        );
+        let domain = format!("example.com{}", injection);
+        for domain in [domain.as_str(), "example.com; h=to", "-example.com", "a..b"] {
+            assert_eq!(
+                err(builder("s20", domain, &["From"])),
+                DKIMError::BuilderError("invalid signing domain")
+            );
+        }
+        let header = format!("From{}", injection);
+        let headers = ["From", &header];
+        assert_eq!(
+            err(builder("s20", "example.com", &headers)),
+            DKIMError::BuilderError("invalid header name")
+        );
+        for builder in [
+            builder("s20", "example.com", &["From"]).with_copied_headers_list(vec![header.clone()]),
+            builder("s20", "example.com", &["From"]).with_oversigning(vec!["To:".to_owned()]),
+        ] {
+            assert_eq!(err(builder), DKIMError::BuilderError("invalid header name"));
+        }
+        assert_eq!(
+            SignerBuilder::new()
+                .with_extra_tag("zk", &format!("1{}", injection))
+                .err()
+                .unwrap(),
+            DKIMError::BuilderError("invalid tag value")
+        );
+
+        // Internationalized domains are signed with their A-labels
+        let header = builder("s20", "B\u{fc}cher.example", &["From"])
+            .build()
+            .unwrap()
+            .sign(&email)
+            .unwrap();
+        assert!(header.contains(" d=xn--bcher-kva.example;"), "{}", header);
+    }
+
+    #[test]
+    fn test_sign_missing_header_policy() {
+        // No Date header
+        let email = mailparse::parse_mail(
+            "Subject: subject\r\nMessage-ID: <1@cloudflare.com>\r\nFrom: Sven Sauleau <sven@cloudflare.com>\r\n\r\nHello Alice\r\n"
+                .as_bytes(),
+        )
+        .unwrap();
+        let private_key = DkimPrivateKey::from_file("./test/keys/openssl-ed25519.pem").unwrap();
+        let sign = |policy| {
+            SignerBuilder::new()
+                .with_signed_headers(&["From", "Date", "Message-ID"])
+                .unwrap()
+                .with_private_key(private_key.clone())
+                .with_selector("s20")
+                .with_signing_domain("example.com")
+                .with_missing_header_policy(policy)
+                .without_folding()
+                .build()
+                .unwrap()
+                .sign(&email)
+        };
+
+        assert!(sign(MissingHeaderPolicy::SignAsAbsent)
+            .unwrap()
+            .contains(" h=from:date:message-id;"));
+        assert!(sign(MissingHeaderPolicy::SkipSilently)
+            .unwrap()
+            .contains(" h=from:message-id;"));
+        assert_eq!(
+            sign(MissingHeaderPolicy::Error).unwrap_err(),
+            DKIMError::FailedToSign("missing signed headers: Date".to_owned())
+        );
+    }
+
+    #[test]
+    fn test_sign_recommended_headers() {
+        let email = mailparse::parse_mail(
+            "Return-Path: <sven@cloudflare.com>\r\nReceived: from a\r\nTo: alice@example.com\r\nSubject: subject\r\nList-Id: <list.example.com>\r\nX-Mailer: test\r\nFrom: Sven Sauleau <sven@cloudflare.com>\r\n\r\nHello Alice\r\n"
+                .as_bytes(),
+        )
+        .unwrap();
+        let private_key =
+            rsa::RsaPrivateKey::read_pkcs1_pem_file(Path::new("./test/keys/2022.private")).unwrap();
+        let signer = SignerBuilder::new()
+            .with_header_selection(HeaderSelection::RecommendedPresent)
+            .with_private_key(DkimPrivateKey::Rsa(private_key))
+            .with_selector("s20")
+            .with_signing_domain("example.com")
+            .without_folding()
+            .build()
+            .unwrap();
+        assert_eq!(
+            signer.selected_headers(&email).unwrap(),
+            vec!["to", "subject", "list-id", "from"]
+        );
+        let header = signer.sign(&email).unwrap();
+        assert!(header.contains("; h=to:subject:list-id:from;"));
+
+        let email = mailparse::parse_mail(
+            "To: alice@example.com\r\nSubject: subject\r\n\r\nHello Alice\r\n".as_bytes(),
+        )
+        .unwrap();
+        assert_eq!(
+            signer.sign(&email).unwrap_err(),
+            DKIMError::FailedToSign("missing From header".to_owned())
+        );
+    }
+
+    #[test]
+    fn test_sign_key_and_provider() {
+        let private_key =
+            rsa::RsaPrivateKey::read_pkcs1_pem_file(Path::new("./test/keys/2022.private")).unwrap();
+        let res = SignerBuilder::new()
+            .with_signed_headers(&["From", "Subject"])
+            .unwrap()
+            .with_private_key(DkimPrivateKey::Rsa(private_key.clone()))
+            .with_signature_provider(Arc::new(DkimPrivateKey::Rsa(private_key)))
+            .with_selector("s20")
+            .with_signing_domain("example.com")
+            .build();
+        assert!(matches!(
+            res,
+            Err(DKIMError::BuilderError(
+                "private key and signature provider are mutually exclusive"
+            ))
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_sign_async() {
+        let email = mailparse::parse_mail(
+            "Subject: subject\r\nFrom: Sven Sauleau <sven@cloudflare.com>\r\n\r\nHello Alice\r\n"
+                .as_bytes(),
+        )
+        .unwrap();
+        let file_content = fs::read("./test/keys/ed.private").unwrap();
+        let file_decoded = general_purpose::STANDARD.decode(file_content).unwrap();
+        let secret_key = ed25519_dalek::SecretKey::try_from(file_decoded).unwrap();
+        let time = chrono::Utc.with_ymd_and_hms(2021, 1, 1, 0, 0, 1).unwrap();
+
+        let builder = || {
+            SignerBuilder::new()
+                .with_signed_headers(&["From", "Subject"])
+                .unwrap()
+                .with_selector("s20")
+                .with_signing_domain("example.com")
+                .with_time(time)
+        };
+        let signing_key = || ed25519_dalek::SigningKey::from_bytes(&secret_key);
+
+        // The in-memory key gives the same signature with both paths
+        let header = builder()
+            .with_private_key(DkimPrivateKey::Ed25519(signing_key()))
+            .build()
+            .unwrap()
+            .sign(&email)
+            .unwrap();
+        let async_header = builder()
+            .with_private_key(DkimPrivateKey::Ed25519(signing_key()))
+            .build()
+            .unwrap()
+            .sign_async(&email)
+            .await
+            .unwrap();
+        let provider_header = builder()
+            .with_signature_provider(Arc::new(DkimPrivateKey::Ed25519(signing_key())))
+            .build()
+            .unwrap()
+            .sign_async(&email)
+            .await
+            .unwrap();
+        assert_eq!(header, async_header);
+        assert_eq!(header, provider_header);
+    }
+
+    #[test]
+    fn test_sign_clock() {
+        let email = mailparse::parse_mail(
+            "Subject: subject\r\nFrom: Sven Sauleau <sven@cloudflare.com>\r\n\r\nHello Alice\r\n"
+                .as_bytes(),
+        )
+        .unwrap();
+        let private_key =
+            rsa::RsaPrivateKey::read_pkcs1_pem_file(Path::new("./test/keys/2022.private")).unwrap();
+        let time = chrono::Utc.with_ymd_and_hms(2021, 1, 1, 0, 0, 1).unwrap();
+
+        let header = SignerBuilder::new()
+            .with_signed_headers(&["From", "Subject"])
+            .unwrap()
+            .with_private_key(DkimPrivateKey::Rsa(private_key))
+            .with_selector("s20")
+            .with_signing_domain("example.com")
+            .with_clock(Arc::new(crate::FixedClock(time.naive_utc())))
+            .with_expiry(chrono::Duration::hours(3))
+            .without_folding()
+            .build()
+            .unwrap()
+            .sign(&email)
+            .unwrap();
+        assert!(header.contains("; t=1609459201; x=1609470001;"));
+    }
+
+    #[tokio::test]
+    async fn test_sign_self_check() {
+        let email = mailparse::parse_mail(
+            "Subject: subject\r\nFrom: Sven Sauleau <sven@cloudflare.com>\r\n\r\nHello Alice\r\n"
+                .as_bytes(),
+        )
+        .unwrap();
+        let rsa_key = || {
+            DkimPrivateKey::Rsa(
+                rsa::RsaPrivateKey::read_pkcs1_pem_file(Path::new("./test/keys/2022.private"))
+                    .unwrap(),
+            )
+        };
+        let file_content = fs::read("./test/keys/ed.private").unwrap();
+        let file_decoded = general_purpose::STANDARD.decode(file_content).unwrap();
+        let secret_key = ed25519_dalek::SecretKey::try_from(file_decoded).unwrap();
+        let ed25519_key =
+            || DkimPrivateKey::Ed25519(ed25519_dalek::SigningKey::from_bytes(&secret_key));
+        let time = chrono::Utc.with_ymd_and_hms(2021, 1, 1, 0, 0, 1).unwrap();
+
+        let builder = || {
+            SignerBuilder::new()
+                .with_signed_headers(&["From", "Subject"])
+                .unwrap()
+                .with_selector("s20")
+                .with_signing_domain("example.com")
+                .with_self_check(true)
+        };
+
+        // The timestamps are checked against the signing time
+        builder()
+            .with_private_key(rsa_key())
+            .with_clock(Arc::new(crate::FixedClock(time.naive_utc())))
+            .with_expiry(chrono::Duration::hours(3))
+            .build()
+            .unwrap()
+            .sign(&email)
+            .unwrap();
+        builder()
+            .with_private_key(ed25519_key())
+            .with_header_canonicalization(canonicalization::Type::Relaxed)
+            .with_body_canonicalization(canonicalization::Type::Relaxed)
+            .with_body_length()
+            .build()
+            .unwrap()
+            .sign(&email)
+            .unwrap();
+
+        // A signature that doesn't match the key is rejected
+        struct MismatchedProvider {
+            private_key: DkimPrivateKey,
+            public_key: Option<DkimPublicKey>,
+        }
+        impl SignatureProvider for MismatchedProvider {
+            fn algorithm(&self) -> hash::HashAlgo {
+                SignatureProvider::algorithm(&self.private_key)
+            }
+
+            fn sign<'a>(
+                &'a self,
+                algo: hash::HashAlgo,
+                digest: &'a [u8],
+            ) -> BoxFuture<'a, Result<Vec<u8>, DKIMError>> {
+                SignatureProvider::sign(&self.private_key, algo, digest)
+            }
+
+            fn public_key(&self) -> Option<DkimPublicKey> {
+                self.public_key.clone()
+            }
+        }
+        let other_key = DkimPrivateKey::from_file("./test/keys/openssl-ed25519.pem").unwrap();
+        let res = builder()
+            .with_signature_provider(Arc::new(MismatchedProvider {
+                private_key: ed25519_key(),
+                public_key: Some(other_key.public_key()),
+            }))
+            .build()
+            .unwrap()
+            .sign_async(&email)
+            .await;
+        assert_eq!(
+            res.unwrap_err(),
+            DKIMError::SelfCheckFailed(Box::new(DKIMError::SignatureDidNotVerify))
+        );
+
+        let res = builder()
+            .with_signature_provider(Arc::new(MismatchedProvider {
+                private_key: ed25519_key(),
+                public_key: None,
+            }))
+            .build();
+        assert!(matches!(
+            res,
+            Err(DKIMError::BuilderError(
+                "self check requires the public key of the signature provider"
+            ))
+        ));
+    }
+
+    #[test]
+    fn test_sign_timestamp() {
+        let email = mailparse::parse_mail(
+            "Subject: subject\r\nFrom: Sven Sauleau <sven@cloudflare.com>\r\n\r\nHello Alice\r\n"
+                .as_bytes(),
+        )
+        .unwrap();
+        let file_content = fs::read("./test/keys/ed.private").unwrap();
+        let file_decoded = general_purpose::STANDARD.decode(file_content).unwrap();
+        let secret_key = ed25519_dalek::SecretKey::try_from(file_decoded).unwrap();
+        let builder = || {
+            SignerBuilder::new()
+                .with_signed_headers(&["From", "Subject"])
+                .unwrap()
+                .with_private_key(DkimPrivateKey::Ed25519(
+                    ed25519_dalek::SigningKey::from_bytes(&secret_key),
+                ))
+                .with_selector("brisbane")
+                .with_signing_domain("example.com")
+                .without_folding()
+        };
+
+        // A pinned timestamp makes Ed25519 signatures reproducible
+        let sign_pinned = || {
+            builder()
+                .with_timestamp(1609459201)
+                .unwrap()
+                .build()
+                .unwrap()
+                .sign(&email)
+                .unwrap()
+        };
+        let header = sign_pinned();
+        assert_eq!(header, sign_pinned());
+        assert!(header.contains("; t=1609459201;"));
+
+        let header = builder()
+            .without_timestamp()
+            .with_expiry(chrono::Duration::hours(1))
+            .build()
+            .unwrap()
+            .sign(&email)
+            .unwrap();
+        assert!(!header.contains("; t="));
+        assert!(header.contains("; x="));
+
+        assert!(builder().with_timestamp(i64::MAX).is_err());
     }
 
-    #[ignore]
     #[test]
     fn test_sign_ed25519() {
         let raw_email = r#"From: Joe SixPack <joe@football.example.com>
@@ -347,7 +1853,6 @@ Joe."#
 
         let signing_key = ed25519_dalek::SigningKey::from_bytes(&secret_key);
 
-        let logger = test_logger();
         let time = chrono::Utc
             .with_ymd_and_hms(2018, 6, 10, 13, 38, 29)
             .unwrap();
@@ -368,13 +1873,14 @@ Joe."#
             .with_body_canonicalization(canonicalization::Type::Relaxed)
             .with_header_canonicalization(canonicalization::Type::Relaxed)
             .with_selector("brisbane")
-            .with_logger(&logger)
             .with_signing_domain("football.example.com")
             .with_time(time)
             .build()
             .unwrap();
         let header = signer.sign(&email).unwrap();
 
-        assert_eq!(header, "DKIM-Signature: v=1; a=ed25519-sha256; d=football.example.com; s=brisbane; c=relaxed/relaxed; bh=2jUSOH9NhtVGCQWNr9BrIAPreKQjO6Sn7XIkfJVOzv8=; h=from:to:subject:date:message-id:from:subject:date; t=1528637909; b=wITr2H3sBuBfMsnUwlRTO7Oq/C/jd2vubDm50DrXtMFEBLRiz9GfrgCozcg764+gYqWXV3Snd1ynYh8sJ5BXBg==;")
+        // b= as computed by `openssl pkeyutl -sign -rawin` with the key over
+        // the SHA-256 digest of the relaxed canonicalized headers (RFC 8463)
+        assert_eq!(header, "DKIM-Signature: v=1; a=ed25519-sha256; d=football.example.com; s=brisbane;\r\n c=relaxed/relaxed; h=from:to:subject:date:message-id:from:subject:date;\r\n t=1528637909; bh=2jUSOH9NhtVGCQWNr9BrIAPreKQjO6Sn7XIkfJVOzv8=; b=3uVkGmy+ZskC\r\n 9xmXxqXHLWuI4epredHDek8cnghZ14JtsPQ2xLjwnmwlpidQcO59i5rz2a1plSSyrlOrHyzgAA==;")
     }
 }
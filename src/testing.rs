@@ -0,0 +1,116 @@
+//! Keys, key records and signed messages for the tests of code using cfdkim.
+//!
+//! This module is meant for tests only and not for production key
+//! generation: the keys come from the thread-local random generator of
+//! `rand`, are never zeroized and are only as safe as the test setup using
+//! them. Generate production keys with a dedicated tool like opendkim-genkey
+//! or OpenSSL.
+
+use crate::dns::StaticLookup;
+use crate::public_key::KeyType;
+use crate::{canonicalization, DkimPrivateKey, DkimPublicKey, SignerBuilder, DNS_NAMESPACE};
+use rand::RngCore;
+
+/// Size of the generated RSA keys, the one recommended by RFC 8301
+const RSA_BITS: usize = 2048;
+
+/// Generate a key pair of the given type, see the [module](self)
+/// documentation
+pub fn generate_keypair(key_type: KeyType) -> (DkimPrivateKey, DkimPublicKey) {
+    let mut rng = rand::thread_rng();
+    let private_key = match key_type {
+        KeyType::Rsa => DkimPrivateKey::Rsa(
+            rsa::RsaPrivateKey::new(&mut rng, RSA_BITS).expect("failed to generate RSA key"),
+        ),
+        KeyType::Ed25519 => {
+            let mut secret_key = ed25519_dalek::SecretKey::default();
+            rng.fill_bytes(&mut secret_key);
+            DkimPrivateKey::Ed25519(ed25519_dalek::SigningKey::from_bytes(&secret_key))
+        }
+    };
+    let public_key = private_key.public_key();
+    (private_key, public_key)
+}
+
+/// Value of the key TXT record to publish for a public key, without optional
+/// tags
+pub fn dns_record_for(public_key: &DkimPublicKey) -> String {
+    crate::public_key::to_dns_record(public_key, &Default::default())
+}
+
+/// Build a message from its headers and body, with CRLF line endings, and
+/// sign all its headers with relaxed canonicalization. Returns the message
+/// with the DKIM-Signature header.
+///
+/// Panics if the message can't be signed, like when it has no From header.
+pub fn sign_test_message(
+    body: &str,
+    headers: &[(&str, &str)],
+    private_key: &DkimPrivateKey,
+    selector: &str,
+    domain: &str,
+) -> Vec<u8> {
+    let mut email = String::new();
+    for (name, value) in headers {
+        email += &format!("{}: {}\r\n", name, value);
+    }
+    email += "\r\n";
+    email += body;
+
+    let names: Vec<&str> = headers.iter().map(|(name, _)| *name).collect();
+    SignerBuilder::new()
+        .with_signed_headers(&names)
+        .expect("invalid signed headers")
+        .with_private_key(private_key.clone())
+        .with_header_canonicalization(canonicalization::Type::Relaxed)
+        .with_body_canonicalization(canonicalization::Type::Relaxed)
+        .with_selector(selector)
+        .with_signing_domain(domain)
+        .build()
+        .expect("failed to build the signer")
+        .sign_and_attach(email.as_bytes())
+        .expect("failed to sign the test message")
+}
+
+/// [StaticLookup] answering the key record of each selector, domain and
+/// public key, see [dns_record_for]
+pub fn static_lookup(keys: &[(&str, &str, &DkimPublicKey)]) -> StaticLookup {
+    let mut lookup = StaticLookup::default();
+    for (selector, domain, public_key) in keys {
+        lookup.insert(
+            &format!("{}.{}.{}", selector, DNS_NAMESPACE, domain),
+            &dns_record_for(public_key),
+        );
+    }
+    lookup
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{verify_email_with_resolver, Summary};
+    use std::sync::Arc;
+
+    #[tokio::test]
+    async fn test_testing_utilities() {
+        for key_type in [KeyType::Rsa, KeyType::Ed25519] {
+            let (private_key, public_key) = generate_keypair(key_type);
+            assert_eq!(public_key.key_type(), key_type);
+            assert_eq!(private_key.public_key(), public_key);
+
+            let signed_email = sign_test_message(
+                "Hello Alice\r\n",
+                &[("From", "bob@example.com"), ("Subject", "Hello")],
+                &private_key,
+                "s1",
+                "example.com",
+            );
+            let email = mailparse::parse_mail(&signed_email).unwrap();
+            let lookup = Arc::new(static_lookup(&[("s1", "example.com", &public_key)]));
+            let res = verify_email_with_resolver("example.com", &email, lookup)
+                .await
+                .unwrap();
+            assert_eq!(res.outcome(), Summary::Pass, "{:?}", key_type);
+        }
+    }
+}
@@ -0,0 +1,352 @@
+//! Bindings of the high-level API for JavaScript, with the `wasm-bindings`
+//! feature. The DNS queries are done by the caller, typically with fetch:
+//!
+//! ```js
+//! const [url] = dns_query_urls(email);
+//! const response = await fetch(url, { headers: { accept: "application/dns-json" } });
+//! const result = verify_with_dns_response(email, await response.text());
+//! ```
+//!
+//! Failures throw an `Error` with the `code` of the [DKIMError] (see the
+//! `serde` feature) and its `classification`.
+
+// Only the bindings and the tests use the functions
+#![cfg_attr(not(target_arch = "wasm32"), allow(dead_code))]
+
+use crate::dns::doh_json::{self, DnsQuery, DohProvider};
+use crate::dns::{Lookup, LookupError, TxtAnswer, TxtRecord};
+use crate::{verify_raw_email, DKIMError, DKIMResult, SignatureSelector};
+use futures::future::BoxFuture;
+use futures::FutureExt;
+use std::sync::Arc;
+
+/// [Lookup] answering from the DoH JSON responses to the queries of the key
+/// records
+struct ResponseLookup {
+    answers: Vec<(String, Result<TxtAnswer, LookupError>)>,
+}
+
+impl ResponseLookup {
+    /// Parse the response of each query, found by the URL of the query
+    fn new(queries: &[DnsQuery], responses: &[(&str, &str)]) -> Self {
+        let answers = queries
+            .iter()
+            .filter_map(|query| {
+                let (_, response) = responses.iter().find(|(url, _)| *url == query.url)?;
+                let name = query.name();
+                let answer = doh_json::parse_response(&name, response);
+                Some((name, answer))
+            })
+            .collect();
+        Self { answers }
+    }
+
+    fn answer(&self, name: &str) -> Result<TxtAnswer, LookupError> {
+        let name = name.trim_end_matches('.');
+        self.answers
+            .iter()
+            .find(|(owner, _)| owner.eq_ignore_ascii_case(name))
+            .map(|(_, answer)| answer.clone())
+            .unwrap_or_else(|| {
+                Err(LookupError::Other(DKIMError::KeyUnavailable(format!(
+                    "no DNS response for {}",
+                    name
+                ))))
+            })
+    }
+}
+
+impl Lookup for ResponseLookup {
+    fn lookup_txt<'a>(&'a self, name: &'a str) -> BoxFuture<'a, Result<Vec<String>, DKIMError>> {
+        let res = self
+            .answer(name)
+            .map(|answer| answer.records.iter().map(TxtRecord::text).collect())
+            .map_err(DKIMError::from);
+        Box::pin(futures::future::ready(res))
+    }
+
+    fn lookup_txt_answer<'a>(
+        &'a self,
+        name: &'a str,
+    ) -> BoxFuture<'a, Result<TxtAnswer, LookupError>> {
+        Box::pin(futures::future::ready(self.answer(name)))
+    }
+}
+
+/// Verify the email with the DoH JSON responses to the queries of
+/// [doh_json::get_dns_query_urls], given with the URL of their query
+pub(crate) fn verify_with_dns_responses(
+    email_bytes: &[u8],
+    from_domain: &str,
+    provider: &DohProvider,
+    responses: &[(&str, &str)],
+) -> Result<DKIMResult, DKIMError> {
+    let queries = doh_json::get_dns_query_urls(email_bytes, provider)?;
+    let resolver = Arc::new(ResponseLookup::new(&queries, responses));
+
+    // The lookup answers immediately, the verification never waits
+    verify_raw_email(from_domain, email_bytes, resolver)
+        .now_or_never()
+        .unwrap_or_else(|| {
+            Err(DKIMError::UnknownInternalError(
+                "verification didn't complete".to_owned(),
+            ))
+        })
+}
+
+/// Verify the first signature of the email with the DoH JSON response to the
+/// query of its key, the first of [dns_query_urls]
+pub(crate) fn verify_with_dns_response(
+    email_bytes: &[u8],
+    dns_json: &str,
+) -> Result<DKIMResult, DKIMError> {
+    let provider = DohProvider::Google;
+    let query = doh_json::get_dns_query_urls(email_bytes, &provider)?
+        .into_iter()
+        .find(|query| query.index == 0)
+        .ok_or_else(|| DKIMError::SignatureNotFound(SignatureSelector::Index(0).to_string()))?;
+    verify_with_dns_responses(
+        email_bytes,
+        &query.domain,
+        &provider,
+        &[(&query.url, dns_json)],
+    )
+}
+
+/// Google Public DNS URLs of the key queries of every signature, in header
+/// order
+pub(crate) fn dns_query_urls(email_bytes: &[u8]) -> Result<Vec<String>, DKIMError> {
+    Ok(
+        doh_json::get_dns_query_urls(email_bytes, &DohProvider::Google)?
+            .into_iter()
+            .map(|query| query.url)
+            .collect(),
+    )
+}
+
+#[cfg(target_arch = "wasm32")]
+mod bindings {
+    use crate::dns::doh_json::{self, DohProvider};
+    use crate::DKIMError;
+    use wasm_bindgen::prelude::*;
+
+    /// JavaScript `Error` with the code and classification of the error
+    fn js_error(err: DKIMError) -> JsValue {
+        let error = js_sys::Error::new(&err.to_string());
+        let code = serde_json::to_value(&err)
+            .ok()
+            .and_then(|value| value.get("code")?.as_str().map(str::to_owned));
+        let classification = match err.classification() {
+            crate::ErrorClass::Permfail => "permfail",
+            crate::ErrorClass::Tempfail => "tempfail",
+        };
+        let _ = js_sys::Reflect::set(&error, &"code".into(), &code.into());
+        let _ = js_sys::Reflect::set(&error, &"classification".into(), &classification.into());
+        error.into()
+    }
+
+    /// Serialize a value with its JSON representation
+    fn to_js<T: serde::Serialize>(value: &T) -> Result<JsValue, JsValue> {
+        let json = serde_json::to_string(value)
+            .map_err(|err| js_error(DKIMError::UnknownInternalError(err.to_string())))?;
+        js_sys::JSON::parse(&json)
+    }
+
+    /// Verify the first signature of the email with the DoH JSON response to
+    /// the query of its key, returning the serialized `DKIMResult`
+    #[wasm_bindgen]
+    pub fn verify_with_dns_response(
+        email_bytes: &[u8],
+        dns_json: &str,
+    ) -> Result<JsValue, JsValue> {
+        let result = super::verify_with_dns_response(email_bytes, dns_json).map_err(js_error)?;
+        to_js(&result)
+    }
+
+    /// Canonicalized headers and body of the first signature and its decoded
+    /// signature, as `{ headers, body, signature }` of Uint8Arrays
+    #[wasm_bindgen]
+    pub fn canonicalize(email_bytes: &[u8]) -> Result<JsValue, JsValue> {
+        let (headers, body, signature) =
+            crate::canonicalize_signed_email(email_bytes).map_err(js_error)?;
+        let object = js_sys::Object::new();
+        for (key, value) in [
+            ("headers", headers),
+            ("body", body),
+            ("signature", signature),
+        ] {
+            let array = js_sys::Uint8Array::from(value.as_slice());
+            js_sys::Reflect::set(&object, &key.into(), &array)?;
+        }
+        Ok(object.into())
+    }
+
+    /// Google Public DNS URLs of the key queries of every signature, in
+    /// header order
+    #[wasm_bindgen]
+    pub fn dns_query_urls(email_bytes: &[u8]) -> Result<Vec<String>, JsValue> {
+        super::dns_query_urls(email_bytes).map_err(js_error)
+    }
+
+    /// Resolver of the given endpoint, Google Public DNS by default
+    fn provider(endpoint: Option<String>) -> DohProvider {
+        endpoint.map_or(DohProvider::Google, DohProvider::Custom)
+    }
+
+    /// Key queries of every signature, as `{ index, selector, domain, url }`
+    #[wasm_bindgen]
+    pub fn dns_queries(email_bytes: &[u8], endpoint: Option<String>) -> Result<JsValue, JsValue> {
+        let queries =
+            doh_json::get_dns_query_urls(email_bytes, &provider(endpoint)).map_err(js_error)?;
+        to_js(&queries)
+    }
+
+    /// Verify the email with the responses to the queries of
+    /// [dns_queries], `responses[i]` being the response of `urls[i]`
+    #[wasm_bindgen]
+    pub fn verify_with_dns_responses(
+        email_bytes: &[u8],
+        from_domain: &str,
+        endpoint: Option<String>,
+        urls: Vec<String>,
+        responses: Vec<String>,
+    ) -> Result<JsValue, JsValue> {
+        let responses: Vec<(&str, &str)> = urls
+            .iter()
+            .map(String::as_str)
+            .zip(responses.iter().map(String::as_str))
+            .collect();
+        let result = super::verify_with_dns_responses(
+            email_bytes,
+            from_domain,
+            &provider(endpoint),
+            &responses,
+        )
+        .map_err(js_error)?;
+        to_js(&result)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::canonicalization::Type;
+    use crate::{DkimPrivateKey, SignerBuilder};
+    use rsa::pkcs1::DecodeRsaPrivateKey;
+
+    /// Signed email and the key record of its signature
+    fn signed_email() -> (String, String) {
+        sign(
+            "s1",
+            "example.com",
+            "Subject: Hello\r\nFrom: Alice <alice@example.com>\r\n\r\nHello\r\n",
+        )
+    }
+
+    fn sign(selector: &str, domain: &str, raw_email: &str) -> (String, String) {
+        let private_key = DkimPrivateKey::Rsa(
+            rsa::RsaPrivateKey::read_pkcs1_pem_file("./test/keys/2022.private").unwrap(),
+        );
+        let email = mailparse::parse_mail(raw_email.as_bytes()).unwrap();
+        let record = private_key.to_dns_record();
+        let header = SignerBuilder::new()
+            .with_signed_headers(&["From", "Subject"])
+            .unwrap()
+            .with_private_key(private_key)
+            .with_header_canonicalization(Type::Relaxed)
+            .with_selector(selector)
+            .with_signing_domain(domain)
+            .build()
+            .unwrap()
+            .sign(&email)
+            .unwrap();
+        (format!("{}\r\n{}", header, raw_email), record)
+    }
+
+    fn dns_response(name: &str, record: &str) -> String {
+        format!(
+            r#"{{"Status":0,"AD":false,"Question":[{{"name":"{0}.","type":16}}],"Answer":[{{"name":"{0}.","type":16,"TTL":300,"data":"\"{1}\""}}]}}"#,
+            name, record
+        )
+    }
+
+    #[test]
+    fn test_verify_with_dns_response() {
+        let (email, record) = signed_email();
+        assert_eq!(
+            dns_query_urls(email.as_bytes()).unwrap(),
+            vec!["https://dns.google/resolve?name=s1._domainkey.example.com&type=TXT"]
+        );
+
+        let name = "s1._domainkey.example.com";
+        let response = dns_response(name, &record);
+        let result = verify_with_dns_response(email.as_bytes(), &response).unwrap();
+        assert_eq!(result.with_detail(), "pass");
+
+        // The response of another query is rejected
+        let response = dns_response("s2._domainkey.example.com", &record);
+        let result = verify_with_dns_response(email.as_bytes(), &response).unwrap();
+        assert_eq!(
+            result.error().as_ref().map(DKIMError::root_cause),
+            Some(&DKIMError::KeyUnavailable(
+                "DoH response for s2._domainkey.example.com instead of s1._domainkey.example.com"
+                    .to_owned()
+            ))
+        );
+
+        let response = r#"{"Status":3}"#;
+        let result = verify_with_dns_response(email.as_bytes(), response).unwrap();
+        assert_eq!(
+            result.error().as_ref().map(DKIMError::root_cause),
+            Some(&DKIMError::NoKeyForSignature)
+        );
+
+        assert_eq!(
+            verify_with_dns_response(b"Subject: Hello\r\n\r\nHello\r\n", response).err(),
+            Some(DKIMError::SignatureNotFound(
+                SignatureSelector::Index(0).to_string()
+            ))
+        );
+    }
+
+    #[test]
+    fn test_verify_with_dns_responses() {
+        let (email, _) = signed_email();
+        let (email, record) = sign("s2", "example.net", &email);
+        let provider = DohProvider::Cloudflare;
+        let queries = doh_json::get_dns_query_urls(email.as_bytes(), &provider).unwrap();
+        assert_eq!(queries.len(), 2);
+        assert_eq!(
+            (queries[0].index, queries[0].name()),
+            (0, "s2._domainkey.example.net".to_owned())
+        );
+        assert_eq!(queries[1].name(), "s1._domainkey.example.com");
+
+        // The responses are matched with their query by URL, in any order
+        let responses = [
+            dns_response("s1._domainkey.example.com", &record),
+            dns_response("s2._domainkey.example.net", &record),
+        ];
+        let responses = [
+            (queries[1].url.as_str(), responses[0].as_str()),
+            (queries[0].url.as_str(), responses[1].as_str()),
+        ];
+        for domain in ["example.com", "example.net"] {
+            let result =
+                verify_with_dns_responses(email.as_bytes(), domain, &provider, &responses).unwrap();
+            assert_eq!(result.with_detail(), "pass");
+            assert_eq!(result.domain_used(), domain);
+        }
+
+        // A missing response fails the signature
+        let result =
+            verify_with_dns_responses(email.as_bytes(), "example.com", &provider, &responses[1..])
+                .unwrap();
+        assert_eq!(
+            result.error().as_ref().map(DKIMError::root_cause),
+            Some(&DKIMError::KeyUnavailable(
+                "no DNS response for s1._domainkey.example.com".to_owned()
+            ))
+        );
+    }
+}